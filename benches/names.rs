@@ -0,0 +1,100 @@
+//! Cost of the peer and group names on the send path: validating and allocating the name at
+//! every call versus the handles of Ipcon::unicast_target() and Ipcon::group_publisher().
+//!
+//! The send benchmarks need the IPCON kernel module and are skipped without it.
+//!
+//! ```sh
+//! cargo bench --bench names
+//! ```
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use ipcon_sys::ipcon::{Ipcon, IPF_RCV_IF, IPF_SND_IF};
+use ipcon_sys::ipcon_error::IpconError;
+use ipcon_sys::ipcon_name::{AsGroupName, AsPeerName, GroupName, PeerName};
+use std::sync::Arc;
+
+const PAYLOAD: &[u8] = b"0123456789abcdef";
+
+fn resolve(c: &mut Criterion) {
+    let mut g = c.benchmark_group("resolve");
+    let peer = PeerName::new("bench.receiver").unwrap();
+    let group = GroupName::new("bench.group").unwrap();
+
+    g.bench_function("peer/str", |b| {
+        b.iter(|| {
+            black_box("bench.receiver")
+                .as_peer_name()
+                .map(|p| p.as_c_str().as_ptr())
+        })
+    });
+    g.bench_function("peer/handle", |b| {
+        b.iter(|| {
+            black_box(&peer)
+                .as_peer_name()
+                .map(|p| p.as_c_str().as_ptr())
+        })
+    });
+    g.bench_function("group/str", |b| {
+        b.iter(|| {
+            black_box("bench.group")
+                .as_group_name()
+                .map(|g| g.as_c_str().as_ptr())
+        })
+    });
+    g.bench_function("group/handle", |b| {
+        b.iter(|| {
+            black_box(&group)
+                .as_group_name()
+                .map(|g| g.as_c_str().as_ptr())
+        })
+    });
+    g.finish();
+}
+
+fn send(c: &mut Criterion) {
+    let (sender, receiver) = match (
+        Ipcon::new(Some("bench.sender"), Some(IPF_SND_IF)),
+        Ipcon::new(Some("bench.receiver"), Some(IPF_RCV_IF)),
+    ) {
+        (Ok(s), Ok(r)) => (s, Arc::new(r)),
+        _ => {
+            eprintln!("IPCON kernel module not available, send benchmarks skipped.");
+            return;
+        }
+    };
+
+    /* Drain the receiver so that the unicast sends never wait for room. */
+    let drain = {
+        let receiver = receiver.clone();
+        std::thread::spawn(move || loop {
+            if let Err(e) = receiver.receive_msg() {
+                if *e.current_context() == IpconError::Cancelled {
+                    break;
+                }
+            }
+        })
+    };
+
+    sender.register_group("bench.group").unwrap();
+    let target = sender.unicast_target("bench.receiver").unwrap();
+    let publisher = sender.group_publisher("bench.group").unwrap();
+
+    let mut g = c.benchmark_group("send");
+    g.bench_function("unicast/str", |b| {
+        b.iter(|| sender.send_unicast_msg("bench.receiver", PAYLOAD))
+    });
+    g.bench_function("unicast/target", |b| b.iter(|| target.send(PAYLOAD)));
+    g.bench_function("multicast/str", |b| {
+        b.iter(|| sender.send_multicast("bench.group", PAYLOAD, false))
+    });
+    g.bench_function("multicast/publisher", |b| {
+        b.iter(|| publisher.send(PAYLOAD, false))
+    });
+    g.finish();
+
+    receiver.close();
+    let _ = drain.join();
+}
+
+criterion_group!(benches, resolve, send);
+criterion_main!(benches);
@@ -0,0 +1,249 @@
+//! Cost of ipcon-sys on top of the transport: unicast round-trip latency, multicast fan-out,
+//! payload sizes, sync versus AsyncIpcon, and the decoding of the messages read from libipcon.
+//!
+//! Every benchmark runs on the loopback transport of ipcon_loopback, through Ipcon as the kernel
+//! benchmarks do: only the libipcon calls are replaced, the statistics, the shutdown handle and
+//! the fd polling of Ipcon are measured as well. The same benchmarks run on the IPCON kernel
+//! module when it is present, and are skipped otherwise. AsyncIpcon is measured with the `async`
+//! feature:
+//!
+//! ```sh
+//! cargo bench --bench throughput --features async
+//! ```
+
+use criterion::measurement::WallTime;
+use criterion::{
+    black_box, criterion_group, criterion_main, BenchmarkGroup, BenchmarkId, Criterion, Throughput,
+};
+use error_stack::Result;
+use ipcon_sys::ipcon::{Ipcon, Transport, IPF_DEFAULT};
+use ipcon_sys::ipcon_error::IpconError;
+use ipcon_sys::ipcon_loopback::LoopbackBus;
+use ipcon_sys::ipcon_msg::{IpconKevent, IpconMsg, LibIpconMsg, IPCON_MAX_PAYLOAD_LEN};
+use ipcon_sys::ipcon_name::{GroupName, PeerName};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+const PAYLOAD_SIZES: [usize; 4] = [0, 64, 512, IPCON_MAX_PAYLOAD_LEN];
+const FANOUT: [usize; 3] = [1, 4, 16];
+const FANOUT_PAYLOAD: usize = 64;
+
+fn transports() -> Vec<(&'static str, Transport)> {
+    let mut transports = vec![("loopback", Transport::Loopback(LoopbackBus::new()))];
+
+    if Ipcon::new(None, Some(IPF_DEFAULT)).is_ok() {
+        transports.push(("kernel", Transport::Kernel));
+    } else {
+        eprintln!("IPCON kernel module not available, kernel benchmarks skipped.");
+    }
+
+    transports
+}
+
+fn peer(t: &Transport, name: &str) -> Result<Ipcon, IpconError> {
+    t.peer(Some(name), Some(IPF_DEFAULT))
+}
+
+/* Call f with every user message received by the peer until it is closed. */
+fn serve<F>(peer: Arc<Ipcon>, mut f: F) -> JoinHandle<()>
+where
+    F: FnMut(&Ipcon, Vec<u8>) + Send + 'static,
+{
+    std::thread::spawn(move || loop {
+        match peer.receive_msg() {
+            Ok(IpconMsg::IpconMsgUser(body)) => f(&peer, body.buf),
+            Ok(_) => {}
+            Err(e) if *e.current_context() == IpconError::Cancelled => break,
+            Err(e) => eprintln!("Receive failed: {:?}", e),
+        }
+    })
+}
+
+fn decode(c: &mut Criterion) {
+    let mut g = c.benchmark_group("decode");
+
+    for size in PAYLOAD_SIZES {
+        let buf = vec![0x5a; size];
+        let normal = LibIpconMsg::normal("bench.client", &buf).unwrap();
+        let group = LibIpconMsg::group("bench.server", "bench.group", &buf).unwrap();
+
+        g.throughput(Throughput::Bytes(size as u64));
+        g.bench_with_input(BenchmarkId::new("unicast", size), &normal, |b, m| {
+            b.iter(|| Result::<IpconMsg, IpconError>::from(black_box(*m)))
+        });
+        g.bench_with_input(BenchmarkId::new("multicast", size), &group, |b, m| {
+            b.iter(|| Result::<IpconMsg, IpconError>::from(black_box(*m)))
+        });
+    }
+
+    let kevent = LibIpconMsg::kevent(IpconKevent::new_group_added("bench.server", "g").unwrap());
+    g.throughput(Throughput::Elements(1));
+    g.bench_function("kevent", |b| {
+        b.iter(|| Result::<IpconMsg, IpconError>::from(black_box(kevent)))
+    });
+    g.finish();
+}
+
+fn unicast_rtt(
+    g: &mut BenchmarkGroup<WallTime>,
+    name: &str,
+    t: &Transport,
+) -> Result<(), IpconError> {
+    let client = peer(t, "bench.client")?;
+    let server = Arc::new(peer(t, "bench.echo")?);
+    let client_name = PeerName::new("bench.client")?;
+    let server_name = PeerName::new("bench.echo")?;
+
+    let echo = serve(server.clone(), move |server, buf| {
+        if let Err(e) = server.send_unicast_msg(&client_name, &buf) {
+            eprintln!("Echo failed: {:?}", e);
+        }
+    });
+
+    for size in PAYLOAD_SIZES {
+        let buf = vec![0x5a; size];
+
+        g.throughput(Throughput::Bytes(size as u64));
+        g.bench_with_input(BenchmarkId::new(name, size), &buf, |b, buf| {
+            b.iter(|| {
+                client.send_unicast_msg(&server_name, buf).unwrap();
+                client.receive_msg().unwrap()
+            })
+        });
+    }
+
+    server.close();
+    let _ = echo.join();
+    Ok(())
+}
+
+fn multicast_fanout(
+    g: &mut BenchmarkGroup<WallTime>,
+    name: &str,
+    t: &Transport,
+) -> Result<(), IpconError> {
+    let publisher = peer(t, "bench.publisher")?;
+    let publisher_name = PeerName::new("bench.publisher")?;
+    let group = GroupName::new("bench.fanout")?;
+    let buf = vec![0x5a; FANOUT_PAYLOAD];
+    publisher.register_group(&group)?;
+
+    for n in FANOUT {
+        let (ack, acks) = mpsc::channel();
+        let mut subscribers = Vec::new();
+
+        for i in 0..n {
+            let subscriber = Arc::new(peer(t, &format!("bench.sub{}", i))?);
+            subscriber.join_group(&publisher_name, &group)?;
+
+            let ack = ack.clone();
+            let thread = serve(subscriber.clone(), move |_, _| {
+                let _ = ack.send(());
+            });
+            subscribers.push((subscriber, thread));
+        }
+
+        g.throughput(Throughput::Elements(n as u64));
+        g.bench_with_input(BenchmarkId::new(name, n), &n, |b, &n| {
+            b.iter(|| {
+                publisher.send_multicast(&group, &buf, false).unwrap();
+                for _ in 0..n {
+                    acks.recv().unwrap();
+                }
+            })
+        });
+
+        for (subscriber, thread) in subscribers {
+            subscriber.close();
+            let _ = thread.join();
+        }
+    }
+
+    Ok(())
+}
+
+fn unicast(c: &mut Criterion) {
+    let mut g = c.benchmark_group("unicast_rtt");
+
+    for (name, t) in transports() {
+        if let Err(e) = unicast_rtt(&mut g, name, &t) {
+            eprintln!("{} unicast benchmark failed: {:?}", name, e);
+        }
+    }
+
+    g.finish();
+}
+
+fn multicast(c: &mut Criterion) {
+    let mut g = c.benchmark_group("multicast_fanout");
+
+    for (name, t) in transports() {
+        if let Err(e) = multicast_fanout(&mut g, name, &t) {
+            eprintln!("{} multicast benchmark failed: {:?}", name, e);
+        }
+    }
+
+    g.finish();
+}
+
+#[cfg(feature = "async")]
+fn unicast_rtt_async(
+    g: &mut BenchmarkGroup<WallTime>,
+    name: &str,
+    t: &Transport,
+) -> Result<(), IpconError> {
+    use ipcon_sys::ipcon_async::AsyncIpcon;
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| error_stack::Report::new(IpconError::SystemErrorOther).attach_printable(e))?;
+    let client = AsyncIpcon::from(peer(t, "bench.aclient")?);
+    let server = Arc::new(peer(t, "bench.aecho")?);
+    let client_name = PeerName::new("bench.aclient")?;
+    let server_name = PeerName::new("bench.aecho")?;
+
+    let echo = serve(server.clone(), move |server, buf| {
+        if let Err(e) = server.send_unicast_msg(&client_name, &buf) {
+            eprintln!("Echo failed: {:?}", e);
+        }
+    });
+
+    for size in PAYLOAD_SIZES {
+        let buf = vec![0x5a; size];
+
+        g.throughput(Throughput::Bytes(size as u64));
+        g.bench_with_input(BenchmarkId::new(name, size), &buf, |b, buf| {
+            b.iter(|| {
+                rt.block_on(async {
+                    client.send_unicast_msg(&server_name, buf).await.unwrap();
+                    client.receive_msg().await.unwrap()
+                })
+            })
+        });
+    }
+
+    server.close();
+    let _ = echo.join();
+    Ok(())
+}
+
+#[cfg(feature = "async")]
+fn unicast_async(c: &mut Criterion) {
+    let mut g = c.benchmark_group("unicast_rtt_async");
+
+    for (name, t) in transports() {
+        if let Err(e) = unicast_rtt_async(&mut g, name, &t) {
+            eprintln!("{} async unicast benchmark failed: {:?}", name, e);
+        }
+    }
+
+    g.finish();
+}
+
+#[cfg(not(feature = "async"))]
+fn unicast_async(_c: &mut Criterion) {}
+
+criterion_group!(benches, decode, unicast, multicast, unicast_async);
+criterion_main!(benches);
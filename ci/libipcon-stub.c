@@ -0,0 +1,78 @@
+/*
+ * Link-time stand-in of libipcon for CI, the unit and integration tests only use LoopbackBus.
+ * Every call fails as if the IPCON kernel module was missing.
+ */
+#include <errno.h>
+#include <stddef.h>
+
+struct timeval;
+
+void *ipcon_create_handler(const char *peer_name, unsigned long flags)
+{
+	return NULL;
+}
+
+void ipcon_free_handler(void *handler)
+{
+}
+
+int is_peer_present(void *handler, const char *peer)
+{
+	return 0;
+}
+
+int is_group_present(void *handler, const char *peer, const char *group)
+{
+	return 0;
+}
+
+int ipcon_send_unicast(void *handler, const char *peer, const unsigned char *buf, size_t size)
+{
+	return -ENOSYS;
+}
+
+int ipcon_register_group(void *handler, const char *name)
+{
+	return -ENOSYS;
+}
+
+int ipcon_unregister_group(void *handler, const char *name)
+{
+	return -ENOSYS;
+}
+
+int ipcon_join_group(void *handler, const char *srv_name, const char *grp_name)
+{
+	return -ENOSYS;
+}
+
+int ipcon_leave_group(void *handler, const char *srv_name, const char *grp_name)
+{
+	return -ENOSYS;
+}
+
+int ipcon_send_multicast(void *handler, const char *name, const unsigned char *buf, size_t size,
+			 int sync)
+{
+	return -ENOSYS;
+}
+
+int ipcon_rcv_timeout(void *handler, void *im, const struct timeval *timeout)
+{
+	return -ENOSYS;
+}
+
+int ipcon_get_read_fd(void *handler)
+{
+	return -ENOSYS;
+}
+
+int ipcon_get_write_fd(void *handler)
+{
+	return -ENOSYS;
+}
+
+int ipcon_get_ctrl_fd(void *handler)
+{
+	return -ENOSYS;
+}
@@ -0,0 +1,25 @@
+#![no_main]
+
+use ipcon_sys::ipcon_msg::IpconKevent;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    /* Feed the input as the raw event received from libipcon, zero padded or truncated. */
+    let mut raw = vec![0_u8; std::mem::size_of::<IpconKevent>()];
+    let len = data.len().min(raw.len());
+    raw[..len].copy_from_slice(&data[..len]);
+
+    let kevent = IpconKevent::from_bytes(&raw).expect("size_of::<IpconKevent>() bytes");
+    assert_eq!(kevent.to_bytes(), raw);
+
+    if kevent.get_string().is_ok() {
+        assert!(
+            kevent.peer_added().is_some()
+                || kevent.peer_removed().is_some()
+                || kevent.group_added().is_some()
+                || kevent.group_removed().is_some()
+        );
+    }
+
+    let _ = kevent.to_string();
+});
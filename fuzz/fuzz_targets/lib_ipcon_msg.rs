@@ -0,0 +1,24 @@
+#![no_main]
+
+use ipcon_sys::ipcon_error::IpconError;
+use ipcon_sys::ipcon_msg::{IpconMsg, LibIpconMsg, IPCON_MAX_PAYLOAD_LEN};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    /* Feed the input as the raw message received from libipcon, zero padded or truncated. */
+    let mut raw = vec![0_u8; std::mem::size_of::<LibIpconMsg>()];
+    let len = data.len().min(raw.len());
+    raw[..len].copy_from_slice(&data[..len]);
+
+    let lmsg = LibIpconMsg::from_bytes(&raw).expect("size_of::<LibIpconMsg>() bytes");
+    let msg: error_stack::Result<IpconMsg, IpconError> = lmsg.into();
+
+    match msg {
+        Ok(IpconMsg::IpconMsgUser(body)) => assert!(body.buf.len() <= IPCON_MAX_PAYLOAD_LEN),
+        Ok(IpconMsg::IpconMsgKevent(kevent)) => {
+            kevent.get_string().expect("validated kevent");
+        }
+        Ok(IpconMsg::IpconMsgInvalid) => {}
+        Err(e) => assert_eq!(*e.current_context(), IpconError::InvalidLibIpconMsg),
+    }
+});
@@ -0,0 +1,77 @@
+#!/usr/bin/env python3
+"""Minimal client of ripcon_bridge, see src/ipcon_frame.rs of ipcon-sys for the framing."""
+
+import socket
+import struct
+import sys
+
+FRAME_UNICAST = 1
+FRAME_MULTICAST = 2
+FRAME_MESSAGE = 3
+FRAME_ERROR = 4
+
+
+def send_frame(sock, kind, body):
+    sock.sendall(struct.pack(">IB", len(body) + 1, kind) + body)
+
+
+def name(s):
+    b = s.encode()
+    return struct.pack("B", len(b)) + b
+
+
+def send_unicast(sock, peer, payload):
+    send_frame(sock, FRAME_UNICAST, name(peer) + payload)
+
+
+def send_multicast(sock, group, payload, sync=False):
+    send_frame(sock, FRAME_MULTICAST, struct.pack("B", int(sync)) + name(group) + payload)
+
+
+def recv_exact(sock, n):
+    buf = b""
+    while len(buf) < n:
+        chunk = sock.recv(n - len(buf))
+        if not chunk:
+            raise EOFError("bridge closed the connection")
+        buf += chunk
+    return buf
+
+
+def recv_frame(sock):
+    (length,) = struct.unpack(">I", recv_exact(sock, 4))
+    data = recv_exact(sock, length)
+    return data[0], data[1:]
+
+
+def parse_message(body):
+    msg_type = body[0]
+    off = 1
+    peer_len = body[off]
+    peer = body[off + 1 : off + 1 + peer_len].decode()
+    off += 1 + peer_len
+    group_len = body[off]
+    group = body[off + 1 : off + 1 + group_len].decode()
+    off += 1 + group_len
+    return msg_type, peer, group, body[off:]
+
+
+def main():
+    path = sys.argv[1] if len(sys.argv) > 1 else "/run/ipcon-bridge.sock"
+    sock = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+    sock.connect(path)
+
+    if len(sys.argv) > 3:
+        send_unicast(sock, sys.argv[2], sys.argv[3].encode())
+
+    while True:
+        kind, body = recv_frame(sock)
+        if kind == FRAME_MESSAGE:
+            msg_type, peer, group, payload = parse_message(body)
+            print(msg_type, peer, group, payload)
+        elif kind == FRAME_ERROR:
+            print("error", body[0], body[1:].decode(errors="replace"))
+
+
+if __name__ == "__main__":
+    main()
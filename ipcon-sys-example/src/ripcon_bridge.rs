@@ -0,0 +1,86 @@
+use clap::Parser;
+#[allow(unused)]
+use error_stack::{Report, Result, ResultExt};
+use ipcon_sys::{
+    ipcon::{self, Ipcon},
+    ipcon_error::IpconError,
+    ipcon_uds::{UdsBridge, UdsBridgeConfig},
+};
+
+#[allow(unused)]
+use jlogger_tracing::{
+    jdebug, jerror, jinfo, jtrace, jwarn, JloggerBuilder, LevelFilter, LogTimeFormat,
+};
+
+/// Expose an IPCON peer over a Unix domain socket.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// Path of the Unix domain socket.
+    #[arg(short, long, default_value = "/run/ipcon-bridge.sock")]
+    socket: String,
+
+    /// Name of the bridge peer.
+    #[arg(short, long, default_value = "ipcon-bridge")]
+    name: String,
+
+    /// Peer the clients can exchange unicast messages with. All peers if not specified.
+    #[arg(short, long)]
+    peer: Vec<String>,
+
+    /// Group registered by the bridge, the clients can send multicast messages to it.
+    #[arg(short, long)]
+    group: Vec<String>,
+
+    /// Group forwarded to the clients, specified as <group>@<peer>.
+    #[arg(short = 'j', long)]
+    subscribe: Vec<String>,
+
+    /// User id allowed to connect besides the user id of the bridge.
+    #[arg(long)]
+    uid: Vec<u32>,
+
+    /// Group id allowed to connect.
+    #[arg(long)]
+    gid: Vec<u32>,
+}
+
+fn main() -> Result<(), IpconError> {
+    JloggerBuilder::new()
+        .log_time(LogTimeFormat::TimeStamp)
+        .log_console(true)
+        .build();
+
+    let cli = Cli::parse();
+
+    let mut subscriptions = Vec::new();
+    for s in &cli.subscribe {
+        match s.split_once('@') {
+            Some((group, peer)) => subscriptions.push((peer.to_owned(), group.to_owned())),
+            None => {
+                return Err(Report::new(IpconError::InvalidName))
+                    .attach_printable(format!("Invalid subscription {}, use <group>@<peer>", s))
+            }
+        }
+    }
+
+    let config = UdsBridgeConfig {
+        peers: if cli.peer.is_empty() {
+            None
+        } else {
+            Some(cli.peer)
+        },
+        groups: cli.group,
+        subscriptions,
+        uids: cli.uid,
+        gids: cli.gid,
+    };
+
+    let ipcon = Ipcon::new(Some(&cli.name), Some(ipcon::IPF_DEFAULT))
+        .attach_printable("Failed to create Ipcon handler")?;
+
+    let bridge = UdsBridge::new(ipcon, &cli.socket, config)?;
+
+    jinfo!("Bridge {} listening on {}", cli.name, cli.socket);
+    bridge.run()
+}
@@ -0,0 +1,189 @@
+use clap::{Parser, Subcommand};
+#[allow(unused)]
+use error_stack::{Report, Result, ResultExt};
+use ipcon_sys::{
+    ipcon::{self, Ipcon},
+    ipcon_capture::{CaptureDirection, CaptureRecord, PcapngReader},
+    ipcon_error::IpconError,
+    ipcon_msg::IpconMsgType,
+};
+use std::collections::HashSet;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+#[allow(unused)]
+use jlogger_tracing::{
+    jdebug, jerror, jinfo, jtrace, jwarn, JloggerBuilder, LevelFilter, LogTimeFormat,
+};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the messages recorded in a capture file.
+    Inspect {
+        file: String,
+
+        /// Dump the payloads in hex.
+        #[arg(short = 'x', long)]
+        hex: bool,
+    },
+
+    /// Send again the messages recorded in a capture file.
+    Replay {
+        file: String,
+
+        /// Peer name used to send the messages.
+        #[arg(short, long)]
+        name: Option<String>,
+
+        /// Send the unicast messages to this peer instead of the recorded one.
+        #[arg(long)]
+        to: Option<String>,
+
+        /// Replay the received messages instead of the sent ones, `--to` is required to replay
+        /// the received unicast messages.
+        #[arg(short, long)]
+        received: bool,
+
+        /// Keep the original timing between the messages.
+        #[arg(short, long)]
+        timing: bool,
+    },
+}
+
+fn dump_hex(buf: &[u8]) {
+    for (i, chunk) in buf.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        println!("    {:04x}  {:<48} {}", i * 16, hex.join(" "), ascii);
+    }
+}
+
+fn inspect(file: &str, hex: bool) -> Result<(), IpconError> {
+    for (i, record) in PcapngReader::open(file)?.enumerate() {
+        let record = record?;
+        let ts = record
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let direction = match record.direction {
+            CaptureDirection::Sent => "->",
+            CaptureDirection::Received => "<-",
+        };
+        let target = match &record.group {
+            Some(group) => format!("{}@{}", group, record.peer),
+            None => record.peer.clone(),
+        };
+
+        println!(
+            "{:6} {}.{:06} {} {:?} {} ({} bytes)",
+            i + 1,
+            ts.as_secs(),
+            ts.subsec_micros(),
+            direction,
+            record.msg_type,
+            target,
+            record.payload.len()
+        );
+
+        if hex {
+            dump_hex(&record.payload);
+        }
+    }
+
+    Ok(())
+}
+
+fn replay(
+    file: &str,
+    name: Option<&str>,
+    to: Option<&str>,
+    received: bool,
+    timing: bool,
+) -> Result<(), IpconError> {
+    let direction = if received {
+        CaptureDirection::Received
+    } else {
+        CaptureDirection::Sent
+    };
+
+    let records = PcapngReader::open(file)?
+        .collect::<Result<Vec<CaptureRecord>, IpconError>>()?
+        .into_iter()
+        .filter(|r| r.direction == direction);
+
+    let ipcon = Ipcon::new(name, Some(ipcon::IPF_DEFAULT))
+        .attach_printable("Failed to create Ipcon handler")?;
+
+    let mut groups = HashSet::new();
+    let mut first = None;
+    let start = Instant::now();
+
+    for record in records {
+        if timing {
+            let first = *first.get_or_insert(record.timestamp);
+            let offset = record
+                .timestamp
+                .duration_since(first)
+                .unwrap_or(Duration::ZERO);
+            if let Some(wait) = offset.checked_sub(start.elapsed()) {
+                std::thread::sleep(wait);
+            }
+        }
+
+        let ret = match (record.msg_type, &record.group) {
+            (IpconMsgType::IpconMsgTypeNormal, _) => match (to, received) {
+                (Some(peer), _) => ipcon.send_unicast_msg(peer, &record.payload),
+                (None, false) => ipcon.send_unicast_msg(&record.peer, &record.payload),
+                (None, true) => continue,
+            },
+            (IpconMsgType::IpconMsgTypeGroup, Some(group)) => {
+                if groups.insert(group.clone()) {
+                    ipcon.register_group(group)?;
+                }
+                ipcon.send_multicast(group, &record.payload, false)
+            }
+            _ => continue,
+        };
+
+        if let Err(e) = ret {
+            jerror!("{:?}", e);
+        }
+    }
+
+    Ok(())
+}
+
+fn main() -> Result<(), IpconError> {
+    JloggerBuilder::new()
+        .log_time(LogTimeFormat::TimeStamp)
+        .log_console(true)
+        .build();
+
+    let cli = Cli::parse();
+
+    match cli.command {
+        Command::Inspect { file, hex } => inspect(&file, hex),
+        Command::Replay {
+            file,
+            name,
+            to,
+            received,
+            timing,
+        } => replay(&file, name.as_deref(), to.as_deref(), received, timing),
+    }
+}
@@ -0,0 +1,103 @@
+use clap::Parser;
+#[allow(unused)]
+use error_stack::{Report, Result, ResultExt};
+use ipcon_sys::{
+    ipcon::{self, Ipcon},
+    ipcon_dbus::{DbusBridge, DbusBridgeConfig, DbusSignalRoute},
+    ipcon_error::IpconError,
+};
+
+#[allow(unused)]
+use jlogger_tracing::{
+    jdebug, jerror, jinfo, jtrace, jwarn, JloggerBuilder, LevelFilter, LogTimeFormat,
+};
+
+/// Expose an IPCON peer as a D-Bus service.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// Address of the bus, the session bus if not specified.
+    #[arg(short, long)]
+    address: Option<String>,
+
+    /// Well-known name of the service.
+    #[arg(short, long, default_value = "org.ipcon.Bridge")]
+    service: String,
+
+    /// Name of the bridge peer.
+    #[arg(short, long, default_value = "ipcon-dbus")]
+    name: String,
+
+    /// Peer the D-Bus clients can exchange unicast messages with. All peers if not specified.
+    #[arg(short, long)]
+    peer: Vec<String>,
+
+    /// Group registered by the bridge, the D-Bus clients can send multicast messages to it.
+    #[arg(short, long)]
+    group: Vec<String>,
+
+    /// Group forwarded as D-Bus signals, specified as <group>@<peer>.
+    #[arg(short = 'j', long)]
+    subscribe: Vec<String>,
+
+    /// D-Bus signals multicast to a bridge group, specified as <interface>=<group>.
+    #[arg(long)]
+    signal: Vec<String>,
+}
+
+fn main() -> Result<(), IpconError> {
+    JloggerBuilder::new()
+        .log_time(LogTimeFormat::TimeStamp)
+        .log_console(true)
+        .build();
+
+    let cli = Cli::parse();
+
+    let mut subscriptions = Vec::new();
+    for s in &cli.subscribe {
+        match s.split_once('@') {
+            Some((group, peer)) => subscriptions.push((peer.to_owned(), group.to_owned())),
+            None => {
+                return Err(Report::new(IpconError::InvalidName))
+                    .attach_printable(format!("Invalid subscription {}, use <group>@<peer>", s))
+            }
+        }
+    }
+
+    let mut signals = Vec::new();
+    for s in &cli.signal {
+        match s.split_once('=') {
+            Some((interface, group)) => signals.push(DbusSignalRoute {
+                interface: interface.to_owned(),
+                member: None,
+                group: group.to_owned(),
+            }),
+            None => {
+                return Err(Report::new(IpconError::InvalidName))
+                    .attach_printable(format!("Invalid signal {}, use <interface>=<group>", s))
+            }
+        }
+    }
+
+    let config = DbusBridgeConfig {
+        address: cli.address,
+        service: cli.service.clone(),
+        peers: if cli.peer.is_empty() {
+            None
+        } else {
+            Some(cli.peer)
+        },
+        groups: cli.group,
+        subscriptions,
+        signals,
+        ..Default::default()
+    };
+
+    let ipcon = Ipcon::new(Some(&cli.name), Some(ipcon::IPF_DEFAULT))
+        .attach_printable("Failed to create Ipcon handler")?;
+
+    let bridge = DbusBridge::new(ipcon, config)?;
+
+    jinfo!("Bridge {} serving {}", cli.name, cli.service);
+    bridge.run()
+}
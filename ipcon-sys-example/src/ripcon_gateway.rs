@@ -0,0 +1,108 @@
+use clap::Parser;
+#[allow(unused)]
+use error_stack::{Report, Result, ResultExt};
+use ipcon_sys::{
+    ipcon_error::IpconError,
+    ipcon_gateway::{Gateway, GatewayConfig},
+};
+use std::time::Duration;
+
+#[allow(unused)]
+use jlogger_tracing::{
+    jdebug, jerror, jinfo, jtrace, jwarn, JloggerBuilder, LevelFilter, LogTimeFormat,
+};
+
+/// Federate IPCON peers and groups with a remote host over TCP.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// Address to listen on, for example 0.0.0.0:7450.
+    #[arg(short, long, conflicts_with = "connect")]
+    listen: Option<String>,
+
+    /// Address of the remote gateway to connect to.
+    #[arg(short, long)]
+    connect: Option<String>,
+
+    /// Name of the gateway peer.
+    #[arg(short, long, default_value = "ipcon-gateway")]
+    name: String,
+
+    /// Local peer exported to the remote host.
+    #[arg(short, long)]
+    peer: Vec<String>,
+
+    /// Local group mirrored on the remote host, specified as <group>@<peer>.
+    #[arg(short, long)]
+    group: Vec<String>,
+
+    /// Remote peer accepted on the local host, a trailing `*` matches any suffix.
+    /// All the remote peers are accepted if omitted.
+    #[arg(short, long)]
+    import: Vec<String>,
+
+    /// Prefix of the names of the proxies of the remote peers.
+    #[arg(long, default_value = "remote.")]
+    prefix: String,
+
+    /// Idle time in seconds after which the proxy of a remote sender is removed.
+    #[arg(long, default_value_t = 60)]
+    requester_ttl: u64,
+
+    /// Reconnect interval in milliseconds.
+    #[arg(short, long, default_value_t = 1000)]
+    reconnect: u64,
+}
+
+fn main() -> Result<(), IpconError> {
+    JloggerBuilder::new()
+        .log_time(LogTimeFormat::TimeStamp)
+        .log_console(true)
+        .build();
+
+    let cli = Cli::parse();
+
+    let mut groups = Vec::new();
+    for g in &cli.group {
+        match g.split_once('@') {
+            Some((group, peer)) => groups.push((peer.to_owned(), group.to_owned())),
+            None => {
+                return Err(Report::new(IpconError::InvalidName))
+                    .attach_printable(format!("Invalid group {}, use <group>@<peer>", g))
+            }
+        }
+    }
+
+    let mut config = GatewayConfig {
+        name: cli.name,
+        peers: cli.peer,
+        groups,
+        prefix: cli.prefix,
+        requester_ttl: Duration::from_secs(cli.requester_ttl),
+        reconnect_interval: Duration::from_millis(cli.reconnect),
+        ..Default::default()
+    };
+
+    if !cli.import.is_empty() {
+        config.imports = cli.import;
+    }
+
+    let _gateway = match (cli.listen, cli.connect) {
+        (Some(addr), _) => {
+            jinfo!("Gateway listening on {}", addr);
+            Gateway::listen(&addr, config)?
+        }
+        (None, Some(addr)) => {
+            jinfo!("Gateway connecting to {}", addr);
+            Gateway::connect(&addr, config)?
+        }
+        (None, None) => {
+            return Err(Report::new(IpconError::InvalidData))
+                .attach_printable("Either --listen or --connect is required")
+        }
+    };
+
+    loop {
+        std::thread::park();
+    }
+}
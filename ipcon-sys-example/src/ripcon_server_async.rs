@@ -1,10 +1,10 @@
 #[allow(unused)]
-use error_stack::{IntoReport, Report, Result, ResultExt};
+use error_stack::{Report, Result, ResultExt};
 use ipcon_sys::{
-    ipcon,
-    ipcon_async::AsyncIpcon,
+    ipcon::{self, Ipcon},
     ipcon_error::IpconError,
-    ipcon_msg::{IpconMsg, IpconMsgType},
+    ipcon_msg::IpconMsgType,
+    ipcon_server::{MsgFilter, ServerBuilder},
 };
 
 #[allow(unused)]
@@ -19,17 +19,36 @@ async fn main() -> Result<(), IpconError> {
         .log_console(true)
         .build();
 
-    let ipcon = AsyncIpcon::new(Some("ipcon-str-server"), Some(ipcon::IPF_DEFAULT))
+    let ipcon = Ipcon::new(Some("ipcon-str-server"), Some(ipcon::IPF_DEFAULT))
         .attach_printable("Failed to create Ipcon handler")?;
 
-    jinfo!("Start to waiting for message.");
-    loop {
-        match ipcon.receive_msg().await? {
-            IpconMsg::IpconMsgUser(msg) if (msg.msg_type == IpconMsgType::IpconMsgTypeNormal) => {
-                let body = String::from_utf8(msg.buf).unwrap();
+    let server = ServerBuilder::new(ipcon)
+        .on(
+            MsgFilter::any().msg_type(IpconMsgType::IpconMsgTypeNormal),
+            |_ctx, msg| async move {
+                let body = String::from_utf8_lossy(&msg.buf).into_owned();
                 jinfo!(sender = msg.peer, msg = body);
-            }
-            _ => {}
-        }
-    }
+                Ok(())
+            },
+        )
+        .on_kevent(|_ctx, kevent| async move {
+            jinfo!("{}", kevent);
+            Ok(())
+        })
+        .on_start(|_ctx| {
+            jinfo!("Start to waiting for message.");
+            Ok(())
+        })
+        .tokio(tokio::runtime::Handle::current())
+        .build()?;
+
+    let handle = server.handle();
+    tokio::spawn(async move {
+        let _ = tokio::signal::ctrl_c().await;
+        handle.shutdown();
+    });
+
+    tokio::task::spawn_blocking(move || server.run())
+        .await
+        .map_err(|e| Report::new(IpconError::Unexpected).attach_printable(e.to_string()))?
 }
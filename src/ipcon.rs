@@ -1,13 +1,27 @@
 extern crate libc;
+use crate::ipcon_capture::{
+    CaptureDirection, CaptureRecord, CaptureSink, PcapngWriter, CAPTURE_FLUSH_INTERVAL,
+};
 use crate::ipcon_error::IpconError;
-use crate::ipcon_msg::{IpconMsg, LibIpconMsg, IPCON_MAX_NAME_LEN, IPCON_MAX_PAYLOAD_LEN};
+use crate::ipcon_loopback::{LoopbackBus, LoopbackPeer};
+use crate::ipcon_msg::{IpconMsg, IpconMsgType, LibIpconMsg, IPCON_MAX_PAYLOAD_LEN};
+use crate::ipcon_name::{AsGroupName, AsPeerName, GroupName, NamePolicy, PeerName};
+use crate::ipcon_shutdown::ShutdownHandle;
+use crate::ipcon_stats::{IpconStats, StatsCollector, StatsTarget};
+use crate::ipcon_trace;
 use error_stack::{Report, Result, ResultExt};
 #[allow(unused)]
 use jlogger_tracing::{jdebug, jerror, jinfo, jwarn};
 use libc::{c_void, size_t};
 use nix::errno::Errno;
-use std::ffi::CString;
+use std::borrow::Cow;
+use std::ops::Deref;
 use std::os::raw::{c_char, c_uchar};
+use std::os::unix::io::BorrowedFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, Weak};
+use std::time::{Duration, Instant, SystemTime};
 
 #[link(name = "ipcon")]
 extern "C" {
@@ -15,7 +29,6 @@ extern "C" {
     fn ipcon_free_handler(handler: *mut c_void);
     fn is_peer_present(handler: *mut c_void, peer: *const c_char) -> i32;
     fn is_group_present(handler: *mut c_void, peer: *const c_char, group: *const c_char) -> i32;
-    fn ipcon_rcv(handler: *mut c_void, msg: &LibIpconMsg) -> i32;
     fn ipcon_send_unicast(
         handler: *mut c_void,
         peer: *const c_char,
@@ -53,8 +66,24 @@ extern "C" {
 
 /// IPCON peer.
 pub struct Ipcon {
-    handler: usize,
+    backend: Backend,
+    /* Every libipcon call holds the read lock. */
+    closed: RwLock<bool>,
+    shutdown: ShutdownHandle,
     name: Option<String>,
+    trace: AtomicBool,
+    stats: StatsCollector,
+    capture: Arc<Mutex<CaptureState>>,
+    /* Set while a capture sink is installed, so that no record is built otherwise. */
+    capturing: AtomicBool,
+}
+
+/* The capture sink, and the number of sinks installed so far which identifies the thread
+ * flushing the current one. */
+#[derive(Default)]
+struct CaptureState {
+    sink: Option<Box<dyn CaptureSink>>,
+    generation: u64,
 }
 
 pub type IpconFlag = std::os::raw::c_ulong;
@@ -76,40 +105,209 @@ fn errno_to_error(i: i32) -> IpconError {
     }
 }
 
+/// Check a name against the NamePolicy of the process.
 pub fn valid_name(name: &str) -> Result<(), IpconError> {
-    let mut error_str = None;
+    NamePolicy::current().check(name)
+}
 
-    if name.is_empty() {
-        error_str = Some("Name is null".to_owned());
-    }
+/// Transport carrying the messages of the peers.
+#[derive(Clone, Debug, Default)]
+pub enum Transport {
+    /// The IPCON kernel module, through libipcon.
+    #[default]
+    Kernel,
+    /// An in-process bus, see ipcon_loopback.
+    Loopback(LoopbackBus),
+}
 
-    if name.len() > IPCON_MAX_NAME_LEN {
-        error_str = Some(format!(
-            "Name is too long {} > {}",
-            name.len(),
-            IPCON_MAX_NAME_LEN
-        ));
-    }
+impl Transport {
+    /// Create a peer on the transport, see Ipcon::new().
+    pub fn peer(
+        &self,
+        peer_name: Option<&str>,
+        flag: Option<IpconFlag>,
+    ) -> Result<Ipcon, IpconError> {
+        let name = match peer_name {
+            Some(a) => {
+                Some(PeerName::local(a).attach_printable(format!("Invalid peer name: {}", a))?)
+            }
+            None => None,
+        };
 
-    if name.trim() != name {
-        error_str = Some("Name has blank character".to_owned());
+        Ipcon::create(self, name.as_ref(), flag)
     }
 
-    if let Some(err_str) = error_str {
-        Err(Report::new(IpconError::InvalidName)).attach_printable(err_str)
-    } else {
-        Ok(())
+    /// Create a named peer on the transport, the name is used as is.
+    pub fn peer_with_name(
+        &self,
+        peer_name: &PeerName,
+        flag: Option<IpconFlag>,
+    ) -> Result<Ipcon, IpconError> {
+        Ipcon::create(self, Some(peer_name), flag)
     }
 }
 
-impl Drop for Ipcon {
+/* The libipcon handler or the loopback peer behind an Ipcon.
+ * The methods return a negative errno on failure, as libipcon does. */
+enum Backend {
+    Lib(usize),
+    Loopback(LoopbackPeer),
+}
+
+impl Drop for Backend {
     fn drop(&mut self) {
-        unsafe {
-            ipcon_free_handler(Ipcon::to_handler(self.handler));
+        if let Backend::Lib(h) = self {
+            unsafe { ipcon_free_handler(Ipcon::to_handler(*h)) }
         }
     }
 }
 
+impl Backend {
+    fn is_peer_present(&self, peer: &PeerName) -> bool {
+        match self {
+            Backend::Lib(h) => unsafe {
+                is_peer_present(Ipcon::to_handler(*h), peer.as_c_str().as_ptr()) != 0
+            },
+            Backend::Loopback(l) => l.is_peer_present(peer.as_str()),
+        }
+    }
+
+    fn is_group_present(&self, peer: &PeerName, group: &GroupName) -> bool {
+        match self {
+            Backend::Lib(h) => unsafe {
+                is_group_present(
+                    Ipcon::to_handler(*h),
+                    peer.as_c_str().as_ptr(),
+                    group.as_c_str().as_ptr(),
+                ) != 0
+            },
+            Backend::Loopback(l) => l.is_group_present(peer.as_str(), group.as_str()),
+        }
+    }
+
+    fn send_unicast(&self, peer: &PeerName, buf: &[u8]) -> i32 {
+        match self {
+            Backend::Lib(h) => unsafe {
+                ipcon_send_unicast(
+                    Ipcon::to_handler(*h),
+                    peer.as_c_str().as_ptr(),
+                    buf.as_ptr(),
+                    buf.len() as size_t,
+                )
+            },
+            Backend::Loopback(l) => l.send_unicast(peer.as_str(), buf),
+        }
+    }
+
+    fn register_group(&self, group: &GroupName) -> i32 {
+        match self {
+            Backend::Lib(h) => unsafe {
+                ipcon_register_group(Ipcon::to_handler(*h), group.as_c_str().as_ptr())
+            },
+            Backend::Loopback(l) => l.register_group(group.as_str()),
+        }
+    }
+
+    fn unregister_group(&self, group: &GroupName) -> i32 {
+        match self {
+            Backend::Lib(h) => unsafe {
+                ipcon_unregister_group(Ipcon::to_handler(*h), group.as_c_str().as_ptr())
+            },
+            Backend::Loopback(l) => l.unregister_group(group.as_str()),
+        }
+    }
+
+    fn join_group(&self, peer: &PeerName, group: &GroupName) -> i32 {
+        match self {
+            Backend::Lib(h) => unsafe {
+                ipcon_join_group(
+                    Ipcon::to_handler(*h),
+                    peer.as_c_str().as_ptr(),
+                    group.as_c_str().as_ptr(),
+                )
+            },
+            Backend::Loopback(l) => l.join_group(peer.as_str(), group.as_str()),
+        }
+    }
+
+    fn leave_group(&self, peer: &PeerName, group: &GroupName) -> i32 {
+        match self {
+            Backend::Lib(h) => unsafe {
+                ipcon_leave_group(
+                    Ipcon::to_handler(*h),
+                    peer.as_c_str().as_ptr(),
+                    group.as_c_str().as_ptr(),
+                )
+            },
+            Backend::Loopback(l) => l.leave_group(peer.as_str(), group.as_str()),
+        }
+    }
+
+    fn send_multicast(&self, group: &GroupName, buf: &[u8], sync: bool) -> i32 {
+        match self {
+            Backend::Lib(h) => unsafe {
+                ipcon_send_multicast(
+                    Ipcon::to_handler(*h),
+                    group.as_c_str().as_ptr(),
+                    buf.as_ptr(),
+                    buf.len() as size_t,
+                    sync as i32,
+                )
+            },
+            Backend::Loopback(l) => l.send_multicast(group.as_str(), buf, sync),
+        }
+    }
+
+    /* Receive a pending message without blocking, -ETIMEDOUT if none is pending. */
+    fn rcv(&self, lmsg: &mut LibIpconMsg) -> i32 {
+        match self {
+            Backend::Lib(h) => {
+                let t = libc::timeval {
+                    tv_sec: 0,
+                    tv_usec: 0,
+                };
+                unsafe { ipcon_rcv_timeout(Ipcon::to_handler(*h), lmsg, &t) }
+            }
+            Backend::Loopback(l) => l.rcv(lmsg),
+        }
+    }
+
+    fn read_fd(&self) -> i32 {
+        match self {
+            Backend::Lib(h) => unsafe { ipcon_get_read_fd(Ipcon::to_handler(*h)) },
+            Backend::Loopback(l) => l.read_fd(),
+        }
+    }
+
+    fn write_fd(&self) -> i32 {
+        match self {
+            Backend::Lib(h) => unsafe { ipcon_get_write_fd(Ipcon::to_handler(*h)) },
+            Backend::Loopback(l) => l.write_fd(),
+        }
+    }
+
+    fn ctrl_fd(&self) -> i32 {
+        match self {
+            Backend::Lib(h) => unsafe { ipcon_get_ctrl_fd(Ipcon::to_handler(*h)) },
+            Backend::Loopback(l) => l.ctrl_fd(),
+        }
+    }
+}
+
+/* The peer can't be closed while it is held. */
+struct HandlerGuard<'a> {
+    backend: &'a Backend,
+    _closed: RwLockReadGuard<'a, bool>,
+}
+
+impl Deref for HandlerGuard<'_> {
+    type Target = Backend;
+
+    fn deref(&self) -> &Backend {
+        self.backend
+    }
+}
+
 impl Ipcon {
     pub fn to_handler(u: usize) -> *mut c_void {
         u as *mut c_void
@@ -121,7 +319,8 @@ impl Ipcon {
     }
 
     /// Create an IPCON peer.
-    /// If the name is omitted, an anonymous will be created.
+    /// If the name is omitted, an anonymous will be created. The name is qualified by the
+    /// namespace of the NamePolicy of the process, see PeerName::local().
     /// Following flags can be specified with bitwise OR (|).
     /// * IPF_DISABLE_KEVENT_FILTER  
     ///   By default, IPCON kernel module will only delivery the add/remove notification of
@@ -136,176 +335,224 @@ impl Ipcon {
     ///
     ///   
     pub fn new(peer_name: Option<&str>, flag: Option<IpconFlag>) -> Result<Ipcon, IpconError> {
-        let handler: *mut c_void;
-        let mut flg = 0_usize;
-        let mut name = None;
+        Transport::Kernel.peer(peer_name, flag)
+    }
 
-        if let Some(a) = flag {
-            flg = a as usize;
-        }
+    /// Create a named IPCON peer, the name is used as is.
+    pub fn with_name(peer_name: &PeerName, flag: Option<IpconFlag>) -> Result<Ipcon, IpconError> {
+        Transport::Kernel.peer_with_name(peer_name, flag)
+    }
 
-        let pname = match peer_name {
-            Some(a) => {
-                valid_name(a).attach_printable(format!("Invalid peer name: {}", a))?;
-                name = Some(a.to_string());
-                CString::new(a)
-                    .map_err(|_| Report::new(IpconError::InvalidName))?
-                    .into_raw()
+    fn create(
+        transport: &Transport,
+        peer_name: Option<&PeerName>,
+        flag: Option<IpconFlag>,
+    ) -> Result<Ipcon, IpconError> {
+        let name = peer_name.map(|a| a.as_str().to_owned());
+
+        let backend = match transport {
+            Transport::Kernel => {
+                let flg = flag.unwrap_or(0) as usize;
+                let pname = peer_name.map_or(std::ptr::null(), |a| a.as_c_str().as_ptr());
+                let handler = unsafe { ipcon_create_handler(pname, flg) };
+                (!handler.is_null()).then(|| Backend::Lib(unsafe { Ipcon::from_handler(handler) }))
             }
-            None => std::ptr::null(),
+            Transport::Loopback(bus) => bus.attach(peer_name, flag)?.map(Backend::Loopback),
         };
 
-        unsafe {
-            handler = ipcon_create_handler(pname as *const c_char, flg as usize);
-
-            if !pname.is_null() {
-                /* deallocate the pname */
-                let _ = CString::from_raw(pname as *mut c_char);
-            }
-        }
-        if handler.is_null() {
-            Err(Report::new(IpconError::SystemErrorOther)).attach_printable(format!(
+        let backend = backend
+            .ok_or_else(|| Report::new(IpconError::SystemErrorOther))
+            .attach_printable(format!(
                 "Failed to create ipcon handler for {}, peer name already used?",
                 name.as_deref().unwrap_or("Anon")
-            ))
-        } else {
-            Ok(Ipcon {
-                handler: unsafe { Ipcon::from_handler(handler) },
-                name,
-            })
+            ))?;
+
+        Ok(Ipcon {
+            backend,
+            closed: RwLock::new(false),
+            shutdown: ShutdownHandle::new()?,
+            stats: StatsCollector::new(name.as_deref()),
+            name,
+            trace: AtomicBool::new(false),
+            capture: Arc::new(Mutex::new(CaptureState::default())),
+            capturing: AtomicBool::new(false),
+        })
+    }
+
+    fn handler(&self) -> Result<HandlerGuard<'_>, IpconError> {
+        let closed = self.closed.read().unwrap_or_else(|e| e.into_inner());
+
+        if *closed {
+            return Err(Report::new(IpconError::Cancelled)).attach_printable(format!(
+                "{} is closed",
+                self.name.as_deref().unwrap_or("Anon")
+            ));
         }
+
+        Ok(HandlerGuard {
+            backend: &self.backend,
+            _closed: closed,
+        })
+    }
+
+    /// Get the shutdown handle of the peer.
+    /// Shutting it down makes the receives in progress and the following ones fail with
+    /// IpconError::Cancelled. See ipcon_shutdown for details.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Shut the peer down and close it.
+    /// It waits for the libipcon calls in progress in other threads, all the following
+    /// operations fail with IpconError::Cancelled. The libipcon handler is freed when the peer
+    /// is dropped, so that the file descriptors borrowed from it stay valid.
+    pub fn close(&self) {
+        self.shutdown.shutdown();
+        *self.closed.write().unwrap_or_else(|e| e.into_inner()) = true;
     }
 
     /// Retrieve netlink socket file descriptor of message receiving interface.
     pub fn get_read_fd(&self) -> Result<i32, IpconError> {
-        unsafe {
-            let fd = ipcon_get_read_fd(Ipcon::to_handler(self.handler));
-            if fd < 0 {
-                Err(Report::new(errno_to_error(fd))).attach_printable(format!(
-                    "ipcon_get_read_fd() {} get read fd failed: {}",
-                    self.name.as_deref().unwrap_or("Anon"),
-                    fd
-                ))
-            } else {
-                Ok(fd)
-            }
+        let fd = self.handler()?.read_fd();
+
+        if fd < 0 {
+            Err(Report::new(errno_to_error(fd))).attach_printable(format!(
+                "ipcon_get_read_fd() {} get read fd failed: {}",
+                self.name.as_deref().unwrap_or("Anon"),
+                fd
+            ))
+        } else {
+            Ok(fd)
         }
     }
 
     /// Retrieve netlink socket file descriptor of message sending interface.
     pub fn get_write_fd(&self) -> Result<i32, IpconError> {
-        unsafe {
-            let fd = ipcon_get_write_fd(Ipcon::to_handler(self.handler));
-            if fd < 0 {
-                Err(Report::new(errno_to_error(fd))).attach_printable(format!(
-                    "ipcon_get_write_fd() {} get write fd failed: {}",
-                    self.name.as_deref().unwrap_or("Anon"),
-                    fd
-                ))
-            } else {
-                Ok(fd)
-            }
+        let fd = self.handler()?.write_fd();
+
+        if fd < 0 {
+            Err(Report::new(errno_to_error(fd))).attach_printable(format!(
+                "ipcon_get_write_fd() {} get write fd failed: {}",
+                self.name.as_deref().unwrap_or("Anon"),
+                fd
+            ))
+        } else {
+            Ok(fd)
         }
     }
 
     /// Retrieve netlink socket file descriptor of control interface.
     pub fn get_ctrl_fd(&self) -> Result<i32, IpconError> {
-        unsafe {
-            let fd = ipcon_get_ctrl_fd(Ipcon::to_handler(self.handler));
-            if fd < 0 {
-                Err(Report::new(errno_to_error(fd))).attach_printable(format!(
-                    "ipcon_get_ctrl_fd() {} get ctrl fd failed: {}",
-                    self.name.as_deref().unwrap_or("Anon"),
-                    fd
-                ))
-            } else {
-                Ok(fd)
-            }
+        let fd = self.handler()?.ctrl_fd();
+
+        if fd < 0 {
+            Err(Report::new(errno_to_error(fd))).attach_printable(format!(
+                "ipcon_get_ctrl_fd() {} get ctrl fd failed: {}",
+                self.name.as_deref().unwrap_or("Anon"),
+                fd
+            ))
+        } else {
+            Ok(fd)
         }
     }
 
+    /// Borrow the file descriptor of message receiving interface.
+    /// It can be registered to poll/epoll based event loops, it is readable when a message may
+    /// be pending. See try_receive_msg().
+    pub fn read_fd(&self) -> Result<BorrowedFd<'_>, IpconError> {
+        let fd = self.get_read_fd()?;
+        /* The handler and its sockets are only freed when the peer is dropped. */
+        Ok(unsafe { BorrowedFd::borrow_raw(fd) })
+    }
+
+    /// Borrow the file descriptor of message sending interface.
+    pub fn write_fd(&self) -> Result<BorrowedFd<'_>, IpconError> {
+        let fd = self.get_write_fd()?;
+        Ok(unsafe { BorrowedFd::borrow_raw(fd) })
+    }
+
+    /// Borrow the file descriptor of control interface.
+    pub fn ctrl_fd(&self) -> Result<BorrowedFd<'_>, IpconError> {
+        let fd = self.get_ctrl_fd()?;
+        Ok(unsafe { BorrowedFd::borrow_raw(fd) })
+    }
+
     /// Inquiry whether a peer is present.
-    pub fn is_peer_present(&self, peer: &str) -> bool {
-        let mut present = false;
-        let p = match CString::new(peer) {
+    pub fn is_peer_present<P: AsPeerName + ?Sized>(&self, peer: &P) -> bool {
+        let p = match peer.as_peer_name() {
             Ok(a) => a,
             Err(_) => return false,
         };
 
-        unsafe {
-            let ptr = p.into_raw();
-            let ret = is_peer_present(Ipcon::to_handler(self.handler), ptr as *const c_char);
-            if ret != 0 {
-                present = true;
-            }
-
-            let _ = CString::from_raw(ptr);
-        }
+        let h = match self.handler() {
+            Ok(h) => h,
+            Err(_) => return false,
+        };
 
-        present
+        h.is_peer_present(&p)
     }
 
     /// Inquiry whether the group of a peer is present.
-    pub fn is_group_present(&self, peer: &str, group: &str) -> bool {
-        let mut present = false;
-        let p = match CString::new(peer) {
-            Ok(a) => a,
-            Err(_) => return false,
+    pub fn is_group_present<P, G>(&self, peer: &P, group: &G) -> bool
+    where
+        P: AsPeerName + ?Sized,
+        G: AsGroupName + ?Sized,
+    {
+        let (p, g) = match (peer.as_peer_name(), group.as_group_name()) {
+            (Ok(p), Ok(g)) => (p, g),
+            _ => return false,
         };
 
-        let g = match CString::new(group) {
-            Ok(a) => a,
+        let h = match self.handler() {
+            Ok(h) => h,
             Err(_) => return false,
         };
 
-        unsafe {
-            let ptr = p.into_raw();
-            let pgtr = g.into_raw();
-            let ret = is_group_present(
-                Ipcon::to_handler(self.handler),
-                ptr as *const c_char,
-                pgtr as *const c_char,
-            );
-            let _ = CString::from_raw(ptr);
-            let _ = CString::from_raw(pgtr);
-
-            if ret != 0 {
-                present = true;
-            }
-        }
-
-        present
+        h.is_group_present(&p, &g)
     }
 
     /// Receive IPCON message.
     /// This function will fail if the peer doesn't enable IPF_RCV_IF.
+    /// It blocks until a message comes or the peer is shut down, see shutdown_handle().
     pub fn receive_msg(&self) -> Result<IpconMsg, IpconError> {
-        let lmsg = LibIpconMsg::new();
-
-        unsafe {
-            let ret = ipcon_rcv(Ipcon::to_handler(self.handler), &lmsg);
-            if ret < 0 {
-                return Err(Report::new(errno_to_error(ret))).attach_printable(format!(
-                    "ipcon_rcv() {} receive message failed: {}",
-                    self.name.as_deref().unwrap_or("Anon"),
-                    ret
-                ));
-            }
-        }
-
-        lmsg.into()
+        self.do_receive(None)
     }
 
     /// Send an unicast IPCON message to a specific peer.
     /// This function will fail if the peer doesn't enable IPF_SND_IF.
-    pub fn send_unicast_msg(&self, peer: &str, buf: &[u8]) -> Result<(), IpconError> {
+    pub fn send_unicast_msg<P: AsPeerName + ?Sized>(
+        &self,
+        peer: &P,
+        buf: &[u8],
+    ) -> Result<(), IpconError> {
         self.send_unicast_msg_by_ref(peer, buf)
     }
 
     /// Send an unicast IPCON message to a specific peer.
     /// This function will fail if the peer doesn't enable IPF_SND_IF.
-    pub fn send_unicast_msg_by_ref(&self, peer: &str, buf: &[u8]) -> Result<(), IpconError> {
-        valid_name(peer).attach_printable(format!("Invalid peer name: {}", peer))?;
+    pub fn send_unicast_msg_by_ref<P: AsPeerName + ?Sized>(
+        &self,
+        peer: &P,
+        buf: &[u8],
+    ) -> Result<(), IpconError> {
+        let ret = peer
+            .as_peer_name()
+            .attach_printable("send_unicast_msg() invalid peer name")
+            .and_then(|p| {
+                self.do_send_unicast_msg(&p, buf)?;
+                self.stats.sent(StatsTarget::Peer(p.as_str()), buf.len());
+                Ok(())
+            });
+
+        if let Err(e) = &ret {
+            self.stats.send_error(*e.current_context());
+        }
+
+        ret
+    }
+
+    fn do_send_unicast_msg(&self, peer: &PeerName, buf: &[u8]) -> Result<(), IpconError> {
+        let buf = self.outgoing(buf)?;
 
         if buf.len() > IPCON_MAX_PAYLOAD_LEN {
             return Err(Report::new(IpconError::InvalidData)).attach_printable(format!(
@@ -315,156 +562,155 @@ impl Ipcon {
             ));
         }
 
-        let pname = CString::new(peer).map_err(|_| Report::new(IpconError::InvalidData))?;
-
-        unsafe {
-            let ptr = pname.into_raw();
-            let ret = ipcon_send_unicast(
-                Ipcon::to_handler(self.handler),
-                ptr as *const c_char,
-                buf.as_ptr(),
-                buf.len() as size_t,
-            );
+        let ret = self.handler()?.send_unicast(peer, &buf);
 
-            let _ = CString::from_raw(ptr);
-
-            if ret < 0 {
-                return Err(Report::new(errno_to_error(ret))).attach_printable(format!(
-                    "send_unicast_msg() {} send message to peer `{}` failed: {}",
-                    self.name.as_deref().unwrap_or("Anon"),
-                    peer,
-                    ret
-                ));
-            }
+        if ret < 0 {
+            return Err(Report::new(errno_to_error(ret))).attach_printable(format!(
+                "send_unicast_msg() {} send message to peer `{}` failed: {}",
+                self.name.as_deref().unwrap_or("Anon"),
+                peer,
+                ret
+            ));
         }
 
+        self.capture(IpconMsgType::IpconMsgTypeNormal, peer.as_str(), None, &buf);
+
         Ok(())
     }
 
     /// Register a multicast group.
-    pub fn register_group(&self, group: &str) -> Result<(), IpconError> {
-        valid_name(group).attach_printable("register_group error: invalid group name")?;
-
-        let g = CString::new(group).map_err(|_| Report::new(IpconError::InvalidName))?;
-
-        unsafe {
-            let ptr = g.into_raw();
-            let ret = ipcon_register_group(Ipcon::to_handler(self.handler), ptr as *const c_char);
-            let _ = CString::from_raw(ptr);
-            if ret < 0 {
-                return Err(Report::new(errno_to_error(ret))).attach_printable(format!(
-                    "ipcon_register_group() {} register `{}` failed: {}",
-                    self.name.as_deref().unwrap_or("Anon"),
-                    group,
-                    ret
-                ));
-            }
+    pub fn register_group<G: AsGroupName + ?Sized>(&self, group: &G) -> Result<(), IpconError> {
+        let g = group
+            .as_group_name()
+            .attach_printable("register_group error: invalid group name")?;
+
+        let ret = self.handler()?.register_group(&g);
+        if ret < 0 {
+            return Err(Report::new(errno_to_error(ret))).attach_printable(format!(
+                "ipcon_register_group() {} register `{}` failed: {}",
+                self.name.as_deref().unwrap_or("Anon"),
+                g,
+                ret
+            ));
         }
 
         Ok(())
     }
 
     /// Unregister a multicast group.
-    pub fn unregister_group(&self, group: &str) -> Result<(), IpconError> {
-        valid_name(group).attach_printable(format!("Invalid group name: {}", group))?;
-
-        let g = CString::new(group).map_err(|_| Report::new(IpconError::InvalidName))?;
-
-        unsafe {
-            let ptr = g.into_raw();
-            let ret = ipcon_unregister_group(Ipcon::to_handler(self.handler), ptr as *const c_char);
-            let _ = CString::from_raw(ptr);
-            if ret < 0 {
-                return Err(Report::new(errno_to_error(ret))).attach_printable(format!(
-                    "ipcon_unregister_group() {} unregister `{}` failed: {}",
-                    self.name.as_deref().unwrap_or("Anon"),
-                    group,
-                    ret
-                ));
-            }
+    pub fn unregister_group<G: AsGroupName + ?Sized>(&self, group: &G) -> Result<(), IpconError> {
+        let g = group
+            .as_group_name()
+            .attach_printable("unregister_group error: invalid group name")?;
+
+        let ret = self.handler()?.unregister_group(&g);
+        if ret < 0 {
+            return Err(Report::new(errno_to_error(ret))).attach_printable(format!(
+                "ipcon_unregister_group() {} unregister `{}` failed: {}",
+                self.name.as_deref().unwrap_or("Anon"),
+                g,
+                ret
+            ));
         }
 
         Ok(())
     }
 
     /// Subscribe a multicast group of a peer.
-    pub fn join_group(&self, peer: &str, group: &str) -> Result<(), IpconError> {
-        valid_name(peer).attach_printable(format!("Invalid peer name: {}", peer))?;
-        valid_name(group).attach_printable(format!("Invalid group name: {}", group))?;
-
-        let p = CString::new(peer).map_err(|_| Report::new(IpconError::InvalidName))?;
-        let g = CString::new(group).map_err(|_| Report::new(IpconError::InvalidName))?;
-
-        unsafe {
-            let ptr = p.into_raw();
-            let pgtr = g.into_raw();
-            let ret = ipcon_join_group(
-                Ipcon::to_handler(self.handler),
-                ptr as *const c_char,
-                pgtr as *const c_char,
-            );
-            let _ = CString::from_raw(ptr);
-            let _ = CString::from_raw(pgtr);
-            if ret < 0 {
-                return Err(Report::new(errno_to_error(ret))).attach_printable(format!(
-                    "ipcon_join_group() {} join `{}@{}` failed: {}",
-                    self.name.as_deref().unwrap_or("Anon"),
-                    group,
-                    peer,
-                    ret
-                ));
-            }
+    pub fn join_group<P, G>(&self, peer: &P, group: &G) -> Result<(), IpconError>
+    where
+        P: AsPeerName + ?Sized,
+        G: AsGroupName + ?Sized,
+    {
+        let p = peer
+            .as_peer_name()
+            .attach_printable("join_group error: invalid peer name")?;
+        let g = group
+            .as_group_name()
+            .attach_printable("join_group error: invalid group name")?;
+
+        let ret = self.handler()?.join_group(&p, &g);
+        if ret < 0 {
+            return Err(Report::new(errno_to_error(ret))).attach_printable(format!(
+                "ipcon_join_group() {} join `{}@{}` failed: {}",
+                self.name.as_deref().unwrap_or("Anon"),
+                g,
+                p,
+                ret
+            ));
         }
 
         Ok(())
     }
 
     /// Unsubscribe a multicast group of a peer.
-    pub fn leave_group(&self, peer: &str, group: &str) -> Result<(), IpconError> {
-        valid_name(peer).attach_printable(format!("Invalid peer name: {}", peer))?;
-        valid_name(group).attach_printable(format!("Invalid group name: {}", group))?;
-
-        let p = CString::new(peer).map_err(|_| Report::new(IpconError::InvalidName))?;
-        let g = CString::new(group).map_err(|_| Report::new(IpconError::InvalidName))?;
-
-        unsafe {
-            let ptr = p.into_raw();
-            let pgtr = g.into_raw();
-            let ret = ipcon_leave_group(
-                Ipcon::to_handler(self.handler),
-                ptr as *const c_char,
-                pgtr as *const c_char,
-            );
-            let _ = CString::from_raw(ptr);
-            let _ = CString::from_raw(pgtr);
-
-            if ret < 0 {
-                return Err(Report::new(errno_to_error(ret))).attach_printable(format!(
-                    "ipcon_leave_group() {} leave `{}@{}` failed: {}",
-                    self.name.as_deref().unwrap_or("Anon"),
-                    group,
-                    peer,
-                    ret
-                ));
-            }
+    pub fn leave_group<P, G>(&self, peer: &P, group: &G) -> Result<(), IpconError>
+    where
+        P: AsPeerName + ?Sized,
+        G: AsGroupName + ?Sized,
+    {
+        let p = peer
+            .as_peer_name()
+            .attach_printable("leave_group error: invalid peer name")?;
+        let g = group
+            .as_group_name()
+            .attach_printable("leave_group error: invalid group name")?;
+
+        let ret = self.handler()?.leave_group(&p, &g);
+        if ret < 0 {
+            return Err(Report::new(errno_to_error(ret))).attach_printable(format!(
+                "ipcon_leave_group() {} leave `{}@{}` failed: {}",
+                self.name.as_deref().unwrap_or("Anon"),
+                g,
+                p,
+                ret
+            ));
         }
 
         Ok(())
     }
 
     /// Send multicast messages to an owned group.
-    pub fn send_multicast(&self, group: &str, buf: &[u8], sync: bool) -> Result<(), IpconError> {
+    pub fn send_multicast<G: AsGroupName + ?Sized>(
+        &self,
+        group: &G,
+        buf: &[u8],
+        sync: bool,
+    ) -> Result<(), IpconError> {
         self.send_multicast_by_ref(group, buf, sync)
     }
 
     /// Send multicast messages to an owned group.
-    pub fn send_multicast_by_ref(
+    pub fn send_multicast_by_ref<G: AsGroupName + ?Sized>(
+        &self,
+        group: &G,
+        buf: &[u8],
+        sync: bool,
+    ) -> Result<(), IpconError> {
+        let ret = group
+            .as_group_name()
+            .attach_printable("send_multicast() invalid group name")
+            .and_then(|g| {
+                self.do_send_multicast(&g, buf, sync)?;
+                self.stats
+                    .sent(StatsTarget::OwnedGroup(g.as_str()), buf.len());
+                Ok(())
+            });
+
+        if let Err(e) = &ret {
+            self.stats.send_error(*e.current_context());
+        }
+
+        ret
+    }
+
+    fn do_send_multicast(
         &self,
-        group: &str,
+        group: &GroupName,
         buf: &[u8],
         sync: bool,
     ) -> Result<(), IpconError> {
-        valid_name(group).attach_printable(format!("Invalid group name: {}", group))?;
+        let buf = self.outgoing(buf)?;
 
         if buf.len() > IPCON_MAX_PAYLOAD_LEN {
             return Err(Report::new(IpconError::InvalidData)).attach_printable(format!(
@@ -474,59 +720,107 @@ impl Ipcon {
             ));
         }
 
-        let g = CString::new(group).map_err(|_| Report::new(IpconError::InvalidName))?;
+        let ret = self.handler()?.send_multicast(group, &buf, sync);
 
-        let mut s: i32 = 0;
-        if sync {
-            s = 1;
+        if ret < 0 {
+            return Err(Report::new(errno_to_error(ret))).attach_printable(format!(
+                "ipcon_send_multicast() to `{}@{}` failed: {}",
+                group,
+                self.name.as_deref().unwrap_or("Anon"),
+                ret
+            ));
         }
 
-        unsafe {
-            let pgtr = g.into_raw();
-            let ret = ipcon_send_multicast(
-                Ipcon::to_handler(self.handler),
-                pgtr as *const c_char,
-                buf.as_ptr(),
-                buf.len() as size_t,
-                s,
-            );
-            let _ = CString::from_raw(pgtr);
-
-            if ret < 0 {
-                return Err(Report::new(errno_to_error(ret))).attach_printable(format!(
-                    "ipcon_send_multicast() to `{}@{}` failed: {}",
-                    group,
-                    self.name.as_deref().unwrap_or("Anon"),
-                    ret
-                ));
-            }
-        }
+        self.capture(
+            IpconMsgType::IpconMsgTypeGroup,
+            self.name.as_deref().unwrap_or("Anon"),
+            Some(group.as_str()),
+            &buf,
+        );
 
         Ok(())
     }
 
+    /// Get a handle sending unicast messages to a peer.
+    /// The name is validated once, sending through the handle doesn't allocate it again.
+    pub fn unicast_target<P: AsPeerName + ?Sized>(
+        &self,
+        peer: &P,
+    ) -> Result<Target<'_>, IpconError> {
+        Ok(Target {
+            ipcon: self,
+            peer: peer.as_peer_name()?.into_owned(),
+        })
+    }
+
+    /// Get a handle sending multicast messages to an owned group.
+    /// The name is validated once, sending through the handle doesn't allocate it again.
+    pub fn group_publisher<G: AsGroupName + ?Sized>(
+        &self,
+        group: &G,
+    ) -> Result<Publisher<'_>, IpconError> {
+        Ok(Publisher {
+            ipcon: self,
+            group: group.as_group_name()?.into_owned(),
+        })
+    }
+
     /// Receiving message with timeout.
     /// receive_msg() will block until a message come. receive_msg_timeout() adds a timeout to
     /// it.The timeout is specified with seconds and microseconds.
     pub fn receive_msg_timeout(&self, tv_sec: u32, tv_usec: u32) -> Result<IpconMsg, IpconError> {
-        let lmsg = LibIpconMsg::new();
-        let t = libc::timeval {
-            tv_sec: tv_sec as libc::time_t,
-            tv_usec: tv_usec as libc::suseconds_t,
-        };
+        let timeout = Duration::from_secs(tv_sec as u64) + Duration::from_micros(tv_usec as u64);
+        self.do_receive(Some(timeout))
+    }
+
+    /// Receive a message if one is pending, without blocking.
+    /// Unlike receive_msg_nonblock(), no pending message is not an error. It is meant to drain
+    /// the messages once the read fd is reported readable by an event loop.
+    pub fn try_receive_msg(&self) -> Result<Option<IpconMsg>, IpconError> {
+        self.shutdown.check()?;
+
+        let mut lmsg = LibIpconMsg::new();
+        let ret = self.handler()?.rcv(&mut lmsg);
+
+        if ret >= 0 {
+            return self.received(lmsg).map(Some);
+        }
+
+        let err = errno_to_error(ret);
+        if err == IpconError::SysErrorTimeOut {
+            return Ok(None);
+        }
 
-        unsafe {
-            let ret = ipcon_rcv_timeout(Ipcon::to_handler(self.handler), &lmsg, &t);
-            if ret < 0 {
-                return Err(Report::new(errno_to_error(ret))).attach_printable(format!(
-                    "ipcon_rcv_timeout() {} receive message failed: {}",
-                    self.name.as_deref().unwrap_or("Anon"),
-                    ret
+        self.stats.receive_error(err);
+        Err(Report::new(err)).attach_printable(format!(
+            "ipcon_rcv_timeout() {} receive message failed: {}",
+            self.name.as_deref().unwrap_or("Anon"),
+            ret
+        ))
+    }
+
+    /* Poll the read fd together with the shutdown eventfd instead of blocking in libipcon,
+     * libipcon is only called when a message may be pending and the handler lock is released
+     * while waiting, so that close() is not blocked by a receiving thread. */
+    fn do_receive(&self, timeout: Option<Duration>) -> Result<IpconMsg, IpconError> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+
+        loop {
+            if let Some(msg) = self.try_receive_msg()? {
+                return Ok(msg);
+            }
+
+            let left = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            if left == Some(Duration::ZERO) {
+                self.stats.receive_error(IpconError::SysErrorTimeOut);
+                return Err(Report::new(IpconError::SysErrorTimeOut)).attach_printable(format!(
+                    "{} receive message timed out",
+                    self.name.as_deref().unwrap_or("Anon")
                 ));
             }
-        }
 
-        lmsg.into()
+            self.shutdown.wait_readable(self.get_read_fd()?, left)?;
+        }
     }
 
     /// Receiving message without block.
@@ -534,4 +828,245 @@ impl Ipcon {
     pub fn receive_msg_nonblock(&self) -> Result<IpconMsg, IpconError> {
         self.receive_msg_timeout(0, 0)
     }
+
+    /// Enable or disable trace context propagation.
+    /// When enabled, the trace context of the current thread is sent with every message, and the
+    /// trace context carried by a received message is stored in its IpconMsgBody. Both the
+    /// sending and the receiving peers should enable it. See ipcon_trace for details.
+    pub fn set_trace_propagation(&self, enable: bool) {
+        self.trace.store(enable, Ordering::Relaxed);
+    }
+
+    /// Whether trace context propagation is enabled.
+    pub fn trace_propagation(&self) -> bool {
+        self.trace.load(Ordering::Relaxed)
+    }
+
+    fn outgoing<'a>(&self, buf: &'a [u8]) -> Result<Cow<'a, [u8]>, IpconError> {
+        if self.trace_propagation() {
+            Ok(Cow::Owned(ipcon_trace::inject(buf)?))
+        } else {
+            Ok(Cow::Borrowed(buf))
+        }
+    }
+
+    fn received(&self, lmsg: LibIpconMsg) -> Result<IpconMsg, IpconError> {
+        let msg: Result<IpconMsg, IpconError> = lmsg.into();
+
+        if let Ok(m) = &msg {
+            self.capture_record(|| CaptureRecord::from_msg(m, CaptureDirection::Received));
+        }
+
+        let msg = if self.trace_propagation() {
+            msg.map(ipcon_trace::extract)
+        } else {
+            msg
+        };
+
+        match &msg {
+            Ok(IpconMsg::IpconMsgUser(body)) => match &body.group {
+                Some(group) => self
+                    .stats
+                    .received(StatsTarget::Group(&body.peer, group), body.buf.len()),
+                None => self
+                    .stats
+                    .received(StatsTarget::Peer(&body.peer), body.buf.len()),
+            },
+            Ok(IpconMsg::IpconMsgKevent(_)) => self.stats.received_kevent(),
+            Ok(IpconMsg::IpconMsgInvalid) => {}
+            Err(e) => self.stats.receive_error(*e.current_context()),
+        }
+
+        msg
+    }
+
+    /// Get a snapshot of the statistics of the peer.
+    pub fn stats(&self) -> IpconStats {
+        self.stats.snapshot()
+    }
+
+    /// Reset the statistics of the peer.
+    pub fn reset_stats(&self) {
+        self.stats.reset()
+    }
+
+    /// Label the `ipcon_messages_total` and `ipcon_bytes_total` metrics with the remote peer or
+    /// group. It is disabled by default, since every remote adds new time series to the metrics.
+    /// See ipcon_stats for details.
+    #[cfg(feature = "metrics")]
+    pub fn set_metrics_per_remote(&self, enable: bool) {
+        self.stats.set_metrics_per_remote(enable)
+    }
+
+    fn lock_capture(&self) -> MutexGuard<'_, CaptureState> {
+        self.capture.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /* Flush the sink of a generation every CAPTURE_FLUSH_INTERVAL, until it is replaced or the
+     * peer is dropped. */
+    fn spawn_capture_flusher(capture: Weak<Mutex<CaptureState>>, generation: u64) {
+        let spawned = std::thread::Builder::new()
+            .name("ipcon-capture".to_owned())
+            .spawn(move || loop {
+                std::thread::sleep(CAPTURE_FLUSH_INTERVAL);
+
+                let capture = match capture.upgrade() {
+                    Some(capture) => capture,
+                    None => break,
+                };
+                let mut state = capture.lock().unwrap_or_else(|e| e.into_inner());
+                if state.generation != generation {
+                    break;
+                }
+                if let Some(Err(e)) = state.sink.as_mut().map(|sink| sink.flush()) {
+                    jwarn!("Failed to flush capture: {:?}", e);
+                }
+            });
+
+        if let Err(e) = spawned {
+            jwarn!("Failed to spawn capture flush thread: {}", e);
+        }
+    }
+
+    /// Set the capture sink recording all the messages sent and received by the peer.
+    /// Capturing is stopped if None is specified.
+    /// The sink is flushed every CAPTURE_FLUSH_INTERVAL, the previous sink is flushed and
+    /// dropped.
+    pub fn set_capture(&self, sink: Option<Box<dyn CaptureSink>>) {
+        let mut capture = self.lock_capture();
+        self.capturing.store(sink.is_some(), Ordering::Relaxed);
+        capture.generation += 1;
+        if sink.is_some() {
+            Ipcon::spawn_capture_flusher(Arc::downgrade(&self.capture), capture.generation);
+        }
+        let previous = std::mem::replace(&mut capture.sink, sink);
+        drop(capture);
+
+        if let Some(mut previous) = previous {
+            if let Err(e) = previous.flush() {
+                jwarn!("Failed to flush capture: {:?}", e);
+            }
+        }
+    }
+
+    /// Write out the messages buffered by the capture sink.
+    pub fn flush_capture(&self) -> Result<(), IpconError> {
+        match self.lock_capture().sink.as_mut() {
+            Some(sink) => sink.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Record all the messages sent and received by the peer to a pcapng file.
+    /// See ipcon_capture for the format of the file.
+    pub fn capture_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), IpconError> {
+        let writer = PcapngWriter::create(path)?;
+        self.set_capture(Some(Box::new(writer)));
+        Ok(())
+    }
+
+    fn capture_record<F: FnOnce() -> Option<CaptureRecord>>(&self, f: F) {
+        if !self.capturing.load(Ordering::Relaxed) {
+            return;
+        }
+
+        /* The record is built before taking the lock, only the sink is serialized. */
+        let record = match f() {
+            Some(record) => record,
+            None => return,
+        };
+
+        if let Some(sink) = self.lock_capture().sink.as_mut() {
+            if let Err(e) = sink.capture(&record) {
+                jwarn!("Failed to capture message: {:?}", e);
+            }
+        }
+    }
+
+    fn capture(&self, msg_type: IpconMsgType, peer: &str, group: Option<&str>, buf: &[u8]) {
+        self.capture_record(|| {
+            Some(CaptureRecord {
+                timestamp: SystemTime::now(),
+                direction: CaptureDirection::Sent,
+                msg_type,
+                peer: peer.to_owned(),
+                group: group.map(|g| g.to_owned()),
+                payload: buf.to_vec(),
+            })
+        });
+    }
+}
+
+/// Unicast destination resolved by Ipcon::unicast_target().
+#[derive(Clone)]
+pub struct Target<'a> {
+    ipcon: &'a Ipcon,
+    peer: PeerName,
+}
+
+impl Target<'_> {
+    /// Get the destination peer.
+    pub fn peer(&self) -> &PeerName {
+        &self.peer
+    }
+
+    /// Inquiry whether the destination peer is present.
+    pub fn is_present(&self) -> bool {
+        self.ipcon.is_peer_present(&self.peer)
+    }
+
+    /// Send an unicast message to the destination peer.
+    pub fn send(&self, buf: &[u8]) -> Result<(), IpconError> {
+        self.ipcon.send_unicast_msg_by_ref(&self.peer, buf)
+    }
+}
+
+/// Owned group resolved by Ipcon::group_publisher().
+#[derive(Clone)]
+pub struct Publisher<'a> {
+    ipcon: &'a Ipcon,
+    group: GroupName,
+}
+
+impl Publisher<'_> {
+    /// Get the group.
+    pub fn group(&self) -> &GroupName {
+        &self.group
+    }
+
+    /// Send a multicast message to the group.
+    pub fn send(&self, buf: &[u8], sync: bool) -> Result<(), IpconError> {
+        self.ipcon.send_multicast_by_ref(&self.group, buf, sync)
+    }
+}
+
+/// Receive path of an IPCON peer.
+/// Message handling code written against this trait can be fed with the messages of a recorded
+/// session by ipcon_replay::SessionReplayer instead of a live Ipcon.
+pub trait IpconReceive {
+    /// Receive a message, blocking until one comes.
+    /// Implementations which can be shut down fail with IpconError::Cancelled.
+    fn receive_msg(&self) -> Result<IpconMsg, IpconError>;
+
+    /// Receive a message, failing with SysErrorTimeOut if none comes before the timeout.
+    fn receive_msg_timeout(&self, tv_sec: u32, tv_usec: u32) -> Result<IpconMsg, IpconError>;
+
+    /// Receive a message without blocking.
+    fn receive_msg_nonblock(&self) -> Result<IpconMsg, IpconError> {
+        self.receive_msg_timeout(0, 0)
+    }
+}
+
+impl IpconReceive for Ipcon {
+    fn receive_msg(&self) -> Result<IpconMsg, IpconError> {
+        Ipcon::receive_msg(self)
+    }
+
+    fn receive_msg_timeout(&self, tv_sec: u32, tv_usec: u32) -> Result<IpconMsg, IpconError> {
+        Ipcon::receive_msg_timeout(self, tv_sec, tv_usec)
+    }
+
+    fn receive_msg_nonblock(&self) -> Result<IpconMsg, IpconError> {
+        Ipcon::receive_msg_nonblock(self)
+    }
 }
@@ -1,16 +1,28 @@
 use crate::ipcon::{Ipcon, IpconFlag};
+use crate::ipcon_capture::CaptureSink;
 use crate::ipcon_error::IpconError;
 use crate::ipcon_msg::IpconMsg;
+use crate::ipcon_name::{AsGroupName, AsPeerName};
+use crate::ipcon_shutdown::ShutdownHandle;
+use crate::ipcon_stats::IpconStats;
+use std::os::unix::io::AsRawFd;
 use tokio::io::unix::AsyncFd;
 #[allow(unused)]
 use {
-    error_stack::{Context, IntoReport, Result, ResultExt},
+    error_stack::{Context, Report, Result, ResultExt},
     jlogger_tracing::{jdebug, jerror, jinfo, jtrace, jwarn},
 };
 
 #[link(name = "ipcon")]
 extern "C" {}
 
+/* Register a fd of the peer with the tokio runtime.
+ * The fds of the peer stay open as long as the peer, which outlives the AsyncFd borrowing it in
+ * every method. */
+fn register_fd<T: AsRawFd>(fd: T) -> std::io::Result<AsyncFd<T>> {
+    unsafe { AsyncFd::register(fd) }.map_err(std::io::Error::from)
+}
+
 /// Async version of IPCON peer.
 pub struct AsyncIpcon {
     ih: Ipcon,
@@ -38,8 +50,8 @@ impl AsyncIpcon {
     }
 
     /// Inquiry whether a peer is present.
-    pub async fn is_peer_present(&self, peer: &str) -> bool {
-        let async_ctrl = AsyncFd::new(self.ih.get_ctrl_fd().unwrap()).unwrap();
+    pub async fn is_peer_present<P: AsPeerName + ?Sized>(&self, peer: &P) -> bool {
+        let async_ctrl = register_fd(self.ih.get_ctrl_fd().unwrap()).unwrap();
 
         loop {
             let mut guide = async_ctrl.writable().await.unwrap();
@@ -53,8 +65,12 @@ impl AsyncIpcon {
     }
 
     /// Inquiry whether the group of a peer is present.
-    pub async fn is_group_present(&self, peer: &str, group: &str) -> bool {
-        let async_ctrl = AsyncFd::new(self.ih.get_ctrl_fd().unwrap()).unwrap();
+    pub async fn is_group_present<P, G>(&self, peer: &P, group: &G) -> bool
+    where
+        P: AsPeerName + ?Sized,
+        G: AsGroupName + ?Sized,
+    {
+        let async_ctrl = register_fd(self.ih.get_ctrl_fd().unwrap()).unwrap();
 
         loop {
             let mut guide = async_ctrl.writable().await.unwrap();
@@ -67,12 +83,28 @@ impl AsyncIpcon {
 
     /// Receive IPCON message.
     /// This function will fail if the peer doesn't enable IPF_RCV_IF.
+    /// It fails with IpconError::Cancelled once the peer is shut down, see shutdown_handle().
     pub async fn receive_msg(&self) -> Result<IpconMsg, IpconError> {
-        let async_ctrl = AsyncFd::new(self.ih.get_read_fd().unwrap()).unwrap();
+        let async_ctrl = register_fd(self.ih.get_read_fd().unwrap()).unwrap();
+        let shutdown = self.ih.shutdown_handle();
+        let async_shutdown = register_fd(shutdown.clone())
+            .map_err(|e| Report::new(IpconError::SystemErrorOther).attach_printable(e))
+            .attach_printable("Failed to watch the shutdown eventfd")?;
 
         loop {
-            let mut guide = async_ctrl.readable().await.unwrap();
-            match guide.try_io(|_inner| Ok(self.ih.receive_msg())) {
+            shutdown.check()?;
+
+            let mut guide = tokio::select! {
+                guide = async_ctrl.readable() => guide.unwrap(),
+                _ = async_shutdown.readable() => continue,
+            };
+            /* No pending message clears the readiness, and the read fd is polled again. */
+            let received = guide.try_io(|_inner| match self.ih.try_receive_msg() {
+                Ok(Some(msg)) => Ok(Ok(msg)),
+                Ok(None) => Err(std::io::ErrorKind::WouldBlock.into()),
+                Err(e) => Ok(Err(e)),
+            });
+            match received {
                 Ok(ret) => return ret.unwrap().attach_printable("Async receive_msg() failed."),
                 Err(_would_block) => {}
             }
@@ -81,12 +113,16 @@ impl AsyncIpcon {
 
     /// Send an unicast IPCON message to a specific peer.
     /// This function will fail if the peer doesn't enable IPF_SND_IF.
-    pub async fn send_unicast_msg(&self, peer: &str, buf: &[u8]) -> Result<(), IpconError> {
-        let async_ctrl = AsyncFd::new(self.ih.get_write_fd().unwrap()).unwrap();
+    pub async fn send_unicast_msg<P: AsPeerName + ?Sized>(
+        &self,
+        peer: &P,
+        buf: &[u8],
+    ) -> Result<(), IpconError> {
+        let async_ctrl = register_fd(self.ih.get_write_fd().unwrap()).unwrap();
 
         loop {
             let mut guide = async_ctrl.writable().await.unwrap();
-            match guide.try_io(|_inner| Ok(self.ih.send_unicast_msg_by_ref(peer, &buf))) {
+            match guide.try_io(|_inner| Ok(self.ih.send_unicast_msg_by_ref(peer, buf))) {
                 Ok(ret) => {
                     return ret
                         .unwrap()
@@ -98,8 +134,11 @@ impl AsyncIpcon {
     }
 
     /// Register a multicast group.
-    pub async fn register_group(&self, group: &str) -> Result<(), IpconError> {
-        let async_ctrl = AsyncFd::new(self.ih.get_ctrl_fd().unwrap()).unwrap();
+    pub async fn register_group<G: AsGroupName + ?Sized>(
+        &self,
+        group: &G,
+    ) -> Result<(), IpconError> {
+        let async_ctrl = register_fd(self.ih.get_ctrl_fd().unwrap()).unwrap();
 
         loop {
             let mut guide = async_ctrl.writable().await.unwrap();
@@ -115,8 +154,11 @@ impl AsyncIpcon {
     }
 
     /// Unregister a multicast group.
-    pub async fn unregister_group(&self, group: &str) -> Result<(), IpconError> {
-        let async_ctrl = AsyncFd::new(self.ih.get_ctrl_fd().unwrap()).unwrap();
+    pub async fn unregister_group<G: AsGroupName + ?Sized>(
+        &self,
+        group: &G,
+    ) -> Result<(), IpconError> {
+        let async_ctrl = register_fd(self.ih.get_ctrl_fd().unwrap()).unwrap();
 
         loop {
             let mut guide = async_ctrl.writable().await.unwrap();
@@ -132,8 +174,12 @@ impl AsyncIpcon {
     }
 
     /// Subscribe a multicast group of a peer.
-    pub async fn join_group(&self, peer: &str, group: &str) -> Result<(), IpconError> {
-        let async_ctrl = AsyncFd::new(self.ih.get_ctrl_fd().unwrap()).unwrap();
+    pub async fn join_group<P, G>(&self, peer: &P, group: &G) -> Result<(), IpconError>
+    where
+        P: AsPeerName + ?Sized,
+        G: AsGroupName + ?Sized,
+    {
+        let async_ctrl = register_fd(self.ih.get_ctrl_fd().unwrap()).unwrap();
 
         loop {
             let mut guide = async_ctrl.writable().await.unwrap();
@@ -145,8 +191,12 @@ impl AsyncIpcon {
     }
 
     /// Unsubscribe a multicast group of a peer.
-    pub async fn leave_group(&self, peer: &str, group: &str) -> Result<(), IpconError> {
-        let async_ctrl = AsyncFd::new(self.ih.get_ctrl_fd().unwrap()).unwrap();
+    pub async fn leave_group<P, G>(&self, peer: &P, group: &G) -> Result<(), IpconError>
+    where
+        P: AsPeerName + ?Sized,
+        G: AsGroupName + ?Sized,
+    {
+        let async_ctrl = register_fd(self.ih.get_ctrl_fd().unwrap()).unwrap();
 
         loop {
             let mut guide = async_ctrl.writable().await.unwrap();
@@ -158,17 +208,17 @@ impl AsyncIpcon {
     }
 
     /// Send multicast messages to an owned group.
-    pub async fn send_multicast(
+    pub async fn send_multicast<G: AsGroupName + ?Sized>(
         &self,
-        group: &str,
+        group: &G,
         buf: &[u8],
         sync: bool,
     ) -> Result<(), IpconError> {
-        let async_ctrl = AsyncFd::new(self.ih.get_write_fd().unwrap()).unwrap();
+        let async_ctrl = register_fd(self.ih.get_write_fd().unwrap()).unwrap();
 
         loop {
             let mut guide = async_ctrl.writable().await.unwrap();
-            match guide.try_io(|_inner| Ok(self.ih.send_multicast_by_ref(group, &buf, sync))) {
+            match guide.try_io(|_inner| Ok(self.ih.send_multicast_by_ref(group, buf, sync))) {
                 Ok(ret) => {
                     return ret
                         .unwrap()
@@ -191,4 +241,90 @@ impl AsyncIpcon {
     pub fn receive_msg_nonblock(&self) -> Result<IpconMsg, IpconError> {
         self.ih.receive_msg_nonblock()
     }
+
+    /// Get the shutdown handle of the peer.
+    /// See Ipcon::shutdown_handle(). A pending receive_msg() future is woken up by it.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.ih.shutdown_handle()
+    }
+
+    /// Enable or disable trace context propagation.
+    /// See Ipcon::set_trace_propagation().
+    pub fn set_trace_propagation(&self, enable: bool) {
+        self.ih.set_trace_propagation(enable)
+    }
+
+    /// Whether trace context propagation is enabled.
+    pub fn trace_propagation(&self) -> bool {
+        self.ih.trace_propagation()
+    }
+
+    /// Get a snapshot of the statistics of the peer.
+    pub fn stats(&self) -> IpconStats {
+        self.ih.stats()
+    }
+
+    /// Reset the statistics of the peer.
+    pub fn reset_stats(&self) {
+        self.ih.reset_stats()
+    }
+
+    /// Label the traffic metrics with the remote peer or group.
+    /// See Ipcon::set_metrics_per_remote().
+    #[cfg(feature = "metrics")]
+    pub fn set_metrics_per_remote(&self, enable: bool) {
+        self.ih.set_metrics_per_remote(enable)
+    }
+
+    /// Set the capture sink recording all the messages sent and received by the peer.
+    /// See Ipcon::set_capture().
+    pub fn set_capture(&self, sink: Option<Box<dyn CaptureSink>>) {
+        self.ih.set_capture(sink)
+    }
+
+    /// Write out the messages buffered by the capture sink.
+    pub fn flush_capture(&self) -> Result<(), IpconError> {
+        self.ih.flush_capture()
+    }
+
+    /// Record all the messages sent and received by the peer to a pcapng file.
+    pub fn capture_to_file<P: AsRef<std::path::Path>>(&self, path: P) -> Result<(), IpconError> {
+        self.ih.capture_to_file(path)
+    }
+}
+
+/// Make an async peer of a peer, for instance a peer of a LoopbackBus or created with
+/// Ipcon::with_name().
+impl From<Ipcon> for AsyncIpcon {
+    fn from(ih: Ipcon) -> AsyncIpcon {
+        AsyncIpcon { ih }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipcon::IPF_DEFAULT;
+    use crate::ipcon_loopback::LoopbackBus;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn spurious_readiness_does_not_block() {
+        let bus = LoopbackBus::new();
+        let a = AsyncIpcon::from(bus.peer(Some("a"), Some(IPF_DEFAULT)).unwrap());
+        let b = bus.peer(Some("b"), Some(IPF_DEFAULT)).unwrap();
+
+        /* The read fd is readable without any pending message. */
+        let fd = a.ih.get_read_fd().unwrap();
+        nix::unistd::write(fd, &1_u64.to_ne_bytes()).unwrap();
+
+        let pending = tokio::time::timeout(Duration::from_millis(100), a.receive_msg()).await;
+        assert!(pending.is_err());
+
+        b.send_unicast_msg("a", b"ping").unwrap();
+        match a.receive_msg().await.unwrap() {
+            IpconMsg::IpconMsgUser(body) => assert_eq!(body.buf, b"ping"),
+            m => panic!("Unexpected message {:?}", m),
+        }
+    }
 }
@@ -0,0 +1,181 @@
+//! # calloop integration
+//! IpconSource is a calloop event source calling back with every received message, the peer is
+//! passed to the callback to reply:
+//!
+//! ```ignore
+//! let mut event_loop: EventLoop<State> = EventLoop::try_new()?;
+//! let source = IpconSource::new(ipcon)?;
+//!
+//! event_loop
+//!     .handle()
+//!     .insert_source(source, |msg, ipcon, state| {
+//!         if let IpconMsg::IpconMsgUser(body) = msg {
+//!             let _ = ipcon.send_unicast_msg(&body.peer, b"OK");
+//!         }
+//!     })?;
+//!
+//! event_loop.run(None, &mut state, |_| {})?;
+//! ```
+//!
+//! All the pending messages are dispatched when the read fd of the peer is readable. A failed
+//! receive is returned by the dispatch of the event loop. The shutdown eventfd of the peer is
+//! registered too: once the peer is shut down (see ipcon_shutdown), the source removes itself
+//! from the event loop.
+
+use crate::ipcon::Ipcon;
+use crate::ipcon_error::IpconError;
+use crate::ipcon_msg::IpconMsg;
+use crate::ipcon_shutdown::ShutdownHandle;
+use calloop::generic::{FdWrapper, Generic};
+use calloop::{EventSource, Interest, Mode, Poll, PostAction, Readiness, Token, TokenFactory};
+use std::os::unix::io::{AsFd, BorrowedFd};
+#[allow(unused)]
+use {
+    error_stack::{Report, Result, ResultExt},
+    jlogger_tracing::{jdebug, jerror, jinfo, jtrace, jwarn},
+};
+
+/// IPCON peer as a calloop event source.
+pub struct IpconSource {
+    ipcon: Ipcon,
+    source: Generic<FdWrapper<i32>, Report<IpconError>>,
+    shutdown: Generic<ShutdownHandle, Report<IpconError>>,
+}
+
+impl IpconSource {
+    /// Create a source from a peer.
+    /// It fails if the peer doesn't enable IPF_RCV_IF.
+    pub fn new(ipcon: Ipcon) -> Result<IpconSource, IpconError> {
+        let fd = ipcon
+            .get_read_fd()
+            .attach_printable("calloop source needs the message receiving interface")?;
+
+        /* The fd is owned by the peer, which is owned by the source. */
+        let fd = unsafe { FdWrapper::new(fd) };
+
+        let shutdown = ipcon.shutdown_handle();
+
+        Ok(IpconSource {
+            ipcon,
+            source: Generic::new_with_error(fd, Interest::READ, Mode::Level),
+            shutdown: Generic::new_with_error(shutdown, Interest::READ, Mode::Level),
+        })
+    }
+
+    /// Get the peer of the source.
+    pub fn ipcon(&self) -> &Ipcon {
+        &self.ipcon
+    }
+
+    /// Get the peer back.
+    pub fn into_inner(self) -> Ipcon {
+        self.ipcon
+    }
+}
+
+impl EventSource for IpconSource {
+    type Event = IpconMsg;
+    type Metadata = Ipcon;
+    type Ret = ();
+    type Error = Report<IpconError>;
+
+    fn process_events<F>(
+        &mut self,
+        readiness: Readiness,
+        token: Token,
+        mut callback: F,
+    ) -> std::result::Result<PostAction, Self::Error>
+    where
+        F: FnMut(Self::Event, &mut Self::Metadata) -> Self::Ret,
+    {
+        let ipcon = &mut self.ipcon;
+
+        let action = self
+            .shutdown
+            .process_events(readiness, token, |_, _| Ok(PostAction::Remove))?;
+        if action == PostAction::Remove {
+            return Ok(action);
+        }
+
+        self.source.process_events(readiness, token, |_, _| loop {
+            match ipcon.try_receive_msg() {
+                Ok(Some(msg)) => callback(msg, ipcon),
+                Ok(None) => return Ok(PostAction::Continue),
+                Err(e) if *e.current_context() == IpconError::Cancelled => {
+                    return Ok(PostAction::Remove)
+                }
+                Err(e) => return Err(e),
+            }
+        })
+    }
+
+    fn register(
+        &mut self,
+        poll: &mut Poll,
+        token_factory: &mut TokenFactory,
+    ) -> calloop::Result<()> {
+        self.source.register(poll, token_factory)?;
+        self.shutdown.register(poll, token_factory)
+    }
+
+    fn reregister(
+        &mut self,
+        poll: &mut Poll,
+        token_factory: &mut TokenFactory,
+    ) -> calloop::Result<()> {
+        self.source.reregister(poll, token_factory)?;
+        self.shutdown.reregister(poll, token_factory)
+    }
+
+    fn unregister(&mut self, poll: &mut Poll) -> calloop::Result<()> {
+        self.source.unregister(poll)?;
+        self.shutdown.unregister(poll)
+    }
+}
+
+impl AsFd for IpconSource {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.source.get_ref().as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipcon::IPF_DEFAULT;
+    use crate::ipcon_loopback::LoopbackBus;
+    use calloop::EventLoop;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn shutdown_removes_the_source() {
+        let bus = LoopbackBus::new();
+        let a = bus.peer(Some("a"), Some(IPF_DEFAULT)).unwrap();
+        let b = bus.peer(Some("b"), Some(IPF_DEFAULT)).unwrap();
+        let shutdown = a.shutdown_handle();
+
+        let mut event_loop: EventLoop<Vec<IpconMsg>> = EventLoop::try_new().unwrap();
+        event_loop
+            .handle()
+            .insert_source(IpconSource::new(a).unwrap(), |msg, _, received| {
+                received.push(msg)
+            })
+            .unwrap();
+
+        let mut received = Vec::new();
+        b.send_unicast_msg("a", b"hello").unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_secs(1)), &mut received)
+            .unwrap();
+        assert_eq!(received.len(), 1);
+
+        /* Woken up by the shutdown, the removed source drops the peer. */
+        shutdown.shutdown();
+        let start = Instant::now();
+        event_loop
+            .dispatch(Some(Duration::from_secs(5)), &mut received)
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(!b.is_peer_present("a"));
+    }
+}
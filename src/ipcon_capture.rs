@@ -0,0 +1,630 @@
+//! # Packet capture
+//! Messages sent and received by an Ipcon peer can be recorded to a pcapng file with
+//! Ipcon::capture_to_file() or Ipcon::set_capture(). The file can be opened with Wireshark by
+//! using the Lua dissector `wireshark/ipcon.lua` shipped with this crate, or read back with
+//! PcapngReader.
+//!
+//! The packets use the LINKTYPE_USER0 link type (147). Each packet holds one message:
+//!
+//! ```text
+//! offset  size  field
+//!  0      1     version (1)
+//!  1      1     direction (0: sent, 1: received)
+//!  2      1     message type (0: normal, 1: group, 2: kevent)
+//!  3      1     reserved
+//!  4      32    peer name, NUL padded
+//!  36     32    group name, NUL padded
+//!  68     4     payload length (big endian)
+//!  72     -     payload
+//! ```
+//!
+//! The payload of a kevent message is the raw `struct ipcon_kevent` of libipcon.
+
+use crate::ipcon_error::IpconError;
+use crate::ipcon_msg::{IpconKevent, IpconMsg, IpconMsgBody, IpconMsgType, IPCON_MAX_NAME_LEN};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+#[allow(unused)]
+use {
+    error_stack::{Report, Result, ResultExt},
+    jlogger_tracing::{jdebug, jerror, jinfo, jtrace, jwarn},
+};
+
+/// Link type of the IPCON capture records (LINKTYPE_USER0).
+pub const LINKTYPE_IPCON: u16 = 147;
+
+/// Longest block accepted by PcapngReader, longer blocks are rejected as invalid data.
+pub const PCAPNG_MAX_BLOCK_LEN: usize = 16 * 1024 * 1024;
+
+/// Interval between two flushes of the capture sink installed on an Ipcon peer.
+pub const CAPTURE_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+const CAPTURE_VERSION: u8 = 1;
+const CAPTURE_HEADER_LEN: usize = 8 + 2 * IPCON_MAX_NAME_LEN;
+
+const BLOCK_SHB: u32 = 0x0A0D_0D0A;
+const BLOCK_IDB: u32 = 0x0000_0001;
+const BLOCK_EPB: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const OPT_END: u16 = 0;
+const OPT_IF_NAME: u16 = 2;
+const OPT_IF_TSRESOL: u16 = 9;
+
+/// Direction of a captured message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaptureDirection {
+    Sent,
+    Received,
+}
+
+/// A captured message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CaptureRecord {
+    pub timestamp: SystemTime,
+    pub direction: CaptureDirection,
+    pub msg_type: IpconMsgType,
+    /// The destination peer of a sent unicast message, or the sender of the other messages.
+    pub peer: String,
+    pub group: Option<String>,
+    pub payload: Vec<u8>,
+}
+
+fn write_name(v: &mut Vec<u8>, name: &str) {
+    let mut field = [0_u8; IPCON_MAX_NAME_LEN];
+    let len = name.len().min(IPCON_MAX_NAME_LEN);
+    field[..len].copy_from_slice(&name.as_bytes()[..len]);
+    v.extend_from_slice(&field);
+}
+
+fn read_name(field: &[u8]) -> Result<String, IpconError> {
+    let end = field.iter().position(|&c| c == 0).unwrap_or(field.len());
+    String::from_utf8(field[..end].to_vec())
+        .map_err(|_| Report::new(IpconError::InvalidName))
+        .attach_printable("Invalid name in capture record")
+}
+
+impl CaptureRecord {
+    /// Create a record of a message, timestamped with the current time.
+    pub fn from_msg(msg: &IpconMsg, direction: CaptureDirection) -> Option<CaptureRecord> {
+        let (msg_type, peer, group, payload) = match msg {
+            IpconMsg::IpconMsgUser(body) => (
+                body.msg_type,
+                body.peer.clone(),
+                body.group.clone(),
+                body.buf.clone(),
+            ),
+            IpconMsg::IpconMsgKevent(k) => (
+                IpconMsgType::IpconMsgTypeKevent,
+                crate::ipcon::IPCON_KERNEL_NAME.to_owned(),
+                Some(crate::ipcon::IPCON_KERNEL_GROUP_NAME.to_owned()),
+                k.to_bytes(),
+            ),
+            IpconMsg::IpconMsgInvalid => return None,
+        };
+
+        Some(CaptureRecord {
+            timestamp: SystemTime::now(),
+            direction,
+            msg_type,
+            peer,
+            group,
+            payload,
+        })
+    }
+
+    /// Convert the record back to the message.
+    pub fn to_msg(&self) -> Result<IpconMsg, IpconError> {
+        let msg = match self.msg_type {
+            IpconMsgType::IpconMsgTypeNormal | IpconMsgType::IpconMsgTypeGroup => {
+                IpconMsg::IpconMsgUser(IpconMsgBody::new(
+                    self.msg_type,
+                    self.peer.clone(),
+                    self.group.clone(),
+                    self.payload.clone(),
+                ))
+            }
+            IpconMsgType::IpconMsgTypeKevent => {
+                IpconMsg::IpconMsgKevent(IpconKevent::from_bytes(&self.payload)?)
+            }
+            IpconMsgType::IpconMsgTypeInvalid => IpconMsg::IpconMsgInvalid,
+        };
+
+        Ok(msg)
+    }
+
+    /// Encode the record as the data of a LINKTYPE_IPCON packet.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(CAPTURE_HEADER_LEN + self.payload.len());
+
+        v.push(CAPTURE_VERSION);
+        v.push(match self.direction {
+            CaptureDirection::Sent => 0,
+            CaptureDirection::Received => 1,
+        });
+        v.push(match self.msg_type {
+            IpconMsgType::IpconMsgTypeNormal => 0,
+            IpconMsgType::IpconMsgTypeGroup => 1,
+            IpconMsgType::IpconMsgTypeKevent => 2,
+            IpconMsgType::IpconMsgTypeInvalid => 3,
+        });
+        v.push(0);
+        write_name(&mut v, &self.peer);
+        write_name(&mut v, self.group.as_deref().unwrap_or(""));
+        v.extend_from_slice(&(self.payload.len() as u32).to_be_bytes());
+        v.extend_from_slice(&self.payload);
+
+        v
+    }
+
+    /// Decode the data of a LINKTYPE_IPCON packet.
+    pub fn decode(data: &[u8], timestamp: SystemTime) -> Result<CaptureRecord, IpconError> {
+        if data.len() < CAPTURE_HEADER_LEN || data[0] != CAPTURE_VERSION {
+            return Err(Report::new(IpconError::InvalidData))
+                .attach_printable("Invalid capture record header");
+        }
+
+        let direction = match data[1] {
+            0 => CaptureDirection::Sent,
+            1 => CaptureDirection::Received,
+            d => {
+                return Err(Report::new(IpconError::InvalidData))
+                    .attach_printable(format!("Invalid capture direction {}", d))
+            }
+        };
+
+        let msg_type = match data[2] {
+            0 => IpconMsgType::IpconMsgTypeNormal,
+            1 => IpconMsgType::IpconMsgTypeGroup,
+            2 => IpconMsgType::IpconMsgTypeKevent,
+            _ => IpconMsgType::IpconMsgTypeInvalid,
+        };
+
+        let peer = read_name(&data[4..36])?;
+        let group = read_name(&data[36..68])?;
+        let len = u32::from_be_bytes([data[68], data[69], data[70], data[71]]) as usize;
+        let payload = data
+            .get(CAPTURE_HEADER_LEN..CAPTURE_HEADER_LEN + len)
+            .ok_or_else(|| Report::new(IpconError::InvalidData))
+            .attach_printable("Truncated capture record")?;
+
+        Ok(CaptureRecord {
+            timestamp,
+            direction,
+            msg_type,
+            peer,
+            group: if group.is_empty() { None } else { Some(group) },
+            payload: payload.to_vec(),
+        })
+    }
+}
+
+/// Destination of captured messages.
+pub trait CaptureSink: Send {
+    fn capture(&mut self, record: &CaptureRecord) -> Result<(), IpconError>;
+
+    /// Write out the records buffered by the sink.
+    fn flush(&mut self) -> Result<(), IpconError> {
+        Ok(())
+    }
+}
+
+fn io_error(e: std::io::Error) -> Report<IpconError> {
+    Report::new(IpconError::SystemErrorOther).attach_printable(e.to_string())
+}
+
+fn pad4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// pcapng file writer of IPCON messages.
+///
+/// Installed as the capture sink of a peer, the records are flushed every
+/// CAPTURE_FLUSH_INTERVAL, by Ipcon::flush_capture(), and when the writer is dropped.
+pub struct PcapngWriter<W: Write> {
+    w: W,
+}
+
+impl PcapngWriter<BufWriter<File>> {
+    /// Create a pcapng file.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, IpconError> {
+        let f = File::create(path.as_ref())
+            .map_err(io_error)
+            .attach_printable(format!("Failed to create {}", path.as_ref().display()))?;
+        PcapngWriter::new(BufWriter::new(f))
+    }
+}
+
+impl<W: Write> PcapngWriter<W> {
+    /// Create a pcapng writer, the section header and the interface description are written
+    /// immediately.
+    pub fn new(w: W) -> Result<Self, IpconError> {
+        let mut writer = PcapngWriter { w };
+
+        let mut shb = Vec::new();
+        shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        shb.extend_from_slice(&1_u16.to_le_bytes());
+        shb.extend_from_slice(&0_u16.to_le_bytes());
+        shb.extend_from_slice(&(-1_i64).to_le_bytes());
+        writer.write_block(BLOCK_SHB, &shb)?;
+
+        let mut idb = Vec::new();
+        idb.extend_from_slice(&LINKTYPE_IPCON.to_le_bytes());
+        idb.extend_from_slice(&0_u16.to_le_bytes());
+        idb.extend_from_slice(&0_u32.to_le_bytes());
+        PcapngWriter::<W>::push_option(&mut idb, OPT_IF_NAME, b"ipcon");
+        PcapngWriter::<W>::push_option(&mut idb, OPT_IF_TSRESOL, &[6]);
+        PcapngWriter::<W>::push_option(&mut idb, OPT_END, &[]);
+        writer.write_block(BLOCK_IDB, &idb)?;
+
+        Ok(writer)
+    }
+
+    fn push_option(v: &mut Vec<u8>, code: u16, value: &[u8]) {
+        v.extend_from_slice(&code.to_le_bytes());
+        v.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        v.extend_from_slice(value);
+        v.resize(pad4(v.len()), 0);
+    }
+
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> Result<(), IpconError> {
+        let total = (12 + pad4(body.len())) as u32;
+        let mut v = Vec::with_capacity(total as usize);
+
+        v.extend_from_slice(&block_type.to_le_bytes());
+        v.extend_from_slice(&total.to_le_bytes());
+        v.extend_from_slice(body);
+        v.resize(8 + pad4(body.len()), 0);
+        v.extend_from_slice(&total.to_le_bytes());
+
+        self.w.write_all(&v).map_err(io_error)
+    }
+
+    /// Write a record as an enhanced packet block.
+    pub fn write_record(&mut self, record: &CaptureRecord) -> Result<(), IpconError> {
+        let data = record.encode();
+        let ts = record
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        let mut epb = Vec::with_capacity(20 + data.len());
+        epb.extend_from_slice(&0_u32.to_le_bytes());
+        epb.extend_from_slice(&((ts >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(ts as u32).to_le_bytes());
+        epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&data);
+
+        self.write_block(BLOCK_EPB, &epb)
+    }
+
+    /// Flush the underlying writer.
+    pub fn flush(&mut self) -> Result<(), IpconError> {
+        self.w.flush().map_err(io_error)
+    }
+}
+
+impl<W: Write> Drop for PcapngWriter<W> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            jwarn!("Failed to flush capture: {:?}", e);
+        }
+    }
+}
+
+impl<W: Write + Send> CaptureSink for PcapngWriter<W> {
+    fn capture(&mut self, record: &CaptureRecord) -> Result<(), IpconError> {
+        self.write_record(record)
+    }
+
+    fn flush(&mut self) -> Result<(), IpconError> {
+        PcapngWriter::flush(self)
+    }
+}
+
+/// pcapng file reader of IPCON messages.
+/// Packets of other link types are skipped.
+pub struct PcapngReader<R: Read> {
+    r: R,
+    big_endian: bool,
+    /* Link type and timestamp unit in nanoseconds of each interface. */
+    interfaces: Vec<(u16, u64)>,
+}
+
+impl PcapngReader<BufReader<File>> {
+    /// Open a pcapng file.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, IpconError> {
+        let f = File::open(path.as_ref())
+            .map_err(io_error)
+            .attach_printable(format!("Failed to open {}", path.as_ref().display()))?;
+        Ok(PcapngReader::new(BufReader::new(f)))
+    }
+}
+
+impl<R: Read> PcapngReader<R> {
+    pub fn new(r: R) -> Self {
+        PcapngReader {
+            r,
+            big_endian: false,
+            interfaces: Vec::new(),
+        }
+    }
+
+    fn u16_at(&self, b: &[u8], off: usize) -> u16 {
+        let v = [b[off], b[off + 1]];
+        if self.big_endian {
+            u16::from_be_bytes(v)
+        } else {
+            u16::from_le_bytes(v)
+        }
+    }
+
+    fn u32_at(&self, b: &[u8], off: usize) -> u32 {
+        let v = [b[off], b[off + 1], b[off + 2], b[off + 3]];
+        if self.big_endian {
+            u32::from_be_bytes(v)
+        } else {
+            u32::from_le_bytes(v)
+        }
+    }
+
+    /// Read the next block, returning its type and body.
+    fn read_block(&mut self) -> Result<Option<(u32, Vec<u8>)>, IpconError> {
+        let mut head = [0_u8; 8];
+        match self.r.read_exact(&mut head[..4]) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(io_error(e)),
+        }
+        self.r.read_exact(&mut head[4..]).map_err(io_error)?;
+
+        let block_type = u32::from_le_bytes([head[0], head[1], head[2], head[3]]);
+        if block_type == BLOCK_SHB {
+            /* The byte order is defined by the section header block itself. */
+            let mut magic = [0_u8; 4];
+            self.r.read_exact(&mut magic).map_err(io_error)?;
+            self.big_endian = match u32::from_le_bytes(magic) {
+                BYTE_ORDER_MAGIC => false,
+                m if m.swap_bytes() == BYTE_ORDER_MAGIC => true,
+                _ => {
+                    return Err(Report::new(IpconError::InvalidData))
+                        .attach_printable("Invalid pcapng byte order magic")
+                }
+            };
+            self.interfaces.clear();
+
+            let total = self.u32_at(&head, 4) as usize;
+            if !(28..=PCAPNG_MAX_BLOCK_LEN).contains(&total) {
+                return Err(Report::new(IpconError::InvalidData))
+                    .attach_printable("Invalid pcapng section header length");
+            }
+            let mut rest = vec![0_u8; total - 12];
+            self.r.read_exact(&mut rest).map_err(io_error)?;
+            return Ok(Some((BLOCK_SHB, rest)));
+        }
+
+        let block_type = self.u32_at(&head, 0);
+        let total = self.u32_at(&head, 4) as usize;
+        if !(12..=PCAPNG_MAX_BLOCK_LEN).contains(&total) || !total.is_multiple_of(4) {
+            return Err(Report::new(IpconError::InvalidData))
+                .attach_printable(format!("Invalid pcapng block length {}", total));
+        }
+
+        let mut body = vec![0_u8; total - 8];
+        self.r.read_exact(&mut body).map_err(io_error)?;
+        body.truncate(total - 12);
+
+        Ok(Some((block_type, body)))
+    }
+
+    fn parse_idb(&mut self, body: &[u8]) -> Result<(), IpconError> {
+        if body.len() < 8 {
+            return Err(Report::new(IpconError::InvalidData))
+                .attach_printable("Truncated interface description block");
+        }
+
+        let link_type = self.u16_at(body, 0);
+        let mut unit = 1_000;
+        let mut off = 8;
+        while off + 4 <= body.len() {
+            let code = self.u16_at(body, off);
+            let len = self.u16_at(body, off + 2) as usize;
+            if code == OPT_END || off + 4 + len > body.len() {
+                break;
+            }
+
+            if code == OPT_IF_TSRESOL && len == 1 {
+                let r = body[off + 4];
+                unit = if r & 0x80 == 0 {
+                    10_u64
+                        .checked_pow(9_u32.saturating_sub(r as u32))
+                        .unwrap_or(1)
+                } else {
+                    1_000_000_000 >> (r & 0x7f).min(30)
+                }
+                .max(1);
+            }
+
+            off += 4 + pad4(len);
+        }
+
+        self.interfaces.push((link_type, unit));
+        Ok(())
+    }
+
+    fn parse_epb(&self, body: &[u8]) -> Result<Option<CaptureRecord>, IpconError> {
+        if body.len() < 20 {
+            return Err(Report::new(IpconError::InvalidData))
+                .attach_printable("Truncated enhanced packet block");
+        }
+
+        let interface = self.u32_at(body, 0) as usize;
+        let (link_type, unit) = match self.interfaces.get(interface) {
+            Some(i) => *i,
+            None => {
+                return Err(Report::new(IpconError::InvalidData))
+                    .attach_printable(format!("Unknown interface {}", interface))
+            }
+        };
+
+        if link_type != LINKTYPE_IPCON {
+            return Ok(None);
+        }
+
+        let ts = ((self.u32_at(body, 4) as u64) << 32) | self.u32_at(body, 8) as u64;
+        let len = self.u32_at(body, 12) as usize;
+        let data = body
+            .get(20..20 + len)
+            .ok_or_else(|| Report::new(IpconError::InvalidData))
+            .attach_printable("Truncated packet data")?;
+
+        let timestamp = UNIX_EPOCH + Duration::from_nanos(ts.saturating_mul(unit));
+        CaptureRecord::decode(data, timestamp).map(Some)
+    }
+
+    /// Read the next record.
+    /// None is returned at the end of the file.
+    pub fn read_record(&mut self) -> Result<Option<CaptureRecord>, IpconError> {
+        while let Some((block_type, body)) = self.read_block()? {
+            match block_type {
+                BLOCK_IDB => self.parse_idb(&body)?,
+                BLOCK_EPB => {
+                    if let Some(record) = self.parse_epb(&body)? {
+                        return Ok(Some(record));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl<R: Read> Iterator for PcapngReader<R> {
+    type Item = Result<CaptureRecord, IpconError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipcon::IPF_DEFAULT;
+    use crate::ipcon_loopback::LoopbackBus;
+
+    fn record(direction: CaptureDirection, group: Option<&str>, payload: &[u8]) -> CaptureRecord {
+        CaptureRecord {
+            /* The file keeps microseconds. */
+            timestamp: UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456),
+            direction,
+            msg_type: if group.is_some() {
+                IpconMsgType::IpconMsgTypeGroup
+            } else {
+                IpconMsgType::IpconMsgTypeNormal
+            },
+            peer: "peer".to_owned(),
+            group: group.map(|g| g.to_owned()),
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[test]
+    fn pcapng_round_trip() {
+        let records = vec![
+            record(CaptureDirection::Sent, None, b"hello"),
+            record(CaptureDirection::Received, Some("group"), b"odd"),
+            record(CaptureDirection::Received, None, b""),
+        ];
+
+        let mut file = Vec::new();
+        let mut writer = PcapngWriter::new(&mut file).unwrap();
+        for r in &records {
+            writer.write_record(r).unwrap();
+        }
+        drop(writer);
+
+        let read = PcapngReader::new(file.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(read, records);
+    }
+
+    #[test]
+    fn oversized_blocks_are_rejected() {
+        let mut file = Vec::new();
+        drop(PcapngWriter::new(&mut file).unwrap());
+        file.extend_from_slice(&BLOCK_EPB.to_le_bytes());
+        file.extend_from_slice(&((PCAPNG_MAX_BLOCK_LEN + 4) as u32).to_le_bytes());
+
+        /* Rejected from its length, before reading the block. */
+        let mut reader = PcapngReader::new(file.as_slice().chain(std::io::repeat(0)));
+        let e = reader.read_record().unwrap_err();
+        assert!(format!("{:?}", e).contains("Invalid pcapng block length"));
+    }
+
+    #[test]
+    fn capture_is_flushed_when_stopped() {
+        let bus = LoopbackBus::new();
+        let a = bus.peer(Some("a"), Some(IPF_DEFAULT)).unwrap();
+        let b = bus.peer(Some("b"), Some(IPF_DEFAULT)).unwrap();
+
+        let path =
+            std::env::temp_dir().join(format!("ipcon-capture-{}.pcapng", std::process::id()));
+        a.capture_to_file(&path).unwrap();
+        a.send_unicast_msg("b", b"ping").unwrap();
+        b.receive_msg().unwrap();
+        b.send_unicast_msg("a", b"pong").unwrap();
+        a.receive_msg().unwrap();
+        a.set_capture(None);
+
+        let read = PcapngReader::open(&path)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[0].direction, CaptureDirection::Sent);
+        assert_eq!(read[0].peer, "b");
+        assert_eq!(read[0].payload, b"ping");
+        assert_eq!(read[1].direction, CaptureDirection::Received);
+        assert_eq!(read[1].peer, "b");
+        assert_eq!(read[1].payload, b"pong");
+    }
+
+    #[test]
+    fn capture_is_flushed_periodically() {
+        let bus = LoopbackBus::new();
+        let a = bus.peer(Some("a"), Some(IPF_DEFAULT)).unwrap();
+        let _b = bus.peer(Some("b"), Some(IPF_DEFAULT)).unwrap();
+
+        let path = std::env::temp_dir().join(format!(
+            "ipcon-capture-periodic-{}.pcapng",
+            std::process::id()
+        ));
+        a.capture_to_file(&path).unwrap();
+        a.send_unicast_msg("b", b"ping").unwrap();
+
+        /* Nothing is sent or received anymore, the record is written out by the timer. */
+        let captured = || {
+            PcapngReader::open(&path)
+                .map(|r| r.filter_map(|record| record.ok()).count())
+                .unwrap_or(0)
+        };
+        let start = std::time::Instant::now();
+        while captured() == 0 && start.elapsed() < 3 * CAPTURE_FLUSH_INTERVAL {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        let count = captured();
+
+        a.set_capture(None);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(count, 1);
+    }
+}
@@ -0,0 +1,559 @@
+//! # Declarative peer configuration
+//! IpconConfig describes a peer, the groups it owns and the groups it subscribes, and is loaded
+//! from a TOML or YAML file:
+//!
+//! ```toml
+//! name = "server"
+//! receive = true
+//! send = true
+//! kevent_filter = true
+//! groups = ["news", "weather"]
+//!
+//! [[subscriptions]]
+//! peer = "clock"
+//! group = "tick"
+//! required = true
+//!
+//! [timeouts]
+//! join_ms = 2000
+//! receive_ms = 500
+//! ```
+//!
+//! Every field is optional: the name defaults to an anonymous peer, both interfaces and the
+//! kevent filter are enabled, and there is neither group nor subscription.
+//!
+//! connect() creates the peer, registers the owned groups and joins the subscriptions. A
+//! required subscription waits up to `join_ms` for its group to be present and fails the
+//! connection, an optional one only logs a warning. Each failure names the item of the
+//! configuration, for example `subscriptions[0] tick@clock`. connect_with() does the same on
+//! another transport, such as a LoopbackBus in tests.
+//!
+//! receive_msg() receives a message of the peer, failing with IpconError::SysErrorTimeOut if
+//! none comes within `receive_ms`. It blocks if `receive_ms` is not set.
+
+use crate::ipcon::{
+    valid_name, Ipcon, IpconFlag, Transport, IPF_DISABLE_KEVENT_FILTER, IPF_RCV_IF, IPF_SND_IF,
+};
+use crate::ipcon_error::IpconError;
+use crate::ipcon_msg::IpconMsg;
+use crate::ipcon_name::PeerName;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{Duration, Instant};
+#[allow(unused)]
+use {
+    error_stack::{Report, Result, ResultExt},
+    jlogger_tracing::{jdebug, jerror, jinfo, jtrace, jwarn},
+};
+
+/// Interval at which the presence of the group of a required subscription is checked.
+const JOIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+fn io_error(e: std::io::Error) -> Report<IpconError> {
+    Report::new(IpconError::SystemErrorOther).attach_printable(e)
+}
+
+/// Subscription of a group of another peer.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SubscriptionConfig {
+    pub peer: String,
+    pub group: String,
+    /// Fail connect() if the group can't be joined.
+    #[serde(default)]
+    pub required: bool,
+}
+
+/// Timeouts of a peer, in milliseconds.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    /// How long a required subscription waits for its group to be present.
+    pub join_ms: u64,
+    /// Receive timeout of the service, see IpconConfig::receive_msg().
+    pub receive_ms: Option<u64>,
+}
+
+/// Configuration of an IPCON peer.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpconConfig {
+    /// Peer name, anonymous if omitted.
+    pub name: Option<String>,
+    /// Enable the message receiving interface (IPF_RCV_IF).
+    pub receive: bool,
+    /// Enable the message sending interface (IPF_SND_IF).
+    pub send: bool,
+    /// Only deliver the kernel events of interest, IPF_DISABLE_KEVENT_FILTER if false.
+    pub kevent_filter: bool,
+    /// Groups owned by the peer.
+    pub groups: Vec<String>,
+    /// Groups of other peers to join.
+    pub subscriptions: Vec<SubscriptionConfig>,
+    pub timeouts: TimeoutConfig,
+}
+
+impl Default for IpconConfig {
+    fn default() -> Self {
+        IpconConfig {
+            name: None,
+            receive: true,
+            send: true,
+            kevent_filter: true,
+            groups: Vec::new(),
+            subscriptions: Vec::new(),
+            timeouts: TimeoutConfig::default(),
+        }
+    }
+}
+
+impl IpconConfig {
+    /// Parse a TOML configuration.
+    pub fn from_toml_str(s: &str) -> Result<IpconConfig, IpconError> {
+        toml::from_str(s)
+            .map_err(|e| Report::new(IpconError::InvalidData).attach_printable(e.to_string()))
+            .attach_printable("Invalid TOML peer configuration")
+    }
+
+    /// Parse a YAML configuration.
+    pub fn from_yaml_str(s: &str) -> Result<IpconConfig, IpconError> {
+        serde_yaml::from_str(s)
+            .map_err(|e| Report::new(IpconError::InvalidData).attach_printable(e.to_string()))
+            .attach_printable("Invalid YAML peer configuration")
+    }
+
+    /// Load a configuration file, its format is selected by the extension: `.toml`, `.yaml` or
+    /// `.yml`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<IpconConfig, IpconError> {
+        let path = path.as_ref();
+        let s = std::fs::read_to_string(path)
+            .map_err(io_error)
+            .attach_printable(format!("Failed to read {}", path.display()))?;
+
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => IpconConfig::from_toml_str(&s),
+            Some("yaml") | Some("yml") => IpconConfig::from_yaml_str(&s),
+            _ => Err(Report::new(IpconError::InvalidData))
+                .attach_printable("Unknown configuration format, expected .toml, .yaml or .yml"),
+        }
+        .attach_printable(format!("Invalid configuration file {}", path.display()))
+    }
+
+    /// Flags of the peer.
+    pub fn flags(&self) -> IpconFlag {
+        let mut flags = 0;
+
+        if self.receive {
+            flags |= IPF_RCV_IF;
+        }
+
+        if self.send {
+            flags |= IPF_SND_IF;
+        }
+
+        if !self.kevent_filter {
+            flags |= IPF_DISABLE_KEVENT_FILTER;
+        }
+
+        flags
+    }
+
+    /// Receive timeout of the service, None to block.
+    pub fn receive_timeout(&self) -> Option<Duration> {
+        self.timeouts.receive_ms.map(Duration::from_millis)
+    }
+
+    /// Receive a message of a peer created from the configuration, within the receive timeout
+    /// if there is one.
+    pub fn receive_msg(&self, ipcon: &Ipcon) -> Result<IpconMsg, IpconError> {
+        match self.receive_timeout() {
+            Some(t) => ipcon.receive_msg_timeout(
+                t.as_secs().try_into().unwrap_or(u32::MAX),
+                t.subsec_micros(),
+            ),
+            None => ipcon.receive_msg(),
+        }
+    }
+
+    /// Check the configuration, all the invalid items are reported.
+    pub fn validate(&self) -> Result<(), IpconError> {
+        let mut report: Option<Report<IpconError>> = None;
+        let mut check = |ret: Result<(), IpconError>, item: String| {
+            if let Err(e) = ret {
+                let e = e.attach_printable(item);
+                match report.as_mut() {
+                    Some(r) => r.extend_one(e),
+                    None => report = Some(e),
+                }
+            }
+        };
+
+        if let Some(name) = &self.name {
+            check(
+                PeerName::local(name).map(|_| ()),
+                format!("name `{}`", name),
+            );
+        }
+
+        if !self.receive && !self.send {
+            check(
+                Err(Report::new(IpconError::InvalidData))
+                    .attach_printable("Neither receive nor send interface is enabled"),
+                "receive, send".to_owned(),
+            );
+        }
+
+        for (i, group) in self.groups.iter().enumerate() {
+            check(valid_name(group), format!("groups[{}] `{}`", i, group));
+        }
+
+        for (i, s) in self.subscriptions.iter().enumerate() {
+            let item = format!("subscriptions[{}] {}@{}", i, s.group, s.peer);
+            check(valid_name(&s.peer), item.clone());
+            check(valid_name(&s.group), item.clone());
+
+            if !self.receive {
+                check(
+                    Err(Report::new(IpconError::InvalidData))
+                        .attach_printable("Subscription needs the receive interface"),
+                    item,
+                );
+            }
+        }
+
+        match report {
+            Some(r) => Err(r),
+            None => Ok(()),
+        }
+    }
+
+    /// Create the peer, register the owned groups and join the subscriptions.
+    pub fn connect(&self) -> Result<Ipcon, IpconError> {
+        self.connect_with(&Transport::Kernel)
+    }
+
+    /// Same as connect(), on a transport.
+    pub fn connect_with(&self, transport: &Transport) -> Result<Ipcon, IpconError> {
+        self.validate()
+            .attach_printable("Invalid peer configuration")?;
+
+        let ipcon = transport.peer(self.name.as_deref(), Some(self.flags()))?;
+
+        for (i, group) in self.groups.iter().enumerate() {
+            ipcon
+                .register_group(group)
+                .attach_printable(format!("groups[{}] `{}`", i, group))?;
+        }
+
+        for (i, s) in self.subscriptions.iter().enumerate() {
+            let item = format!("subscriptions[{}] {}@{}", i, s.group, s.peer);
+
+            match self.join(&ipcon, s) {
+                Ok(()) => {}
+                Err(e) if s.required => return Err(e.attach_printable(item)),
+                Err(e) => jwarn!("Optional {} not joined: {:?}", item, e),
+            }
+        }
+
+        Ok(ipcon)
+    }
+
+    fn join(&self, ipcon: &Ipcon, s: &SubscriptionConfig) -> Result<(), IpconError> {
+        if s.required {
+            let deadline = Instant::now() + Duration::from_millis(self.timeouts.join_ms);
+
+            while !ipcon.is_group_present(&s.peer, &s.group) {
+                if Instant::now() >= deadline {
+                    return Err(Report::new(IpconError::SystemErrorNotExist)).attach_printable(
+                        format!("Group not present after {} ms", self.timeouts.join_ms),
+                    );
+                }
+
+                std::thread::sleep(JOIN_POLL_INTERVAL);
+            }
+        }
+
+        ipcon.join_group(&s.peer, &s.group)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipcon::IPF_DEFAULT;
+    use crate::ipcon_loopback::LoopbackBus;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+    use tracing_subscriber::Registry;
+
+    /* Layer collecting the messages of the warnings. */
+    #[derive(Clone, Default)]
+    struct Warnings(Arc<Mutex<Vec<String>>>);
+
+    impl Visit for Warnings {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                self.0.lock().unwrap().push(format!("{:?}", value));
+            }
+        }
+    }
+
+    impl<S: tracing::Subscriber> Layer<S> for Warnings {
+        fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+            if *event.metadata().level() == tracing::Level::WARN {
+                event.record(&mut self.clone());
+            }
+        }
+    }
+
+    const TOML: &str = r#"
+name = "server"
+receive = true
+send = true
+kevent_filter = false
+groups = ["news", "weather"]
+
+[[subscriptions]]
+peer = "clock"
+group = "tick"
+required = true
+
+[timeouts]
+join_ms = 2000
+receive_ms = 500
+"#;
+
+    const YAML: &str = r#"
+name: server
+kevent_filter: false
+groups: [news, weather]
+subscriptions:
+  - peer: clock
+    group: tick
+    required: true
+timeouts:
+  join_ms: 2000
+  receive_ms: 500
+"#;
+
+    #[test]
+    fn toml_and_yaml_agree() {
+        let config = IpconConfig::from_toml_str(TOML).unwrap();
+
+        assert_eq!(config.name.as_deref(), Some("server"));
+        assert_eq!(config.groups, ["news", "weather"]);
+        assert_eq!(
+            config.subscriptions,
+            [SubscriptionConfig {
+                peer: "clock".to_owned(),
+                group: "tick".to_owned(),
+                required: true,
+            }]
+        );
+        assert_eq!(config.receive_timeout(), Some(Duration::from_millis(500)));
+        assert_eq!(
+            config.flags(),
+            IPF_RCV_IF | IPF_SND_IF | IPF_DISABLE_KEVENT_FILTER
+        );
+        assert!(config.validate().is_ok());
+
+        assert_eq!(IpconConfig::from_yaml_str(YAML).unwrap(), config);
+    }
+
+    #[test]
+    fn fields_are_optional() {
+        let config = IpconConfig::from_toml_str("").unwrap();
+
+        assert_eq!(config, IpconConfig::default());
+        assert_eq!(config.flags(), IPF_RCV_IF | IPF_SND_IF);
+        assert_eq!(config.receive_timeout(), None);
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert!(IpconConfig::from_toml_str("nmae = \"server\"").is_err());
+        assert!(IpconConfig::from_yaml_str("timeouts: {join: 1}").is_err());
+        assert!(IpconConfig::from_toml_str("groups = \"news\"").is_err());
+    }
+
+    #[test]
+    fn all_invalid_items_are_reported() {
+        let config = IpconConfig::from_toml_str(
+            r#"
+receive = false
+groups = ["news", ""]
+
+[[subscriptions]]
+peer = "clock"
+group = "tick"
+"#,
+        )
+        .unwrap();
+
+        let report = format!("{:?}", config.validate().unwrap_err());
+        assert!(report.contains("groups[1]"));
+        assert!(report.contains("subscriptions[0] tick@clock"));
+        assert!(!report.contains("groups[0]"));
+    }
+
+    #[test]
+    fn file_format_follows_the_extension() {
+        let dir = std::env::temp_dir();
+        let id = std::process::id();
+        let toml = dir.join(format!("ipcon-config-{}.toml", id));
+        let yml = dir.join(format!("ipcon-config-{}.yml", id));
+        let txt = dir.join(format!("ipcon-config-{}.txt", id));
+        std::fs::write(&toml, TOML).unwrap();
+        std::fs::write(&yml, YAML).unwrap();
+        std::fs::write(&txt, TOML).unwrap();
+
+        let from_toml = IpconConfig::from_file(&toml);
+        let from_yml = IpconConfig::from_file(&yml);
+        let from_txt = IpconConfig::from_file(&txt);
+        for path in [&toml, &yml, &txt] {
+            std::fs::remove_file(path).unwrap();
+        }
+
+        assert_eq!(from_toml.unwrap(), from_yml.unwrap());
+        assert!(from_txt.is_err());
+    }
+
+    #[test]
+    fn connect_registers_the_owned_groups() {
+        let bus = LoopbackBus::new();
+        let config = IpconConfig::from_toml_str(
+            r#"
+name = "server"
+groups = ["news", "weather"]
+
+[timeouts]
+receive_ms = 50
+"#,
+        )
+        .unwrap();
+
+        let server = config
+            .connect_with(&Transport::Loopback(bus.clone()))
+            .unwrap();
+        let client = bus.peer(Some("client"), Some(IPF_DEFAULT)).unwrap();
+        assert!(client.is_group_present("server", "news"));
+        assert!(client.is_group_present("server", "weather"));
+
+        assert_eq!(
+            *config.receive_msg(&server).unwrap_err().current_context(),
+            IpconError::SysErrorTimeOut
+        );
+        client.send_unicast_msg("server", b"hello").unwrap();
+        match config.receive_msg(&server).unwrap() {
+            IpconMsg::IpconMsgUser(body) => assert_eq!(body.buf, b"hello"),
+            m => panic!("Unexpected message {:?}", m),
+        }
+    }
+
+    #[test]
+    fn required_subscription_waits_for_its_group() {
+        let bus = LoopbackBus::new();
+        let transport = Transport::Loopback(bus.clone());
+        let config = IpconConfig::from_toml_str(
+            r#"
+[[subscriptions]]
+peer = "clock"
+group = "tick"
+required = true
+
+[timeouts]
+join_ms = 2000
+"#,
+        )
+        .unwrap();
+
+        let clock = bus.peer(Some("clock"), Some(IPF_DEFAULT)).unwrap();
+        let connecting = std::thread::spawn(move || config.connect_with(&transport));
+        std::thread::sleep(Duration::from_millis(100));
+        clock.register_group("tick").unwrap();
+
+        let client = connecting.join().unwrap().unwrap();
+        clock.send_multicast("tick", b"0", false).unwrap();
+        assert!(client.receive_msg_timeout(1, 0).is_ok());
+    }
+
+    #[test]
+    fn required_subscription_times_out() {
+        let bus = LoopbackBus::new();
+        let config = IpconConfig::from_toml_str(
+            r#"
+[[subscriptions]]
+peer = "clock"
+group = "tick"
+
+[[subscriptions]]
+peer = "clock"
+group = "tock"
+required = true
+
+[timeouts]
+join_ms = 100
+"#,
+        )
+        .unwrap();
+
+        let start = Instant::now();
+        let err = config
+            .connect_with(&Transport::Loopback(bus))
+            .err()
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(*err.current_context(), IpconError::SystemErrorNotExist);
+
+        let report = format!("{:?}", err);
+        assert!(report.contains("subscriptions[1] tock@clock"));
+        assert!(report.contains("Group not present after 100 ms"));
+        assert!(!report.contains("subscriptions[0]"));
+    }
+
+    #[test]
+    fn optional_subscription_is_skipped() {
+        let bus = LoopbackBus::new();
+        let clock = bus.peer(Some("clock"), Some(IPF_DEFAULT)).unwrap();
+        clock.register_group("tock").unwrap();
+        let config = IpconConfig::from_toml_str(
+            r#"
+[[subscriptions]]
+peer = "clock"
+group = "tick"
+
+[[subscriptions]]
+peer = "clock"
+group = "tock"
+"#,
+        )
+        .unwrap();
+
+        /* The missing group only logs a warning, the following subscriptions are joined. */
+        let warnings = Warnings::default();
+        let subscriber = Registry::default().with(warnings.clone());
+        let client = tracing::subscriber::with_default(subscriber, || {
+            config.connect_with(&Transport::Loopback(bus)).unwrap()
+        });
+        let warnings = warnings.0.lock().unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("Optional subscriptions[0] tick@clock not joined"));
+
+        clock.send_multicast("tock", b"0", false).unwrap();
+        assert!(client.receive_msg_timeout(1, 0).is_ok());
+    }
+
+    #[test]
+    fn failed_group_names_the_item() {
+        let config = IpconConfig::from_toml_str(r#"groups = ["news", "news"]"#).unwrap();
+
+        let err = config
+            .connect_with(&Transport::Loopback(LoopbackBus::new()))
+            .err()
+            .unwrap();
+        let report = format!("{:?}", err);
+        assert!(report.contains("groups[1] `news`"));
+        assert!(!report.contains("groups[0]"));
+    }
+}
@@ -0,0 +1,458 @@
+//! # D-Bus bridge
+//! With the `dbus` feature, DbusBridge maps an Ipcon peer to a D-Bus service. The bridge owns a
+//! well-known name on the bus and serves the `org.ipcon.Bridge` interface at an object path:
+//!
+//! ```text
+//! method Send(s peer, ay payload)                 send an unicast message to peer
+//! method Multicast(s group, ay payload, b sync)   send a multicast message to a bridge group
+//! method IsPeerPresent(s peer) -> b
+//! signal Message(s peer, ay payload)              unicast message received by the bridge peer
+//! signal GroupMessage(s peer, s group, ay payload) message of a subscribed group
+//! ```
+//!
+//! The reverse direction is configured with routes:
+//!
+//! * With a DbusMethod, the unicast messages received by the bridge peer are delivered as
+//!   method calls `(s peer, ay payload) -> ay` instead of Message signals. A non-empty reply is
+//!   sent back to the peer. The calls are made by DbusBridgeConfig::method_workers threads, so
+//!   that a slow method doesn't hold back the other messages. The messages waiting for a worker
+//!   are queued up to DbusBridgeConfig::method_queue, further messages are dropped.
+//! * With DbusSignalRoute, the D-Bus signals carrying a byte array (`ay`) are multicast to a
+//!   group registered by the bridge peer.
+//!
+//! The interface is served as soon as the bridge is created, run() forwards the IPCON messages
+//! and the routed signals until stop() is called.
+
+use crate::ipcon::Ipcon;
+use crate::ipcon_error::IpconError;
+use crate::ipcon_msg::{IpconMsg, IpconMsgType};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use zbus::blocking::{connection, Connection, MessageIterator};
+use zbus::fdo;
+use zbus::object_server::SignalEmitter;
+use zbus::MatchRule;
+#[allow(unused)]
+use {
+    error_stack::{Report, Result, ResultExt},
+    jlogger_tracing::{jdebug, jerror, jinfo, jtrace, jwarn},
+};
+
+/// Name of the interface served by the bridge.
+pub const DBUS_INTERFACE: &str = "org.ipcon.Bridge";
+
+/// Receive timeout of the bridge peer in microseconds, the stop request is checked at this
+/// interval.
+const BRIDGE_RECEIVE_TIMEOUT_US: u32 = 100_000;
+
+fn dbus_error(e: zbus::Error) -> Report<IpconError> {
+    Report::new(IpconError::SystemErrorOther).attach_printable(format!("D-Bus error: {}", e))
+}
+
+/* Map an IPCON error to a D-Bus error, with the attached messages. */
+fn fdo_error(e: Report<IpconError>) -> fdo::Error {
+    let message = e
+        .frames()
+        .filter_map(|f| {
+            f.downcast_ref::<String>()
+                .cloned()
+                .or_else(|| f.downcast_ref::<&str>().map(|s| s.to_string()))
+        })
+        .collect::<Vec<String>>()
+        .join(": ");
+    let message = format!("{}: {}", e.current_context(), message);
+
+    match e.current_context() {
+        IpconError::SysErrorPermission => fdo::Error::AccessDenied(message),
+        IpconError::SysErrorTimeOut => fdo::Error::Timeout(message),
+        IpconError::InvalidName | IpconError::InvalidData | IpconError::SysErrorInvalidValue => {
+            fdo::Error::InvalidArgs(message)
+        }
+        _ => fdo::Error::Failed(message),
+    }
+}
+
+/// D-Bus method called for the unicast messages received by the bridge peer.
+#[derive(Clone, Debug)]
+pub struct DbusMethod {
+    /// Bus name of the service.
+    pub destination: String,
+    /// Object path.
+    pub path: String,
+    /// Interface of the method.
+    pub interface: String,
+    /// Name of the method.
+    pub member: String,
+}
+
+/// D-Bus signals multicast to an IPCON group.
+#[derive(Clone, Debug)]
+pub struct DbusSignalRoute {
+    /// Interface of the signals.
+    pub interface: String,
+    /// Name of the signals, all the signals of the interface if None.
+    pub member: Option<String>,
+    /// Group registered by the bridge peer the signals are multicast to.
+    pub group: String,
+}
+
+/// Configuration of a DbusBridge.
+#[derive(Clone, Debug)]
+pub struct DbusBridgeConfig {
+    /// Address of the bus, the session bus if None.
+    pub address: Option<String>,
+    /// Well-known name of the bridge.
+    pub service: String,
+    /// Object path of the bridge.
+    pub path: String,
+    /// Peers the D-Bus clients can exchange unicast messages with, all peers if None.
+    pub peers: Option<Vec<String>>,
+    /// Groups registered by the bridge peer, the D-Bus clients can send multicast messages to
+    /// them.
+    pub groups: Vec<String>,
+    /// Groups of other peers joined by the bridge peer: (peer, group).
+    pub subscriptions: Vec<(String, String)>,
+    /// Method called for the received unicast messages, instead of emitting Message signals.
+    pub method: Option<DbusMethod>,
+    /// Number of threads calling the method.
+    pub method_workers: usize,
+    /// Number of received messages waiting for a method call.
+    pub method_queue: usize,
+    /// Signals multicast to the groups of the bridge peer.
+    pub signals: Vec<DbusSignalRoute>,
+}
+
+impl Default for DbusBridgeConfig {
+    fn default() -> Self {
+        DbusBridgeConfig {
+            address: None,
+            service: "org.ipcon.Bridge".to_owned(),
+            path: "/org/ipcon/Bridge".to_owned(),
+            peers: None,
+            groups: Vec::new(),
+            subscriptions: Vec::new(),
+            method: None,
+            method_workers: 4,
+            method_queue: 64,
+            signals: Vec::new(),
+        }
+    }
+}
+
+impl DbusBridgeConfig {
+    fn peer_allowed(&self, peer: &str) -> bool {
+        self.peers
+            .as_ref()
+            .is_none_or(|peers| peers.iter().any(|p| p == peer))
+    }
+
+    fn subscribed(&self, peer: &str, group: &str) -> bool {
+        self.subscriptions
+            .iter()
+            .any(|(p, g)| p == peer && g == group)
+    }
+}
+
+struct BridgeInterface {
+    ipcon: Arc<Ipcon>,
+    config: DbusBridgeConfig,
+}
+
+#[zbus::interface(name = "org.ipcon.Bridge")]
+impl BridgeInterface {
+    fn send(&self, peer: &str, payload: Vec<u8>) -> fdo::Result<()> {
+        if !self.config.peer_allowed(peer) {
+            return Err(fdo::Error::AccessDenied(format!(
+                "Peer {} is not exposed",
+                peer
+            )));
+        }
+
+        self.ipcon
+            .send_unicast_msg(peer, &payload)
+            .map_err(fdo_error)
+    }
+
+    fn multicast(&self, group: &str, payload: Vec<u8>, sync: bool) -> fdo::Result<()> {
+        if !self.config.groups.iter().any(|g| g == group) {
+            return Err(fdo::Error::AccessDenied(format!(
+                "Group {} is not exposed",
+                group
+            )));
+        }
+
+        self.ipcon
+            .send_multicast(group, &payload, sync)
+            .map_err(fdo_error)
+    }
+
+    fn is_peer_present(&self, peer: &str) -> bool {
+        self.ipcon.is_peer_present(peer)
+    }
+
+    #[zbus(signal)]
+    async fn message(emitter: &SignalEmitter<'_>, peer: &str, payload: &[u8]) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn group_message(
+        emitter: &SignalEmitter<'_>,
+        peer: &str,
+        group: &str,
+        payload: &[u8],
+    ) -> zbus::Result<()>;
+}
+
+/// Bridge between an Ipcon peer and a D-Bus service.
+pub struct DbusBridge {
+    ipcon: Arc<Ipcon>,
+    config: DbusBridgeConfig,
+    conn: Connection,
+    stop: AtomicBool,
+}
+
+impl DbusBridge {
+    /// Create a bridge and serve its interface on the bus.
+    /// The groups of the configuration and of the signal routes are registered and the
+    /// subscriptions are joined.
+    pub fn new(ipcon: Ipcon, config: DbusBridgeConfig) -> Result<DbusBridge, IpconError> {
+        let mut config = config;
+        for route in &config.signals {
+            if !config.groups.contains(&route.group) {
+                config.groups.push(route.group.clone());
+            }
+        }
+
+        for group in &config.groups {
+            ipcon.register_group(group)?;
+        }
+
+        for (peer, group) in &config.subscriptions {
+            ipcon.join_group(peer, group)?;
+        }
+
+        let ipcon = Arc::new(ipcon);
+        let iface = BridgeInterface {
+            ipcon: ipcon.clone(),
+            config: config.clone(),
+        };
+
+        let builder = match &config.address {
+            Some(address) => connection::Builder::address(address.as_str()),
+            None => connection::Builder::session(),
+        };
+
+        let conn = builder
+            .and_then(|b| b.serve_at(config.path.as_str(), iface))
+            .and_then(|b| b.name(config.service.as_str()))
+            .and_then(|b| b.build())
+            .map_err(dbus_error)
+            .attach_printable(format!("Failed to serve {} on the bus", config.service))?;
+
+        Ok(DbusBridge {
+            ipcon,
+            config,
+            conn,
+            stop: AtomicBool::new(false),
+        })
+    }
+
+    /// Get the bridge peer.
+    pub fn ipcon(&self) -> &Ipcon {
+        &self.ipcon
+    }
+
+    /// Get the bus connection.
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    fn call(&self, method: &DbusMethod, peer: &str, buf: &[u8]) -> Result<(), IpconError> {
+        let reply = self
+            .conn
+            .call_method(
+                Some(method.destination.as_str()),
+                method.path.as_str(),
+                Some(method.interface.as_str()),
+                method.member.as_str(),
+                &(peer, buf),
+            )
+            .map_err(dbus_error)?;
+
+        let reply: Vec<u8> = reply.body().deserialize().map_err(dbus_error)?;
+        if !reply.is_empty() {
+            self.ipcon.send_unicast_msg(peer, &reply)?;
+        }
+
+        Ok(())
+    }
+
+    /* Call the method for the queued messages until the queue is closed. */
+    fn call_worker(&self, method: &DbusMethod, queue: &Mutex<Receiver<(String, Vec<u8>)>>) {
+        loop {
+            let next = queue.lock().unwrap_or_else(|e| e.into_inner()).recv();
+            let (peer, buf) = match next {
+                Ok(call) => call,
+                Err(_) => break,
+            };
+
+            if let Err(e) = self.call(method, &peer, &buf) {
+                jwarn!("Failed to call {}: {:?}", method.member, e);
+            }
+        }
+    }
+
+    fn forward_msg(
+        &self,
+        msg: IpconMsg,
+        calls: Option<&SyncSender<(String, Vec<u8>)>>,
+    ) -> Result<(), IpconError> {
+        let body = match msg {
+            IpconMsg::IpconMsgUser(body) => body,
+            _ => return Ok(()),
+        };
+
+        let emitted = match (&body.msg_type, &body.group) {
+            (IpconMsgType::IpconMsgTypeNormal, _) if self.config.peer_allowed(&body.peer) => {
+                if let Some(calls) = calls {
+                    return match calls.try_send((body.peer, body.buf)) {
+                        Ok(()) => Ok(()),
+                        Err(TrySendError::Full((peer, _))) => {
+                            Err(Report::new(IpconError::SystemErrorOther)).attach_printable(
+                                format!("Method call queue is full, message of {} dropped", peer),
+                            )
+                        }
+                        Err(TrySendError::Disconnected(_)) => {
+                            Err(Report::new(IpconError::Cancelled))
+                                .attach_printable("Method workers are stopped")
+                        }
+                    };
+                }
+
+                self.conn.emit_signal(
+                    None::<&str>,
+                    self.config.path.as_str(),
+                    DBUS_INTERFACE,
+                    "Message",
+                    &(body.peer.as_str(), body.buf.as_slice()),
+                )
+            }
+            (IpconMsgType::IpconMsgTypeGroup, Some(group))
+                if self.config.subscribed(&body.peer, group) =>
+            {
+                self.conn.emit_signal(
+                    None::<&str>,
+                    self.config.path.as_str(),
+                    DBUS_INTERFACE,
+                    "GroupMessage",
+                    &(body.peer.as_str(), group.as_str(), body.buf.as_slice()),
+                )
+            }
+            _ => return Ok(()),
+        };
+
+        emitted.map_err(dbus_error)
+    }
+
+    fn forward(&self, calls: Option<SyncSender<(String, Vec<u8>)>>) {
+        while !self.stop.load(Ordering::Relaxed) {
+            let msg = match self.ipcon.receive_msg_timeout(0, BRIDGE_RECEIVE_TIMEOUT_US) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    if *e.current_context() == IpconError::Cancelled {
+                        break;
+                    }
+
+                    if *e.current_context() != IpconError::SysErrorTimeOut {
+                        jwarn!("Bridge receive failed: {:?}", e);
+                    }
+                    continue;
+                }
+            };
+
+            if let Err(e) = self.forward_msg(msg, calls.as_ref()) {
+                jwarn!("Failed to forward message to D-Bus: {:?}", e);
+            }
+        }
+    }
+
+    fn signals(&self, route: &DbusSignalRoute) -> Result<MessageIterator, IpconError> {
+        let mut rule = MatchRule::builder()
+            .msg_type(zbus::message::Type::Signal)
+            .interface(route.interface.as_str())
+            .map_err(dbus_error)?;
+
+        if let Some(member) = &route.member {
+            rule = rule.member(member.as_str()).map_err(dbus_error)?;
+        }
+
+        MessageIterator::for_match_rule(rule.build(), &self.conn, None).map_err(dbus_error)
+    }
+
+    fn route(&self, route: &DbusSignalRoute, signals: MessageIterator) {
+        for msg in signals {
+            if self.stop.load(Ordering::Relaxed) {
+                break;
+            }
+
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(e) => {
+                    jwarn!("Failed to receive signal: {}", e);
+                    continue;
+                }
+            };
+
+            let buf: Vec<u8> = match msg.body().deserialize() {
+                Ok(buf) => buf,
+                Err(e) => {
+                    jdebug!("Signal without byte array dropped: {}", e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = self.ipcon.send_multicast(&route.group, &buf, false) {
+                jwarn!("Failed to multicast signal to {}: {:?}", route.group, e);
+            }
+        }
+    }
+
+    /// Forward the messages until stop() is called.
+    pub fn run(&self) -> Result<(), IpconError> {
+        let mut routes = Vec::new();
+        for route in &self.config.signals {
+            routes.push((route, self.signals(route)?));
+        }
+
+        std::thread::scope(|s| {
+            for (route, signals) in routes {
+                s.spawn(move || self.route(route, signals));
+            }
+
+            /* The workers exit once forward() drops the sender and the queue is drained. */
+            let calls = self.config.method.as_ref().map(|method| {
+                let (tx, rx) = sync_channel(self.config.method_queue);
+                let rx = Arc::new(Mutex::new(rx));
+                for _ in 0..self.config.method_workers.max(1) {
+                    let rx = rx.clone();
+                    s.spawn(move || self.call_worker(method, &rx));
+                }
+                tx
+            });
+
+            self.forward(calls);
+        });
+
+        Ok(())
+    }
+
+    /// Stop run(). The bus connection is closed.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+
+        /* Wake up the signal routes. */
+        if let Err(e) = self.conn.clone().close() {
+            jdebug!("Failed to close the bus connection: {}", e);
+        }
+    }
+}
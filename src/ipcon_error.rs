@@ -2,7 +2,7 @@ use std::{error::Error, fmt::Display};
 
 use error_stack::Report;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum IpconError {
     InvalidName,
     InvalidKevent,
@@ -13,6 +13,7 @@ pub enum IpconError {
     SysErrorPermission,
     SystemErrorNotExist,
     SystemErrorOther,
+    Cancelled,
     Unexpected,
 }
 
@@ -28,6 +29,7 @@ impl Display for IpconError {
             IpconError::SystemErrorNotExist => "Entry (peer/group) not exist",
             IpconError::SysErrorPermission => "Permission denied system error",
             IpconError::SystemErrorOther => "Other system error",
+            IpconError::Cancelled => "Operation cancelled",
             _ => "Unexpected error",
         };
 
@@ -43,7 +45,7 @@ impl From<Report<IpconError>> for IpconError {
 
 impl From<IpconError> for std::io::Error {
     fn from(e: IpconError) -> Self {
-        std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+        std::io::Error::other(e.to_string())
     }
 }
 
@@ -59,6 +61,7 @@ impl From<std::io::Error> for IpconError {
             "Entry (peer/group) not exist" => IpconError::SystemErrorNotExist,
             "Permission denied system error" => IpconError::SysErrorPermission,
             "Other system error" => IpconError::SystemErrorOther,
+            "Operation cancelled" => IpconError::Cancelled,
             _ => IpconError::Unexpected,
         }
     }
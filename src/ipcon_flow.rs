@@ -0,0 +1,785 @@
+//! # Flow control
+//! Credit based flow control of unicast messages.
+//!
+//! Before sending the first message to a peer, the sender asks the receiver for a window, and asks
+//! again every open retry interval until it is granted. The receiver grants credits up to its
+//! window size, and every data message consumes one credit. The send and the receive directions
+//! between two peers are controlled independently.
+//! The receiver returns credits once it has consumed half of the window, so a sender never has
+//! more messages in flight than the receiver accepted. When no credit is left, the sender blocks
+//! (or awaits with AsyncIpcon) instead of flooding the socket buffer of a slow receiver.
+//! The credits carry the number of messages consumed since the window was opened, and a sender
+//! starved for an open retry interval probes the receiver for it. So a lost credit message is
+//! recovered, and a receiver which has restarted asks the sender to open the window again.
+//!
+//! Both sides must feed every received message to FlowControl::handle_msg(), which consumes the
+//! control messages and strips the flow control header from the data messages.
+
+use crate::ipcon::{valid_name, Ipcon};
+use crate::ipcon_error::IpconError;
+use crate::ipcon_msg::{IpconMsg, IpconMsgType, IPCON_MAX_PAYLOAD_LEN};
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+#[allow(unused)]
+use {
+    error_stack::{Report, Result, ResultExt},
+    jlogger_tracing::{jdebug, jerror, jinfo, jtrace, jwarn},
+};
+
+#[cfg(feature = "async")]
+use crate::ipcon_async::AsyncIpcon;
+
+const FLOW_MAGIC: u8 = 0x46;
+const FLOW_KIND_DATA: u8 = 1;
+const FLOW_KIND_OPEN: u8 = 2;
+const FLOW_KIND_CREDIT: u8 = 3;
+const FLOW_KIND_GRANT: u8 = 4;
+const FLOW_KIND_PROBE: u8 = 5;
+const FLOW_KIND_RESET: u8 = 6;
+
+/// Length of the header of a data message: magic(1) + kind(1).
+pub const FLOW_HEADER_LEN: usize = 2;
+
+/// Maximum payload length of a flow controlled message.
+pub const FLOW_MAX_PAYLOAD_LEN: usize = IPCON_MAX_PAYLOAD_LEN - FLOW_HEADER_LEN;
+
+/// Default receive window in messages.
+pub const FLOW_DEFAULT_WINDOW: u32 = 64;
+
+/// Default time to wait for the window before asking the receiver again.
+pub const FLOW_DEFAULT_OPEN_RETRY: Duration = Duration::from_secs(1);
+
+fn control_msg(kind: u8, value: u32) -> Vec<u8> {
+    let mut v = vec![FLOW_MAGIC, kind];
+    v.extend_from_slice(&value.to_be_bytes());
+    v
+}
+
+/// Flow control counters of a peer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FlowStats {
+    /// Window granted by the peer.
+    pub window: u32,
+    /// Credits left to send to the peer.
+    pub credits: u32,
+    /// Window granted to the peer.
+    pub granted: u32,
+    /// Messages sent to the peer.
+    pub sent: u64,
+    /// Messages received from the peer.
+    pub received: u64,
+    /// Number of sends which had to wait for credits.
+    pub blocked_count: u64,
+    /// Total time spent waiting for credits.
+    pub blocked_time: Duration,
+}
+
+/* Window requested from the peer. */
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+enum OpenState {
+    #[default]
+    Closed,
+    /* Requested at the instant, not granted yet. */
+    Opening(Instant),
+    Open,
+}
+
+#[derive(Default)]
+struct PeerFlow {
+    /* The counters, with the send window and credits. */
+    stats: FlowStats,
+    open: OpenState,
+    /* Messages sent since the window was granted, and the count acknowledged by the peer. */
+    sent: u32,
+    acked: u32,
+    /* Out of credits since the instant, or the last probe. */
+    starved: Option<Instant>,
+    /* Messages consumed from the peer since it opened the window. */
+    accepted: u32,
+    /* Messages consumed from the peer and not returned as credits yet. */
+    consumed: u32,
+}
+
+impl PeerFlow {
+    /* Credits left from the window, the messages sent and not acknowledged are in flight. */
+    fn update_credits(&mut self) {
+        let in_flight = self.sent.wrapping_sub(self.acked);
+        self.stats.credits = self.stats.window.saturating_sub(in_flight);
+    }
+}
+
+/// Credit based flow control state of an IPCON peer.
+/// It is shared by the sending threads and the receiving thread of the peer.
+pub struct FlowControl {
+    window: u32,
+    open_retry: Duration,
+    peers: Mutex<HashMap<String, PeerFlow>>,
+    cond: Condvar,
+    #[cfg(feature = "async")]
+    notify: tokio::sync::Notify,
+}
+
+/// Reply to be sent after handling a received message.
+struct FlowReply {
+    peer: String,
+    buf: Vec<u8>,
+}
+
+enum Credit {
+    Taken,
+    /* The control message to send to the peer before trying again. */
+    Request(Vec<u8>),
+    Wait,
+}
+
+impl FlowControl {
+    /// Create a flow control state granting `window` messages to each sender.
+    pub fn new(window: u32) -> FlowControl {
+        FlowControl {
+            window: window.max(1),
+            open_retry: FLOW_DEFAULT_OPEN_RETRY,
+            peers: Mutex::new(HashMap::new()),
+            cond: Condvar::new(),
+            #[cfg(feature = "async")]
+            notify: tokio::sync::Notify::new(),
+        }
+    }
+
+    /// Set the time to wait for the window before asking the receiver again.
+    /// FLOW_DEFAULT_OPEN_RETRY is used by default.
+    pub fn with_open_retry(mut self, retry: Duration) -> FlowControl {
+        self.open_retry = retry;
+        self
+    }
+
+    /// The receive window granted to each sender.
+    pub fn window(&self) -> u32 {
+        self.window
+    }
+
+    /// Flow control counters of a peer.
+    pub fn stats(&self, peer: &str) -> Option<FlowStats> {
+        self.lock().get(peer).map(|p| p.stats)
+    }
+
+    /// Flow control counters of all peers.
+    pub fn all_stats(&self) -> HashMap<String, FlowStats> {
+        self.lock()
+            .iter()
+            .map(|(name, p)| (name.clone(), p.stats))
+            .collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, PeerFlow>> {
+        self.peers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn check_send(peer: &str, buf: &[u8]) -> Result<Vec<u8>, IpconError> {
+        valid_name(peer).attach_printable(format!("Invalid peer name: {}", peer))?;
+
+        if buf.len() > FLOW_MAX_PAYLOAD_LEN {
+            return Err(Report::new(IpconError::InvalidData)).attach_printable(format!(
+                "Buffer length is too large {} > {}",
+                buf.len(),
+                FLOW_MAX_PAYLOAD_LEN
+            ));
+        }
+
+        let mut v = Vec::with_capacity(FLOW_HEADER_LEN + buf.len());
+        v.push(FLOW_MAGIC);
+        v.push(FLOW_KIND_DATA);
+        v.extend_from_slice(buf);
+        Ok(v)
+    }
+
+    fn take_credit(&self, peers: &mut HashMap<String, PeerFlow>, peer: &str) -> Credit {
+        let p = peers.entry(peer.to_owned()).or_default();
+
+        if p.stats.credits > 0 {
+            p.stats.credits -= 1;
+            p.stats.sent += 1;
+            p.sent = p.sent.wrapping_add(1);
+            return Credit::Taken;
+        }
+
+        match p.open {
+            /* The credits may have been lost, ask the peer how many messages it consumed. */
+            OpenState::Open => match p.starved {
+                Some(at) if at.elapsed() >= self.open_retry => {
+                    jdebug!(peer = peer, "Flow credits starved, probe");
+                    p.starved = Some(Instant::now());
+                    Credit::Request(control_msg(FLOW_KIND_PROBE, 0))
+                }
+                Some(_) => Credit::Wait,
+                None => {
+                    p.starved = Some(Instant::now());
+                    Credit::Wait
+                }
+            },
+            OpenState::Opening(at) if at.elapsed() < self.open_retry => Credit::Wait,
+            _ => {
+                if p.open != OpenState::Closed {
+                    jdebug!(peer = peer, "Flow window not granted, ask again");
+                }
+                p.open = OpenState::Opening(Instant::now());
+                Credit::Request(control_msg(FLOW_KIND_OPEN, self.window))
+            }
+        }
+    }
+
+    /* Give back the credit taken by a message which could not be sent. */
+    fn refund_credit(&self, peer: &str) {
+        if let Some(p) = self.lock().get_mut(peer) {
+            if p.open == OpenState::Open && p.sent != p.acked {
+                p.stats.sent -= 1;
+                p.sent = p.sent.wrapping_sub(1);
+                p.update_credits();
+            }
+        }
+        self.wake();
+    }
+
+    /* Time to wait for credits, bounded by the open retry so that the open request is sent
+     * again, and by the timeout of the send. None is returned if the timeout has expired. */
+    fn wait_time(&self, start: Instant, timeout: Option<Duration>) -> Option<Duration> {
+        match timeout {
+            Some(t) => match t.checked_sub(start.elapsed()) {
+                Some(left) if !left.is_zero() => Some(left.min(self.open_retry)),
+                _ => None,
+            },
+            None => Some(self.open_retry),
+        }
+    }
+
+    fn record_blocked(&self, peer: &str, since: Option<Instant>) {
+        if let Some(since) = since {
+            if let Some(p) = self.lock().get_mut(peer) {
+                p.stats.blocked_count += 1;
+                p.stats.blocked_time += since.elapsed();
+            }
+        }
+    }
+
+    /// Send a flow controlled unicast message to a peer.
+    /// If no credit is left, it blocks until the peer returns credits. If `timeout` is specified
+    /// and no credit is returned in time, it fails with SysErrorTimeOut. Sending fails as well if
+    /// the window can not be requested, for example because the peer is not present.
+    pub fn send_unicast_msg(
+        &self,
+        ipcon: &Ipcon,
+        peer: &str,
+        buf: &[u8],
+        timeout: Option<Duration>,
+    ) -> Result<(), IpconError> {
+        let v = FlowControl::check_send(peer, buf)?;
+        let start = Instant::now();
+        let mut blocked = None;
+
+        let mut peers = self.lock();
+        loop {
+            match self.take_credit(&mut peers, peer) {
+                Credit::Taken => break,
+                Credit::Request(request) => {
+                    drop(peers);
+                    ipcon
+                        .send_unicast_msg(peer, &request)
+                        .inspect_err(|_| self.reset(peer))?;
+                    peers = self.lock();
+                    continue;
+                }
+                Credit::Wait => {}
+            }
+
+            blocked.get_or_insert(start);
+            let wait = match self.wait_time(start, timeout) {
+                Some(wait) => wait,
+                None => {
+                    drop(peers);
+                    self.record_blocked(peer, blocked);
+                    return Err(Report::new(IpconError::SysErrorTimeOut)).attach_printable(
+                        format!(
+                            "No credit from `{}` in {:?}",
+                            peer,
+                            timeout.unwrap_or_default()
+                        ),
+                    );
+                }
+            };
+
+            peers = self
+                .cond
+                .wait_timeout(peers, wait)
+                .map(|(g, _)| g)
+                .unwrap_or_else(|e| e.into_inner().0);
+        }
+        drop(peers);
+
+        self.record_blocked(peer, blocked);
+        ipcon
+            .send_unicast_msg(peer, &v)
+            .inspect_err(|_| self.refund_credit(peer))
+    }
+
+    /// Async version of send_unicast_msg().
+    #[cfg(feature = "async")]
+    pub async fn send_unicast_msg_async(
+        &self,
+        ipcon: &AsyncIpcon,
+        peer: &str,
+        buf: &[u8],
+        timeout: Option<Duration>,
+    ) -> Result<(), IpconError> {
+        let v = FlowControl::check_send(peer, buf)?;
+        let start = Instant::now();
+        let mut blocked = None;
+
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let credit = self.take_credit(&mut self.lock(), peer);
+            match credit {
+                Credit::Taken => break,
+                Credit::Request(request) => {
+                    if let Err(e) = ipcon.send_unicast_msg(peer, &request).await {
+                        self.reset(peer);
+                        return Err(e);
+                    }
+                    continue;
+                }
+                Credit::Wait => {}
+            }
+
+            blocked.get_or_insert(start);
+            match self.wait_time(start, timeout) {
+                Some(wait) => {
+                    let _ = tokio::time::timeout(wait, notified).await;
+                }
+                None => {
+                    self.record_blocked(peer, blocked);
+                    return Err(Report::new(IpconError::SysErrorTimeOut)).attach_printable(
+                        format!(
+                            "No credit from `{}` in {:?}",
+                            peer,
+                            timeout.unwrap_or_default()
+                        ),
+                    );
+                }
+            }
+        }
+
+        self.record_blocked(peer, blocked);
+        ipcon
+            .send_unicast_msg(peer, &v)
+            .await
+            .inspect_err(|_| self.refund_credit(peer))
+    }
+
+    /// Forget the flow control state of a peer.
+    /// This is done automatically when the peer removed kevent is handled.
+    pub fn reset(&self, peer: &str) {
+        self.lock().remove(peer);
+        self.wake();
+    }
+
+    fn wake(&self) {
+        self.cond.notify_all();
+        #[cfg(feature = "async")]
+        self.notify.notify_waiters();
+    }
+
+    fn process(&self, msg: IpconMsg) -> (Option<IpconMsg>, Option<FlowReply>) {
+        let mut body = match msg {
+            IpconMsg::IpconMsgUser(body)
+                if body.msg_type == IpconMsgType::IpconMsgTypeNormal
+                    && body.buf.len() >= FLOW_HEADER_LEN
+                    && body.buf[0] == FLOW_MAGIC =>
+            {
+                body
+            }
+            IpconMsg::IpconMsgKevent(k) => {
+                if let Some(peer) = k.peer_removed() {
+                    self.reset(&peer);
+                }
+                return (Some(IpconMsg::IpconMsgKevent(k)), None);
+            }
+            _ => return (Some(msg), None),
+        };
+
+        let value = body
+            .buf
+            .get(2..6)
+            .map(|v| u32::from_be_bytes([v[0], v[1], v[2], v[3]]));
+
+        match (body.buf[1], value) {
+            (FLOW_KIND_DATA, _) => {
+                let mut peers = self.lock();
+                let p = peers.entry(body.peer.clone()).or_default();
+                p.stats.received += 1;
+                p.accepted = p.accepted.wrapping_add(1);
+                p.consumed += 1;
+
+                let window = match p.stats.granted {
+                    0 => self.window,
+                    granted => granted,
+                };
+                let reply = if p.consumed >= (window / 2).max(1) {
+                    p.consumed = 0;
+                    Some(FlowReply {
+                        peer: body.peer.clone(),
+                        buf: control_msg(FLOW_KIND_CREDIT, p.accepted),
+                    })
+                } else {
+                    None
+                };
+                drop(peers);
+
+                body.buf.drain(..FLOW_HEADER_LEN);
+                (Some(IpconMsg::IpconMsgUser(body)), reply)
+            }
+
+            (FLOW_KIND_OPEN, Some(requested)) => {
+                /* The sender starts over, the data sent before are all consumed. */
+                let window = requested.clamp(1, self.window);
+                let mut peers = self.lock();
+                let p = peers.entry(body.peer.clone()).or_default();
+                p.stats.granted = window;
+                p.accepted = 0;
+                p.consumed = 0;
+                drop(peers);
+
+                jdebug!(peer = body.peer, window = window, "Flow window opened");
+                (
+                    None,
+                    Some(FlowReply {
+                        peer: body.peer,
+                        buf: control_msg(FLOW_KIND_GRANT, window),
+                    }),
+                )
+            }
+
+            (FLOW_KIND_PROBE, _) => {
+                /* A sender unknown here has to open the window again. */
+                let mut peers = self.lock();
+                let reply = match peers.get_mut(&body.peer) {
+                    Some(p) if p.stats.granted > 0 => {
+                        p.consumed = 0;
+                        control_msg(FLOW_KIND_CREDIT, p.accepted)
+                    }
+                    _ => control_msg(FLOW_KIND_RESET, 0),
+                };
+                drop(peers);
+
+                (
+                    None,
+                    Some(FlowReply {
+                        peer: body.peer,
+                        buf: reply,
+                    }),
+                )
+            }
+
+            (FLOW_KIND_GRANT, Some(window)) => {
+                let mut peers = self.lock();
+                let p = peers.entry(body.peer).or_default();
+
+                /* Only the first grant of repeated open requests is used. */
+                if matches!(p.open, OpenState::Opening(_)) {
+                    p.open = OpenState::Open;
+                    p.stats.window = window;
+                    p.sent = 0;
+                    p.acked = 0;
+                    p.starved = None;
+                    p.update_credits();
+                }
+                drop(peers);
+
+                self.wake();
+                (None, None)
+            }
+
+            (FLOW_KIND_CREDIT, Some(accepted)) => {
+                let mut peers = self.lock();
+                let p = peers.entry(body.peer).or_default();
+
+                /* Credits older than the ones already received are ignored. */
+                if p.open == OpenState::Open
+                    && accepted.wrapping_sub(p.acked) <= p.sent.wrapping_sub(p.acked)
+                {
+                    p.acked = accepted;
+                    p.starved = None;
+                    p.update_credits();
+                }
+                drop(peers);
+
+                self.wake();
+                (None, None)
+            }
+
+            (FLOW_KIND_RESET, _) => {
+                let mut peers = self.lock();
+                if let Some(p) = peers.get_mut(&body.peer) {
+                    jdebug!(peer = body.peer, "Flow window reset by the peer");
+                    p.open = OpenState::Closed;
+                    p.starved = None;
+                    p.stats.credits = 0;
+                }
+                drop(peers);
+
+                self.wake();
+                (None, None)
+            }
+
+            _ => (Some(IpconMsg::IpconMsgUser(body)), None),
+        }
+    }
+
+    /// Handle a received message.
+    /// Flow control messages are consumed and None is returned. The flow control header is
+    /// removed from data messages, other messages are returned untouched.
+    pub fn handle_msg(&self, ipcon: &Ipcon, msg: IpconMsg) -> Result<Option<IpconMsg>, IpconError> {
+        let (msg, reply) = self.process(msg);
+
+        if let Some(r) = reply {
+            ipcon
+                .send_unicast_msg(&r.peer, &r.buf)
+                .attach_printable(format!("Failed to return credits to `{}`", r.peer))?;
+        }
+
+        Ok(msg)
+    }
+
+    /// Async version of handle_msg().
+    #[cfg(feature = "async")]
+    pub async fn handle_msg_async(
+        &self,
+        ipcon: &AsyncIpcon,
+        msg: IpconMsg,
+    ) -> Result<Option<IpconMsg>, IpconError> {
+        let (msg, reply) = self.process(msg);
+
+        if let Some(r) = reply {
+            ipcon
+                .send_unicast_msg(&r.peer, &r.buf)
+                .await
+                .attach_printable(format!("Failed to return credits to `{}`", r.peer))?;
+        }
+
+        Ok(msg)
+    }
+}
+
+impl Default for FlowControl {
+    fn default() -> Self {
+        FlowControl::new(FLOW_DEFAULT_WINDOW)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipcon::IPF_DEFAULT;
+    use crate::ipcon_loopback::LoopbackBus;
+
+    const SHORT: Option<Duration> = Some(Duration::from_millis(20));
+
+    /* Feed all the queued messages of the peer to its flow control, return the data. */
+    fn pump(flow: &FlowControl, ipcon: &Ipcon) -> Vec<Vec<u8>> {
+        let mut data = Vec::new();
+        while let Ok(msg) = ipcon.receive_msg_nonblock() {
+            if let Some(IpconMsg::IpconMsgUser(body)) = flow.handle_msg(ipcon, msg).unwrap() {
+                data.push(body.buf);
+            }
+        }
+        data
+    }
+
+    fn is_timeout(r: Result<(), IpconError>) -> bool {
+        matches!(r, Err(e) if *e.current_context() == IpconError::SysErrorTimeOut)
+    }
+
+    #[test]
+    fn credits_bound_the_messages_in_flight() {
+        let bus = LoopbackBus::new();
+        let a = bus.peer(Some("a"), Some(IPF_DEFAULT)).unwrap();
+        let b = bus.peer(Some("b"), Some(IPF_DEFAULT)).unwrap();
+        let flow_a = FlowControl::default();
+        let flow_b = FlowControl::new(4);
+
+        /* The window is requested, b doesn't answer yet. */
+        assert!(is_timeout(flow_a.send_unicast_msg(&a, "b", b"0", SHORT)));
+        assert!(pump(&flow_b, &b).is_empty());
+        assert!(pump(&flow_a, &a).is_empty());
+        assert_eq!(flow_a.stats("b").unwrap().window, 4);
+
+        for i in 0..4 {
+            flow_a.send_unicast_msg(&a, "b", &[i], SHORT).unwrap();
+        }
+        assert!(is_timeout(flow_a.send_unicast_msg(&a, "b", b"4", SHORT)));
+
+        /* Half of the window is returned once consumed. */
+        assert_eq!(pump(&flow_b, &b), [[0], [1], [2], [3]]);
+        pump(&flow_a, &a);
+        assert_eq!(flow_a.stats("b").unwrap().credits, 4);
+
+        let stats = flow_b.stats("a").unwrap();
+        assert_eq!((stats.granted, stats.received), (4, 4));
+        let stats = flow_a.stats("b").unwrap();
+        assert_eq!((stats.sent, stats.blocked_count), (4, 2));
+    }
+
+    #[test]
+    fn open_is_sent_again() {
+        let bus = LoopbackBus::new();
+        let a = bus.peer(Some("a"), Some(IPF_DEFAULT)).unwrap();
+        let b = bus.peer(Some("b"), Some(IPF_DEFAULT)).unwrap();
+        let flow_a = FlowControl::default().with_open_retry(Duration::from_millis(5));
+        let flow_b = FlowControl::new(4);
+
+        assert!(is_timeout(flow_a.send_unicast_msg(
+            &a,
+            "b",
+            b"",
+            Some(Duration::from_millis(50))
+        )));
+        let mut opens = 0;
+        while let Ok(msg) = b.receive_msg_nonblock() {
+            assert!(flow_b.handle_msg(&b, msg).unwrap().is_none());
+            opens += 1;
+        }
+        assert!(opens > 1);
+
+        /* Every open request is granted, only the first grant is used. */
+        pump(&flow_a, &a);
+        assert_eq!(flow_a.stats("b").unwrap().credits, 4);
+    }
+
+    #[test]
+    fn directions_are_independent() {
+        let bus = LoopbackBus::new();
+        let a = bus.peer(Some("a"), Some(IPF_DEFAULT)).unwrap();
+        let b = bus.peer(Some("b"), Some(IPF_DEFAULT)).unwrap();
+        let flow_a = FlowControl::new(8);
+        let flow_b = FlowControl::new(4);
+
+        /* Both open their window at the same time. */
+        assert!(is_timeout(flow_a.send_unicast_msg(&a, "b", b"", SHORT)));
+        assert!(is_timeout(flow_b.send_unicast_msg(&b, "a", b"", SHORT)));
+        pump(&flow_a, &a);
+        pump(&flow_b, &b);
+        pump(&flow_a, &a);
+        pump(&flow_b, &b);
+
+        for (flow, peer) in [(&flow_a, "b"), (&flow_b, "a")] {
+            let stats = flow.stats(peer).unwrap();
+            assert_eq!((stats.window, stats.credits, stats.granted), (4, 4, 4));
+        }
+
+        /* Data received from a peer don't change the credits to send to it. */
+        for i in 0..3 {
+            flow_a.send_unicast_msg(&a, "b", &[i], SHORT).unwrap();
+        }
+        assert_eq!(pump(&flow_b, &b).len(), 3);
+        assert_eq!(flow_b.stats("a").unwrap().credits, 4);
+
+        pump(&flow_a, &a);
+        let stats = flow_a.stats("b").unwrap();
+        assert_eq!((stats.credits, stats.sent), (3, 3));
+    }
+
+    /* Open the window of 4 messages from a to b, and use all the credits. */
+    fn open_and_fill(flow_a: &FlowControl, a: &Ipcon, flow_b: &FlowControl, b: &Ipcon) {
+        assert!(is_timeout(flow_a.send_unicast_msg(a, "b", b"", SHORT)));
+        pump(flow_b, b);
+        pump(flow_a, a);
+        for i in 0..4 {
+            flow_a.send_unicast_msg(a, "b", &[i], SHORT).unwrap();
+        }
+    }
+
+    #[test]
+    fn lost_credits_are_probed() {
+        let bus = LoopbackBus::new();
+        let a = bus.peer(Some("a"), Some(IPF_DEFAULT)).unwrap();
+        let b = bus.peer(Some("b"), Some(IPF_DEFAULT)).unwrap();
+        let flow_a = FlowControl::default().with_open_retry(Duration::from_millis(5));
+        let flow_b = FlowControl::new(4);
+        open_and_fill(&flow_a, &a, &flow_b, &b);
+
+        /* The credits returned by b are dropped. */
+        assert_eq!(pump(&flow_b, &b).len(), 4);
+        while a.receive_msg_nonblock().is_ok() {}
+        assert_eq!(flow_a.stats("b").unwrap().credits, 0);
+
+        /* The starved sender probes b, which answers with the messages it consumed. */
+        assert!(is_timeout(flow_a.send_unicast_msg(
+            &a,
+            "b",
+            b"4",
+            Some(Duration::from_millis(50))
+        )));
+        assert!(pump(&flow_b, &b).is_empty());
+        pump(&flow_a, &a);
+        assert_eq!(flow_a.stats("b").unwrap().credits, 4);
+
+        flow_a.send_unicast_msg(&a, "b", b"4", SHORT).unwrap();
+        assert_eq!(pump(&flow_b, &b), [b"4"]);
+    }
+
+    #[test]
+    fn restarted_receiver_opens_again() {
+        let bus = LoopbackBus::new();
+        let a = bus.peer(Some("a"), Some(IPF_DEFAULT)).unwrap();
+        let b = bus.peer(Some("b"), Some(IPF_DEFAULT)).unwrap();
+        let flow_a = FlowControl::default().with_open_retry(Duration::from_millis(5));
+        let flow_b = FlowControl::new(4);
+        open_and_fill(&flow_a, &a, &flow_b, &b);
+
+        /* b restarts, the peer removed kevent is not handled by a. */
+        drop(b);
+        let b = bus.peer(Some("b"), Some(IPF_DEFAULT)).unwrap();
+        let flow_b = FlowControl::new(2);
+
+        let timeout = Some(Duration::from_millis(50));
+        assert!(is_timeout(flow_a.send_unicast_msg(&a, "b", b"", timeout)));
+        pump(&flow_b, &b);
+        pump(&flow_a, &a);
+        assert!(is_timeout(flow_a.send_unicast_msg(&a, "b", b"", timeout)));
+        pump(&flow_b, &b);
+        pump(&flow_a, &a);
+
+        let stats = flow_a.stats("b").unwrap();
+        assert_eq!((stats.window, stats.credits), (2, 2));
+    }
+
+    #[test]
+    fn failed_send_returns_the_credit() {
+        let bus = LoopbackBus::new();
+        let a = bus.peer(Some("a"), Some(IPF_DEFAULT)).unwrap();
+        let b = bus.peer(Some("b"), Some(IPF_DEFAULT)).unwrap();
+        let flow_a = FlowControl::default();
+        let flow_b = FlowControl::new(8);
+
+        assert!(is_timeout(flow_a.send_unicast_msg(&a, "b", b"", SHORT)));
+        pump(&flow_b, &b);
+        pump(&flow_a, &a);
+
+        drop(b);
+        assert!(flow_a.send_unicast_msg(&a, "b", b"", SHORT).is_err());
+        let stats = flow_a.stats("b").unwrap();
+        assert_eq!((stats.credits, stats.sent), (8, 0));
+    }
+
+    #[test]
+    fn absent_peer_fails() {
+        let bus = LoopbackBus::new();
+        let a = bus.peer(Some("a"), Some(IPF_DEFAULT)).unwrap();
+        let flow = FlowControl::default();
+
+        let e = flow.send_unicast_msg(&a, "nobody", b"", None).unwrap_err();
+        assert_eq!(*e.current_context(), IpconError::SystemErrorNotExist);
+        assert!(flow.stats("nobody").is_none());
+    }
+}
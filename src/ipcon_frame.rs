@@ -0,0 +1,439 @@
+//! # Stream framing
+//! Length-prefixed framing of IPCON messages over a byte stream, used by the Unix domain socket
+//! bridge (see ipcon_uds). The layout is simple enough to be implemented by a client written in
+//! any language. All integers are big endian.
+//!
+//! ```text
+//! frame   := length(u32) kind(u8) body          length counts kind and body
+//!
+//! kind 1  unicast   : peer_len(u8) peer payload
+//! kind 2  multicast : flags(u8) group_len(u8) group payload        flags bit 0: sync
+//! kind 3  message   : type(u8) peer_len(u8) peer group_len(u8) group payload
+//!                     type 0: normal, 1: group, 2: kevent (payload is struct ipcon_kevent)
+//! kind 4  error     : code(u8) message(UTF-8)
+//!                     code 0: unexpected, 1: invalid name, 2: invalid kevent,
+//!                     3: invalid libipcon message, 4: invalid data, 5: timeout,
+//!                     6: invalid value, 7: permission, 8: not exist, 9: other,
+//!                     10: cancelled
+//! kind 5  forward   : from_len(u8) from to_len(u8) to payload
+//! kind 6  heartbeat : (empty)
+//! ```
+//!
+//! Unicast and multicast frames are requests to send a message, message frames carry a received
+//! IpconMsg and error frames report a failed request. Forward frames carry an unicast message
+//! between two peers on different hosts, and heartbeat frames keep an idle link alive, see
+//! ipcon_gateway.
+
+use crate::ipcon_error::IpconError;
+use crate::ipcon_msg::{IpconKevent, IpconMsg, IpconMsgBody, IpconMsgType, IPCON_MAX_PAYLOAD_LEN};
+use std::io::{Read, Write};
+#[allow(unused)]
+use {
+    error_stack::{Report, Result, ResultExt},
+    jlogger_tracing::{jdebug, jerror, jinfo, jtrace, jwarn},
+};
+
+/// Maximum value of the length field of a frame.
+pub const FRAME_MAX_LEN: usize = 4096;
+
+const FRAME_UNICAST: u8 = 1;
+const FRAME_MULTICAST: u8 = 2;
+const FRAME_MESSAGE: u8 = 3;
+const FRAME_ERROR: u8 = 4;
+const FRAME_FORWARD: u8 = 5;
+const FRAME_HEARTBEAT: u8 = 6;
+
+fn error_code(e: IpconError) -> u8 {
+    match e {
+        IpconError::InvalidName => 1,
+        IpconError::InvalidKevent => 2,
+        IpconError::InvalidLibIpconMsg => 3,
+        IpconError::InvalidData => 4,
+        IpconError::SysErrorTimeOut => 5,
+        IpconError::SysErrorInvalidValue => 6,
+        IpconError::SysErrorPermission => 7,
+        IpconError::SystemErrorNotExist => 8,
+        IpconError::SystemErrorOther => 9,
+        IpconError::Cancelled => 10,
+        IpconError::Unexpected => 0,
+    }
+}
+
+fn error_from_code(code: u8) -> IpconError {
+    match code {
+        1 => IpconError::InvalidName,
+        2 => IpconError::InvalidKevent,
+        3 => IpconError::InvalidLibIpconMsg,
+        4 => IpconError::InvalidData,
+        5 => IpconError::SysErrorTimeOut,
+        6 => IpconError::SysErrorInvalidValue,
+        7 => IpconError::SysErrorPermission,
+        8 => IpconError::SystemErrorNotExist,
+        9 => IpconError::SystemErrorOther,
+        10 => IpconError::Cancelled,
+        _ => IpconError::Unexpected,
+    }
+}
+
+/// A frame exchanged over a stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Frame {
+    /// Request to send an unicast message to peer.
+    Unicast { peer: String, buf: Vec<u8> },
+    /// Request to send a multicast message to a group.
+    Multicast {
+        group: String,
+        buf: Vec<u8>,
+        sync: bool,
+    },
+    /// A received message.
+    Message(IpconMsg),
+    /// A failed request.
+    Error { error: IpconError, message: String },
+    /// An unicast message sent by peer `from` to peer `to`.
+    Forward {
+        from: String,
+        to: String,
+        buf: Vec<u8>,
+    },
+    /// Sign of life of an idle link.
+    Heartbeat,
+}
+
+fn invalid(s: &str) -> Report<IpconError> {
+    Report::new(IpconError::InvalidData).attach_printable(format!("Invalid frame: {}", s))
+}
+
+fn push_name(v: &mut Vec<u8>, name: &str) -> Result<(), IpconError> {
+    let len = u8::try_from(name.len()).map_err(|_| invalid("name too long"))?;
+    v.push(len);
+    v.extend_from_slice(name.as_bytes());
+    Ok(())
+}
+
+/* Cursor over the body of a frame. */
+struct Body<'a>(&'a [u8]);
+
+impl<'a> Body<'a> {
+    fn u8(&mut self) -> Result<u8, IpconError> {
+        let (&b, rest) = self.0.split_first().ok_or_else(|| invalid("truncated"))?;
+        self.0 = rest;
+        Ok(b)
+    }
+
+    fn name(&mut self) -> Result<String, IpconError> {
+        let len = self.u8()? as usize;
+        if len > self.0.len() {
+            return Err(invalid("truncated name"));
+        }
+
+        let (name, rest) = self.0.split_at(len);
+        self.0 = rest;
+        String::from_utf8(name.to_vec())
+            .map_err(|_| Report::new(IpconError::InvalidName))
+            .attach_printable("Invalid name in frame")
+    }
+
+    fn payload(self) -> Result<Vec<u8>, IpconError> {
+        if self.0.len() > IPCON_MAX_PAYLOAD_LEN {
+            return Err(invalid("payload too large"));
+        }
+        Ok(self.0.to_vec())
+    }
+}
+
+impl Frame {
+    /// Encode the frame, including its length prefix.
+    pub fn encode(&self) -> Result<Vec<u8>, IpconError> {
+        let mut v = vec![0_u8; 4];
+
+        match self {
+            Frame::Unicast { peer, buf } => {
+                v.push(FRAME_UNICAST);
+                push_name(&mut v, peer)?;
+                v.extend_from_slice(buf);
+            }
+            Frame::Multicast { group, buf, sync } => {
+                v.push(FRAME_MULTICAST);
+                v.push(*sync as u8);
+                push_name(&mut v, group)?;
+                v.extend_from_slice(buf);
+            }
+            Frame::Message(IpconMsg::IpconMsgUser(body)) => {
+                v.push(FRAME_MESSAGE);
+                v.push(match body.msg_type {
+                    IpconMsgType::IpconMsgTypeNormal => 0,
+                    IpconMsgType::IpconMsgTypeGroup => 1,
+                    _ => return Err(invalid("unexpected message type")),
+                });
+                push_name(&mut v, &body.peer)?;
+                push_name(&mut v, body.group.as_deref().unwrap_or(""))?;
+                v.extend_from_slice(&body.buf);
+            }
+            Frame::Message(IpconMsg::IpconMsgKevent(kevent)) => {
+                v.push(FRAME_MESSAGE);
+                v.push(2);
+                push_name(&mut v, crate::ipcon::IPCON_KERNEL_NAME)?;
+                push_name(&mut v, crate::ipcon::IPCON_KERNEL_GROUP_NAME)?;
+                v.extend_from_slice(&kevent.to_bytes());
+            }
+            Frame::Message(IpconMsg::IpconMsgInvalid) => {
+                return Err(invalid("invalid message"));
+            }
+            Frame::Error { error, message } => {
+                v.push(FRAME_ERROR);
+                v.push(error_code(*error));
+                v.extend_from_slice(message.as_bytes());
+            }
+            Frame::Forward { from, to, buf } => {
+                v.push(FRAME_FORWARD);
+                push_name(&mut v, from)?;
+                push_name(&mut v, to)?;
+                v.extend_from_slice(buf);
+            }
+            Frame::Heartbeat => v.push(FRAME_HEARTBEAT),
+        }
+
+        let len = v.len() - 4;
+        if len > FRAME_MAX_LEN {
+            return Err(invalid("frame too large"));
+        }
+        v[..4].copy_from_slice(&(len as u32).to_be_bytes());
+
+        Ok(v)
+    }
+
+    /// Decode a frame from its kind and body, the length prefix excluded.
+    pub fn decode(data: &[u8]) -> Result<Frame, IpconError> {
+        let mut body = Body(data);
+
+        let frame = match body.u8()? {
+            FRAME_UNICAST => Frame::Unicast {
+                peer: body.name()?,
+                buf: body.payload()?,
+            },
+            FRAME_MULTICAST => {
+                let flags = body.u8()?;
+                Frame::Multicast {
+                    group: body.name()?,
+                    buf: body.payload()?,
+                    sync: flags & 0x1 != 0,
+                }
+            }
+            FRAME_MESSAGE => {
+                let msg_type = body.u8()?;
+                let peer = body.name()?;
+                let group = body.name()?;
+                let buf = body.payload()?;
+
+                let msg = match msg_type {
+                    0 => IpconMsg::IpconMsgUser(IpconMsgBody::new(
+                        IpconMsgType::IpconMsgTypeNormal,
+                        peer,
+                        None,
+                        buf,
+                    )),
+                    1 => IpconMsg::IpconMsgUser(IpconMsgBody::new(
+                        IpconMsgType::IpconMsgTypeGroup,
+                        peer,
+                        Some(group),
+                        buf,
+                    )),
+                    2 => IpconMsg::IpconMsgKevent(IpconKevent::from_bytes(&buf)?),
+                    t => return Err(invalid(&format!("message type {}", t))),
+                };
+
+                Frame::Message(msg)
+            }
+            FRAME_ERROR => {
+                let error = error_from_code(body.u8()?);
+                Frame::Error {
+                    error,
+                    message: String::from_utf8_lossy(body.0).into_owned(),
+                }
+            }
+            FRAME_FORWARD => Frame::Forward {
+                from: body.name()?,
+                to: body.name()?,
+                buf: body.payload()?,
+            },
+            FRAME_HEARTBEAT => Frame::Heartbeat,
+            k => return Err(invalid(&format!("kind {}", k))),
+        };
+
+        Ok(frame)
+    }
+}
+
+fn io_error(e: std::io::Error) -> Report<IpconError> {
+    Report::new(IpconError::SystemErrorOther).attach_printable(e.to_string())
+}
+
+/// Write a frame to a stream.
+pub fn write_frame<W: Write>(w: &mut W, frame: &Frame) -> Result<(), IpconError> {
+    let v = frame.encode()?;
+    w.write_all(&v).map_err(io_error)?;
+    w.flush().map_err(io_error)
+}
+
+/// Read a frame from a stream.
+/// None is returned if the stream is closed between two frames.
+pub fn read_frame<R: Read>(r: &mut R) -> Result<Option<Frame>, IpconError> {
+    let mut len = [0_u8; 4];
+    match r.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(io_error(e)),
+    }
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len == 0 || len > FRAME_MAX_LEN {
+        return Err(invalid(&format!("length {}", len)));
+    }
+
+    let mut data = vec![0_u8; len];
+    r.read_exact(&mut data).map_err(io_error)?;
+    Frame::decode(&data).map(Some)
+}
+
+/// Take the first complete frame out of a buffer of received bytes.
+/// None is returned if the buffer doesn't hold a complete frame yet.
+pub fn take_frame(buf: &mut Vec<u8>) -> Result<Option<Frame>, IpconError> {
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+
+    let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    if len == 0 || len > FRAME_MAX_LEN {
+        return Err(invalid(&format!("length {}", len)));
+    }
+
+    if buf.len() < 4 + len {
+        return Ok(None);
+    }
+
+    let frame = Frame::decode(&buf[4..4 + len]);
+    buf.drain(..4 + len);
+    frame.map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames() -> Vec<Frame> {
+        vec![
+            Frame::Unicast {
+                peer: "server".to_owned(),
+                buf: b"hello".to_vec(),
+            },
+            Frame::Multicast {
+                group: "news".to_owned(),
+                buf: b"flash".to_vec(),
+                sync: true,
+            },
+            Frame::Message(IpconMsg::IpconMsgUser(IpconMsgBody::new(
+                IpconMsgType::IpconMsgTypeNormal,
+                "client".to_owned(),
+                None,
+                b"ping".to_vec(),
+            ))),
+            Frame::Message(IpconMsg::IpconMsgUser(IpconMsgBody::new(
+                IpconMsgType::IpconMsgTypeGroup,
+                "server".to_owned(),
+                Some("news".to_owned()),
+                Vec::new(),
+            ))),
+            Frame::Message(IpconMsg::IpconMsgKevent(
+                IpconKevent::new_group_added("server", "news").unwrap(),
+            )),
+            Frame::Error {
+                error: IpconError::SystemErrorNotExist,
+                message: "No such peer".to_owned(),
+            },
+            Frame::Forward {
+                from: "a".to_owned(),
+                to: "b".to_owned(),
+                buf: vec![0; IPCON_MAX_PAYLOAD_LEN],
+            },
+            Frame::Heartbeat,
+        ]
+    }
+
+    #[test]
+    fn stream_round_trip() {
+        let mut stream = Vec::new();
+        for frame in frames() {
+            write_frame(&mut stream, &frame).unwrap();
+        }
+
+        let mut r = stream.as_slice();
+        for frame in frames() {
+            let read = read_frame(&mut r).unwrap().unwrap();
+            match (&read, &frame) {
+                (
+                    Frame::Message(IpconMsg::IpconMsgKevent(a)),
+                    Frame::Message(IpconMsg::IpconMsgKevent(b)),
+                ) => assert_eq!(a.group_added(), b.group_added()),
+                _ => assert_eq!(read, frame),
+            }
+        }
+        assert!(read_frame(&mut r).unwrap().is_none());
+    }
+
+    #[test]
+    fn frames_are_taken_once_complete() {
+        let frame = frames().remove(0);
+        let encoded = frame.encode().unwrap();
+
+        let mut buf = encoded[..encoded.len() - 1].to_vec();
+        assert!(take_frame(&mut buf).unwrap().is_none());
+
+        buf.push(encoded[encoded.len() - 1]);
+        buf.extend_from_slice(&encoded[..2]);
+        assert_eq!(take_frame(&mut buf).unwrap(), Some(frame));
+        assert_eq!(buf, encoded[..2]);
+    }
+
+    #[test]
+    fn error_codes_round_trip() {
+        for error in [
+            IpconError::Unexpected,
+            IpconError::InvalidName,
+            IpconError::InvalidKevent,
+            IpconError::InvalidLibIpconMsg,
+            IpconError::InvalidData,
+            IpconError::SysErrorTimeOut,
+            IpconError::SysErrorInvalidValue,
+            IpconError::SysErrorPermission,
+            IpconError::SystemErrorNotExist,
+            IpconError::SystemErrorOther,
+            IpconError::Cancelled,
+        ] {
+            assert_eq!(error_from_code(error_code(error)), error);
+        }
+    }
+
+    #[test]
+    fn invalid_frames_are_rejected() {
+        let long_name = Frame::Unicast {
+            peer: "p".repeat(256),
+            buf: Vec::new(),
+        };
+        assert!(long_name.encode().is_err());
+
+        let too_large = Frame::Forward {
+            from: "a".to_owned(),
+            to: "b".to_owned(),
+            buf: vec![0; FRAME_MAX_LEN],
+        };
+        assert!(too_large.encode().is_err());
+
+        assert!(read_frame(&mut [0_u8, 0, 0, 0].as_slice()).is_err());
+        assert!(take_frame(&mut vec![0xff, 0xff, 0xff, 0xff]).is_err());
+        assert!(Frame::decode(&[]).is_err());
+        assert!(Frame::decode(&[9]).is_err());
+        assert!(Frame::decode(&[FRAME_UNICAST, 5, b'a']).is_err());
+        assert!(Frame::decode(&[FRAME_MESSAGE, 7, 0, 0]).is_err());
+    }
+}
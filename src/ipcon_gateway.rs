@@ -0,0 +1,939 @@
+//! # TCP gateway
+//! IPCON is host local. A Gateway links two hosts over TCP, optionally secured with TLS (`tls`
+//! feature), and federates selected peers and groups:
+//!
+//! * Each exported peer of one host is represented on the other host by a local proxy peer with
+//!   the same name, prefixed by GatewayConfig::prefix. Unicast messages sent to the proxy are
+//!   forwarded to the exported peer, which sees them coming from a proxy of the original sender,
+//!   so that it can reply as usual.
+//! * Each exported group is registered by the proxy of its owner and the messages multicast to
+//!   it are mirrored.
+//! * The added and removed kernel events of the exported peers and groups are mirrored by
+//!   creating and removing the proxies and their groups.
+//!
+//! The receiving side decides which remote peers get a proxy: only the remote names matching
+//! GatewayConfig::imports are accepted, and a proxy is never created with the name of a peer
+//! present on the local host. The default prefix `remote.` keeps the proxies apart from the
+//! local names. The proxies of the remote senders, which were not announced by a kernel event,
+//! are removed after GatewayConfig::requester_ttl without traffic.
+//!
+//! One gateway connects to the other one, which listens. The connecting side reconnects when
+//! the link is lost, and all the proxies are removed while the link is down. The listening
+//! side serves one link at a time, a newly accepted link replaces the current one, so that a
+//! reconnecting gateway is not held back by a half-open link.
+//!
+//! Both sides send a heartbeat every GatewayConfig::heartbeat_interval, and a link on which
+//! nothing is received for GatewayConfig::link_timeout is closed. The timeout bounds the TLS
+//! handshake and the blocked writes as well.
+//!
+//! The gateways exchange the frames of ipcon_frame.
+
+use crate::ipcon::{
+    Ipcon, Transport, IPCON_KERNEL_GROUP_NAME, IPCON_KERNEL_NAME, IPF_DEFAULT,
+    IPF_DISABLE_KEVENT_FILTER,
+};
+use crate::ipcon_error::IpconError;
+use crate::ipcon_frame::{take_frame, Frame};
+use crate::ipcon_msg::{IpconKevent, IpconMsg, IpconMsgType};
+use crate::ipcon_name::PeerName;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+#[allow(unused)]
+use {
+    error_stack::{Report, Result, ResultExt},
+    jlogger_tracing::{jdebug, jerror, jinfo, jtrace, jwarn},
+};
+
+/// Receive timeout of the gateway peers in microseconds, the stop request is checked at this
+/// interval.
+const GATEWAY_RECEIVE_TIMEOUT_US: u32 = 100_000;
+
+/// Interval of the removal of the idle proxies.
+const GATEWAY_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+fn io_error(e: std::io::Error) -> Report<IpconError> {
+    Report::new(IpconError::SystemErrorOther).attach_printable(e.to_string())
+}
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Configuration of a Gateway.
+#[derive(Clone, Debug)]
+pub struct GatewayConfig {
+    /// Name of the gateway peer.
+    pub name: String,
+    /// Local peers exported to the remote host.
+    pub peers: Vec<String>,
+    /// Local groups mirrored on the remote host: (peer, group). The owner should be exported.
+    pub groups: Vec<(String, String)>,
+    /// Remote peers accepted on the local host, a trailing `*` matches any suffix.
+    /// The messages of the other remote peers are dropped.
+    pub imports: Vec<String>,
+    /// Prefix of the names of the proxy peers standing for the remote peers.
+    pub prefix: String,
+    /// Idle time after which the proxy of a remote sender, not announced by the remote
+    /// gateway, is removed.
+    pub requester_ttl: Duration,
+    /// Delay before reconnecting when the link is lost.
+    pub reconnect_interval: Duration,
+    /// Interval of the heartbeats sent on the link.
+    pub heartbeat_interval: Duration,
+    /// Time without receiving anything after which the link is closed, it should be a few
+    /// heartbeat intervals of the remote gateway.
+    pub link_timeout: Duration,
+    /// Transport of the gateway peer and of the proxies.
+    pub transport: Transport,
+}
+
+impl Default for GatewayConfig {
+    fn default() -> Self {
+        GatewayConfig {
+            name: "ipcon-gateway".to_owned(),
+            peers: Vec::new(),
+            groups: Vec::new(),
+            imports: vec!["*".to_owned()],
+            prefix: "remote.".to_owned(),
+            requester_ttl: Duration::from_secs(60),
+            reconnect_interval: Duration::from_secs(1),
+            heartbeat_interval: Duration::from_secs(5),
+            link_timeout: Duration::from_secs(15),
+            transport: Transport::Kernel,
+        }
+    }
+}
+
+enum Endpoint {
+    Connect(String),
+    Listen(TcpListener),
+}
+
+enum Security {
+    Plain,
+    #[cfg(feature = "tls")]
+    Client(
+        Arc<rustls::ClientConfig>,
+        rustls::pki_types::ServerName<'static>,
+    ),
+    #[cfg(feature = "tls")]
+    Server(Arc<rustls::ServerConfig>),
+}
+
+/* A connection to the remote gateway.
+ * The write lock serializes the writes to the socket. With TLS, the records are produced under
+ * the connection lock and written out under the write lock only, so that a blocked write
+ * doesn't keep the receiving side from processing the incoming records. */
+struct Link {
+    tcp: TcpStream,
+    write: Mutex<()>,
+    #[cfg(feature = "tls")]
+    tls: Option<Mutex<rustls::Connection>>,
+}
+
+#[cfg(feature = "tls")]
+fn tls_error(e: rustls::Error) -> Report<IpconError> {
+    Report::new(IpconError::SystemErrorOther).attach_printable(format!("TLS error: {}", e))
+}
+
+impl Link {
+    fn new(tcp: TcpStream, security: &Security, timeout: Duration) -> Result<Link, IpconError> {
+        let _ = tcp.set_nodelay(true);
+        /* A zero duration is refused, it would mean no timeout. */
+        let timeout = Some(timeout.max(Duration::from_millis(1)));
+        tcp.set_read_timeout(timeout).map_err(io_error)?;
+        tcp.set_write_timeout(timeout).map_err(io_error)?;
+
+        #[cfg(feature = "tls")]
+        let tls = {
+            let conn: Option<rustls::Connection> = match security {
+                Security::Plain => None,
+                Security::Client(config, name) => Some(
+                    rustls::ClientConnection::new(config.clone(), name.clone())
+                        .map_err(tls_error)?
+                        .into(),
+                ),
+                Security::Server(config) => Some(
+                    rustls::ServerConnection::new(config.clone())
+                        .map_err(tls_error)?
+                        .into(),
+                ),
+            };
+
+            match conn {
+                Some(mut conn) => {
+                    let mut s = &tcp;
+                    while conn.is_handshaking() {
+                        conn.complete_io(&mut s)
+                            .map_err(io_error)
+                            .attach_printable("TLS handshake failed")?;
+                    }
+                    Some(Mutex::new(conn))
+                }
+                None => None,
+            }
+        };
+
+        #[cfg(not(feature = "tls"))]
+        let Security::Plain = security;
+
+        Ok(Link {
+            tcp,
+            write: Mutex::new(()),
+            #[cfg(feature = "tls")]
+            tls,
+        })
+    }
+
+    fn send(&self, frame: &Frame) -> Result<(), IpconError> {
+        let data = frame.encode()?;
+
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            lock(tls).writer().write_all(&data).map_err(io_error)?;
+            return self.flush_tls(tls);
+        }
+
+        let _guard = lock(&self.write);
+        (&self.tcp).write_all(&data).map_err(io_error)
+    }
+
+    /* Write out the pending TLS records, the connection is not locked while writing. */
+    #[cfg(feature = "tls")]
+    fn flush_tls(&self, tls: &Mutex<rustls::Connection>) -> Result<(), IpconError> {
+        let _guard = lock(&self.write);
+
+        let mut records = Vec::new();
+        {
+            let mut conn = lock(tls);
+            while conn.wants_write() {
+                conn.write_tls(&mut records).map_err(io_error)?;
+            }
+        }
+
+        (&self.tcp).write_all(&records).map_err(io_error)
+    }
+
+    /* Append received data to buf, false is returned at the end of the stream. */
+    fn fill(&self, buf: &mut Vec<u8>) -> Result<bool, IpconError> {
+        let mut chunk = [0_u8; 4096];
+        let n = (&self.tcp).read(&mut chunk).map_err(|e| match e.kind() {
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => {
+                Report::new(IpconError::SysErrorTimeOut).attach_printable("Link timed out")
+            }
+            _ => io_error(e),
+        })?;
+        if n == 0 {
+            return Ok(false);
+        }
+
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            let mut received = &chunk[..n];
+            let mut open = true;
+            let mut conn = lock(tls);
+
+            while !received.is_empty() {
+                conn.read_tls(&mut received).map_err(io_error)?;
+                conn.process_new_packets().map_err(tls_error)?;
+
+                let mut plain = [0_u8; 4096];
+                loop {
+                    match conn.reader().read(&mut plain) {
+                        Ok(0) => {
+                            open = false;
+                            break;
+                        }
+                        Ok(n) => buf.extend_from_slice(&plain[..n]),
+                        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                        Err(e) => return Err(io_error(e)),
+                    }
+                }
+            }
+
+            if conn.wants_write() {
+                drop(conn);
+                self.flush_tls(tls)?;
+            }
+
+            return Ok(open);
+        }
+
+        buf.extend_from_slice(&chunk[..n]);
+        Ok(true)
+    }
+
+    fn next_frame(&self, buf: &mut Vec<u8>) -> Result<Option<Frame>, IpconError> {
+        loop {
+            if let Some(frame) = take_frame(buf)? {
+                return Ok(Some(frame));
+            }
+
+            if !self.fill(buf)? {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn close(&self) {
+        let _ = self.tcp.shutdown(Shutdown::Both);
+    }
+}
+
+/* Local peer standing for a remote peer. */
+struct Proxy {
+    ipcon: Arc<Ipcon>,
+    groups: HashSet<String>,
+    /* Announced by a kernel event, not expired. */
+    announced: bool,
+    last_used: Instant,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+struct GatewayInner {
+    config: GatewayConfig,
+    ipcon: Ipcon,
+    link: Mutex<Option<Arc<Link>>>,
+    /* Proxies by remote peer name. */
+    proxies: Mutex<HashMap<String, Proxy>>,
+    /* Local peers which sent messages through a proxy, the remote side can reply to them. */
+    requesters: Mutex<HashSet<String>>,
+    stop: AtomicBool,
+}
+
+impl GatewayInner {
+    fn proxies(&self) -> MutexGuard<'_, HashMap<String, Proxy>> {
+        lock(&self.proxies)
+    }
+
+    fn requesters(&self) -> MutexGuard<'_, HashSet<String>> {
+        lock(&self.requesters)
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    /* Sleep for d unless the gateway is stopped. */
+    fn pause(&self, d: Duration) {
+        let step = Duration::from_millis(100);
+        let mut left = d;
+        while !self.stopped() && !left.is_zero() {
+            let t = left.min(step);
+            std::thread::sleep(t);
+            left -= t;
+        }
+    }
+
+    fn send(&self, frame: &Frame) {
+        let link = lock(&self.link).clone();
+
+        match link {
+            Some(link) => {
+                if let Err(e) = link.send(frame) {
+                    jwarn!("Gateway {} link failed: {:?}", self.config.name, e);
+                    link.close();
+                }
+            }
+            None => jdebug!(
+                "Gateway {} is not connected, message dropped",
+                self.config.name
+            ),
+        }
+    }
+
+    fn is_proxy(&self, name: &str) -> bool {
+        name.strip_prefix(self.config.prefix.as_str())
+            .is_some_and(|remote| self.proxies().contains_key(remote))
+    }
+
+    fn proxy_loop(self: Arc<Self>, remote: String, ipcon: Arc<Ipcon>, stop: Arc<AtomicBool>) {
+        while !stop.load(Ordering::Relaxed) && !self.stopped() {
+            let msg = match ipcon.receive_msg_timeout(0, GATEWAY_RECEIVE_TIMEOUT_US) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    if *e.current_context() == IpconError::Cancelled {
+                        break;
+                    }
+
+                    if *e.current_context() != IpconError::SysErrorTimeOut {
+                        jwarn!("Proxy of {} receive failed: {:?}", remote, e);
+                    }
+                    continue;
+                }
+            };
+
+            let body = match msg {
+                IpconMsg::IpconMsgUser(body)
+                    if body.msg_type == IpconMsgType::IpconMsgTypeNormal =>
+                {
+                    body
+                }
+                _ => continue,
+            };
+
+            if self.is_proxy(&body.peer) {
+                jwarn!(
+                    "Message from proxy {} to proxy of {} dropped",
+                    body.peer,
+                    remote
+                );
+                continue;
+            }
+
+            if let Some(p) = self.proxies().get_mut(&remote) {
+                p.last_used = Instant::now();
+            }
+
+            self.requesters().insert(body.peer.clone());
+            self.send(&Frame::Forward {
+                from: body.peer,
+                to: remote.clone(),
+                buf: body.buf,
+            });
+        }
+    }
+
+    fn imported(&self, remote: &str) -> bool {
+        self.config
+            .imports
+            .iter()
+            .any(|i| match i.strip_suffix('*') {
+                Some(prefix) => remote.starts_with(prefix),
+                None => i == remote,
+            })
+    }
+
+    /* Get the proxy of a remote peer, creating it if needed. */
+    fn proxy(self: &Arc<Self>, remote: &str, announced: bool) -> Result<Arc<Ipcon>, IpconError> {
+        let mut proxies = self.proxies();
+        if let Some(p) = proxies.get_mut(remote) {
+            p.announced |= announced;
+            p.last_used = Instant::now();
+            return Ok(p.ipcon.clone());
+        }
+
+        if !self.imported(remote) {
+            return Err(Report::new(IpconError::SysErrorPermission))
+                .attach_printable(format!("Remote peer {} is not imported", remote));
+        }
+
+        /* Proxies mirror the remote names, the namespace of the process doesn't apply. */
+        let name = PeerName::new(&format!("{}{}", self.config.prefix, remote))?;
+        if self.ipcon.is_peer_present(&name) {
+            return Err(Report::new(IpconError::SysErrorPermission))
+                .attach_printable(format!("Proxy {} would replace a local peer", name));
+        }
+
+        let ipcon = Arc::new(
+            self.config
+                .transport
+                .peer_with_name(&name, Some(IPF_DEFAULT))
+                .attach_printable(format!("Failed to create proxy {}", name))?,
+        );
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let inner = self.clone();
+            let remote = remote.to_owned();
+            let ipcon = ipcon.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || inner.proxy_loop(remote, ipcon, stop))
+        };
+
+        proxies.insert(
+            remote.to_owned(),
+            Proxy {
+                ipcon: ipcon.clone(),
+                groups: HashSet::new(),
+                announced,
+                last_used: Instant::now(),
+                stop,
+                thread: Some(thread),
+            },
+        );
+        jinfo!("Proxy {} created", name);
+
+        Ok(ipcon)
+    }
+
+    /* Get the proxy of a remote peer with a registered group. */
+    fn proxy_group(self: &Arc<Self>, remote: &str, group: &str) -> Result<Arc<Ipcon>, IpconError> {
+        let ipcon = self.proxy(remote, true)?;
+
+        let added = self
+            .proxies()
+            .get_mut(remote)
+            .is_some_and(|p| p.groups.insert(group.to_owned()));
+        if added {
+            ipcon.register_group(group)?;
+        }
+
+        Ok(ipcon)
+    }
+
+    fn remove_proxy(&self, remote: &str) {
+        /* The proxy thread is joined out of the lock. */
+        let proxy = self.proxies().remove(remote);
+        if proxy.is_some() {
+            jinfo!("Proxy of {} removed", remote);
+        }
+    }
+
+    /* Remove the proxies of the remote senders idle for requester_ttl. */
+    fn expire_proxies(&self) {
+        let ttl = self.config.requester_ttl;
+        let expired: Vec<(String, Proxy)> = {
+            let mut proxies = self.proxies();
+            let names: Vec<String> = proxies
+                .iter()
+                .filter(|(_, p)| !p.announced && p.last_used.elapsed() > ttl)
+                .map(|(n, _)| n.clone())
+                .collect();
+            names
+                .into_iter()
+                .filter_map(|n| proxies.remove_entry(&n))
+                .collect()
+        };
+
+        /* The proxy threads are joined out of the lock. */
+        for (remote, proxy) in expired {
+            drop(proxy);
+            jinfo!("Idle proxy of {} removed", remote);
+        }
+    }
+
+    fn remove_proxy_group(&self, remote: &str, group: &str) -> Result<(), IpconError> {
+        let ipcon = self
+            .proxies()
+            .get_mut(remote)
+            .filter(|p| p.groups.contains(group))
+            .map(|p| {
+                p.groups.remove(group);
+                p.ipcon.clone()
+            });
+
+        match ipcon {
+            Some(ipcon) => ipcon.unregister_group(group),
+            None => Ok(()),
+        }
+    }
+
+    fn handle_frame(self: &Arc<Self>, frame: Frame) -> Result<(), IpconError> {
+        match frame {
+            Frame::Forward { from, to, buf } => {
+                if !self.config.peers.contains(&to) && !self.requesters().contains(&to) {
+                    return Err(Report::new(IpconError::SysErrorPermission))
+                        .attach_printable(format!("Peer {} is not exported", to));
+                }
+
+                self.proxy(&from, false)?.send_unicast_msg(&to, &buf)
+            }
+
+            Frame::Message(IpconMsg::IpconMsgUser(body)) => match &body.group {
+                Some(group) => self
+                    .proxy_group(&body.peer, group)?
+                    .send_multicast(group, &body.buf, false),
+                None => Err(Report::new(IpconError::InvalidData))
+                    .attach_printable("Unexpected unicast message frame"),
+            },
+
+            Frame::Message(IpconMsg::IpconMsgKevent(kevent)) => {
+                if let Some(peer) = kevent.peer_added() {
+                    self.proxy(&peer, true)?;
+                } else if let Some(peer) = kevent.peer_removed() {
+                    self.remove_proxy(&peer);
+                } else if let Some((peer, group)) = kevent.group_added() {
+                    self.proxy_group(&peer, &group)?;
+                } else if let Some((peer, group)) = kevent.group_removed() {
+                    self.remove_proxy_group(&peer, &group)?;
+                }
+                Ok(())
+            }
+
+            Frame::Heartbeat => Ok(()),
+
+            f => Err(Report::new(IpconError::InvalidData))
+                .attach_printable(format!("Unexpected frame: {:?}", f)),
+        }
+    }
+
+    fn exported_group(&self, peer: &str, group: &str) -> bool {
+        self.config
+            .groups
+            .iter()
+            .any(|(p, g)| p == peer && g == group)
+    }
+
+    fn exported_kevent(&self, kevent: &IpconKevent) -> bool {
+        if let Some(peer) = kevent.peer_added().or_else(|| kevent.peer_removed()) {
+            return self.config.peers.contains(&peer);
+        }
+
+        if let Some((peer, group)) = kevent.group_added().or_else(|| kevent.group_removed()) {
+            return self.exported_group(&peer, &group);
+        }
+
+        false
+    }
+
+    fn join_group(&self, peer: &str, group: &str) {
+        if let Err(e) = self.ipcon.join_group(peer, group) {
+            jwarn!("Failed to join exported group {}@{}: {:?}", group, peer, e);
+        }
+    }
+
+    /* Forward the exported groups and kernel events to the remote gateway, and send the
+     * heartbeats. */
+    fn local_loop(&self) {
+        let mut swept = Instant::now();
+        let mut heartbeat = Instant::now();
+
+        while !self.stopped() {
+            if swept.elapsed() >= GATEWAY_SWEEP_INTERVAL {
+                self.expire_proxies();
+                swept = Instant::now();
+            }
+
+            if heartbeat.elapsed() >= self.config.heartbeat_interval {
+                if lock(&self.link).is_some() {
+                    self.send(&Frame::Heartbeat);
+                }
+                heartbeat = Instant::now();
+            }
+
+            let msg = match self
+                .ipcon
+                .receive_msg_timeout(0, GATEWAY_RECEIVE_TIMEOUT_US)
+            {
+                Ok(msg) => msg,
+                Err(e) => {
+                    if *e.current_context() == IpconError::Cancelled {
+                        break;
+                    }
+
+                    if *e.current_context() != IpconError::SysErrorTimeOut {
+                        jwarn!("Gateway {} receive failed: {:?}", self.config.name, e);
+                    }
+                    continue;
+                }
+            };
+
+            match &msg {
+                IpconMsg::IpconMsgUser(body) => match &body.group {
+                    Some(group) if self.exported_group(&body.peer, group) => {}
+                    _ => continue,
+                },
+                IpconMsg::IpconMsgKevent(kevent) if self.exported_kevent(kevent) => {
+                    if let Some((peer, group)) = kevent.group_added() {
+                        self.join_group(&peer, &group);
+                    }
+                }
+                _ => continue,
+            }
+
+            self.send(&Frame::Message(msg));
+        }
+    }
+
+    /* Announce the exported peers and groups present when the link is established. */
+    fn announce(&self) -> Result<(), IpconError> {
+        for peer in &self.config.peers {
+            if self.ipcon.is_peer_present(peer) {
+                let kevent = IpconKevent::new_peer_added(peer)?;
+                self.send(&Frame::Message(IpconMsg::IpconMsgKevent(kevent)));
+            }
+        }
+
+        for (peer, group) in &self.config.groups {
+            if self.ipcon.is_group_present(peer, group) {
+                let kevent = IpconKevent::new_group_added(peer, group)?;
+                self.send(&Frame::Message(IpconMsg::IpconMsgKevent(kevent)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /* Establish the link on a connected socket and serve it until it is lost. */
+    fn serve(self: &Arc<Self>, tcp: TcpStream, security: &Security) {
+        let link = match Link::new(tcp, security, self.config.link_timeout) {
+            Ok(link) => Arc::new(link),
+            Err(e) => {
+                jwarn!("Gateway {} link failed: {:?}", self.config.name, e);
+                return;
+            }
+        };
+
+        /* The gateway may have been stopped during the handshake. */
+        {
+            let mut current = lock(&self.link);
+            if self.stopped() {
+                return;
+            }
+            *current = Some(link.clone());
+        }
+        jinfo!("Gateway {} connected", self.config.name);
+
+        if let Err(e) = self.announce() {
+            jwarn!("{:?}", e);
+        }
+
+        let mut buf = Vec::new();
+        loop {
+            match link.next_frame(&mut buf) {
+                Ok(Some(frame)) => {
+                    if let Err(e) = self.handle_frame(frame) {
+                        jwarn!("Gateway {} frame dropped: {:?}", self.config.name, e);
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    if !self.stopped() {
+                        jwarn!("Gateway {} link lost: {:?}", self.config.name, e);
+                    }
+                    break;
+                }
+            }
+        }
+
+        link.close();
+        *lock(&self.link) = None;
+
+        /* The remote peers are gone with the link. */
+        let proxies: Vec<Proxy> = self.proxies().drain().map(|(_, p)| p).collect();
+        drop(proxies);
+        self.requesters().clear();
+        jinfo!("Gateway {} disconnected", self.config.name);
+    }
+
+    fn connect_loop(self: Arc<Self>, addr: String, security: Security) {
+        while !self.stopped() {
+            match TcpStream::connect(&addr) {
+                Ok(tcp) => self.serve(tcp, &security),
+                Err(e) => jdebug!(
+                    "Gateway {} failed to connect {}: {}",
+                    self.config.name,
+                    addr,
+                    e
+                ),
+            }
+
+            self.pause(self.config.reconnect_interval);
+        }
+    }
+
+    /* Serve the accepted links in turn, each one replacing the previous one. */
+    fn listen_loop(self: Arc<Self>, listener: TcpListener, security: Security) {
+        let security = Arc::new(security);
+        let mut current: Option<(TcpStream, JoinHandle<()>)> = None;
+
+        while !self.stopped() {
+            let tcp = match listener.accept() {
+                Ok((tcp, _)) => tcp,
+                Err(e) => {
+                    jwarn!("Gateway {} failed to accept: {}", self.config.name, e);
+                    self.pause(GATEWAY_SWEEP_INTERVAL);
+                    continue;
+                }
+            };
+
+            /* Closing the socket ends the handshake or the serving of the previous link. */
+            if let Some((previous, thread)) = current.take() {
+                if !self.stopped() {
+                    jinfo!("Gateway {} link replaced", self.config.name);
+                }
+                let _ = previous.shutdown(Shutdown::Both);
+                let _ = thread.join();
+            }
+
+            if self.stopped() {
+                break;
+            }
+
+            let handle = match tcp.try_clone() {
+                Ok(handle) => handle,
+                Err(e) => {
+                    jwarn!("Gateway {} failed to accept: {}", self.config.name, e);
+                    continue;
+                }
+            };
+
+            let inner = self.clone();
+            let security = security.clone();
+            let thread = std::thread::spawn(move || inner.serve(tcp, &security));
+            current = Some((handle, thread));
+        }
+
+        if let Some((previous, thread)) = current {
+            let _ = previous.shutdown(Shutdown::Both);
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Gateway federating IPCON peers and groups with a remote host.
+pub struct Gateway {
+    inner: Arc<GatewayInner>,
+    local_addr: Option<SocketAddr>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Gateway {
+    fn start(
+        endpoint: Endpoint,
+        security: Security,
+        config: GatewayConfig,
+    ) -> Result<Gateway, IpconError> {
+        let ipcon = config
+            .transport
+            .peer(
+                Some(&config.name),
+                Some(IPF_DEFAULT | IPF_DISABLE_KEVENT_FILTER),
+            )
+            .attach_printable("Failed to create gateway peer")?;
+        ipcon.join_group(IPCON_KERNEL_NAME, IPCON_KERNEL_GROUP_NAME)?;
+
+        let local_addr = match &endpoint {
+            Endpoint::Listen(listener) => Some(listener.local_addr().map_err(io_error)?),
+            Endpoint::Connect(_) => None,
+        };
+
+        let inner = Arc::new(GatewayInner {
+            config,
+            ipcon,
+            link: Mutex::new(None),
+            proxies: Mutex::new(HashMap::new()),
+            requesters: Mutex::new(HashSet::new()),
+            stop: AtomicBool::new(false),
+        });
+
+        for (peer, group) in &inner.config.groups {
+            if inner.ipcon.is_group_present(peer, group) {
+                inner.join_group(peer, group);
+            }
+        }
+
+        let local = {
+            let inner = inner.clone();
+            std::thread::spawn(move || inner.local_loop())
+        };
+
+        let link = {
+            let inner = inner.clone();
+            match endpoint {
+                Endpoint::Connect(addr) => {
+                    std::thread::spawn(move || inner.connect_loop(addr, security))
+                }
+                Endpoint::Listen(listener) => {
+                    std::thread::spawn(move || inner.listen_loop(listener, security))
+                }
+            }
+        };
+
+        Ok(Gateway {
+            inner,
+            local_addr,
+            threads: vec![local, link],
+        })
+    }
+
+    fn bind(addr: &str) -> Result<Endpoint, IpconError> {
+        let listener = TcpListener::bind(addr)
+            .map_err(io_error)
+            .attach_printable(format!("Failed to bind {}", addr))?;
+        Ok(Endpoint::Listen(listener))
+    }
+
+    /// Start a gateway connecting to the remote gateway listening on addr.
+    pub fn connect(addr: &str, config: GatewayConfig) -> Result<Gateway, IpconError> {
+        Gateway::start(Endpoint::Connect(addr.to_owned()), Security::Plain, config)
+    }
+
+    /// Start a gateway listening on addr.
+    pub fn listen(addr: &str, config: GatewayConfig) -> Result<Gateway, IpconError> {
+        Gateway::start(Gateway::bind(addr)?, Security::Plain, config)
+    }
+
+    /// Start a gateway connecting with TLS to the remote gateway listening on addr.
+    /// server_name is the name verified against the certificate of the remote gateway.
+    #[cfg(feature = "tls")]
+    pub fn connect_tls(
+        addr: &str,
+        server_name: &str,
+        tls: Arc<rustls::ClientConfig>,
+        config: GatewayConfig,
+    ) -> Result<Gateway, IpconError> {
+        let name = rustls::pki_types::ServerName::try_from(server_name.to_owned())
+            .map_err(|_| Report::new(IpconError::InvalidName))
+            .attach_printable(format!("Invalid server name {}", server_name))?;
+
+        Gateway::start(
+            Endpoint::Connect(addr.to_owned()),
+            Security::Client(tls, name),
+            config,
+        )
+    }
+
+    /// Start a gateway listening on addr and accepting TLS connections.
+    #[cfg(feature = "tls")]
+    pub fn listen_tls(
+        addr: &str,
+        tls: Arc<rustls::ServerConfig>,
+        config: GatewayConfig,
+    ) -> Result<Gateway, IpconError> {
+        Gateway::start(Gateway::bind(addr)?, Security::Server(tls), config)
+    }
+
+    /// Address the gateway listens on.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Whether the link with the remote gateway is established.
+    pub fn is_connected(&self) -> bool {
+        lock(&self.inner.link).is_some()
+    }
+
+    /// Names of the remote peers which have a local proxy.
+    pub fn proxies(&self) -> Vec<String> {
+        self.inner.proxies().keys().cloned().collect()
+    }
+}
+
+impl Drop for Gateway {
+    fn drop(&mut self) {
+        self.inner.stop.store(true, Ordering::Relaxed);
+
+        if let Some(link) = lock(&self.inner.link).as_ref() {
+            link.close();
+        }
+
+        /* Wake up the accept loop. */
+        if let Some(addr) = self.local_addr {
+            let _ = TcpStream::connect(addr);
+        }
+
+        for t in self.threads.drain(..) {
+            let _ = t.join();
+        }
+
+        let proxies: Vec<Proxy> = self.inner.proxies().drain().map(|(_, p)| p).collect();
+        drop(proxies);
+    }
+}
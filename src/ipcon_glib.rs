@@ -0,0 +1,324 @@
+//! # glib integration
+//! GTK and GStreamer applications run a glib main loop instead of tokio. GlibIpcon adds the read
+//! fd of a peer to the default main context and calls back with every received message on the
+//! thread running it:
+//!
+//! ```ignore
+//! let main_loop = glib::MainLoop::new(None, false);
+//! let peer = GlibIpcon::new(ipcon, move |_ipcon, msg| {
+//!     label.set_text(&format!("{:?}", msg));
+//!     glib::ControlFlow::Continue
+//! })?;
+//!
+//! let sender = peer.sender();
+//! glib::spawn_future_local(async move {
+//!     if let Err(e) = sender.send_unicast_msg("server", b"hello").await {
+//!         jwarn!("{:?}", e);
+//!     }
+//! });
+//!
+//! main_loop.run();
+//! ```
+//!
+//! Sending may block, so GlibSender hands the messages over to a sender thread and returns a
+//! future completed with the result, which can be awaited on the main context.
+//!
+//! The callback is removed when it returns ControlFlow::Break, when the peer is shut down (see
+//! ipcon_shutdown) or when GlibIpcon is dropped. The shutdown eventfd of the peer is watched
+//! too, so that a shutdown removes the callback without waiting for a message.
+
+use crate::ipcon::Ipcon;
+use crate::ipcon_error::IpconError;
+use crate::ipcon_msg::IpconMsg;
+use futures::channel::oneshot;
+use glib::{ControlFlow, IOCondition, MainContext, SourceId};
+use std::future::Future;
+use std::os::unix::io::AsRawFd;
+use std::sync::{mpsc, Arc, Mutex};
+#[allow(unused)]
+use {
+    error_stack::{Report, Result, ResultExt},
+    jlogger_tracing::{jdebug, jerror, jinfo, jtrace, jwarn},
+};
+
+type Job = Box<dyn FnOnce(&Ipcon) + Send>;
+
+/* Sources of the read fd and of the shutdown eventfd, removed together. */
+type Sources = Arc<Mutex<Vec<SourceId>>>;
+
+fn remove_sources(sources: &Sources) {
+    let ids = std::mem::take(&mut *sources.lock().unwrap_or_else(|e| e.into_inner()));
+    let context = MainContext::default();
+
+    for id in ids {
+        if let Some(source) = context.find_source_by_id(&id) {
+            source.destroy();
+        }
+    }
+}
+
+/// IPCON peer attached to the default glib main context.
+pub struct GlibIpcon {
+    ipcon: Arc<Ipcon>,
+    sources: Sources,
+    sender: GlibSender,
+}
+
+impl GlibIpcon {
+    /// Attach a peer to the default main context, f is called with every received message.
+    /// It must be called from the thread owning the default main context, usually the main
+    /// thread. It fails if the peer doesn't enable IPF_RCV_IF.
+    pub fn new<F>(ipcon: Ipcon, mut f: F) -> Result<GlibIpcon, IpconError>
+    where
+        F: FnMut(&Ipcon, IpconMsg) -> ControlFlow + 'static,
+    {
+        let fd = ipcon
+            .get_read_fd()
+            .attach_printable("glib source needs the message receiving interface")?;
+        let ipcon = Arc::new(ipcon);
+
+        let (jobs, rx) = mpsc::channel::<Job>();
+        let worker_ipcon = ipcon.clone();
+        /* The sender thread ends once the pending messages are sent and every sender is gone. */
+        std::thread::Builder::new()
+            .name("ipcon-glib-sender".to_owned())
+            .spawn(move || {
+                for job in rx {
+                    job(&worker_ipcon);
+                }
+            })
+            .map_err(|e| Report::new(IpconError::SystemErrorOther).attach_printable(e))
+            .attach_printable("Failed to spawn sender thread")?;
+
+        let sources: Sources = Arc::new(Mutex::new(Vec::with_capacity(2)));
+
+        let source_ipcon = ipcon.clone();
+        let source_sources = sources.clone();
+        let source = glib::unix_fd_add_local(fd, IOCondition::IN, move |_, _| loop {
+            match source_ipcon.try_receive_msg() {
+                Ok(Some(msg)) => {
+                    if f(&source_ipcon, msg).is_break() {
+                        remove_sources(&source_sources);
+                        return ControlFlow::Break;
+                    }
+                }
+                Ok(None) => return ControlFlow::Continue,
+                Err(e) if *e.current_context() == IpconError::Cancelled => {
+                    remove_sources(&source_sources);
+                    return ControlFlow::Break;
+                }
+                Err(e) => {
+                    jwarn!("glib source receive failed: {:?}", e);
+                    return ControlFlow::Continue;
+                }
+            }
+        });
+
+        /* The handle owns the eventfd, it is kept by the callback. */
+        let shutdown_handle = ipcon.shutdown_handle();
+        let shutdown_sources = sources.clone();
+        let shutdown =
+            glib::unix_fd_add_local(shutdown_handle.as_raw_fd(), IOCondition::IN, move |_, _| {
+                if !shutdown_handle.is_shutdown() {
+                    return ControlFlow::Continue;
+                }
+
+                remove_sources(&shutdown_sources);
+                ControlFlow::Break
+            });
+
+        sources
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .extend([source, shutdown]);
+
+        Ok(GlibIpcon {
+            ipcon,
+            sources,
+            sender: GlibSender { jobs },
+        })
+    }
+
+    /// Get the peer.
+    pub fn ipcon(&self) -> &Ipcon {
+        &self.ipcon
+    }
+
+    /// Get a sender of the peer, usable from the main context without blocking.
+    pub fn sender(&self) -> GlibSender {
+        self.sender.clone()
+    }
+}
+
+impl Drop for GlibIpcon {
+    fn drop(&mut self) {
+        /* The sources are already gone if a callback returned ControlFlow::Break. */
+        remove_sources(&self.sources);
+    }
+}
+
+/// Sender of a GlibIpcon, sending the messages from a dedicated thread.
+#[derive(Clone)]
+pub struct GlibSender {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl GlibSender {
+    /// Send an unicast message, the returned future is completed with the result.
+    pub fn send_unicast_msg(
+        &self,
+        peer: &str,
+        buf: &[u8],
+    ) -> impl Future<Output = Result<(), IpconError>> {
+        let peer = peer.to_owned();
+        let buf = buf.to_vec();
+        self.submit(move |ipcon| ipcon.send_unicast_msg(&peer, &buf))
+    }
+
+    /// Send a multicast message to an owned group, the returned future is completed with the
+    /// result.
+    pub fn send_multicast(
+        &self,
+        group: &str,
+        buf: &[u8],
+        sync: bool,
+    ) -> impl Future<Output = Result<(), IpconError>> {
+        let group = group.to_owned();
+        let buf = buf.to_vec();
+        self.submit(move |ipcon| ipcon.send_multicast(&group, &buf, sync))
+    }
+
+    fn submit<F>(&self, f: F) -> impl Future<Output = Result<(), IpconError>>
+    where
+        F: FnOnce(&Ipcon) -> Result<(), IpconError> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+
+        /* If the sender thread is gone, the job and tx are dropped and rx fails. */
+        let _ = self.jobs.send(Box::new(move |ipcon: &Ipcon| {
+            let _ = tx.send(f(ipcon));
+        }));
+
+        async move {
+            match rx.await {
+                Ok(ret) => ret,
+                Err(_) => Err(Report::new(IpconError::Cancelled))
+                    .attach_printable("glib sender thread is gone"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipcon::IPF_DEFAULT;
+    use crate::ipcon_loopback::LoopbackBus;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    /* The sources are attached to the default main context, owned by one thread at a time. */
+    static CONTEXT: Mutex<()> = Mutex::new(());
+
+    /* Run the default main context until cond is true, or for a while if it stays false. */
+    fn iterate_until(cond: impl Fn() -> bool) -> bool {
+        let context = MainContext::default();
+        let deadline = Instant::now() + Duration::from_secs(2);
+
+        while !cond() {
+            if Instant::now() > deadline {
+                return false;
+            }
+            context.iteration(false);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        true
+    }
+
+    fn payload(msg: IpconMsg) -> Vec<u8> {
+        match msg {
+            IpconMsg::IpconMsgUser(body) => body.buf,
+            m => panic!("Unexpected message {:?}", m),
+        }
+    }
+
+    fn is_attached(peer: &GlibIpcon) -> bool {
+        !peer.sources.lock().unwrap().is_empty()
+    }
+
+    #[test]
+    fn messages_are_delivered_until_break() {
+        let _context = CONTEXT.lock().unwrap_or_else(|e| e.into_inner());
+        let bus = LoopbackBus::new();
+        let sender = bus.peer(Some("sender"), Some(IPF_DEFAULT)).unwrap();
+        let received = Rc::new(RefCell::new(Vec::new()));
+
+        let r = received.clone();
+        let peer = GlibIpcon::new(
+            bus.peer(Some("receiver"), Some(IPF_DEFAULT)).unwrap(),
+            move |_, msg| {
+                r.borrow_mut().push(payload(msg));
+                if r.borrow().len() == 2 {
+                    ControlFlow::Break
+                } else {
+                    ControlFlow::Continue
+                }
+            },
+        )
+        .unwrap();
+
+        sender.send_unicast_msg("receiver", b"one").unwrap();
+        assert!(iterate_until(|| received.borrow().len() == 1));
+
+        sender.send_unicast_msg("receiver", b"two").unwrap();
+        sender.send_unicast_msg("receiver", b"three").unwrap();
+        assert!(iterate_until(|| !is_attached(&peer)));
+
+        /* The message following the break is left to the peer. */
+        assert!(!iterate_until(|| received.borrow().len() > 2));
+        assert_eq!(*received.borrow(), [b"one".to_vec(), b"two".to_vec()]);
+        let left = peer.ipcon().try_receive_msg().unwrap().unwrap();
+        assert_eq!(payload(left), b"three");
+    }
+
+    #[test]
+    fn shutdown_removes_the_callback() {
+        let _context = CONTEXT.lock().unwrap_or_else(|e| e.into_inner());
+        let bus = LoopbackBus::new();
+        let peer = GlibIpcon::new(
+            bus.peer(Some("receiver"), Some(IPF_DEFAULT)).unwrap(),
+            |_, msg| panic!("Unexpected message {:?}", msg),
+        )
+        .unwrap();
+
+        assert!(!iterate_until(|| !is_attached(&peer)));
+        peer.ipcon().shutdown_handle().shutdown();
+        assert!(iterate_until(|| !is_attached(&peer)));
+    }
+
+    #[test]
+    fn sender_completes_on_the_main_context() {
+        let _context = CONTEXT.lock().unwrap_or_else(|e| e.into_inner());
+        let bus = LoopbackBus::new();
+        let receiver = bus.peer(Some("receiver"), Some(IPF_DEFAULT)).unwrap();
+        let peer = GlibIpcon::new(
+            bus.peer(Some("sender"), Some(IPF_DEFAULT)).unwrap(),
+            |_, msg| panic!("Unexpected message {:?}", msg),
+        )
+        .unwrap();
+
+        let context = MainContext::default();
+        let sender = peer.sender();
+        context
+            .block_on(sender.send_unicast_msg("receiver", b"hello"))
+            .unwrap();
+        assert_eq!(payload(receiver.receive_msg().unwrap()), b"hello");
+
+        let err = context
+            .block_on(sender.send_unicast_msg("nobody", b"hello"))
+            .unwrap_err();
+        assert_eq!(*err.current_context(), IpconError::SystemErrorNotExist);
+    }
+}
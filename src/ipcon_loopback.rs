@@ -0,0 +1,594 @@
+//! # Loopback transport
+//! LoopbackBus is an in-process stand-in for the IPCON kernel module: the Ipcon peers created on
+//! a bus exchange unicast and multicast messages and kernel events without libipcon. It is meant
+//! for tests and benchmarks running on hosts without the kernel module:
+//!
+//! ```ignore
+//! let bus = LoopbackBus::new();
+//! let server = bus.peer(Some("server"), Some(IPF_DEFAULT))?;
+//! let client = bus.peer(Some("client"), Some(IPF_DEFAULT))?;
+//!
+//! server.register_group("news")?;
+//! client.join_group("server", "news")?;
+//! server.send_multicast("news", b"hello", false)?;
+//!
+//! let msg = client.receive_msg()?;
+//! ```
+//!
+//! The peers are Ipcon, only the libipcon calls are replaced by the bus, so that everything built
+//! on Ipcon works as with the kernel module. The messages are carried as LibIpconMsg and decoded
+//! when received, so that the cost of a message on the Rust side is the same as with the kernel.
+//! The read fd of a peer is an eventfd readable while messages are queued, the write and control
+//! fds are always writable.
+//!
+//! Differences with the kernel module:
+//! * The kernel events are delivered to every peer which joined the IPCON_KERNEL_GROUP_NAME
+//!   group of IPCON_KERNEL_NAME, IPF_DISABLE_KEVENT_FILTER has no effect.
+//! * The receive queues are not bounded, sending never blocks and the `sync` flag of
+//!   send_multicast() has no effect.
+//! * The peers are not visible to other processes, nor to other buses.
+
+use crate::ipcon::{
+    Ipcon, IpconFlag, Transport, IPCON_KERNEL_GROUP_NAME, IPCON_KERNEL_NAME, IPF_RCV_IF, IPF_SND_IF,
+};
+use crate::ipcon_error::IpconError;
+use crate::ipcon_msg::{IpconKevent, LibIpconMsg};
+use crate::ipcon_name::PeerName;
+use nix::errno::Errno;
+use nix::sys::eventfd::{eventfd, EfdFlags};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::{Arc, Mutex, MutexGuard};
+#[allow(unused)]
+use {
+    error_stack::{Report, Result, ResultExt},
+    jlogger_tracing::{jdebug, jerror, jinfo, jtrace, jwarn},
+};
+
+fn new_eventfd() -> std::result::Result<OwnedFd, Errno> {
+    let fd = eventfd(0, EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK)?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/* Receive queue of a peer, its eventfd is readable while the queue is not empty. */
+struct Queue {
+    msgs: Mutex<VecDeque<LibIpconMsg>>,
+    fd: OwnedFd,
+}
+
+impl Queue {
+    fn new() -> std::result::Result<Queue, Errno> {
+        Ok(Queue {
+            msgs: Mutex::new(VecDeque::new()),
+            fd: new_eventfd()?,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, VecDeque<LibIpconMsg>> {
+        self.msgs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn push(&self, msg: LibIpconMsg) {
+        let mut msgs = self.lock();
+        msgs.push_back(msg);
+
+        if msgs.len() == 1 {
+            if let Err(e) = nix::unistd::write(self.fd.as_raw_fd(), &1_u64.to_ne_bytes()) {
+                jwarn!("Failed to signal loopback queue: {}", e);
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<LibIpconMsg> {
+        let mut msgs = self.lock();
+        let msg = msgs.pop_front();
+
+        if msg.is_some() && msgs.is_empty() {
+            let mut counter = [0_u8; 8];
+            let _ = nix::unistd::read(self.fd.as_raw_fd(), &mut counter);
+        }
+
+        msg
+    }
+}
+
+struct PeerEntry {
+    flags: IpconFlag,
+    queue: Arc<Queue>,
+    /* Owned groups and their subscribers. */
+    groups: HashMap<String, Vec<(u64, Arc<Queue>)>>,
+}
+
+#[derive(Default)]
+struct BusState {
+    peers: HashMap<String, PeerEntry>,
+    /* Subscribers of the kernel events. */
+    kevent: Vec<(u64, Arc<Queue>)>,
+    next_id: u64,
+}
+
+impl BusState {
+    fn kevent(&self, kevent: Result<IpconKevent, IpconError>) {
+        match kevent {
+            Ok(k) => {
+                let msg = LibIpconMsg::kevent(k);
+                for (_, queue) in &self.kevent {
+                    queue.push(msg);
+                }
+            }
+            Err(e) => jwarn!("Failed to create loopback kevent: {:?}", e),
+        }
+    }
+}
+
+/// In-process IPCON bus.
+#[derive(Clone, Default)]
+pub struct LoopbackBus {
+    state: Arc<Mutex<BusState>>,
+}
+
+impl fmt::Debug for LoopbackBus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LoopbackBus")
+            .field("peers", &self.lock().peers.len())
+            .finish()
+    }
+}
+
+impl LoopbackBus {
+    /// Create an empty bus.
+    pub fn new() -> LoopbackBus {
+        LoopbackBus::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BusState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Create a peer on the bus, see Ipcon::new() for the name and the flags.
+    /// An anonymous peer is named `anon-<n>` in the messages it sends.
+    pub fn peer(
+        &self,
+        peer_name: Option<&str>,
+        flag: Option<IpconFlag>,
+    ) -> Result<Ipcon, IpconError> {
+        Transport::Loopback(self.clone()).peer(peer_name, flag)
+    }
+
+    /* Attach a peer to the bus, None if the name is already used. */
+    pub(crate) fn attach(
+        &self,
+        peer_name: Option<&PeerName>,
+        flag: Option<IpconFlag>,
+    ) -> Result<Option<LoopbackPeer>, IpconError> {
+        let (queue, write, ctrl) = Queue::new()
+            .and_then(|q| Ok((Arc::new(q), new_eventfd()?, new_eventfd()?)))
+            .map_err(|e| Report::new(IpconError::SystemErrorOther).attach_printable(e))
+            .attach_printable("Failed to create loopback eventfd")?;
+
+        let mut state = self.lock();
+        state.next_id += 1;
+        let id = state.next_id;
+
+        let name = match peer_name {
+            Some(n) => n.as_str().to_owned(),
+            None => format!("anon-{}", id),
+        };
+
+        if state.peers.contains_key(&name) {
+            return Ok(None);
+        }
+
+        let flags = flag.unwrap_or(0);
+        state.peers.insert(
+            name.clone(),
+            PeerEntry {
+                flags,
+                queue: queue.clone(),
+                groups: HashMap::new(),
+            },
+        );
+
+        /* The kernel module doesn't report anonymous peers. */
+        let anonymous = peer_name.is_none();
+        if !anonymous {
+            state.kevent(IpconKevent::new_peer_added(&name));
+        }
+
+        Ok(Some(LoopbackPeer {
+            bus: self.clone(),
+            id,
+            name,
+            anonymous,
+            flags,
+            queue,
+            write,
+            ctrl,
+        }))
+    }
+}
+
+/* Backend of an Ipcon attached to a LoopbackBus.
+ * The methods mirror the libipcon functions and return a negative errno on failure. */
+pub(crate) struct LoopbackPeer {
+    bus: LoopbackBus,
+    id: u64,
+    name: String,
+    anonymous: bool,
+    flags: IpconFlag,
+    queue: Arc<Queue>,
+    write: OwnedFd,
+    ctrl: OwnedFd,
+}
+
+fn is_kevent_group(peer: &str, group: &str) -> bool {
+    peer == IPCON_KERNEL_NAME && group == IPCON_KERNEL_GROUP_NAME
+}
+
+impl LoopbackPeer {
+    pub(crate) fn is_peer_present(&self, peer: &str) -> bool {
+        peer == IPCON_KERNEL_NAME || self.bus.lock().peers.contains_key(peer)
+    }
+
+    pub(crate) fn is_group_present(&self, peer: &str, group: &str) -> bool {
+        is_kevent_group(peer, group)
+            || self
+                .bus
+                .lock()
+                .peers
+                .get(peer)
+                .is_some_and(|e| e.groups.contains_key(group))
+    }
+
+    pub(crate) fn send_unicast(&self, peer: &str, buf: &[u8]) -> i32 {
+        if self.flags & IPF_SND_IF == 0 {
+            return -libc::EPERM;
+        }
+
+        let msg = match LibIpconMsg::normal(&self.name, buf) {
+            Ok(m) => m,
+            Err(_) => return -libc::EINVAL,
+        };
+
+        match self.bus.lock().peers.get(peer) {
+            Some(e) if e.flags & IPF_RCV_IF != 0 => {
+                e.queue.push(msg);
+                0
+            }
+            Some(_) => -libc::EPERM,
+            None => -libc::ENOENT,
+        }
+    }
+
+    pub(crate) fn register_group(&self, group: &str) -> i32 {
+        let mut state = self.bus.lock();
+        let groups = match state.peers.get_mut(&self.name) {
+            Some(e) => &mut e.groups,
+            None => return -libc::ENOENT,
+        };
+
+        if groups.contains_key(group) {
+            return -libc::EINVAL;
+        }
+
+        groups.insert(group.to_owned(), Vec::new());
+        state.kevent(IpconKevent::new_group_added(&self.name, group));
+        0
+    }
+
+    pub(crate) fn unregister_group(&self, group: &str) -> i32 {
+        let mut state = self.bus.lock();
+        let removed = state
+            .peers
+            .get_mut(&self.name)
+            .and_then(|e| e.groups.remove(group));
+
+        match removed {
+            Some(_) => {
+                state.kevent(IpconKevent::new_group_removed(&self.name, group));
+                0
+            }
+            None => -libc::ENOENT,
+        }
+    }
+
+    fn subscribers<'a>(
+        state: &'a mut BusState,
+        peer: &str,
+        group: &str,
+    ) -> Option<&'a mut Vec<(u64, Arc<Queue>)>> {
+        if is_kevent_group(peer, group) {
+            Some(&mut state.kevent)
+        } else {
+            state
+                .peers
+                .get_mut(peer)
+                .and_then(|e| e.groups.get_mut(group))
+        }
+    }
+
+    pub(crate) fn join_group(&self, peer: &str, group: &str) -> i32 {
+        if self.flags & IPF_RCV_IF == 0 {
+            return -libc::EPERM;
+        }
+
+        let mut state = self.bus.lock();
+        match LoopbackPeer::subscribers(&mut state, peer, group) {
+            Some(subscribers) => {
+                if !subscribers.iter().any(|(id, _)| *id == self.id) {
+                    subscribers.push((self.id, self.queue.clone()));
+                }
+                0
+            }
+            None => -libc::ENOENT,
+        }
+    }
+
+    pub(crate) fn leave_group(&self, peer: &str, group: &str) -> i32 {
+        let mut state = self.bus.lock();
+        match LoopbackPeer::subscribers(&mut state, peer, group) {
+            Some(subscribers) => {
+                subscribers.retain(|(id, _)| *id != self.id);
+                0
+            }
+            None => -libc::ENOENT,
+        }
+    }
+
+    pub(crate) fn send_multicast(&self, group: &str, buf: &[u8], _sync: bool) -> i32 {
+        if self.flags & IPF_SND_IF == 0 {
+            return -libc::EPERM;
+        }
+
+        let msg = match LibIpconMsg::group(&self.name, group, buf) {
+            Ok(m) => m,
+            Err(_) => return -libc::EINVAL,
+        };
+
+        let state = self.bus.lock();
+        match state
+            .peers
+            .get(&self.name)
+            .and_then(|e| e.groups.get(group))
+        {
+            Some(subscribers) => {
+                for (_, queue) in subscribers {
+                    queue.push(msg);
+                }
+                0
+            }
+            None => -libc::ENOENT,
+        }
+    }
+
+    /* Pop a queued message, -ETIMEDOUT if none is queued as ipcon_rcv_timeout() with a zero
+     * timeout. */
+    pub(crate) fn rcv(&self, lmsg: &mut LibIpconMsg) -> i32 {
+        if self.flags & IPF_RCV_IF == 0 {
+            return -libc::EPERM;
+        }
+
+        match self.queue.pop() {
+            Some(m) => {
+                *lmsg = m;
+                0
+            }
+            None => -libc::ETIMEDOUT,
+        }
+    }
+
+    pub(crate) fn read_fd(&self) -> RawFd {
+        self.queue.fd.as_raw_fd()
+    }
+
+    pub(crate) fn write_fd(&self) -> RawFd {
+        self.write.as_raw_fd()
+    }
+
+    pub(crate) fn ctrl_fd(&self) -> RawFd {
+        self.ctrl.as_raw_fd()
+    }
+}
+
+impl Drop for LoopbackPeer {
+    fn drop(&mut self) {
+        let mut state = self.bus.lock();
+        let entry = state.peers.remove(&self.name);
+
+        state.kevent.retain(|(id, _)| *id != self.id);
+        for e in state.peers.values_mut() {
+            for subscribers in e.groups.values_mut() {
+                subscribers.retain(|(id, _)| *id != self.id);
+            }
+        }
+
+        if let Some(entry) = entry {
+            for group in entry.groups.keys() {
+                state.kevent(IpconKevent::new_group_removed(&self.name, group));
+            }
+        }
+
+        if !self.anonymous {
+            state.kevent(IpconKevent::new_peer_removed(&self.name));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipcon::IPF_DEFAULT;
+    use crate::ipcon_msg::IpconMsg;
+    use nix::poll::{poll, PollFd, PollFlags};
+    use std::time::{Duration, Instant};
+
+    fn user_msg(msg: IpconMsg) -> (String, Option<String>, Vec<u8>) {
+        match msg {
+            IpconMsg::IpconMsgUser(body) => (body.peer, body.group, body.buf),
+            m => panic!("Unexpected message {:?}", m),
+        }
+    }
+
+    fn kevent(msg: IpconMsg) -> IpconKevent {
+        match msg {
+            IpconMsg::IpconMsgKevent(k) => k,
+            m => panic!("Unexpected message {:?}", m),
+        }
+    }
+
+    fn readable(ipcon: &Ipcon) -> bool {
+        let mut fds = [PollFd::new(ipcon.get_read_fd().unwrap(), PollFlags::POLLIN)];
+        poll(&mut fds, 0).unwrap() == 1
+    }
+
+    #[test]
+    fn unicast_and_multicast() {
+        let bus = LoopbackBus::new();
+        let server = bus.peer(Some("server"), Some(IPF_DEFAULT)).unwrap();
+        let client = bus.peer(Some("client"), Some(IPF_DEFAULT)).unwrap();
+
+        assert!(client.is_peer_present("server"));
+        assert!(!client.is_peer_present("nobody"));
+        assert!(bus.peer(Some("server"), Some(IPF_DEFAULT)).is_err());
+
+        assert!(!readable(&server));
+        client.send_unicast_msg("server", b"ping").unwrap();
+        assert!(readable(&server));
+        assert_eq!(
+            user_msg(server.receive_msg().unwrap()),
+            ("client".to_owned(), None, b"ping".to_vec())
+        );
+        assert!(!readable(&server));
+
+        server.register_group("news").unwrap();
+        assert!(server.register_group("news").is_err());
+        assert!(client.is_group_present("server", "news"));
+        client.join_group("server", "news").unwrap();
+        server.send_multicast("news", b"hello", false).unwrap();
+        assert_eq!(
+            user_msg(client.receive_msg_timeout(1, 0).unwrap()),
+            (
+                "server".to_owned(),
+                Some("news".to_owned()),
+                b"hello".to_vec()
+            )
+        );
+
+        client.leave_group("server", "news").unwrap();
+        server.send_multicast("news", b"hello", false).unwrap();
+        assert_eq!(
+            *client.receive_msg_nonblock().unwrap_err().current_context(),
+            IpconError::SysErrorTimeOut
+        );
+
+        assert_eq!(server.stats().total.sent_msgs, 2);
+        assert_eq!(client.stats().total.received_msgs, 1);
+    }
+
+    #[test]
+    fn errors() {
+        let bus = LoopbackBus::new();
+        let sender = bus.peer(Some("sender"), Some(IPF_SND_IF)).unwrap();
+        let receiver = bus.peer(Some("receiver"), Some(IPF_RCV_IF)).unwrap();
+
+        let err = |r: Result<(), IpconError>| *r.unwrap_err().current_context();
+        assert_eq!(
+            err(sender.send_unicast_msg("nobody", b"")),
+            IpconError::SystemErrorNotExist
+        );
+        assert_eq!(
+            err(sender.send_unicast_msg("sender", b"")),
+            IpconError::SysErrorPermission
+        );
+        assert_eq!(
+            err(receiver.send_unicast_msg("receiver", b"")),
+            IpconError::SysErrorPermission
+        );
+        assert_eq!(
+            err(receiver.join_group("sender", "none")),
+            IpconError::SystemErrorNotExist
+        );
+        assert_eq!(
+            err(sender.send_multicast("none", b"", false)),
+            IpconError::SystemErrorNotExist
+        );
+    }
+
+    #[test]
+    fn kernel_events() {
+        let bus = LoopbackBus::new();
+        let watcher = bus.peer(Some("watcher"), Some(IPF_DEFAULT)).unwrap();
+        assert!(watcher.is_group_present(IPCON_KERNEL_NAME, IPCON_KERNEL_GROUP_NAME));
+        watcher
+            .join_group(IPCON_KERNEL_NAME, IPCON_KERNEL_GROUP_NAME)
+            .unwrap();
+
+        let peer = bus.peer(Some("peer"), Some(IPF_DEFAULT)).unwrap();
+        peer.register_group("group").unwrap();
+        let anon = bus.peer(None, Some(IPF_DEFAULT)).unwrap();
+        drop(anon);
+        drop(peer);
+
+        let events: Vec<String> = std::iter::from_fn(|| watcher.receive_msg_nonblock().ok())
+            .map(|m| kevent(m).get_string().unwrap())
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "peer peer added",
+                "group group@peer added",
+                "group group@peer removed",
+                "peer peer removed",
+            ]
+        );
+    }
+
+    #[test]
+    fn blocking_receive() {
+        let bus = LoopbackBus::new();
+        let receiver = bus.peer(Some("receiver"), Some(IPF_DEFAULT)).unwrap();
+        let sender = bus.peer(Some("sender"), Some(IPF_DEFAULT)).unwrap();
+
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            sender.send_unicast_msg("receiver", b"late").unwrap();
+        });
+
+        assert_eq!(
+            user_msg(receiver.receive_msg().unwrap()),
+            ("sender".to_owned(), None, b"late".to_vec())
+        );
+        thread.join().unwrap();
+
+        let start = Instant::now();
+        assert_eq!(
+            *receiver
+                .receive_msg_timeout(0, 50_000)
+                .unwrap_err()
+                .current_context(),
+            IpconError::SysErrorTimeOut
+        );
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn shutdown_wakes_receiver() {
+        let bus = LoopbackBus::new();
+        let peer = Arc::new(bus.peer(Some("peer"), Some(IPF_DEFAULT)).unwrap());
+
+        let receiver = {
+            let peer = peer.clone();
+            std::thread::spawn(move || peer.receive_msg())
+        };
+
+        std::thread::sleep(Duration::from_millis(50));
+        peer.close();
+        let err = receiver.join().unwrap().unwrap_err();
+        assert_eq!(*err.current_context(), IpconError::Cancelled);
+
+        /* The name is released when the peer is dropped. */
+        drop(peer);
+        bus.peer(Some("peer"), Some(IPF_DEFAULT)).unwrap();
+    }
+}
@@ -0,0 +1,209 @@
+//! # mio integration
+//! IpconSource registers the read fd and the shutdown eventfd of a peer to a mio Poll:
+//!
+//! ```ignore
+//! const IPCON: Token = Token(0);
+//!
+//! let mut poll = Poll::new()?;
+//! let mut events = Events::with_capacity(16);
+//! let mut source = IpconSource::new(ipcon)?;
+//! poll.registry().register(&mut source, IPCON, Interest::READABLE)?;
+//!
+//! loop {
+//!     poll.poll(&mut events, None)?;
+//!     for event in events.iter() {
+//!         if event.token() == IPCON {
+//!             for msg in source.messages() {
+//!                 match msg {
+//!                     Ok(msg) => handle_msg(msg),
+//!                     Err(e) => jwarn!("Receive failed: {:?}", e),
+//!                 }
+//!             }
+//!
+//!             if source.is_shutdown() {
+//!                 return Ok(());
+//!             }
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! mio is edge triggered: once the source is reported readable, all the pending messages must be
+//! received, otherwise no new event may come for them. messages() receives them until none is
+//! pending. The shutdown eventfd shares the token of the source, the source is reported readable
+//! once when the peer is shut down (see ipcon_shutdown).
+
+use crate::ipcon::Ipcon;
+use crate::ipcon_error::IpconError;
+use crate::ipcon_msg::IpconMsg;
+use crate::ipcon_shutdown::ShutdownHandle;
+use mio::event::Source;
+use mio::unix::SourceFd;
+use mio::{Interest, Registry, Token};
+use std::io;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+#[allow(unused)]
+use {
+    error_stack::{Report, Result, ResultExt},
+    jlogger_tracing::{jdebug, jerror, jinfo, jtrace, jwarn},
+};
+
+/// IPCON peer registrable to a mio Poll.
+pub struct IpconSource {
+    ipcon: Ipcon,
+    fd: RawFd,
+    shutdown: ShutdownHandle,
+}
+
+impl IpconSource {
+    /// Create a source from a peer.
+    /// It fails if the peer doesn't enable IPF_RCV_IF.
+    pub fn new(ipcon: Ipcon) -> Result<IpconSource, IpconError> {
+        let fd = ipcon
+            .get_read_fd()
+            .attach_printable("mio source needs the message receiving interface")?;
+
+        let shutdown = ipcon.shutdown_handle();
+
+        Ok(IpconSource {
+            ipcon,
+            fd,
+            shutdown,
+        })
+    }
+
+    /// Get the peer of the source.
+    pub fn ipcon(&self) -> &Ipcon {
+        &self.ipcon
+    }
+
+    /// Get the peer back.
+    pub fn into_inner(self) -> Ipcon {
+        self.ipcon
+    }
+
+    /// Whether the peer is shut down, the source then yields no more message.
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.is_shutdown()
+    }
+
+    /// Iterate over the pending messages.
+    /// The iteration ends when no message is pending or the peer is shut down. A failed receive
+    /// is yielded and the iteration goes on, so that one invalid message doesn't hold back the
+    /// next ones.
+    pub fn messages(&self) -> Messages<'_> {
+        Messages {
+            ipcon: &self.ipcon,
+            done: false,
+        }
+    }
+}
+
+impl Source for IpconSource {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.fd).register(registry, token, interests)?;
+        SourceFd(&self.shutdown.as_raw_fd()).register(registry, token, Interest::READABLE)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.fd).reregister(registry, token, interests)?;
+        SourceFd(&self.shutdown.as_raw_fd()).reregister(registry, token, Interest::READABLE)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        SourceFd(&self.fd).deregister(registry)?;
+        SourceFd(&self.shutdown.as_raw_fd()).deregister(registry)
+    }
+}
+
+impl AsFd for IpconSource {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        /* The fd is owned by the peer, which is owned by the source. */
+        unsafe { BorrowedFd::borrow_raw(self.fd) }
+    }
+}
+
+impl AsRawFd for IpconSource {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+/// Iterator over the pending messages of an IpconSource.
+pub struct Messages<'a> {
+    ipcon: &'a Ipcon,
+    done: bool,
+}
+
+impl Iterator for Messages<'_> {
+    type Item = Result<IpconMsg, IpconError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.ipcon.try_receive_msg() {
+            Ok(Some(msg)) => Some(Ok(msg)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) if *e.current_context() == IpconError::Cancelled => {
+                self.done = true;
+                None
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipcon::IPF_DEFAULT;
+    use crate::ipcon_loopback::LoopbackBus;
+    use mio::{Events, Poll};
+    use std::time::Duration;
+
+    const IPCON: Token = Token(0);
+
+    #[test]
+    fn shutdown_wakes_the_poll() {
+        let bus = LoopbackBus::new();
+        let a = bus.peer(Some("a"), Some(IPF_DEFAULT)).unwrap();
+        let b = bus.peer(Some("b"), Some(IPF_DEFAULT)).unwrap();
+        let shutdown = a.shutdown_handle();
+
+        let mut poll = Poll::new().unwrap();
+        let mut events = Events::with_capacity(16);
+        let mut source = IpconSource::new(a).unwrap();
+        poll.registry()
+            .register(&mut source, IPCON, Interest::READABLE)
+            .unwrap();
+
+        b.send_unicast_msg("a", b"hello").unwrap();
+        poll.poll(&mut events, Some(Duration::from_secs(1)))
+            .unwrap();
+        assert!(events.iter().any(|e| e.token() == IPCON));
+        assert_eq!(source.messages().filter(|m| m.is_ok()).count(), 1);
+        assert!(!source.is_shutdown());
+
+        shutdown.shutdown();
+        poll.poll(&mut events, Some(Duration::from_secs(5)))
+            .unwrap();
+        assert!(events.iter().any(|e| e.token() == IPCON));
+        assert_eq!(source.messages().count(), 0);
+        assert!(source.is_shutdown());
+    }
+}
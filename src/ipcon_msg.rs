@@ -1,5 +1,6 @@
+use crate::ipcon::{valid_name, IPCON_KERNEL_GROUP_NAME, IPCON_KERNEL_NAME};
 use crate::ipcon_error::IpconError;
-use std::ffi::CStr;
+use crate::ipcon_trace::TraceContext;
 use std::fmt;
 use std::os::raw::c_char;
 #[allow(unused)]
@@ -21,15 +22,15 @@ pub const IPCON_KEVENT_TYPE_GROUP_REMOVE: IpconKeventType = 3;
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct IpconKeventGroup {
-    pub group_name: [std::os::raw::c_char; IPCON_MAX_NAME_LEN],
-    pub peer_name: [std::os::raw::c_char; IPCON_MAX_NAME_LEN],
+    pub group_name: [c_char; IPCON_MAX_NAME_LEN],
+    pub peer_name: [c_char; IPCON_MAX_NAME_LEN],
 }
 
 /// Peer information of IpconKevent.
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct IpconKeventPeer {
-    pub peer_name: [std::os::raw::c_char; IPCON_MAX_NAME_LEN],
+    pub peer_name: [c_char; IPCON_MAX_NAME_LEN],
 }
 
 #[repr(C)]
@@ -39,32 +40,53 @@ pub union IpconKeventUnion {
     pub group: IpconKeventGroup,
 }
 
-fn c_str_name(name: &[std::os::raw::c_char; IPCON_MAX_NAME_LEN]) -> Result<&str, IpconError> {
-    #[cfg(target_arch = "x86_64")]
-    let name: &[u8; IPCON_MAX_NAME_LEN] = unsafe { std::mem::transmute(name) };
+/// Decode a NUL terminated name stored in a C char array.
+/// The conversion doesn't depend on the signedness of c_char of the target.
+pub fn decode_c_name(name: &[c_char; IPCON_MAX_NAME_LEN]) -> Result<String, IpconError> {
+    let bytes: Vec<u8> = name
+        .iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| u8::from_ne_bytes(c.to_ne_bytes()))
+        .collect();
+
+    if bytes.len() == IPCON_MAX_NAME_LEN {
+        return Err(Report::new(IpconError::InvalidName))
+            .attach_printable(format!("Name is not NUL terminated: {:?}", bytes));
+    }
 
-    let mut end = 0;
-    loop {
-        if name[end] == b'\0' {
-            break;
-        }
+    String::from_utf8(bytes)
+        .map_err(|e| Report::new(IpconError::InvalidName).attach_printable(e.to_string()))
+}
 
-        if end == IPCON_MAX_NAME_LEN - 1 {
-            break;
-        }
+/// Encode a name to a NUL terminated C char array.
+/// The name must be shorter than IPCON_MAX_NAME_LEN and must not contain NUL.
+pub fn encode_c_name(name: &str) -> Result<[c_char; IPCON_MAX_NAME_LEN], IpconError> {
+    if name.len() >= IPCON_MAX_NAME_LEN || name.contains('\0') {
+        return Err(Report::new(IpconError::InvalidName)).attach_printable(format!(
+            "Name can not be stored in a C char array: {:?}",
+            name
+        ));
+    }
 
-        end += 1;
+    let mut v = [0 as c_char; IPCON_MAX_NAME_LEN];
+    for (c, b) in v.iter_mut().zip(name.bytes()) {
+        *c = c_char::from_ne_bytes([b]);
     }
 
-    CStr::from_bytes_with_nul(&name[0..=end])
-        .map_err(|_| Report::new(IpconError::InvalidName))
-        .attach_printable(format!(
-            "Name: {:?}\n End: {}",
-            name.map(|c| c as char),
-            end
-        ))?
-        .to_str()
-        .map_err(|_| Report::new(IpconError::InvalidName))
+    Ok(v)
+}
+
+/* Encode a name after checking it is a valid IPCON name. */
+fn valid_c_name(name: &str) -> Result<[c_char; IPCON_MAX_NAME_LEN], IpconError> {
+    valid_name(name)?;
+    encode_c_name(name)
+}
+
+/* Compare two NUL terminated names. */
+fn c_name_eq(a: &[c_char; IPCON_MAX_NAME_LEN], b: &[c_char; IPCON_MAX_NAME_LEN]) -> bool {
+    a.iter()
+        .take_while(|&&c| c != 0)
+        .eq(b.iter().take_while(|&&c| c != 0))
 }
 
 /// IpconKevent is a group message delivered from the IPCON_KERNEL_GROUP_NAME group of IPCON
@@ -83,7 +105,7 @@ pub struct IpconKevent {
 
 impl IpconKevent {
     /// Get a string of the events like following:
-    /// ```
+    /// ```text
     /// "peer <peer name> added"
     /// "peer <peer name> removed"
     /// "group <group name>@<peer name> added"
@@ -93,23 +115,23 @@ impl IpconKevent {
         let result = match self.ke_type {
             IPCON_KEVENT_TYPE_PEER_ADD => format!(
                 "peer {} added",
-                c_str_name(unsafe { &self.u.peer.peer_name })?
+                decode_c_name(unsafe { &self.u.peer.peer_name })?
             ),
 
             IPCON_KEVENT_TYPE_PEER_REMOVE => format!(
                 "peer {} removed",
-                c_str_name(unsafe { &self.u.peer.peer_name })?
+                decode_c_name(unsafe { &self.u.peer.peer_name })?
             ),
             IPCON_KEVENT_TYPE_GROUP_ADD => format!(
                 "group {}@{} added",
-                c_str_name(unsafe { &self.u.group.group_name })?,
-                c_str_name(unsafe { &self.u.group.peer_name })?
+                decode_c_name(unsafe { &self.u.group.group_name })?,
+                decode_c_name(unsafe { &self.u.group.peer_name })?
             ),
 
             IPCON_KEVENT_TYPE_GROUP_REMOVE => format!(
                 "group {}@{} removed",
-                c_str_name(unsafe { &self.u.group.group_name })?,
-                c_str_name(unsafe { &self.u.group.peer_name })?
+                decode_c_name(unsafe { &self.u.group.group_name })?,
+                decode_c_name(unsafe { &self.u.group.peer_name })?
             ),
             _ => {
                 return Err(Report::new(IpconError::InvalidKevent))
@@ -124,8 +146,7 @@ impl IpconKevent {
     /// IPCON kernel module will not delivery this event of an anonymous peer.
     pub fn peer_added(&self) -> Option<String> {
         match self.ke_type {
-            IPCON_KEVENT_TYPE_PEER_ADD => c_str_name(unsafe { &self.u.peer.peer_name })
-                .map_or(None, |name| Some(name.to_owned())),
+            IPCON_KEVENT_TYPE_PEER_ADD => decode_c_name(unsafe { &self.u.peer.peer_name }).ok(),
             _ => None,
         }
     }
@@ -134,8 +155,7 @@ impl IpconKevent {
     /// IPCON kernel module will not delivery this event of an anonymous peer.
     pub fn peer_removed(&self) -> Option<String> {
         match self.ke_type {
-            IPCON_KEVENT_TYPE_PEER_REMOVE => c_str_name(unsafe { &self.u.peer.peer_name })
-                .map_or(None, |name| Some(name.to_owned())),
+            IPCON_KEVENT_TYPE_PEER_REMOVE => decode_c_name(unsafe { &self.u.peer.peer_name }).ok(),
             _ => None,
         }
     }
@@ -147,10 +167,10 @@ impl IpconKevent {
         match self.ke_type {
             IPCON_KEVENT_TYPE_GROUP_ADD => {
                 if let (Ok(peer_name), Ok(group_name)) = (
-                    c_str_name(unsafe { &self.u.group.peer_name }),
-                    c_str_name(unsafe { &self.u.group.group_name }),
+                    decode_c_name(unsafe { &self.u.group.peer_name }),
+                    decode_c_name(unsafe { &self.u.group.group_name }),
                 ) {
-                    Some((peer_name.to_owned(), group_name.to_owned()))
+                    Some((peer_name, group_name))
                 } else {
                     None
                 }
@@ -166,10 +186,10 @@ impl IpconKevent {
         match self.ke_type {
             IPCON_KEVENT_TYPE_GROUP_REMOVE => {
                 if let (Ok(peer_name), Ok(group_name)) = (
-                    c_str_name(unsafe { &self.u.group.peer_name }),
-                    c_str_name(unsafe { &self.u.group.group_name }),
+                    decode_c_name(unsafe { &self.u.group.peer_name }),
+                    decode_c_name(unsafe { &self.u.group.group_name }),
                 ) {
-                    Some((peer_name.to_owned(), group_name.to_owned()))
+                    Some((peer_name, group_name))
                 } else {
                     None
                 }
@@ -179,6 +199,133 @@ impl IpconKevent {
     }
 }
 
+impl IpconKevent {
+    /// Get the raw bytes of the event, in the layout of `struct ipcon_kevent` of libipcon.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let p = self as *const IpconKevent as *const u8;
+        unsafe { std::slice::from_raw_parts(p, std::mem::size_of::<IpconKevent>()) }.to_vec()
+    }
+
+    /// Create an event from its raw bytes, see to_bytes().
+    pub fn from_bytes(buf: &[u8]) -> Result<IpconKevent, IpconError> {
+        if buf.len() != std::mem::size_of::<IpconKevent>() {
+            return Err(Report::new(IpconError::InvalidKevent)).attach_printable(format!(
+                "Invalid kevent length {} != {}",
+                buf.len(),
+                std::mem::size_of::<IpconKevent>()
+            ));
+        }
+
+        /* Any bit pattern is a valid IpconKevent, the names are checked when they are read. */
+        Ok(unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const IpconKevent) })
+    }
+}
+
+impl IpconKevent {
+    fn new_peer_event(ke_type: IpconKeventType, peer: &str) -> Result<IpconKevent, IpconError> {
+        /* Initialize the whole union, the bytes after the peer name are read by to_bytes(). */
+        let mut u = IpconKeventUnion {
+            group: IpconKeventGroup {
+                group_name: [0; IPCON_MAX_NAME_LEN],
+                peer_name: [0; IPCON_MAX_NAME_LEN],
+            },
+        };
+        u.peer = IpconKeventPeer {
+            peer_name: valid_c_name(peer)?,
+        };
+
+        Ok(IpconKevent { ke_type, u })
+    }
+
+    fn new_group_event(
+        ke_type: IpconKeventType,
+        peer: &str,
+        group: &str,
+    ) -> Result<IpconKevent, IpconError> {
+        Ok(IpconKevent {
+            ke_type,
+            u: IpconKeventUnion {
+                group: IpconKeventGroup {
+                    group_name: valid_c_name(group)?,
+                    peer_name: valid_c_name(peer)?,
+                },
+            },
+        })
+    }
+
+    /// Create a peer added event.
+    pub fn new_peer_added(peer: &str) -> Result<IpconKevent, IpconError> {
+        IpconKevent::new_peer_event(IPCON_KEVENT_TYPE_PEER_ADD, peer)
+    }
+
+    /// Create a peer removed event.
+    pub fn new_peer_removed(peer: &str) -> Result<IpconKevent, IpconError> {
+        IpconKevent::new_peer_event(IPCON_KEVENT_TYPE_PEER_REMOVE, peer)
+    }
+
+    /// Create a group added event of the group owned by peer.
+    pub fn new_group_added(peer: &str, group: &str) -> Result<IpconKevent, IpconError> {
+        IpconKevent::new_group_event(IPCON_KEVENT_TYPE_GROUP_ADD, peer, group)
+    }
+
+    /// Create a group removed event of the group owned by peer.
+    pub fn new_group_removed(peer: &str, group: &str) -> Result<IpconKevent, IpconError> {
+        IpconKevent::new_group_event(IPCON_KEVENT_TYPE_GROUP_REMOVE, peer, group)
+    }
+}
+
+impl fmt::Debug for IpconKevent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut d = f.debug_struct("IpconKevent");
+        d.field("ke_type", &self.ke_type);
+
+        match self.ke_type {
+            IPCON_KEVENT_TYPE_PEER_ADD | IPCON_KEVENT_TYPE_PEER_REMOVE => {
+                d.field(
+                    "peer",
+                    &decode_c_name(unsafe { &self.u.peer.peer_name }).ok(),
+                );
+            }
+            IPCON_KEVENT_TYPE_GROUP_ADD | IPCON_KEVENT_TYPE_GROUP_REMOVE => {
+                d.field(
+                    "peer",
+                    &decode_c_name(unsafe { &self.u.group.peer_name }).ok(),
+                );
+                d.field(
+                    "group",
+                    &decode_c_name(unsafe { &self.u.group.group_name }).ok(),
+                );
+            }
+            _ => {}
+        }
+
+        d.finish()
+    }
+}
+
+impl PartialEq for IpconKevent {
+    /// Events are equal if they have the same type and names, the bytes following the NUL of the
+    /// names are ignored.
+    fn eq(&self, other: &Self) -> bool {
+        if self.ke_type != other.ke_type {
+            return false;
+        }
+
+        match self.ke_type {
+            IPCON_KEVENT_TYPE_PEER_ADD | IPCON_KEVENT_TYPE_PEER_REMOVE => unsafe {
+                c_name_eq(&self.u.peer.peer_name, &other.u.peer.peer_name)
+            },
+            IPCON_KEVENT_TYPE_GROUP_ADD | IPCON_KEVENT_TYPE_GROUP_REMOVE => unsafe {
+                c_name_eq(&self.u.group.peer_name, &other.u.group.peer_name)
+                    && c_name_eq(&self.u.group.group_name, &other.u.group.group_name)
+            },
+            _ => self.to_bytes() == other.to_bytes(),
+        }
+    }
+}
+
+impl Eq for IpconKevent {}
+
 impl fmt::Display for IpconKevent {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.get_string().unwrap_or_else(|e| e.to_string()))
@@ -221,6 +368,75 @@ impl LibIpconMsg {
             },
         }
     }
+
+    fn new_user_msg(
+        msg_type: LibIpconMsgType,
+        peer: &str,
+        group: Option<&str>,
+        buf: &[u8],
+    ) -> Result<LibIpconMsg, IpconError> {
+        if buf.len() > IPCON_MAX_PAYLOAD_LEN {
+            return Err(Report::new(IpconError::InvalidData)).attach_printable(format!(
+                "Buffer length is too large {} > {}",
+                buf.len(),
+                IPCON_MAX_PAYLOAD_LEN
+            ));
+        }
+
+        let mut msg = LibIpconMsg::new();
+        msg.msg_type = msg_type;
+        msg.peer = valid_c_name(peer)?;
+        if let Some(group) = group {
+            msg.group = valid_c_name(group)?;
+        }
+        msg.len = buf.len() as u32;
+        unsafe { msg.u.buf[..buf.len()].copy_from_slice(buf) };
+
+        Ok(msg)
+    }
+
+    /// Create a unicast message received from peer.
+    pub fn normal(peer: &str, buf: &[u8]) -> Result<LibIpconMsg, IpconError> {
+        LibIpconMsg::new_user_msg(LIBIPCON_MSG_TYPE_NORMAL, peer, None, buf)
+    }
+
+    /// Create a multicast message received from the group owned by peer.
+    pub fn group(peer: &str, group: &str, buf: &[u8]) -> Result<LibIpconMsg, IpconError> {
+        LibIpconMsg::new_user_msg(LIBIPCON_MSG_TYPE_GROUP, peer, Some(group), buf)
+    }
+
+    /// Create a kernel event message.
+    pub fn kevent(kevent: IpconKevent) -> LibIpconMsg {
+        let mut msg = LibIpconMsg::new();
+        msg.msg_type = LIBIPCON_MSG_TYPE_KEVENT;
+        msg.peer = encode_c_name(IPCON_KERNEL_NAME).unwrap_or_default();
+        msg.group = encode_c_name(IPCON_KERNEL_GROUP_NAME).unwrap_or_default();
+        msg.len = std::mem::size_of::<IpconKevent>() as u32;
+        msg.u.kevent = kevent;
+
+        msg
+    }
+
+    /* Payload of a normal or group message, truncated to the buffer size. */
+    fn payload(&self) -> &[u8] {
+        let len = (self.len as usize).min(IPCON_MAX_PAYLOAD_LEN);
+        unsafe { &self.u.buf[..len] }
+    }
+
+    /// Create a message from its raw bytes, in the layout of `struct ipcon_msg` of libipcon.
+    /// The content is not checked until the message is converted to an IpconMsg.
+    pub fn from_bytes(buf: &[u8]) -> Result<LibIpconMsg, IpconError> {
+        if buf.len() != std::mem::size_of::<LibIpconMsg>() {
+            return Err(Report::new(IpconError::InvalidLibIpconMsg)).attach_printable(format!(
+                "Invalid message length {} != {}",
+                buf.len(),
+                std::mem::size_of::<LibIpconMsg>()
+            ));
+        }
+
+        /* LibIpconMsg has no padding and any bit pattern of its fields is valid. */
+        Ok(unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const LibIpconMsg) })
+    }
 }
 
 impl Default for LibIpconMsg {
@@ -229,11 +445,61 @@ impl Default for LibIpconMsg {
     }
 }
 
+impl fmt::Debug for LibIpconMsg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut d = f.debug_struct("LibIpconMsg");
+        d.field("msg_type", &self.msg_type)
+            .field("peer", &decode_c_name(&self.peer).ok())
+            .field("group", &decode_c_name(&self.group).ok())
+            .field("len", &self.len);
+
+        match self.msg_type {
+            LIBIPCON_MSG_TYPE_NORMAL | LIBIPCON_MSG_TYPE_GROUP => {
+                d.field("buf", &self.payload());
+            }
+            LIBIPCON_MSG_TYPE_KEVENT => {
+                d.field("kevent", unsafe { &self.u.kevent });
+            }
+            _ => {}
+        }
+
+        d.finish()
+    }
+}
+
+impl PartialEq for LibIpconMsg {
+    /// Messages are equal if they have the same type, names and content. The bytes following the
+    /// NUL of the names and the payload are ignored.
+    fn eq(&self, other: &Self) -> bool {
+        if self.msg_type != other.msg_type
+            || !c_name_eq(&self.peer, &other.peer)
+            || !c_name_eq(&self.group, &other.group)
+        {
+            return false;
+        }
+
+        match self.msg_type {
+            LIBIPCON_MSG_TYPE_NORMAL | LIBIPCON_MSG_TYPE_GROUP => {
+                self.len == other.len && self.payload() == other.payload()
+            }
+            LIBIPCON_MSG_TYPE_KEVENT => unsafe { self.u.kevent == other.u.kevent },
+            _ => true,
+        }
+    }
+}
+
+impl Eq for LibIpconMsg {}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum IpconMsgType {
+    #[cfg_attr(feature = "serde", serde(rename = "normal"))]
     IpconMsgTypeNormal,
+    #[cfg_attr(feature = "serde", serde(rename = "group"))]
     IpconMsgTypeGroup,
+    #[cfg_attr(feature = "serde", serde(rename = "kevent"))]
     IpconMsgTypeKevent,
+    #[cfg_attr(feature = "serde", serde(rename = "invalid"))]
     IpconMsgTypeInvalid,
 }
 
@@ -251,47 +517,210 @@ pub enum IpconMsgType {
 /// * buf  
 ///   Message content.
 ///
+/// The trace context of the message is returned by trace(), see ipcon_trace.
+///
+/// Fields may be added in minor releases, a body is built with new().
+///
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub struct IpconMsgBody {
     pub msg_type: IpconMsgType,
     pub peer: String,
     pub group: Option<String>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::ipcon_serde::payload"))]
     pub buf: Vec<u8>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) trace: Option<TraceContext>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) span: Option<tracing::Span>,
 }
 
+impl IpconMsgBody {
+    /// Create a message body without trace context.
+    pub fn new(
+        msg_type: IpconMsgType,
+        peer: String,
+        group: Option<String>,
+        buf: Vec<u8>,
+    ) -> IpconMsgBody {
+        IpconMsgBody {
+            msg_type,
+            peer,
+            group,
+            buf,
+            trace: None,
+            span: None,
+        }
+    }
+
+    /// Get the trace context of the message.
+    /// It is None if the message was sent without trace propagation or the receiving peer
+    /// doesn't enable it.
+    pub fn trace(&self) -> Option<TraceContext> {
+        self.trace
+    }
+
+    /// Get the span opened for the message when it was received with a trace context.
+    pub fn span(&self) -> Option<&tracing::Span> {
+        self.span.as_ref()
+    }
+}
+
+/* The span is a local handle of the trace context, it is not compared. */
+impl PartialEq for IpconMsgBody {
+    fn eq(&self, other: &Self) -> bool {
+        self.msg_type == other.msg_type
+            && self.peer == other.peer
+            && self.group == other.group
+            && self.buf == other.buf
+            && self.trace == other.trace
+    }
+}
+
+impl Eq for IpconMsgBody {}
+
 /// IPCON message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum IpconMsg {
+    #[cfg_attr(feature = "serde", serde(rename = "user"))]
     IpconMsgUser(IpconMsgBody),
+    #[cfg_attr(feature = "serde", serde(rename = "kevent"))]
     IpconMsgKevent(IpconKevent),
+    #[cfg_attr(feature = "serde", serde(rename = "invalid"))]
     IpconMsgInvalid,
 }
 
 impl From<LibIpconMsg> for Result<IpconMsg, IpconError> {
     fn from(msg: LibIpconMsg) -> Self {
+        let payload = |msg: &LibIpconMsg| -> Result<Vec<u8>, IpconError> {
+            let len = msg.len as usize;
+            if len > IPCON_MAX_PAYLOAD_LEN {
+                return Err(Report::new(IpconError::InvalidLibIpconMsg)).attach_printable(format!(
+                    "Invalid payload length {} > {}",
+                    len, IPCON_MAX_PAYLOAD_LEN
+                ));
+            }
+
+            Ok(unsafe { msg.u.buf[..len].to_vec() })
+        };
+
+        let name = |name: &[c_char; IPCON_MAX_NAME_LEN]| -> Result<String, IpconError> {
+            decode_c_name(name).change_context(IpconError::InvalidLibIpconMsg)
+        };
+
         match msg.msg_type {
             LIBIPCON_MSG_TYPE_NORMAL => {
-                let m = IpconMsgBody {
-                    msg_type: IpconMsgType::IpconMsgTypeNormal,
-                    peer: c_str_name(&msg.peer)?.to_owned(),
-                    group: None,
-                    buf: unsafe { msg.u.buf[..msg.len as usize].to_vec() },
-                };
+                let m = IpconMsgBody::new(
+                    IpconMsgType::IpconMsgTypeNormal,
+                    name(&msg.peer)?,
+                    None,
+                    payload(&msg)?,
+                );
 
                 Ok(IpconMsg::IpconMsgUser(m))
             }
 
             LIBIPCON_MSG_TYPE_GROUP => {
-                let m = IpconMsgBody {
-                    msg_type: IpconMsgType::IpconMsgTypeGroup,
-                    peer: c_str_name(&msg.peer)?.to_owned(),
-                    group: Some(c_str_name(&msg.group)?.to_owned()),
-                    buf: unsafe { msg.u.buf[..msg.len as usize].to_vec() },
-                };
+                let m = IpconMsgBody::new(
+                    IpconMsgType::IpconMsgTypeGroup,
+                    name(&msg.peer)?,
+                    Some(name(&msg.group)?),
+                    payload(&msg)?,
+                );
                 Ok(IpconMsg::IpconMsgUser(m))
             }
 
-            LIBIPCON_MSG_TYPE_KEVENT => Ok(IpconMsg::IpconMsgKevent(unsafe { msg.u.kevent })),
+            LIBIPCON_MSG_TYPE_KEVENT => {
+                let kevent = unsafe { msg.u.kevent };
+
+                /* Check the event type and the names before handing it out. */
+                kevent
+                    .get_string()
+                    .change_context(IpconError::InvalidLibIpconMsg)?;
+
+                Ok(IpconMsg::IpconMsgKevent(kevent))
+            }
             LIBIPCON_MSG_TYPE_INVALID => Ok(IpconMsg::IpconMsgInvalid),
-            _ => Ok(IpconMsg::IpconMsgInvalid),
+            t => Err(Report::new(IpconError::InvalidLibIpconMsg))
+                .attach_printable(format!("Invalid message type {}", t)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kevent_bytes_round_trip() {
+        let kevents = [
+            IpconKevent::new_peer_added("peer").unwrap(),
+            IpconKevent::new_peer_removed("peer").unwrap(),
+            IpconKevent::new_group_added("peer", "group").unwrap(),
+            IpconKevent::new_group_removed("peer", "group").unwrap(),
+        ];
+
+        for kevent in kevents {
+            let bytes = kevent.to_bytes();
+            assert_eq!(bytes.len(), std::mem::size_of::<IpconKevent>());
+
+            let decoded = IpconKevent::from_bytes(&bytes).unwrap();
+            assert_eq!(decoded, kevent);
+            assert_eq!(decoded.to_bytes(), bytes);
+        }
+
+        /* The bytes after the name of a peer event are zeroed. */
+        let peer = IpconKevent::new_peer_added("peer").unwrap().to_bytes();
+        let offset = std::mem::size_of::<IpconKeventType>() + IPCON_MAX_NAME_LEN;
+        assert!(peer[offset..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn kevent_accessors() {
+        let added = IpconKevent::new_peer_added("peer").unwrap();
+        assert_eq!(added.peer_added().as_deref(), Some("peer"));
+        assert_eq!(added.peer_removed(), None);
+        assert_eq!(added.get_string().unwrap(), "peer peer added");
+
+        let removed = IpconKevent::new_group_removed("peer", "group").unwrap();
+        assert_eq!(
+            removed.group_removed(),
+            Some(("peer".to_owned(), "group".to_owned()))
+        );
+        assert_eq!(removed.get_string().unwrap(), "group group@peer removed");
+
+        let mut bytes = added.to_bytes();
+        bytes[..std::mem::size_of::<IpconKeventType>()]
+            .copy_from_slice(&(42 as IpconKeventType).to_ne_bytes());
+        let invalid = IpconKevent::from_bytes(&bytes).unwrap();
+        assert!(invalid.get_string().is_err());
+
+        assert!(IpconKevent::from_bytes(&bytes[1..]).is_err());
+    }
+
+    #[test]
+    fn lib_msg_round_trip() {
+        let kevent = IpconKevent::new_peer_removed("peer").unwrap();
+        let lmsg = LibIpconMsg::kevent(kevent);
+
+        match Result::<IpconMsg, IpconError>::from(lmsg).unwrap() {
+            IpconMsg::IpconMsgKevent(k) => assert_eq!(k, kevent),
+            m => panic!("Unexpected message {:?}", m),
         }
+
+        let group = LibIpconMsg::group("peer", "group", b"hello").unwrap();
+        match Result::<IpconMsg, IpconError>::from(group).unwrap() {
+            IpconMsg::IpconMsgUser(body) => {
+                assert_eq!(body.msg_type, IpconMsgType::IpconMsgTypeGroup);
+                assert_eq!(body.peer, "peer");
+                assert_eq!(body.group.as_deref(), Some("group"));
+                assert_eq!(body.buf, b"hello");
+            }
+            m => panic!("Unexpected message {:?}", m),
+        }
+
+        assert!(LibIpconMsg::normal("peer", &[0; IPCON_MAX_PAYLOAD_LEN + 1]).is_err());
     }
 }
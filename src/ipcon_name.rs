@@ -0,0 +1,428 @@
+//! # Peer and group names
+//! Names are checked against the NamePolicy of the process. The default policy accepts any name
+//! libipcon accepts: shorter than IPCON_MAX_NAME_LEN bytes, which include the NUL terminator,
+//! without NUL nor leading or trailing blank.
+//! Stricter rules are opt-in, NamePolicy::strict() only accepts printable ASCII without blank,
+//! which every C peer handles, and reserves the `ipcon` prefix of the kernel module:
+//!
+//! ```ignore
+//! NamePolicy::strict()
+//!     .reserve("sys.")
+//!     .namespace("media.")
+//!     .install();
+//!
+//! let ipcon = Ipcon::new(Some("player"), None)?;       // peer "media.player"
+//! let mixer = PeerName::local("mixer")?;                  // peer "media.mixer"
+//! let tick = GroupName::new("tick")?;
+//!
+//! ipcon.join_group(&mixer, &tick)?;
+//! ```
+//!
+//! PeerName and GroupName are validated once at construction and keep the C string passed to
+//! libipcon, so that sending to the same peer or group again neither validates nor allocates
+//! the name. Every Ipcon method taking a name accepts them as well as `&str` and `String`,
+//! which are validated at every call.
+//!
+//! The namespace and the reserved prefixes only apply to the name of the peers created by the
+//! process: Ipcon::new() and PeerName::local() prepend the namespace and refuse reserved
+//! prefixes, while PeerName::new() names any peer, including the kernel module.
+
+use crate::ipcon_error::IpconError;
+use crate::ipcon_msg::IPCON_MAX_NAME_LEN;
+use std::borrow::{Borrow, Cow};
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, RwLock};
+#[allow(unused)]
+use {
+    error_stack::{Report, Result, ResultExt},
+    jlogger_tracing::{jdebug, jerror, jinfo, jtrace, jwarn},
+};
+
+/// Characters allowed in a name.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NameCharset {
+    /// Printable ASCII characters except blank.
+    Printable,
+    /// ASCII letters, digits, `_`, `-` and `.`.
+    Portable,
+    /// Any character but NUL, without leading or trailing blank.
+    #[default]
+    Any,
+}
+
+impl NameCharset {
+    fn allows(&self, c: char) -> bool {
+        match self {
+            NameCharset::Printable => c.is_ascii_graphic(),
+            NameCharset::Portable => c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'),
+            NameCharset::Any => c != '\0',
+        }
+    }
+}
+
+/// Rules applied to the peer and group names of the process.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NamePolicy {
+    charset: NameCharset,
+    reserved: Vec<String>,
+    namespace: Option<String>,
+}
+
+static POLICY: RwLock<Option<Arc<NamePolicy>>> = RwLock::new(None);
+
+impl NamePolicy {
+    /// Create the default policy, any character and no reserved prefix.
+    pub fn new() -> NamePolicy {
+        NamePolicy::default()
+    }
+
+    /// Create a strict policy, printable ASCII only and the `ipcon` prefix reserved.
+    pub fn strict() -> NamePolicy {
+        NamePolicy::new()
+            .charset(NameCharset::Printable)
+            .reserve(crate::ipcon::IPCON_KERNEL_NAME)
+    }
+
+    /// Set the allowed characters.
+    pub fn charset(mut self, charset: NameCharset) -> NamePolicy {
+        self.charset = charset;
+        self
+    }
+
+    /// Reserve a prefix, the peers of the process can't use it.
+    pub fn reserve(mut self, prefix: &str) -> NamePolicy {
+        self.reserved.push(prefix.to_owned());
+        self
+    }
+
+    /// Remove all the reserved prefixes.
+    pub fn clear_reserved(mut self) -> NamePolicy {
+        self.reserved.clear();
+        self
+    }
+
+    /// Set the namespace prepended to the name of the peers of the process.
+    pub fn namespace(mut self, namespace: &str) -> NamePolicy {
+        self.namespace = Some(namespace.to_owned());
+        self
+    }
+
+    /// Make this policy the policy of the process.
+    /// The names already validated are not checked again.
+    pub fn install(self) {
+        *POLICY.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(self));
+    }
+
+    /// Get the policy of the process.
+    pub fn current() -> Arc<NamePolicy> {
+        if let Some(policy) = &*POLICY.read().unwrap_or_else(|e| e.into_inner()) {
+            return policy.clone();
+        }
+
+        /* Keep the default policy, so that checking a name doesn't allocate it every time. */
+        POLICY
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_or_insert_with(Default::default)
+            .clone()
+    }
+
+    /// Check a name.
+    pub fn check(&self, name: &str) -> Result<(), IpconError> {
+        let error_str = if name.is_empty() {
+            Some("Name is null".to_owned())
+        } else if name.len() > IPCON_MAX_NAME_LEN - 1 {
+            Some(format!(
+                "Name is too long {} > {}",
+                name.len(),
+                IPCON_MAX_NAME_LEN - 1
+            ))
+        } else if name.trim() != name {
+            Some("Name has blank character".to_owned())
+        } else {
+            name.chars()
+                .find(|&c| !self.charset.allows(c))
+                .map(|c| format!("Name has invalid character {:?}", c))
+        };
+
+        match error_str {
+            Some(err_str) => Err(Report::new(IpconError::InvalidName))
+                .attach_printable(format!("{}: {:?}", err_str, name)),
+            None => Ok(()),
+        }
+    }
+
+    /// Qualify the name of a peer of the process with the namespace and check it.
+    /// The reserved prefixes are refused both before and after the qualification.
+    pub fn local(&self, name: &str) -> Result<String, IpconError> {
+        let qualified = match &self.namespace {
+            Some(ns) if !name.starts_with(ns.as_str()) => format!("{}{}", ns, name),
+            _ => name.to_owned(),
+        };
+
+        for n in [name, qualified.as_str()] {
+            if let Some(prefix) = self.reserved.iter().find(|p| n.starts_with(p.as_str())) {
+                return Err(Report::new(IpconError::InvalidName))
+                    .attach_printable(format!("Name {:?} uses reserved prefix {:?}", n, prefix));
+            }
+        }
+
+        self.check(&qualified)?;
+        Ok(qualified)
+    }
+}
+
+macro_rules! name_type {
+    ($(#[$doc:meta])* $name:ident) => {
+        $(#[$doc])*
+        #[derive(Clone)]
+        pub struct $name {
+            c: CString,
+        }
+
+        /* Compared and hashed as str, as required by Borrow<str>. */
+        impl PartialEq for $name {
+            fn eq(&self, other: &Self) -> bool {
+                self.as_str() == other.as_str()
+            }
+        }
+
+        impl Eq for $name {}
+
+        impl Hash for $name {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                self.as_str().hash(state)
+            }
+        }
+
+        impl $name {
+            /// Get the name.
+            pub fn as_str(&self) -> &str {
+                /* Built from a &str. */
+                self.c.to_str().unwrap_or_default()
+            }
+
+            /// Get the name as a C string.
+            pub fn as_c_str(&self) -> &CStr {
+                &self.c
+            }
+
+            fn checked(name: String) -> Result<$name, IpconError> {
+                let c = CString::new(name).map_err(|e| {
+                    Report::new(IpconError::InvalidName).attach_printable(e.to_string())
+                })?;
+                Ok($name { c })
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}", self.as_str())
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{:?}", self.as_str())
+            }
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                self.as_str()
+            }
+        }
+
+        impl Borrow<str> for $name {
+            fn borrow(&self) -> &str {
+                self.as_str()
+            }
+        }
+
+        impl TryFrom<&str> for $name {
+            type Error = Report<IpconError>;
+
+            fn try_from(name: &str) -> std::result::Result<$name, Self::Error> {
+                $name::new(name)
+            }
+        }
+
+        impl TryFrom<String> for $name {
+            type Error = Report<IpconError>;
+
+            fn try_from(name: String) -> std::result::Result<$name, Self::Error> {
+                $name::new(&name)
+            }
+        }
+    };
+}
+
+name_type!(
+    /// Validated peer name.
+    PeerName
+);
+
+name_type!(
+    /// Validated group name.
+    GroupName
+);
+
+impl PeerName {
+    /// Name any peer, the name is checked by the policy of the process.
+    pub fn new(name: &str) -> Result<PeerName, IpconError> {
+        NamePolicy::current().check(name)?;
+        PeerName::checked(name.to_owned())
+    }
+
+    /// Name a peer of the process, qualified by the namespace of the process.
+    pub fn local(name: &str) -> Result<PeerName, IpconError> {
+        PeerName::checked(NamePolicy::current().local(name)?)
+    }
+}
+
+impl GroupName {
+    /// Name a group, the name is checked by the policy of the process.
+    pub fn new(name: &str) -> Result<GroupName, IpconError> {
+        NamePolicy::current().check(name)?;
+        GroupName::checked(name.to_owned())
+    }
+}
+
+/// Types usable as a peer name.
+pub trait AsPeerName {
+    /// Get the validated peer name.
+    fn as_peer_name(&self) -> Result<Cow<'_, PeerName>, IpconError>;
+}
+
+/// Types usable as a group name.
+pub trait AsGroupName {
+    /// Get the validated group name.
+    fn as_group_name(&self) -> Result<Cow<'_, GroupName>, IpconError>;
+}
+
+impl AsPeerName for PeerName {
+    fn as_peer_name(&self) -> Result<Cow<'_, PeerName>, IpconError> {
+        Ok(Cow::Borrowed(self))
+    }
+}
+
+impl AsPeerName for str {
+    fn as_peer_name(&self) -> Result<Cow<'_, PeerName>, IpconError> {
+        PeerName::new(self).map(Cow::Owned)
+    }
+}
+
+impl AsPeerName for String {
+    fn as_peer_name(&self) -> Result<Cow<'_, PeerName>, IpconError> {
+        self.as_str().as_peer_name()
+    }
+}
+
+impl AsGroupName for GroupName {
+    fn as_group_name(&self) -> Result<Cow<'_, GroupName>, IpconError> {
+        Ok(Cow::Borrowed(self))
+    }
+}
+
+impl AsGroupName for str {
+    fn as_group_name(&self) -> Result<Cow<'_, GroupName>, IpconError> {
+        GroupName::new(self).map(Cow::Owned)
+    }
+}
+
+impl AsGroupName for String {
+    fn as_group_name(&self) -> Result<Cow<'_, GroupName>, IpconError> {
+        self.as_str().as_group_name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn default_policy_is_permissive() {
+        let policy = NamePolicy::new();
+
+        for name in ["ipcon-str-server", "ipcon-bridge", "caf\u{e9}", "a b", "x"] {
+            policy.check(name).unwrap();
+            assert_eq!(policy.local(name).unwrap(), name);
+        }
+
+        for name in ["", " a", "a\t", "a\0b", &"a".repeat(IPCON_MAX_NAME_LEN + 1)] {
+            assert!(policy.check(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn longest_name_fits_a_c_array() {
+        let longest = "a".repeat(IPCON_MAX_NAME_LEN - 1);
+        NamePolicy::new().check(&longest).unwrap();
+        crate::ipcon_msg::IpconKevent::new_group_added(&longest, &longest).unwrap();
+
+        let bus = crate::ipcon_loopback::LoopbackBus::new();
+        let peer = bus
+            .peer(Some(&longest), Some(crate::ipcon::IPF_DEFAULT))
+            .unwrap();
+        peer.send_unicast_msg(&longest, b"self").unwrap();
+        peer.register_group(&longest).unwrap();
+        peer.send_multicast(&longest, b"group", false).unwrap();
+
+        let too_long = "a".repeat(IPCON_MAX_NAME_LEN);
+        assert!(NamePolicy::new().check(&too_long).is_err());
+        assert!(PeerName::new(&too_long).is_err());
+    }
+
+    #[test]
+    fn strict_policy() {
+        let policy = NamePolicy::strict();
+
+        policy.check("media.player").unwrap();
+        assert!(policy.check("caf\u{e9}").is_err());
+        assert!(policy.check("a b").is_err());
+        assert!(policy.local("ipcon-bridge").is_err());
+        /* Other peers can still be named. */
+        policy.check("ipcon-bridge").unwrap();
+
+        let portable = NamePolicy::new().charset(NameCharset::Portable);
+        portable.check("a-b_c.d").unwrap();
+        assert!(portable.check("a:b").is_err());
+    }
+
+    #[test]
+    fn namespace_and_reserved_prefixes() {
+        let policy = NamePolicy::new().reserve("sys").namespace("media.");
+
+        assert_eq!(policy.local("player").unwrap(), "media.player");
+        assert_eq!(policy.local("media.player").unwrap(), "media.player");
+        assert!(policy.local("sysx").is_err());
+
+        let policy = NamePolicy::new().reserve("media.sys").namespace("media.");
+        assert!(policy.local("sysx").is_err());
+        assert!(policy.clear_reserved().local("sysx").is_ok());
+
+        let long = NamePolicy::new().namespace(&"n".repeat(IPCON_MAX_NAME_LEN));
+        assert!(long.local("a").is_err());
+    }
+
+    #[test]
+    fn names_borrow_as_str() {
+        let peer = PeerName::new("peer").unwrap();
+        assert_eq!(peer.as_str(), "peer");
+        assert_eq!(peer.as_c_str().to_bytes(), b"peer");
+        assert_eq!(peer, PeerName::try_from("peer".to_owned()).unwrap());
+
+        let set: HashSet<PeerName> = [peer].into_iter().collect();
+        assert!(set.contains("peer"));
+        assert!(!set.contains("other"));
+
+        assert!(GroupName::new("").is_err());
+        assert!(matches!(
+            "group".as_group_name().unwrap(),
+            Cow::Owned(g) if g.as_str() == "group"
+        ));
+    }
+}
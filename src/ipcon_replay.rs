@@ -0,0 +1,338 @@
+//! # Session record and replay
+//! A session is the sequence of messages received by a peer, recorded with their timestamps to a
+//! pcapng file by a SessionRecorder:
+//!
+//! ```ignore
+//! ipcon.set_capture(Some(Box::new(SessionRecorder::create("session.pcapng")?)));
+//! ```
+//!
+//! A SessionReplayer reads the file back and delivers the messages through the IpconReceive
+//! trait, either as fast as possible or with their original timing. Message handling code
+//! written against IpconReceive can so be run on a recorded session without the IPCON kernel
+//! module, for example to turn a captured bug report into a regression test.
+//!
+//! Any capture file written by Ipcon::capture_to_file() can also be replayed, the sent messages
+//! are skipped.
+
+use crate::ipcon::IpconReceive;
+use crate::ipcon_capture::{
+    CaptureDirection, CaptureRecord, CaptureSink, PcapngReader, PcapngWriter,
+};
+use crate::ipcon_error::IpconError;
+use crate::ipcon_msg::IpconMsg;
+use crate::ipcon_trace;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+#[allow(unused)]
+use {
+    error_stack::{Report, Result, ResultExt},
+    jlogger_tracing::{jdebug, jerror, jinfo, jtrace, jwarn},
+};
+
+/// Capture sink recording the messages received by a peer.
+pub struct SessionRecorder<W: Write> {
+    writer: PcapngWriter<W>,
+}
+
+impl SessionRecorder<BufWriter<File>> {
+    /// Create a session file.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, IpconError> {
+        Ok(SessionRecorder {
+            writer: PcapngWriter::create(path)?,
+        })
+    }
+}
+
+impl<W: Write> SessionRecorder<W> {
+    pub fn new(w: W) -> Result<Self, IpconError> {
+        Ok(SessionRecorder {
+            writer: PcapngWriter::new(w)?,
+        })
+    }
+}
+
+impl<W: Write + Send> CaptureSink for SessionRecorder<W> {
+    fn capture(&mut self, record: &CaptureRecord) -> Result<(), IpconError> {
+        if record.direction != CaptureDirection::Received {
+            return Ok(());
+        }
+
+        self.writer.capture(record)
+    }
+
+    fn flush(&mut self) -> Result<(), IpconError> {
+        self.writer.flush()
+    }
+}
+
+/// Pace of a replayed session.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReplayTiming {
+    /// Deliver every message as soon as it is asked for.
+    AsFastAsPossible,
+    /// Keep the original intervals between the messages.
+    Original,
+    /// Keep the original intervals multiplied by the factor, 0.5 replays twice as fast.
+    /// A negative or NaN factor is taken as 0, and an infinite one holds back all the messages
+    /// after the first one.
+    Scaled(f64),
+}
+
+struct ReplayState {
+    records: VecDeque<CaptureRecord>,
+    /* Time of the first delivery and timestamp of the first record. */
+    origin: Option<(Instant, SystemTime)>,
+}
+
+/// Replay of a recorded session through the IpconReceive trait.
+///
+/// Once all the messages are delivered, the receive functions fail with SystemErrorNotExist, so
+/// that a receive loop terminates at the end of the session.
+pub struct SessionReplayer {
+    timing: ReplayTiming,
+    trace: AtomicBool,
+    state: Mutex<ReplayState>,
+}
+
+impl SessionReplayer {
+    /// Create a replayer of the received messages of the records.
+    pub fn new<I: IntoIterator<Item = CaptureRecord>>(
+        records: I,
+        timing: ReplayTiming,
+    ) -> SessionReplayer {
+        SessionReplayer {
+            timing,
+            trace: AtomicBool::new(false),
+            state: Mutex::new(ReplayState {
+                records: records
+                    .into_iter()
+                    .filter(|r| r.direction == CaptureDirection::Received)
+                    .collect(),
+                origin: None,
+            }),
+        }
+    }
+
+    /// Open a session file.
+    pub fn open<P: AsRef<Path>>(path: P, timing: ReplayTiming) -> Result<Self, IpconError> {
+        let records = PcapngReader::open(path)?.collect::<Result<Vec<_>, IpconError>>()?;
+        Ok(SessionReplayer::new(records, timing))
+    }
+
+    /// Strip the trace envelope of the replayed messages, like a peer with trace propagation
+    /// enabled does. See Ipcon::set_trace_propagation().
+    pub fn set_trace_propagation(&self, enable: bool) {
+        self.trace.store(enable, Ordering::Relaxed);
+    }
+
+    /// Number of messages not delivered yet.
+    pub fn remaining(&self) -> usize {
+        self.lock().records.len()
+    }
+
+    /// Whether all the messages are delivered.
+    pub fn is_finished(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ReplayState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Time left before the next record is due.
+    fn wait_time(&self, state: &mut ReplayState, record: &CaptureRecord) -> Duration {
+        let scale = match self.timing {
+            ReplayTiming::AsFastAsPossible => return Duration::ZERO,
+            ReplayTiming::Original => 1.0,
+            ReplayTiming::Scaled(f) => f.max(0.0),
+        };
+
+        let (start, first) = *state
+            .origin
+            .get_or_insert((Instant::now(), record.timestamp));
+        let offset = match record.timestamp.duration_since(first) {
+            Ok(offset) if !offset.is_zero() => offset,
+            _ => return Duration::ZERO,
+        };
+
+        /* An infinite factor overflows the duration, the record is never due. */
+        Duration::try_from_secs_f64(offset.as_secs_f64() * scale)
+            .unwrap_or(Duration::MAX)
+            .saturating_sub(start.elapsed())
+    }
+
+    fn next_msg(&self, timeout: Option<Duration>) -> Result<IpconMsg, IpconError> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+
+        /* The lock is not held while sleeping, another receiver may take the record meanwhile. */
+        let record = loop {
+            let mut state = self.lock();
+
+            let wait = match state.records.front().cloned() {
+                Some(record) => self.wait_time(&mut state, &record),
+                None => {
+                    return Err(Report::new(IpconError::SystemErrorNotExist))
+                        .attach_printable("Replay session finished")
+                }
+            };
+
+            if wait.is_zero() {
+                if let Some(record) = state.records.pop_front() {
+                    break record;
+                }
+                continue;
+            }
+            drop(state);
+
+            match deadline.map(|d| d.saturating_duration_since(Instant::now())) {
+                Some(left) if left < wait => {
+                    std::thread::sleep(left);
+                    return Err(Report::new(IpconError::SysErrorTimeOut))
+                        .attach_printable("No replayed message before the timeout");
+                }
+                _ => std::thread::sleep(wait),
+            }
+        };
+        let msg = record.to_msg()?;
+
+        if self.trace.load(Ordering::Relaxed) {
+            Ok(ipcon_trace::extract(msg))
+        } else {
+            Ok(msg)
+        }
+    }
+}
+
+impl IpconReceive for SessionReplayer {
+    fn receive_msg(&self) -> Result<IpconMsg, IpconError> {
+        self.next_msg(None)
+    }
+
+    fn receive_msg_timeout(&self, tv_sec: u32, tv_usec: u32) -> Result<IpconMsg, IpconError> {
+        self.next_msg(Some(
+            Duration::from_secs(tv_sec as u64) + Duration::from_micros(tv_usec as u64),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipcon::IPF_DEFAULT;
+    use crate::ipcon_loopback::LoopbackBus;
+    use crate::ipcon_msg::IpconMsgType;
+    use std::time::UNIX_EPOCH;
+
+    fn received(ms: u64, payload: &[u8]) -> CaptureRecord {
+        CaptureRecord {
+            timestamp: UNIX_EPOCH + Duration::from_millis(ms),
+            direction: CaptureDirection::Received,
+            msg_type: IpconMsgType::IpconMsgTypeNormal,
+            peer: "peer".to_owned(),
+            group: None,
+            payload: payload.to_vec(),
+        }
+    }
+
+    fn payload(msg: IpconMsg) -> Vec<u8> {
+        match msg {
+            IpconMsg::IpconMsgUser(body) => body.buf,
+            _ => panic!("Unexpected message"),
+        }
+    }
+
+    #[test]
+    fn recorded_session_is_replayed() {
+        let bus = LoopbackBus::new();
+        let a = bus.peer(Some("a"), Some(IPF_DEFAULT)).unwrap();
+        let b = bus.peer(Some("b"), Some(IPF_DEFAULT)).unwrap();
+
+        let path =
+            std::env::temp_dir().join(format!("ipcon-session-{}.pcapng", std::process::id()));
+        a.set_capture(Some(Box::new(SessionRecorder::create(&path).unwrap())));
+        b.send_unicast_msg("a", b"one").unwrap();
+        a.receive_msg().unwrap();
+        a.send_unicast_msg("b", b"sent").unwrap();
+        b.send_unicast_msg("a", b"two").unwrap();
+        a.receive_msg().unwrap();
+        a.set_capture(None);
+
+        let replayer = SessionReplayer::open(&path, ReplayTiming::AsFastAsPossible).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(replayer.remaining(), 2);
+        assert_eq!(payload(replayer.receive_msg().unwrap()), b"one");
+        assert_eq!(payload(replayer.receive_msg().unwrap()), b"two");
+        assert!(replayer.is_finished());
+        assert_eq!(
+            *replayer.receive_msg().unwrap_err().current_context(),
+            IpconError::SystemErrorNotExist
+        );
+    }
+
+    #[test]
+    fn original_timing_is_scaled() {
+        let replayer = SessionReplayer::new(
+            vec![received(1_000, b"one"), received(2_000, b"two")],
+            ReplayTiming::Scaled(0.1),
+        );
+
+        let start = Instant::now();
+        assert_eq!(payload(replayer.receive_msg().unwrap()), b"one");
+        assert_eq!(
+            *replayer
+                .receive_msg_timeout(0, 10_000)
+                .unwrap_err()
+                .current_context(),
+            IpconError::SysErrorTimeOut
+        );
+        assert_eq!(payload(replayer.receive_msg().unwrap()), b"two");
+
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100));
+        assert!(elapsed < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn invalid_factors_are_clamped() {
+        let records = || vec![received(1_000, b"one"), received(2_000, b"two")];
+
+        let replayer = SessionReplayer::new(records(), ReplayTiming::Scaled(f64::NAN));
+        assert_eq!(payload(replayer.receive_msg().unwrap()), b"one");
+        assert_eq!(payload(replayer.receive_msg().unwrap()), b"two");
+
+        let replayer = SessionReplayer::new(records(), ReplayTiming::Scaled(f64::INFINITY));
+        assert_eq!(payload(replayer.receive_msg().unwrap()), b"one");
+        assert_eq!(
+            *replayer
+                .receive_msg_timeout(0, 10_000)
+                .unwrap_err()
+                .current_context(),
+            IpconError::SysErrorTimeOut
+        );
+    }
+
+    #[test]
+    fn state_is_not_locked_while_waiting() {
+        let replayer = SessionReplayer::new(
+            vec![received(0, b"one"), received(500, b"two")],
+            ReplayTiming::Original,
+        );
+        assert_eq!(payload(replayer.receive_msg().unwrap()), b"one");
+
+        std::thread::scope(|s| {
+            let receiver = s.spawn(|| payload(replayer.receive_msg().unwrap()));
+            std::thread::sleep(Duration::from_millis(50));
+
+            let start = Instant::now();
+            assert_eq!(replayer.remaining(), 1);
+            assert!(start.elapsed() < Duration::from_millis(200));
+            assert_eq!(receiver.join().unwrap(), b"two");
+        });
+    }
+}
@@ -0,0 +1,637 @@
+//! # Send scheduler
+//! A send scheduler queues outgoing messages in several priority classes and drains them onto
+//! the single write interface of a peer in priority order, so that control messages are not
+//! stuck behind bulk traffic.
+//!
+//! Every class has a bounded queue and a drop policy deciding what happens when the queue is
+//! full. Class 0 has the highest priority.
+
+use crate::ipcon::{valid_name, Ipcon};
+use crate::ipcon_error::IpconError;
+use crate::ipcon_msg::IPCON_MAX_PAYLOAD_LEN;
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+#[allow(unused)]
+use {
+    error_stack::{Report, Result, ResultExt},
+    jlogger_tracing::{jdebug, jerror, jinfo, jtrace, jwarn},
+};
+
+#[cfg(feature = "async")]
+use crate::ipcon_async::AsyncIpcon;
+
+/// What to do when the queue of a class is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Drop the oldest queued message to make room for the new one.
+    DropOldest,
+    /// Drop the new message.
+    DropNewest,
+    /// Block (or await) until there is room in the queue.
+    Block,
+}
+
+/// Configuration of a priority class.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PriorityClass {
+    pub capacity: usize,
+    pub policy: DropPolicy,
+}
+
+impl PriorityClass {
+    pub fn new(capacity: usize, policy: DropPolicy) -> PriorityClass {
+        PriorityClass {
+            capacity: capacity.max(1),
+            policy,
+        }
+    }
+}
+
+/// Destination of a scheduled message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SendTarget {
+    /// Unicast message to a peer.
+    Unicast(String),
+    /// Multicast message to an owned group.
+    Multicast { group: String, sync: bool },
+}
+
+/// Counters of a priority class.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ClassStats {
+    pub queued: usize,
+    pub sent: u64,
+    pub dropped: u64,
+    pub failed: u64,
+}
+
+struct Pending {
+    target: SendTarget,
+    buf: Vec<u8>,
+}
+
+struct SchedState {
+    queues: Vec<VecDeque<Pending>>,
+    stats: Vec<ClassStats>,
+    closed: bool,
+}
+
+/// Priority queues shared by the producers and the draining side.
+struct SchedQueues {
+    classes: Vec<PriorityClass>,
+    state: Mutex<SchedState>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    #[cfg(feature = "async")]
+    notify: tokio::sync::Notify,
+}
+
+enum Push {
+    Queued,
+    Dropped,
+    Full(Pending),
+}
+
+impl SchedQueues {
+    fn new(classes: Vec<PriorityClass>) -> Result<SchedQueues, IpconError> {
+        if classes.is_empty() {
+            return Err(Report::new(IpconError::SysErrorInvalidValue))
+                .attach_printable("No priority class specified");
+        }
+
+        let n = classes.len();
+        Ok(SchedQueues {
+            classes,
+            state: Mutex::new(SchedState {
+                queues: (0..n).map(|_| VecDeque::new()).collect(),
+                stats: vec![ClassStats::default(); n],
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            #[cfg(feature = "async")]
+            notify: tokio::sync::Notify::new(),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SchedState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn wake(&self) {
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+        #[cfg(feature = "async")]
+        self.notify.notify_waiters();
+    }
+
+    fn check(&self, class: usize, target: &SendTarget, buf: &[u8]) -> Result<(), IpconError> {
+        if class >= self.classes.len() {
+            return Err(Report::new(IpconError::SysErrorInvalidValue)).attach_printable(format!(
+                "Invalid priority class {} >= {}",
+                class,
+                self.classes.len()
+            ));
+        }
+
+        match target {
+            SendTarget::Unicast(peer) => {
+                valid_name(peer).attach_printable(format!("Invalid peer name: {}", peer))?
+            }
+            SendTarget::Multicast { group, .. } => {
+                valid_name(group).attach_printable(format!("Invalid group name: {}", group))?
+            }
+        }
+
+        if buf.len() > IPCON_MAX_PAYLOAD_LEN {
+            return Err(Report::new(IpconError::InvalidData)).attach_printable(format!(
+                "Buffer length is too large {} > {}",
+                buf.len(),
+                IPCON_MAX_PAYLOAD_LEN
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn try_push(&self, state: &mut SchedState, class: usize, p: Pending) -> Push {
+        let c = self.classes[class];
+        let queue = &mut state.queues[class];
+
+        if queue.len() >= c.capacity {
+            match c.policy {
+                DropPolicy::DropOldest => {
+                    queue.pop_front();
+                    state.stats[class].dropped += 1;
+                }
+                DropPolicy::DropNewest => {
+                    state.stats[class].dropped += 1;
+                    return Push::Dropped;
+                }
+                DropPolicy::Block => return Push::Full(p),
+            }
+        }
+
+        queue.push_back(p);
+        state.stats[class].queued = queue.len();
+        Push::Queued
+    }
+
+    fn closed_error() -> Report<IpconError> {
+        Report::new(IpconError::Cancelled).attach_printable("Send scheduler is closed")
+    }
+
+    fn push(&self, class: usize, target: SendTarget, buf: &[u8]) -> Result<bool, IpconError> {
+        self.check(class, &target, buf)?;
+
+        let mut p = Pending {
+            target,
+            buf: buf.to_vec(),
+        };
+        let mut state = self.lock();
+        loop {
+            if state.closed {
+                return Err(SchedQueues::closed_error());
+            }
+
+            match self.try_push(&mut state, class, p) {
+                Push::Queued => break,
+                Push::Dropped => return Ok(false),
+                Push::Full(back) => {
+                    p = back;
+                    state = self.not_full.wait(state).unwrap_or_else(|e| e.into_inner());
+                }
+            }
+        }
+        drop(state);
+
+        self.wake();
+        Ok(true)
+    }
+
+    #[cfg(feature = "async")]
+    async fn push_async(
+        &self,
+        class: usize,
+        target: SendTarget,
+        buf: &[u8],
+    ) -> Result<bool, IpconError> {
+        self.check(class, &target, buf)?;
+
+        let mut p = Pending {
+            target,
+            buf: buf.to_vec(),
+        };
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let pushed = {
+                let mut state = self.lock();
+                if state.closed {
+                    return Err(SchedQueues::closed_error());
+                }
+                self.try_push(&mut state, class, p)
+            };
+
+            match pushed {
+                Push::Queued => break,
+                Push::Dropped => return Ok(false),
+                Push::Full(back) => {
+                    p = back;
+                    notified.await;
+                }
+            }
+        }
+
+        self.wake();
+        Ok(true)
+    }
+
+    /// Take the message of the highest priority.
+    /// None is returned when the scheduler is closed and all the queues are drained.
+    fn pop(state: &mut SchedState) -> Option<(usize, Pending)> {
+        for (class, queue) in state.queues.iter_mut().enumerate() {
+            if let Some(p) = queue.pop_front() {
+                state.stats[class].queued = queue.len();
+                return Some((class, p));
+            }
+        }
+
+        None
+    }
+
+    fn pop_blocking(&self) -> Option<(usize, Pending)> {
+        let mut state = self.lock();
+        loop {
+            if let Some(p) = SchedQueues::pop(&mut state) {
+                drop(state);
+                self.wake();
+                return Some(p);
+            }
+
+            if state.closed {
+                return None;
+            }
+
+            state = self
+                .not_empty
+                .wait(state)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    #[cfg(feature = "async")]
+    async fn pop_async(&self) -> Option<(usize, Pending)> {
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            {
+                let mut state = self.lock();
+                if let Some(p) = SchedQueues::pop(&mut state) {
+                    drop(state);
+                    self.wake();
+                    return Some(p);
+                }
+
+                if state.closed {
+                    return None;
+                }
+            }
+
+            notified.await;
+        }
+    }
+
+    fn record(&self, class: usize, target: &SendTarget, ret: Result<(), IpconError>) {
+        let mut state = self.lock();
+        match ret {
+            Ok(()) => state.stats[class].sent += 1,
+            Err(e) => {
+                state.stats[class].failed += 1;
+                drop(state);
+                jwarn!("Scheduled send to {:?} failed: {:?}", target, e);
+            }
+        }
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.wake();
+    }
+
+    fn stats(&self) -> Vec<ClassStats> {
+        self.lock().stats.clone()
+    }
+}
+
+/// Send scheduler of an Ipcon peer.
+/// Messages are sent by a dedicated thread. When the scheduler is dropped, the queued messages
+/// are flushed before the thread exits.
+pub struct SendScheduler {
+    queues: Arc<SchedQueues>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl SendScheduler {
+    /// Create a send scheduler draining the queues of `classes` onto `ipcon`.
+    pub fn new(
+        ipcon: Arc<Ipcon>,
+        classes: Vec<PriorityClass>,
+    ) -> Result<SendScheduler, IpconError> {
+        let queues = Arc::new(SchedQueues::new(classes)?);
+        let q = queues.clone();
+
+        let worker = std::thread::Builder::new()
+            .name("ipcon-sched".to_owned())
+            .spawn(move || {
+                while let Some((class, p)) = q.pop_blocking() {
+                    let ret = match &p.target {
+                        SendTarget::Unicast(peer) => ipcon.send_unicast_msg(peer, &p.buf),
+                        SendTarget::Multicast { group, sync } => {
+                            ipcon.send_multicast(group, &p.buf, *sync)
+                        }
+                    };
+                    q.record(class, &p.target, ret);
+                }
+            })
+            .map_err(|_| Report::new(IpconError::SystemErrorOther))
+            .attach_printable("Failed to spawn send scheduler thread")?;
+
+        Ok(SendScheduler {
+            queues,
+            worker: Some(worker),
+        })
+    }
+
+    /// Queue a message in a priority class.
+    /// Returns false if the message was dropped because the queue is full. With the Block policy
+    /// it waits until there is room in the queue.
+    pub fn send(&self, class: usize, target: SendTarget, buf: &[u8]) -> Result<bool, IpconError> {
+        self.queues.push(class, target, buf)
+    }
+
+    /// Queue an unicast message in a priority class.
+    pub fn send_unicast_msg(
+        &self,
+        class: usize,
+        peer: &str,
+        buf: &[u8],
+    ) -> Result<bool, IpconError> {
+        self.send(class, SendTarget::Unicast(peer.to_owned()), buf)
+    }
+
+    /// Queue a multicast message in a priority class.
+    pub fn send_multicast(
+        &self,
+        class: usize,
+        group: &str,
+        buf: &[u8],
+        sync: bool,
+    ) -> Result<bool, IpconError> {
+        self.send(
+            class,
+            SendTarget::Multicast {
+                group: group.to_owned(),
+                sync,
+            },
+            buf,
+        )
+    }
+
+    /// Counters of each priority class.
+    pub fn stats(&self) -> Vec<ClassStats> {
+        self.queues.stats()
+    }
+}
+
+impl Drop for SendScheduler {
+    fn drop(&mut self) {
+        self.queues.close();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Async version of SendScheduler.
+/// Messages are sent by a tokio task, which flushes the queued messages and exits when the
+/// scheduler is dropped.
+#[cfg(feature = "async")]
+pub struct AsyncSendScheduler {
+    queues: Arc<SchedQueues>,
+}
+
+#[cfg(feature = "async")]
+impl AsyncSendScheduler {
+    /// Create a send scheduler draining the queues of `classes` onto `ipcon`.
+    /// It must be called in the context of a tokio runtime.
+    pub fn new(
+        ipcon: Arc<AsyncIpcon>,
+        classes: Vec<PriorityClass>,
+    ) -> Result<AsyncSendScheduler, IpconError> {
+        let queues = Arc::new(SchedQueues::new(classes)?);
+        let q = queues.clone();
+
+        tokio::spawn(async move {
+            while let Some((class, p)) = q.pop_async().await {
+                let ret = match &p.target {
+                    SendTarget::Unicast(peer) => ipcon.send_unicast_msg(peer, &p.buf).await,
+                    SendTarget::Multicast { group, sync } => {
+                        ipcon.send_multicast(group, &p.buf, *sync).await
+                    }
+                };
+                q.record(class, &p.target, ret);
+            }
+        });
+
+        Ok(AsyncSendScheduler { queues })
+    }
+
+    /// Queue a message in a priority class.
+    /// Returns false if the message was dropped because the queue is full. With the Block policy
+    /// it waits until there is room in the queue.
+    pub async fn send(
+        &self,
+        class: usize,
+        target: SendTarget,
+        buf: &[u8],
+    ) -> Result<bool, IpconError> {
+        self.queues.push_async(class, target, buf).await
+    }
+
+    /// Queue an unicast message in a priority class.
+    pub async fn send_unicast_msg(
+        &self,
+        class: usize,
+        peer: &str,
+        buf: &[u8],
+    ) -> Result<bool, IpconError> {
+        self.send(class, SendTarget::Unicast(peer.to_owned()), buf)
+            .await
+    }
+
+    /// Queue a multicast message in a priority class.
+    pub async fn send_multicast(
+        &self,
+        class: usize,
+        group: &str,
+        buf: &[u8],
+        sync: bool,
+    ) -> Result<bool, IpconError> {
+        self.send(
+            class,
+            SendTarget::Multicast {
+                group: group.to_owned(),
+                sync,
+            },
+            buf,
+        )
+        .await
+    }
+
+    /// Counters of each priority class.
+    pub fn stats(&self) -> Vec<ClassStats> {
+        self.queues.stats()
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for AsyncSendScheduler {
+    fn drop(&mut self) {
+        self.queues.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipcon::IPF_DEFAULT;
+    use crate::ipcon_loopback::LoopbackBus;
+    use crate::ipcon_msg::IpconMsg;
+
+    fn unicast(peer: &str) -> SendTarget {
+        SendTarget::Unicast(peer.to_owned())
+    }
+
+    fn drain(q: &SchedQueues) -> Vec<(usize, Vec<u8>)> {
+        let mut state = q.lock();
+        std::iter::from_fn(|| SchedQueues::pop(&mut state))
+            .map(|(class, p)| (class, p.buf))
+            .collect()
+    }
+
+    #[test]
+    fn higher_classes_go_first() {
+        let q = SchedQueues::new(vec![
+            PriorityClass::new(4, DropPolicy::DropNewest),
+            PriorityClass::new(4, DropPolicy::DropNewest),
+        ])
+        .unwrap();
+
+        q.push(1, unicast("peer"), b"bulk1").unwrap();
+        q.push(0, unicast("peer"), b"ctrl1").unwrap();
+        q.push(1, unicast("peer"), b"bulk2").unwrap();
+        q.push(0, unicast("peer"), b"ctrl2").unwrap();
+        assert_eq!(q.stats()[1].queued, 2);
+
+        assert_eq!(
+            drain(&q),
+            vec![
+                (0, b"ctrl1".to_vec()),
+                (0, b"ctrl2".to_vec()),
+                (1, b"bulk1".to_vec()),
+                (1, b"bulk2".to_vec()),
+            ]
+        );
+        assert_eq!(q.stats()[1].queued, 0);
+    }
+
+    #[test]
+    fn drop_policies() {
+        let q = SchedQueues::new(vec![
+            PriorityClass::new(2, DropPolicy::DropOldest),
+            PriorityClass::new(2, DropPolicy::DropNewest),
+        ])
+        .unwrap();
+
+        for buf in [b"1", b"2", b"3"] {
+            assert!(q.push(0, unicast("peer"), buf).unwrap());
+        }
+        assert!(q.push(1, unicast("peer"), b"1").unwrap());
+        assert!(q.push(1, unicast("peer"), b"2").unwrap());
+        assert!(!q.push(1, unicast("peer"), b"3").unwrap());
+
+        let stats = q.stats();
+        assert_eq!(stats[0].dropped, 1);
+        assert_eq!(stats[1].dropped, 1);
+        assert_eq!(
+            drain(&q),
+            vec![
+                (0, b"2".to_vec()),
+                (0, b"3".to_vec()),
+                (1, b"1".to_vec()),
+                (1, b"2".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn block_waits_for_room() {
+        let q = Arc::new(SchedQueues::new(vec![PriorityClass::new(1, DropPolicy::Block)]).unwrap());
+        q.push(0, unicast("peer"), b"1").unwrap();
+
+        let producer = {
+            let q = q.clone();
+            std::thread::spawn(move || q.push(0, unicast("peer"), b"2").unwrap())
+        };
+
+        assert_eq!(q.pop_blocking().unwrap().1.buf, b"1");
+        assert!(producer.join().unwrap());
+        assert_eq!(q.pop_blocking().unwrap().1.buf, b"2");
+    }
+
+    #[test]
+    fn invalid_messages_are_rejected() {
+        let q = SchedQueues::new(vec![PriorityClass::new(1, DropPolicy::Block)]).unwrap();
+
+        assert!(SchedQueues::new(Vec::new()).is_err());
+        assert!(q.push(1, unicast("peer"), b"").is_err());
+        assert!(q.push(0, unicast(""), b"").is_err());
+        assert!(q
+            .push(0, unicast("peer"), &vec![0; IPCON_MAX_PAYLOAD_LEN + 1])
+            .is_err());
+
+        q.push(0, unicast("peer"), b"queued").unwrap();
+        q.close();
+        let e = q.push(0, unicast("peer"), b"late").unwrap_err();
+        assert_eq!(*e.current_context(), IpconError::Cancelled);
+        assert_eq!(q.pop_blocking().unwrap().1.buf, b"queued");
+        assert!(q.pop_blocking().is_none());
+    }
+
+    #[test]
+    fn queued_messages_are_sent_on_drop() {
+        let bus = LoopbackBus::new();
+        let sender = Arc::new(bus.peer(Some("sender"), Some(IPF_DEFAULT)).unwrap());
+        let receiver = bus.peer(Some("receiver"), Some(IPF_DEFAULT)).unwrap();
+
+        let sched =
+            SendScheduler::new(sender, vec![PriorityClass::new(16, DropPolicy::Block)]).unwrap();
+        for i in 0..8_u8 {
+            sched.send_unicast_msg(0, "receiver", &[i]).unwrap();
+        }
+        drop(sched);
+
+        for i in 0..8_u8 {
+            match receiver.receive_msg_nonblock().unwrap() {
+                IpconMsg::IpconMsgUser(body) => assert_eq!(body.buf, [i]),
+                _ => panic!("Unexpected message"),
+            }
+        }
+    }
+}
@@ -0,0 +1,364 @@
+//! # Sequenced multicast
+//! IPCON multicast messages are dropped silently when the socket buffer of a subscriber is full.
+//! SequencedPublisher prepends a small header to every multicast message, holding the epoch of the
+//! publisher and a per-group sequence number. SequenceTracker strips the header on the receiving
+//! side, reports the gaps and counts the lost messages per (peer, group).
+
+use crate::ipcon::{valid_name, Ipcon};
+use crate::ipcon_error::IpconError;
+use crate::ipcon_msg::{IpconMsgBody, IpconMsgType, IPCON_MAX_PAYLOAD_LEN};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+#[allow(unused)]
+use {
+    error_stack::{Report, Result, ResultExt},
+    jlogger_tracing::{jdebug, jerror, jinfo, jtrace, jwarn},
+};
+
+const SEQ_MAGIC: u8 = 0x51;
+
+/// Length of the sequence header: magic(1) + epoch(8) + seq(8).
+pub const SEQ_HEADER_LEN: usize = 17;
+
+/// Maximum payload length of a sequenced message.
+pub const SEQ_MAX_PAYLOAD_LEN: usize = IPCON_MAX_PAYLOAD_LEN - SEQ_HEADER_LEN;
+
+/// Sequence header of a multicast message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SeqHeader {
+    /// Epoch of the publisher, changed every time the publisher is restarted.
+    pub epoch: u64,
+    /// Sequence number of the message in the group, starting from 1.
+    pub seq: u64,
+}
+
+impl SeqHeader {
+    /// Prepend the header to a payload.
+    pub fn encode(&self, buf: &[u8]) -> Result<Vec<u8>, IpconError> {
+        if buf.len() > SEQ_MAX_PAYLOAD_LEN {
+            return Err(Report::new(IpconError::InvalidData)).attach_printable(format!(
+                "Buffer length is too large {} > {}",
+                buf.len(),
+                SEQ_MAX_PAYLOAD_LEN
+            ));
+        }
+
+        let mut v = Vec::with_capacity(SEQ_HEADER_LEN + buf.len());
+        v.push(SEQ_MAGIC);
+        v.extend_from_slice(&self.epoch.to_be_bytes());
+        v.extend_from_slice(&self.seq.to_be_bytes());
+        v.extend_from_slice(buf);
+
+        Ok(v)
+    }
+
+    /// Split a received buffer into the header and the payload.
+    pub fn decode(buf: &[u8]) -> Result<(SeqHeader, &[u8]), IpconError> {
+        if buf.len() < SEQ_HEADER_LEN || buf[0] != SEQ_MAGIC {
+            return Err(Report::new(IpconError::InvalidData))
+                .attach_printable("No sequence header found");
+        }
+
+        let mut epoch = [0_u8; 8];
+        let mut seq = [0_u8; 8];
+        epoch.copy_from_slice(&buf[1..9]);
+        seq.copy_from_slice(&buf[9..17]);
+
+        Ok((
+            SeqHeader {
+                epoch: u64::from_be_bytes(epoch),
+                seq: u64::from_be_bytes(seq),
+            },
+            &buf[SEQ_HEADER_LEN..],
+        ))
+    }
+}
+
+/// Publisher adding sequence headers to multicast messages.
+pub struct SequencedPublisher<'a> {
+    ipcon: &'a Ipcon,
+    epoch: u64,
+    seqs: HashMap<String, u64>,
+}
+
+impl<'a> SequencedPublisher<'a> {
+    /// Create a publisher sending multicast messages through `ipcon`.
+    /// The epoch is taken from the current time.
+    pub fn new(ipcon: &'a Ipcon) -> SequencedPublisher<'a> {
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+
+        SequencedPublisher::with_epoch(ipcon, epoch)
+    }
+
+    /// Create a publisher with a specific epoch.
+    pub fn with_epoch(ipcon: &'a Ipcon, epoch: u64) -> SequencedPublisher<'a> {
+        SequencedPublisher {
+            ipcon,
+            epoch,
+            seqs: HashMap::new(),
+        }
+    }
+
+    /// Epoch of the publisher.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Sequence number of the last message sent to the group.
+    pub fn last_seq(&self, group: &str) -> u64 {
+        self.seqs.get(group).copied().unwrap_or(0)
+    }
+
+    /// Send a sequenced multicast message to an owned group.
+    /// The sequence number is consumed even if sending fails, so that subscribers see the failure
+    /// as a gap.
+    pub fn send_multicast(
+        &mut self,
+        group: &str,
+        buf: &[u8],
+        sync: bool,
+    ) -> Result<(), IpconError> {
+        valid_name(group).attach_printable(format!("Invalid group name: {}", group))?;
+
+        let seq = self.last_seq(group) + 1;
+        let v = SeqHeader {
+            epoch: self.epoch,
+            seq,
+        }
+        .encode(buf)?;
+
+        self.seqs.insert(group.to_owned(), seq);
+        self.ipcon.send_multicast(group, &v, sync)
+    }
+}
+
+/// Gap detected in a sequenced stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeqGap {
+    /// Messages between `expected` and `received` (exclusive) were lost.
+    Lost { expected: u64, received: u64 },
+    /// The publisher restarted with a new epoch. The messages sent by the old publisher after
+    /// the last received one and the messages of the new publisher before `received` are unknown.
+    EpochChanged { old: u64, new: u64, received: u64 },
+    /// The message is older than the last received one (duplicated or reordered).
+    Stale { last: u64, received: u64 },
+}
+
+/// A sequenced message with its header removed.
+pub struct SeqMsg {
+    pub header: SeqHeader,
+    /// The received message, `buf` only holds the payload.
+    pub body: IpconMsgBody,
+    /// Gap detected before this message.
+    pub gap: Option<SeqGap>,
+}
+
+/// Counters of a (peer, group) stream.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SeqStats {
+    pub epoch: u64,
+    pub last_seq: u64,
+    pub received: u64,
+    pub lost: u64,
+    pub stale: u64,
+    pub epoch_changes: u64,
+}
+
+/// Receiver side tracker of sequenced multicast streams.
+#[derive(Default)]
+pub struct SequenceTracker {
+    streams: HashMap<(String, String), SeqStats>,
+}
+
+impl SequenceTracker {
+    pub fn new() -> SequenceTracker {
+        SequenceTracker::default()
+    }
+
+    /// Strip the sequence header from a received group message and check for gaps.
+    /// It fails with InvalidData if the message is not a group message or has no sequence header.
+    pub fn track(&mut self, mut body: IpconMsgBody) -> Result<SeqMsg, IpconError> {
+        let group = match (&body.msg_type, &body.group) {
+            (IpconMsgType::IpconMsgTypeGroup, Some(group)) => group.clone(),
+            _ => {
+                return Err(Report::new(IpconError::InvalidData))
+                    .attach_printable("Not a group message")
+            }
+        };
+
+        let (header, payload) = SeqHeader::decode(&body.buf)
+            .attach_printable(format!("Invalid message from `{}@{}`", group, body.peer))?;
+        body.buf = payload.to_vec();
+
+        let stats = self.streams.entry((body.peer.clone(), group)).or_default();
+
+        let gap = if stats.received == 0 {
+            /* First message of the stream, nothing to compare with. */
+            None
+        } else if header.epoch != stats.epoch {
+            stats.epoch_changes += 1;
+            stats.lost += header.seq.saturating_sub(1);
+            Some(SeqGap::EpochChanged {
+                old: stats.epoch,
+                new: header.epoch,
+                received: header.seq,
+            })
+        } else if header.seq <= stats.last_seq {
+            stats.stale += 1;
+            Some(SeqGap::Stale {
+                last: stats.last_seq,
+                received: header.seq,
+            })
+        } else if header.seq != stats.last_seq + 1 {
+            stats.lost += header.seq - stats.last_seq - 1;
+            Some(SeqGap::Lost {
+                expected: stats.last_seq + 1,
+                received: header.seq,
+            })
+        } else {
+            None
+        };
+
+        if let Some(g) = &gap {
+            jwarn!(
+                peer = body.peer,
+                group = body.group,
+                gap = format!("{:?}", g)
+            );
+        }
+
+        stats.received += 1;
+        if !matches!(gap, Some(SeqGap::Stale { .. })) {
+            stats.epoch = header.epoch;
+            stats.last_seq = header.seq;
+        }
+
+        Ok(SeqMsg { header, body, gap })
+    }
+
+    /// Number of messages lost from the group of the peer.
+    pub fn lost(&self, peer: &str, group: &str) -> u64 {
+        self.stats(peer, group).map_or(0, |s| s.lost)
+    }
+
+    /// Counters of the group of the peer.
+    pub fn stats(&self, peer: &str, group: &str) -> Option<SeqStats> {
+        self.streams
+            .get(&(peer.to_owned(), group.to_owned()))
+            .copied()
+    }
+
+    /// Counters of all the tracked streams, keyed by (peer, group).
+    pub fn all_stats(&self) -> &HashMap<(String, String), SeqStats> {
+        &self.streams
+    }
+
+    /// Forget a stream, for example after the peer has been removed.
+    pub fn reset(&mut self, peer: &str, group: &str) {
+        self.streams.remove(&(peer.to_owned(), group.to_owned()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipcon::IPF_DEFAULT;
+    use crate::ipcon_loopback::LoopbackBus;
+    use crate::ipcon_msg::IpconMsg;
+
+    fn recv(ipcon: &Ipcon) -> IpconMsgBody {
+        match ipcon.receive_msg_timeout(1, 0).unwrap() {
+            IpconMsg::IpconMsgUser(body) => body,
+            m => panic!("Unexpected message {:?}", m),
+        }
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = SeqHeader { epoch: 1, seq: 2 };
+        let buf = header.encode(b"payload").unwrap();
+        assert_eq!(buf.len(), SEQ_HEADER_LEN + 7);
+        assert_eq!(SeqHeader::decode(&buf).unwrap(), (header, &b"payload"[..]));
+
+        assert!(SeqHeader::decode(&buf[..SEQ_HEADER_LEN - 1]).is_err());
+        assert!(SeqHeader::decode(&[0_u8; SEQ_HEADER_LEN]).is_err());
+        assert!(header.encode(&[0_u8; SEQ_MAX_PAYLOAD_LEN]).is_ok());
+        assert!(header.encode(&[0_u8; SEQ_MAX_PAYLOAD_LEN + 1]).is_err());
+    }
+
+    #[test]
+    fn tracker_reports_gaps() {
+        let bus = LoopbackBus::new();
+        let publisher_ipcon = bus.peer(Some("publisher"), Some(IPF_DEFAULT)).unwrap();
+        let subscriber = bus.peer(Some("subscriber"), Some(IPF_DEFAULT)).unwrap();
+        publisher_ipcon.register_group("seq").unwrap();
+        subscriber.join_group("publisher", "seq").unwrap();
+
+        let mut publisher = SequencedPublisher::with_epoch(&publisher_ipcon, 1);
+        let mut tracker = SequenceTracker::new();
+
+        publisher.send_multicast("seq", b"1", false).unwrap();
+        let msg = tracker.track(recv(&subscriber)).unwrap();
+        assert_eq!(msg.header, SeqHeader { epoch: 1, seq: 1 });
+        assert_eq!(msg.body.buf, b"1");
+        assert_eq!(msg.gap, None);
+
+        /* The second message is lost. */
+        publisher.send_multicast("seq", b"2", false).unwrap();
+        let lost = recv(&subscriber);
+        publisher.send_multicast("seq", b"3", false).unwrap();
+        let msg = tracker.track(recv(&subscriber)).unwrap();
+        assert_eq!(
+            msg.gap,
+            Some(SeqGap::Lost {
+                expected: 2,
+                received: 3
+            })
+        );
+
+        let msg = tracker.track(lost).unwrap();
+        assert_eq!(
+            msg.gap,
+            Some(SeqGap::Stale {
+                last: 3,
+                received: 2
+            })
+        );
+
+        let mut restarted = SequencedPublisher::with_epoch(&publisher_ipcon, 2);
+        restarted.send_multicast("seq", b"1", false).unwrap();
+        let msg = tracker.track(recv(&subscriber)).unwrap();
+        assert_eq!(
+            msg.gap,
+            Some(SeqGap::EpochChanged {
+                old: 1,
+                new: 2,
+                received: 1
+            })
+        );
+
+        let stats = tracker.stats("publisher", "seq").unwrap();
+        assert_eq!(stats.received, 4);
+        assert_eq!(stats.lost, 1);
+        assert_eq!(stats.stale, 1);
+        assert_eq!(stats.epoch_changes, 1);
+        assert_eq!((stats.epoch, stats.last_seq), (2, 1));
+
+        tracker.reset("publisher", "seq");
+        assert_eq!(tracker.lost("publisher", "seq"), 0);
+    }
+
+    #[test]
+    fn unicast_is_not_tracked() {
+        let bus = LoopbackBus::new();
+        let a = bus.peer(Some("a"), Some(IPF_DEFAULT)).unwrap();
+        let b = bus.peer(Some("b"), Some(IPF_DEFAULT)).unwrap();
+
+        let buf = SeqHeader { epoch: 1, seq: 1 }.encode(b"").unwrap();
+        a.send_unicast_msg("b", &buf).unwrap();
+        assert!(SequenceTracker::new().track(recv(&b)).is_err());
+    }
+}
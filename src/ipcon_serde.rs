@@ -0,0 +1,297 @@
+//! # Serialization of IPCON messages
+//! With the `serde` feature, IpconMsg, IpconMsgBody, IpconMsgType, IpconKevent and TraceContext
+//! implement Serialize and Deserialize.
+//!
+//! * Payloads are base64 strings in human readable formats like JSON, and byte arrays in binary
+//!   formats. Both forms are accepted when deserializing a human readable format.
+//! * Kernel events are externally tagged enums, for example
+//!   `{"group_added":{"peer":"server","group":"news"}}`.
+//! * Trace and span ids are hexadecimal strings in human readable formats, as in a W3C
+//!   traceparent header.
+//!
+//! The `payload`, `payload_base64` and `payload_bytes` modules can be used with
+//! `#[serde(with = "...")]` to encode payload fields of application types the same way.
+
+use crate::ipcon_msg::IpconKevent;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::ser::{self, Serializer};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+struct PayloadVisitor;
+
+impl<'de> Visitor<'de> for PayloadVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a base64 string or a byte array")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<Vec<u8>, E> {
+        STANDARD.decode(v).map_err(E::custom)
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> std::result::Result<Vec<u8>, E> {
+        Ok(v.to_vec())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> std::result::Result<Vec<u8>, E> {
+        Ok(v)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<Vec<u8>, A::Error> {
+        let mut v = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(b) = seq.next_element::<u8>()? {
+            v.push(b);
+        }
+        Ok(v)
+    }
+}
+
+/// Payload as a base64 string in human readable formats and as bytes otherwise.
+pub mod payload {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(buf: &[u8], s: S) -> std::result::Result<S::Ok, S::Error> {
+        if s.is_human_readable() {
+            super::payload_base64::serialize(buf, s)
+        } else {
+            s.serialize_bytes(buf)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Vec<u8>, D::Error> {
+        if d.is_human_readable() {
+            d.deserialize_any(PayloadVisitor)
+        } else {
+            d.deserialize_byte_buf(PayloadVisitor)
+        }
+    }
+}
+
+/// Payload as a base64 string.
+pub mod payload_base64 {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(buf: &[u8], s: S) -> std::result::Result<S::Ok, S::Error> {
+        s.serialize_str(&STANDARD.encode(buf))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Vec<u8>, D::Error> {
+        d.deserialize_str(PayloadVisitor)
+    }
+}
+
+/// Payload as bytes, an array of numbers in JSON.
+pub mod payload_bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(buf: &[u8], s: S) -> std::result::Result<S::Ok, S::Error> {
+        s.serialize_bytes(buf)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Vec<u8>, D::Error> {
+        d.deserialize_byte_buf(PayloadVisitor)
+    }
+}
+
+/* Identifier written in hexadecimal in human readable formats. */
+struct Hex<T>(T);
+
+macro_rules! hex_id {
+    ($t:ty, $width:expr) => {
+        impl Serialize for Hex<$t> {
+            fn serialize<S: Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+                if s.is_human_readable() {
+                    s.serialize_str(&format!("{:0width$x}", self.0, width = $width))
+                } else {
+                    self.0.serialize(s)
+                }
+            }
+        }
+
+        impl<'de> Deserialize<'de> for Hex<$t> {
+            fn deserialize<D: Deserializer<'de>>(d: D) -> std::result::Result<Self, D::Error> {
+                if d.is_human_readable() {
+                    let s = String::deserialize(d)?;
+                    <$t>::from_str_radix(&s, 16)
+                        .map(Hex)
+                        .map_err(de::Error::custom)
+                } else {
+                    <$t>::deserialize(d).map(Hex)
+                }
+            }
+        }
+    };
+}
+
+hex_id!(u128, 32);
+hex_id!(u64, 16);
+
+pub(crate) mod hex_u128 {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(v: &u128, s: S) -> std::result::Result<S::Ok, S::Error> {
+        Hex(*v).serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<u128, D::Error> {
+        Hex::<u128>::deserialize(d).map(|h| h.0)
+    }
+}
+
+pub(crate) mod hex_u64 {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(v: &u64, s: S) -> std::result::Result<S::Ok, S::Error> {
+        Hex(*v).serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<u64, D::Error> {
+        Hex::<u64>::deserialize(d).map(|h| h.0)
+    }
+}
+
+pub(crate) mod hex_u64_option {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(v: &Option<u64>, s: S) -> std::result::Result<S::Ok, S::Error> {
+        v.map(Hex).serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> std::result::Result<Option<u64>, D::Error> {
+        Option::<Hex<u64>>::deserialize(d).map(|o| o.map(|h| h.0))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum KeventRepr {
+    PeerAdded { peer: String },
+    PeerRemoved { peer: String },
+    GroupAdded { peer: String, group: String },
+    GroupRemoved { peer: String, group: String },
+}
+
+impl Serialize for IpconKevent {
+    fn serialize<S: Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+        let repr = if let Some(peer) = self.peer_added() {
+            KeventRepr::PeerAdded { peer }
+        } else if let Some(peer) = self.peer_removed() {
+            KeventRepr::PeerRemoved { peer }
+        } else if let Some((peer, group)) = self.group_added() {
+            KeventRepr::GroupAdded { peer, group }
+        } else if let Some((peer, group)) = self.group_removed() {
+            KeventRepr::GroupRemoved { peer, group }
+        } else {
+            return Err(ser::Error::custom(format!("Invalid kevent {:?}", self)));
+        };
+
+        repr.serialize(s)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpconKevent {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> std::result::Result<Self, D::Error> {
+        let kevent = match KeventRepr::deserialize(d)? {
+            KeventRepr::PeerAdded { peer } => IpconKevent::new_peer_added(&peer),
+            KeventRepr::PeerRemoved { peer } => IpconKevent::new_peer_removed(&peer),
+            KeventRepr::GroupAdded { peer, group } => IpconKevent::new_group_added(&peer, &group),
+            KeventRepr::GroupRemoved { peer, group } => {
+                IpconKevent::new_group_removed(&peer, &group)
+            }
+        };
+
+        kevent.map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipcon_msg::{IpconMsg, IpconMsgBody, IpconMsgType};
+    use crate::ipcon_trace::TraceContext;
+
+    fn user_msg() -> IpconMsg {
+        IpconMsg::IpconMsgUser(IpconMsgBody::new(
+            IpconMsgType::IpconMsgTypeGroup,
+            "server".to_owned(),
+            Some("news".to_owned()),
+            vec![0, 1, 2, 0xff],
+        ))
+    }
+
+    fn cbor_round_trip<T: Serialize + for<'de> Deserialize<'de>>(v: &T) -> T {
+        let mut buf = Vec::new();
+        ciborium::into_writer(v, &mut buf).unwrap();
+        ciborium::from_reader(buf.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let msg = user_msg();
+        let json = serde_json::to_value(&msg).unwrap();
+
+        assert_eq!(json["user"]["buf"], "AAEC/w==");
+        assert_eq!(serde_json::from_value::<IpconMsg>(json).unwrap(), msg);
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let msg = user_msg();
+        assert_eq!(cbor_round_trip(&msg), msg);
+
+        let ctx = TraceContext {
+            trace_id: 0x0123_4567_89ab_cdef_0123_4567_89ab_cdef,
+            span_id: 0x0123_4567_89ab_cdef,
+            parent_id: Some(1),
+            flags: 1,
+        };
+        assert_eq!(cbor_round_trip(&ctx), ctx);
+    }
+
+    #[test]
+    fn payload_accepts_byte_arrays() {
+        let mut json = serde_json::to_value(user_msg()).unwrap();
+        json["user"]["buf"] = serde_json::json!([0, 1, 2, 255]);
+
+        assert_eq!(
+            serde_json::from_value::<IpconMsg>(json).unwrap(),
+            user_msg()
+        );
+    }
+
+    #[test]
+    fn kevents_are_tagged() {
+        let kevent = IpconKevent::new_group_added("server", "news").unwrap();
+        let json = serde_json::to_string(&kevent).unwrap();
+        assert_eq!(json, r#"{"group_added":{"peer":"server","group":"news"}}"#);
+
+        let back: IpconKevent = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.group_added(), kevent.group_added());
+
+        let kevent = IpconKevent::new_peer_removed("client").unwrap();
+        let back: IpconKevent = cbor_round_trip(&kevent);
+        assert_eq!(back.peer_removed(), Some("client".to_owned()));
+
+        assert!(serde_json::from_str::<IpconKevent>(r#"{"peer_added":{"peer":""}}"#).is_err());
+    }
+
+    #[test]
+    fn ids_are_hexadecimal() {
+        let ctx = TraceContext {
+            trace_id: 0xabc,
+            span_id: 0x12,
+            parent_id: None,
+            flags: 0,
+        };
+        let json = serde_json::to_value(ctx).unwrap();
+
+        assert_eq!(json["trace_id"], "00000000000000000000000000000abc");
+        assert_eq!(json["span_id"], "0000000000000012");
+        assert_eq!(serde_json::from_value::<TraceContext>(json).unwrap(), ctx);
+    }
+}
@@ -0,0 +1,556 @@
+//! # Handler framework
+//! Most IPCON servers repeat the same loop: receive a message, match the user messages and the
+//! kernel events by hand and dispatch them. Server does it once:
+//!
+//! ```ignore
+//! let server = ServerBuilder::new(ipcon)
+//!     .on(MsgFilter::any().peer("client").tag(b"GET"), |ctx, body| async move {
+//!         ctx.ipcon().send_unicast_msg(&body.peer, b"OK")
+//!     })
+//!     .on_kevent(|_ctx, kevent| async move {
+//!         jinfo!("{}", kevent);
+//!         Ok(())
+//!     })
+//!     .on_start(|ctx| ctx.ipcon().register_group("news"))
+//!     .build()?;
+//!
+//! server.run()?;
+//! ```
+//!
+//! Each handler is an actor: it owns a mailbox and handles its messages one after the other, in
+//! the order they were received, while different handlers run concurrently. A user message is
+//! delivered to the first handler whose filter matches it, a kernel event to every kernel event
+//! handler. The handlers run on a `futures` thread pool or on a tokio runtime.
+//!
+//! run() blocks the calling thread in the receive loop until ServerHandle::shutdown() is called.
+//! The server handle wraps the shutdown handle of the peer (see ipcon_shutdown), so the blocked
+//! receive returns at once, as does the delivery of a message to a full mailbox, which drops
+//! the message. The server then stops receiving, lets every handler drain its mailbox and
+//! finally calls the stop hooks, which can still send messages.
+
+use crate::ipcon::Ipcon;
+use crate::ipcon_error::IpconError;
+use crate::ipcon_msg::{IpconKevent, IpconMsg, IpconMsgBody, IpconMsgType};
+use crate::ipcon_shutdown::ShutdownHandle;
+use futures::channel::mpsc;
+use futures::executor::ThreadPool;
+use futures::{SinkExt, StreamExt};
+use std::future::Future;
+use std::pin::{pin, Pin};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+#[allow(unused)]
+use {
+    error_stack::{Report, Result, ResultExt},
+    jlogger_tracing::{jdebug, jerror, jinfo, jtrace, jwarn},
+};
+
+/// Default capacity of the mailbox of a handler.
+pub const DEFAULT_MAILBOX_CAPACITY: usize = 64;
+
+/* Delay before receiving again after a receive failure. */
+const RECEIVE_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+type HandlerFuture = Pin<Box<dyn Future<Output = Result<(), IpconError>> + Send>>;
+type Handler = Box<dyn Fn(ServerContext, Item) -> HandlerFuture + Send + Sync>;
+type Hook = Box<dyn FnOnce(&ServerContext) -> Result<(), IpconError> + Send>;
+
+/// Filter selecting the user messages delivered to a handler.
+/// All the conditions set must be met, MsgFilter::any() matches every user message.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MsgFilter {
+    msg_type: Option<IpconMsgType>,
+    peer: Option<String>,
+    group: Option<String>,
+    tag: Option<Vec<u8>>,
+}
+
+impl MsgFilter {
+    /// Match every user message.
+    pub fn any() -> MsgFilter {
+        MsgFilter::default()
+    }
+
+    /// Match the messages of a type.
+    pub fn msg_type(mut self, msg_type: IpconMsgType) -> MsgFilter {
+        self.msg_type = Some(msg_type);
+        self
+    }
+
+    /// Match the messages sent by a peer.
+    pub fn peer(mut self, peer: &str) -> MsgFilter {
+        self.peer = Some(peer.to_owned());
+        self
+    }
+
+    /// Match the multicast messages of a group.
+    pub fn group(mut self, group: &str) -> MsgFilter {
+        self.group = Some(group.to_owned());
+        self
+    }
+
+    /// Match the messages whose payload starts with tag. The tag is not stripped.
+    pub fn tag(mut self, tag: &[u8]) -> MsgFilter {
+        self.tag = Some(tag.to_vec());
+        self
+    }
+
+    /// Whether a message matches the filter.
+    pub fn matches(&self, body: &IpconMsgBody) -> bool {
+        self.msg_type.as_ref().is_none_or(|t| *t == body.msg_type)
+            && self.peer.as_ref().is_none_or(|p| *p == body.peer)
+            && self
+                .group
+                .as_ref()
+                .is_none_or(|g| body.group.as_ref() == Some(g))
+            && self.tag.as_ref().is_none_or(|t| body.buf.starts_with(t))
+    }
+}
+
+/// Executor running the handlers.
+#[derive(Clone)]
+pub enum ServerExecutor {
+    /// A `futures` thread pool.
+    ThreadPool(ThreadPool),
+    /// A tokio runtime.
+    Tokio(tokio::runtime::Handle),
+}
+
+impl ServerExecutor {
+    fn spawn<F: Future<Output = ()> + Send + 'static>(&self, f: F) {
+        match self {
+            ServerExecutor::ThreadPool(pool) => pool.spawn_ok(f),
+            ServerExecutor::Tokio(handle) => {
+                handle.spawn(f);
+            }
+        }
+    }
+}
+
+/// Handle requesting a Server to shut down.
+#[derive(Clone, Debug)]
+pub struct ServerHandle {
+    shutdown: ShutdownHandle,
+}
+
+impl ServerHandle {
+    /// Request the server to shut down, run() returns once the handlers are done.
+    pub fn shutdown(&self) {
+        self.shutdown.shutdown();
+    }
+
+    /// Whether the shutdown was requested.
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.is_shutdown()
+    }
+}
+
+/// Context passed to the handlers and hooks.
+#[derive(Clone)]
+pub struct ServerContext {
+    ipcon: Arc<Ipcon>,
+    handle: ServerHandle,
+}
+
+impl ServerContext {
+    /// Get the peer of the server.
+    pub fn ipcon(&self) -> &Ipcon {
+        &self.ipcon
+    }
+
+    /// Get the shutdown handle of the server.
+    pub fn handle(&self) -> &ServerHandle {
+        &self.handle
+    }
+}
+
+enum Item {
+    Msg(IpconMsgBody),
+    Kevent(IpconKevent),
+}
+
+struct Route {
+    /* None for a kernel event handler. */
+    filter: Option<MsgFilter>,
+    handler: Handler,
+}
+
+/* Number of running handlers, waited for at shutdown. */
+#[derive(Default)]
+struct Running {
+    count: Mutex<usize>,
+    done: Condvar,
+}
+
+impl Running {
+    fn wait(&self) {
+        let mut count = self.count.lock().unwrap_or_else(|e| e.into_inner());
+        while *count > 0 {
+            count = self.done.wait(count).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+/* Decrement the running count when a handler ends, even by a panic. */
+struct RunningGuard(Arc<Running>);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        let mut count = self.0.count.lock().unwrap_or_else(|e| e.into_inner());
+        *count -= 1;
+        self.0.done.notify_all();
+    }
+}
+
+/// Builder of a Server.
+pub struct ServerBuilder {
+    ipcon: Ipcon,
+    routes: Vec<Route>,
+    on_start: Vec<Hook>,
+    on_stop: Vec<Hook>,
+    executor: Option<ServerExecutor>,
+    mailbox_capacity: usize,
+}
+
+impl ServerBuilder {
+    /// Create a builder of a server receiving with the peer.
+    pub fn new(ipcon: Ipcon) -> ServerBuilder {
+        ServerBuilder {
+            ipcon,
+            routes: Vec::new(),
+            on_start: Vec::new(),
+            on_stop: Vec::new(),
+            executor: None,
+            mailbox_capacity: DEFAULT_MAILBOX_CAPACITY,
+        }
+    }
+
+    /// Add a handler of the user messages matching the filter.
+    /// The handlers are tried in the order they are added.
+    pub fn on<F, Fut>(mut self, filter: MsgFilter, f: F) -> ServerBuilder
+    where
+        F: Fn(ServerContext, IpconMsgBody) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), IpconError>> + Send + 'static,
+    {
+        self.routes.push(Route {
+            filter: Some(filter),
+            handler: Box::new(move |ctx, item| match item {
+                Item::Msg(body) => Box::pin(f(ctx, body)),
+                Item::Kevent(_) => Box::pin(async { Ok(()) }),
+            }),
+        });
+        self
+    }
+
+    /// Add a handler of the kernel events.
+    pub fn on_kevent<F, Fut>(mut self, f: F) -> ServerBuilder
+    where
+        F: Fn(ServerContext, IpconKevent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), IpconError>> + Send + 'static,
+    {
+        self.routes.push(Route {
+            filter: None,
+            handler: Box::new(move |ctx, item| match item {
+                Item::Kevent(kevent) => Box::pin(f(ctx, kevent)),
+                Item::Msg(_) => Box::pin(async { Ok(()) }),
+            }),
+        });
+        self
+    }
+
+    /// Add a hook called before the server starts receiving. A failure aborts run().
+    pub fn on_start<F>(mut self, f: F) -> ServerBuilder
+    where
+        F: FnOnce(&ServerContext) -> Result<(), IpconError> + Send + 'static,
+    {
+        self.on_start.push(Box::new(f));
+        self
+    }
+
+    /// Add a hook called after the handlers are done at shutdown.
+    pub fn on_stop<F>(mut self, f: F) -> ServerBuilder
+    where
+        F: FnOnce(&ServerContext) -> Result<(), IpconError> + Send + 'static,
+    {
+        self.on_stop.push(Box::new(f));
+        self
+    }
+
+    /// Run the handlers on a `futures` thread pool.
+    pub fn thread_pool(mut self, pool: ThreadPool) -> ServerBuilder {
+        self.executor = Some(ServerExecutor::ThreadPool(pool));
+        self
+    }
+
+    /// Run the handlers on a tokio runtime.
+    pub fn tokio(mut self, handle: tokio::runtime::Handle) -> ServerBuilder {
+        self.executor = Some(ServerExecutor::Tokio(handle));
+        self
+    }
+
+    /// Set the capacity of the mailbox of each handler. The receive loop waits when a mailbox
+    /// is full.
+    pub fn mailbox_capacity(mut self, capacity: usize) -> ServerBuilder {
+        self.mailbox_capacity = capacity;
+        self
+    }
+
+    /// Build the server. If no executor is set, a thread pool is created.
+    pub fn build(self) -> Result<Server, IpconError> {
+        let executor = match self.executor {
+            Some(executor) => executor,
+            None => ServerExecutor::ThreadPool(
+                ThreadPool::new()
+                    .map_err(|e| Report::new(IpconError::SystemErrorOther).attach_printable(e))
+                    .attach_printable("Failed to create thread pool")?,
+            ),
+        };
+
+        let handle = ServerHandle {
+            shutdown: self.ipcon.shutdown_handle(),
+        };
+
+        Ok(Server {
+            ctx: ServerContext {
+                ipcon: Arc::new(self.ipcon),
+                handle,
+            },
+            routes: self.routes,
+            on_start: self.on_start,
+            on_stop: self.on_stop,
+            executor,
+            mailbox_capacity: self.mailbox_capacity,
+        })
+    }
+}
+
+/// IPCON server dispatching the received messages to handlers.
+pub struct Server {
+    ctx: ServerContext,
+    routes: Vec<Route>,
+    on_start: Vec<Hook>,
+    on_stop: Vec<Hook>,
+    executor: ServerExecutor,
+    mailbox_capacity: usize,
+}
+
+impl Server {
+    /// Get the handle to shut the server down.
+    pub fn handle(&self) -> ServerHandle {
+        self.ctx.handle.clone()
+    }
+
+    /// Get the peer of the server.
+    pub fn ipcon(&self) -> &Ipcon {
+        &self.ctx.ipcon
+    }
+
+    /* Wait for room in the mailbox, unless the server is shut down meanwhile. */
+    fn deliver(shutdown: &ShutdownHandle, mailbox: &mut mpsc::Sender<Item>, item: Item) {
+        let send = pin!(mailbox.send(item));
+        let cancelled = pin!(shutdown.cancelled());
+
+        match futures::executor::block_on(futures::future::select(send, cancelled)) {
+            futures::future::Either::Left((Ok(()), _)) => {}
+            futures::future::Either::Left((Err(_), _)) => {
+                jwarn!("Handler is gone, message dropped")
+            }
+            futures::future::Either::Right(_) => jdebug!("Server is shut down, message dropped"),
+        }
+    }
+
+    /// Receive and dispatch the messages until shutdown.
+    /// It blocks the calling thread, on tokio it should be called with
+    /// `tokio::task::spawn_blocking()`.
+    pub fn run(self) -> Result<(), IpconError> {
+        let ctx = self.ctx;
+
+        for hook in self.on_start {
+            hook(&ctx).attach_printable("Server start hook failed")?;
+        }
+
+        let running = Arc::new(Running::default());
+        let mut mailboxes = Vec::with_capacity(self.routes.len());
+
+        for route in self.routes {
+            let (tx, mut rx) = mpsc::channel::<Item>(self.mailbox_capacity);
+            let handler = route.handler;
+            let ctx = ctx.clone();
+
+            *running.count.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+            let guard = RunningGuard(running.clone());
+
+            self.executor.spawn(async move {
+                let _guard = guard;
+                while let Some(item) = rx.next().await {
+                    if let Err(e) = handler(ctx.clone(), item).await {
+                        jwarn!("Handler failed: {:?}", e);
+                    }
+                }
+            });
+
+            mailboxes.push((route.filter, tx));
+        }
+
+        let shutdown = &ctx.handle.shutdown;
+
+        loop {
+            let msg = match ctx.ipcon.receive_msg() {
+                Ok(msg) => msg,
+                Err(e) => {
+                    if *e.current_context() == IpconError::Cancelled {
+                        break;
+                    }
+
+                    jwarn!("Server receive failed: {:?}", e);
+                    std::thread::sleep(RECEIVE_ERROR_BACKOFF);
+                    continue;
+                }
+            };
+
+            match msg {
+                IpconMsg::IpconMsgUser(body) => {
+                    match mailboxes
+                        .iter_mut()
+                        .find(|(f, _)| f.as_ref().is_some_and(|f| f.matches(&body)))
+                    {
+                        Some((_, mailbox)) => Server::deliver(shutdown, mailbox, Item::Msg(body)),
+                        None => jdebug!("No handler for message from {}", body.peer),
+                    }
+                }
+                IpconMsg::IpconMsgKevent(kevent) => {
+                    for (_, mailbox) in mailboxes.iter_mut().filter(|(f, _)| f.is_none()) {
+                        Server::deliver(shutdown, mailbox, Item::Kevent(kevent));
+                    }
+                }
+                IpconMsg::IpconMsgInvalid => {}
+            }
+        }
+
+        /* Closing the mailboxes lets the handlers drain them and end. */
+        drop(mailboxes);
+        running.wait();
+
+        for hook in self.on_stop {
+            if let Err(e) = hook(&ctx) {
+                jwarn!("Server stop hook failed: {:?}", e);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipcon::IPF_DEFAULT;
+    use crate::ipcon_loopback::LoopbackBus;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Instant;
+
+    fn wait_for<F: Fn() -> bool>(f: F) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !f() {
+            assert!(Instant::now() < deadline, "Timed out");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn drains_at_shutdown<F: FnOnce(ServerBuilder) -> ServerBuilder>(executor: F) {
+        let bus = LoopbackBus::new();
+        let peer = bus.peer(Some("server"), Some(IPF_DEFAULT)).unwrap();
+        let client = bus.peer(Some("client"), Some(IPF_DEFAULT)).unwrap();
+
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let stopped = Arc::new(Mutex::new(None));
+        let h = handled.clone();
+        let (h2, s) = (handled.clone(), stopped.clone());
+
+        let builder = ServerBuilder::new(peer)
+            .on(MsgFilter::any(), move |_, body| {
+                let h = h.clone();
+                async move {
+                    std::thread::sleep(Duration::from_millis(10));
+                    h.lock().unwrap().push(body.buf);
+                    Ok(())
+                }
+            })
+            .on_stop(move |_| {
+                *s.lock().unwrap() = Some(h2.lock().unwrap().len());
+                Ok(())
+            });
+        let server = executor(builder).build().unwrap();
+        let handle = server.handle();
+        let runner = std::thread::spawn(move || server.run());
+
+        for i in 0..8_u8 {
+            client.send_unicast_msg("server", &[i]).unwrap();
+        }
+
+        /* The receive loop takes the messages long before the slow handler is done. */
+        wait_for(|| !handled.lock().unwrap().is_empty());
+        handle.shutdown();
+        runner.join().unwrap().unwrap();
+
+        let expected: Vec<Vec<u8>> = (0..8_u8).map(|i| vec![i]).collect();
+        assert_eq!(*handled.lock().unwrap(), expected);
+        assert_eq!(*stopped.lock().unwrap(), Some(8));
+    }
+
+    #[test]
+    fn thread_pool_drains_at_shutdown() {
+        drains_at_shutdown(|b| b.thread_pool(ThreadPool::new().unwrap()));
+    }
+
+    #[test]
+    fn tokio_drains_at_shutdown() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        drains_at_shutdown(|b| b.tokio(rt.handle().clone()));
+    }
+
+    #[test]
+    fn shutdown_cancels_delivery_to_full_mailbox() {
+        let bus = LoopbackBus::new();
+        let peer = bus.peer(Some("server"), Some(IPF_DEFAULT)).unwrap();
+        let client = bus.peer(Some("client"), Some(IPF_DEFAULT)).unwrap();
+
+        let handled = Arc::new(Mutex::new(0));
+        let started = Arc::new(AtomicBool::new(false));
+        let gate = Arc::new(AtomicBool::new(false));
+        let (h, st, g) = (handled.clone(), started.clone(), gate.clone());
+
+        /* A mailbox of capacity 1 holds 2 messages, one per sender plus the buffer. */
+        let server = ServerBuilder::new(peer)
+            .mailbox_capacity(1)
+            .on(MsgFilter::any(), move |_, _| {
+                let (h, st, g) = (h.clone(), st.clone(), g.clone());
+                async move {
+                    st.store(true, Ordering::SeqCst);
+                    while !g.load(Ordering::SeqCst) {
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
+                    *h.lock().unwrap() += 1;
+                    Ok(())
+                }
+            })
+            .build()
+            .unwrap();
+        let handle = server.handle();
+        let runner = std::thread::spawn(move || server.run());
+
+        for i in 0..5_u8 {
+            client.send_unicast_msg("server", &[i]).unwrap();
+        }
+
+        wait_for(|| started.load(Ordering::SeqCst));
+        std::thread::sleep(Duration::from_millis(100));
+        handle.shutdown();
+        std::thread::sleep(Duration::from_millis(100));
+        gate.store(true, Ordering::SeqCst);
+        runner.join().unwrap().unwrap();
+
+        /* The message waiting for room is dropped, the next ones are never received. */
+        assert_eq!(*handled.lock().unwrap(), 3);
+    }
+}
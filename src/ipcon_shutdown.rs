@@ -0,0 +1,184 @@
+//! # Shutdown and cancellation
+//! A thread blocked in Ipcon::receive_msg() can only be woken up by a message. Every Ipcon owns
+//! a ShutdownHandle, an eventfd polled together with the read fd of the peer while receiving:
+//!
+//! ```ignore
+//! let handle = ipcon.shutdown_handle();
+//! std::thread::spawn(move || {
+//!     wait_for_signal();
+//!     handle.shutdown();
+//! });
+//!
+//! loop {
+//!     match ipcon.receive_msg() {
+//!         Ok(msg) => handle_msg(msg),
+//!         Err(e) if *e.current_context() == IpconError::Cancelled => break,
+//!         Err(e) => jwarn!("{:?}", e),
+//!     }
+//! }
+//! ```
+//!
+//! Once the handle is shut down, the receives in progress and all the following ones fail with
+//! IpconError::Cancelled. Sending is still possible, so that the pending work can be finished.
+//! Ipcon::close() shuts the handle down and waits until no other thread is inside a libipcon
+//! call, then every operation of the peer fails with IpconError::Cancelled.
+//!
+//! Async code can wait for the shutdown with ShutdownHandle::cancelled().
+
+use crate::ipcon_error::IpconError;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::eventfd::{eventfd, EfdFlags};
+use std::fmt;
+use std::future::Future;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+use std::time::{Duration, Instant};
+#[allow(unused)]
+use {
+    error_stack::{Report, Result, ResultExt},
+    jlogger_tracing::{jdebug, jerror, jinfo, jtrace, jwarn},
+};
+
+struct ShutdownInner {
+    fd: OwnedFd,
+    triggered: AtomicBool,
+    /* Tasks waiting in cancelled(). */
+    wakers: Mutex<Vec<Waker>>,
+}
+
+/// Handle cancelling the blocking receives of a peer.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    inner: Arc<ShutdownInner>,
+}
+
+impl fmt::Debug for ShutdownHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShutdownHandle")
+            .field("fd", &self.inner.fd.as_raw_fd())
+            .field("triggered", &self.is_shutdown())
+            .finish()
+    }
+}
+
+impl ShutdownHandle {
+    /// Create a handle.
+    pub fn new() -> Result<ShutdownHandle, IpconError> {
+        let fd = eventfd(0, EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK)
+            .map_err(|e| Report::new(IpconError::SystemErrorOther).attach_printable(e))
+            .attach_printable("Failed to create shutdown eventfd")?;
+
+        Ok(ShutdownHandle {
+            inner: Arc::new(ShutdownInner {
+                fd: unsafe { OwnedFd::from_raw_fd(fd) },
+                triggered: AtomicBool::new(false),
+                wakers: Mutex::new(Vec::new()),
+            }),
+        })
+    }
+
+    /// Shut down: wake up the receives in progress and make them fail with Cancelled.
+    /// The handle stays shut down.
+    pub fn shutdown(&self) {
+        if self.inner.triggered.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        /* The counter is never read back, the eventfd stays readable for every waiter. */
+        if let Err(e) = nix::unistd::write(self.inner.fd.as_raw_fd(), &1_u64.to_ne_bytes()) {
+            jwarn!("Failed to signal shutdown: {}", e);
+        }
+
+        let wakers =
+            std::mem::take(&mut *self.inner.wakers.lock().unwrap_or_else(|e| e.into_inner()));
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    /// Wait until the handle is shut down.
+    pub fn cancelled(&self) -> impl Future<Output = ()> + '_ {
+        std::future::poll_fn(move |cx| {
+            if self.is_shutdown() {
+                return Poll::Ready(());
+            }
+
+            /* Checked again with the lock held, shutdown() takes the wakers after setting it. */
+            let mut wakers = self.inner.wakers.lock().unwrap_or_else(|e| e.into_inner());
+            if self.is_shutdown() {
+                return Poll::Ready(());
+            }
+
+            if !wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                wakers.push(cx.waker().clone());
+            }
+            Poll::Pending
+        })
+    }
+
+    /// Whether the handle is shut down.
+    pub fn is_shutdown(&self) -> bool {
+        self.inner.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Fail with Cancelled if the handle is shut down.
+    pub fn check(&self) -> Result<(), IpconError> {
+        if self.is_shutdown() {
+            Err(Report::new(IpconError::Cancelled)).attach_printable("Shutdown requested")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Wait until fd is readable or the timeout expires, without timeout if None.
+    /// It fails with Cancelled if the handle is shut down before.
+    pub fn wait_readable(&self, fd: RawFd, timeout: Option<Duration>) -> Result<(), IpconError> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+
+        loop {
+            self.check()?;
+
+            let ms = match deadline {
+                Some(d) => {
+                    /* Round up, so that a short timeout doesn't become a busy loop. */
+                    let left = d.saturating_duration_since(Instant::now());
+                    let ms = left.as_micros().div_ceil(1000);
+                    ms.min(libc::c_int::MAX as u128) as libc::c_int
+                }
+                None => -1,
+            };
+
+            let mut fds = [
+                PollFd::new(fd, PollFlags::POLLIN),
+                PollFd::new(self.inner.fd.as_raw_fd(), PollFlags::POLLIN),
+            ];
+
+            match poll(&mut fds, ms) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {
+                    self.check()?;
+                    return Ok(());
+                }
+                Err(nix::errno::Errno::EINTR) => continue,
+                Err(e) => {
+                    return Err(Report::new(IpconError::SystemErrorOther))
+                        .attach_printable(format!("poll() failed: {}", e))
+                }
+            }
+        }
+    }
+}
+
+impl AsFd for ShutdownHandle {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.fd.as_fd()
+    }
+}
+
+impl AsRawFd for ShutdownHandle {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.fd.as_raw_fd()
+    }
+}
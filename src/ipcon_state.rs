@@ -0,0 +1,649 @@
+//! # State group
+//! A state group is a multicast group whose owner keeps the latest value of every key. A peer
+//! which joins the group late sends a unicast sync request to the owner and receives a snapshot
+//! of the current values, then applies the incremental updates multicasted to the group.
+//!
+//! Every update carries the epoch of the owner and a sequence number in a SeqHeader. A subscriber
+//! which detects a gap in the sequence numbers, or a new epoch after the owner restarted,
+//! requests a new snapshot automatically. The request is sent again if the snapshot does not
+//! arrive within the sync timeout.
+
+use crate::ipcon::{valid_name, Ipcon};
+use crate::ipcon_error::IpconError;
+use crate::ipcon_msg::{IpconMsg, IpconMsgBody, IpconMsgType};
+use crate::ipcon_seq::{SeqHeader, SEQ_MAX_PAYLOAD_LEN};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+#[allow(unused)]
+use {
+    error_stack::{Report, Result, ResultExt},
+    jlogger_tracing::{jdebug, jerror, jinfo, jtrace, jwarn},
+};
+
+const STATE_MAGIC: u8 = 0x53;
+const STATE_KIND_SYNC_REQUEST: u8 = 1;
+const STATE_KIND_SNAPSHOT_BEGIN: u8 = 2;
+const STATE_KIND_SNAPSHOT_ENTRY: u8 = 3;
+const STATE_KIND_SNAPSHOT_END: u8 = 4;
+const STATE_KIND_UPDATE: u8 = 5;
+const STATE_KIND_REMOVE: u8 = 6;
+
+/// magic(1) + kind(1) + group length(1) + key length(2), following the sequence header.
+const STATE_HEADER_LEN: usize = 5;
+
+/// Default time to wait for a snapshot before the sync request is sent again.
+pub const STATE_SYNC_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Decoded state group message.
+/// Every message carries the group it belongs to, so that the snapshots of several state groups
+/// owned by the same peer are not mixed up.
+struct StateFrame<'a> {
+    kind: u8,
+    header: SeqHeader,
+    group: &'a str,
+    key: &'a str,
+    value: &'a [u8],
+}
+
+impl<'a> StateFrame<'a> {
+    fn encode(&self) -> Result<Vec<u8>, IpconError> {
+        let len = STATE_HEADER_LEN + self.group.len() + self.key.len() + self.value.len();
+        if len > SEQ_MAX_PAYLOAD_LEN {
+            return Err(Report::new(IpconError::InvalidData)).attach_printable(format!(
+                "State entry `{}` is too large {} > {}",
+                self.key, len, SEQ_MAX_PAYLOAD_LEN
+            ));
+        }
+
+        let mut buf = Vec::with_capacity(len);
+        buf.push(STATE_MAGIC);
+        buf.push(self.kind);
+        buf.push(self.group.len() as u8);
+        buf.extend_from_slice(&(self.key.len() as u16).to_be_bytes());
+        buf.extend_from_slice(self.group.as_bytes());
+        buf.extend_from_slice(self.key.as_bytes());
+        buf.extend_from_slice(self.value);
+
+        self.header.encode(&buf)
+    }
+
+    fn decode(buf: &'a [u8]) -> Option<StateFrame<'a>> {
+        let (header, buf) = SeqHeader::decode(buf).ok()?;
+        if buf.len() < STATE_HEADER_LEN || buf[0] != STATE_MAGIC {
+            return None;
+        }
+
+        let kind = buf[1];
+        let group_end = STATE_HEADER_LEN + buf[2] as usize;
+        let key_end = group_end + u16::from_be_bytes([buf[3], buf[4]]) as usize;
+        let group = std::str::from_utf8(buf.get(STATE_HEADER_LEN..group_end)?).ok()?;
+        let key = std::str::from_utf8(buf.get(group_end..key_end)?).ok()?;
+
+        Some(StateFrame {
+            kind,
+            header,
+            group,
+            key,
+            value: &buf[key_end..],
+        })
+    }
+}
+
+/// Change applied to the local copy of a state group.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StateChange {
+    /// A snapshot has been applied, the local copy is up to date.
+    Synced,
+    /// The value of the key has been set.
+    Updated(String),
+    /// The key has been removed.
+    Removed(String),
+    /// A gap or a new owner epoch was detected and a new snapshot has been requested.
+    Resync,
+}
+
+/// Owner side of a state group.
+/// The owner must have registered the group with Ipcon::register_group(), and must feed every
+/// received message to handle_msg() so that sync requests from subscribers are answered.
+pub struct StateGroupOwner<'a> {
+    ipcon: &'a Ipcon,
+    group: String,
+    epoch: u64,
+    seq: u64,
+    values: BTreeMap<String, Vec<u8>>,
+}
+
+impl<'a> StateGroupOwner<'a> {
+    /// Create the owner of the state group `group` of the peer `ipcon`.
+    pub fn new(ipcon: &'a Ipcon, group: &str) -> Result<StateGroupOwner<'a>, IpconError> {
+        valid_name(group).attach_printable(format!("Invalid group name: {}", group))?;
+
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+
+        Ok(StateGroupOwner {
+            ipcon,
+            group: group.to_owned(),
+            epoch,
+            seq: 0,
+            values: BTreeMap::new(),
+        })
+    }
+
+    /// Get the current value of a key.
+    pub fn get(&self, key: &str) -> Option<&[u8]> {
+        self.values.get(key).map(|v| v.as_slice())
+    }
+
+    /// Set the value of a key and multicast the update to the group.
+    pub fn set(&mut self, key: &str, value: &[u8]) -> Result<(), IpconError> {
+        let buf = StateFrame {
+            kind: STATE_KIND_UPDATE,
+            header: self.header(self.seq + 1),
+            group: &self.group,
+            key,
+            value,
+        }
+        .encode()?;
+
+        self.seq += 1;
+        self.values.insert(key.to_owned(), value.to_vec());
+        self.ipcon
+            .send_multicast(&self.group, &buf, false)
+            .attach_printable(format!("Failed to update `{}` of state group", key))
+    }
+
+    /// Remove a key and multicast the removal to the group.
+    pub fn remove(&mut self, key: &str) -> Result<(), IpconError> {
+        if !self.values.contains_key(key) {
+            return Ok(());
+        }
+
+        let buf = StateFrame {
+            kind: STATE_KIND_REMOVE,
+            header: self.header(self.seq + 1),
+            group: &self.group,
+            key,
+            value: &[],
+        }
+        .encode()?;
+
+        self.seq += 1;
+        self.values.remove(key);
+        self.ipcon
+            .send_multicast(&self.group, &buf, false)
+            .attach_printable(format!("Failed to remove `{}` from state group", key))
+    }
+
+    /// Handle a received message.
+    /// If the message is a sync request for this group, a snapshot is sent back to the requester
+    /// and true is returned. Otherwise the message is left to the caller and false is returned.
+    pub fn handle_msg(&self, msg: &IpconMsg) -> Result<bool, IpconError> {
+        let body = match msg {
+            IpconMsg::IpconMsgUser(body) if body.msg_type == IpconMsgType::IpconMsgTypeNormal => {
+                body
+            }
+            _ => return Ok(false),
+        };
+
+        match StateFrame::decode(&body.buf) {
+            Some(frame) if frame.kind == STATE_KIND_SYNC_REQUEST && frame.group == self.group => {}
+            _ => return Ok(false),
+        }
+
+        self.send_snapshot(&body.peer)?;
+        Ok(true)
+    }
+
+    fn header(&self, seq: u64) -> SeqHeader {
+        SeqHeader {
+            epoch: self.epoch,
+            seq,
+        }
+    }
+
+    fn send_snapshot(&self, peer: &str) -> Result<(), IpconError> {
+        jdebug!(
+            group = self.group,
+            peer = peer,
+            seq = self.seq,
+            "Send snapshot"
+        );
+
+        let mut frame = StateFrame {
+            kind: STATE_KIND_SNAPSHOT_BEGIN,
+            header: self.header(self.seq),
+            group: &self.group,
+            key: "",
+            value: &[],
+        };
+        self.ipcon.send_unicast_msg(peer, &frame.encode()?)?;
+
+        frame.kind = STATE_KIND_SNAPSHOT_ENTRY;
+        for (key, value) in &self.values {
+            frame.key = key;
+            frame.value = value;
+            self.ipcon.send_unicast_msg(peer, &frame.encode()?)?;
+        }
+
+        frame.kind = STATE_KIND_SNAPSHOT_END;
+        frame.key = "";
+        frame.value = &[];
+        self.ipcon.send_unicast_msg(peer, &frame.encode()?)
+    }
+}
+
+/// An update received before the snapshot is completed.
+struct PendingUpdate {
+    kind: u8,
+    header: SeqHeader,
+    key: String,
+    value: Vec<u8>,
+}
+
+/// Subscriber side of a state group.
+/// All received messages should be fed to handle_msg(), which keeps the local copy of the group
+/// up to date. The sync request is sent again if no snapshot is received within the sync timeout,
+/// check_sync_timeout() should be called periodically when no message is received.
+pub struct StateGroupSubscriber<'a> {
+    ipcon: &'a Ipcon,
+    peer: String,
+    group: String,
+    epoch: Option<u64>,
+    seq: u64,
+    syncing: bool,
+    sync_timeout: Duration,
+    sync_deadline: Option<Instant>,
+    pending: Vec<PendingUpdate>,
+    snapshot: BTreeMap<String, Vec<u8>>,
+    values: BTreeMap<String, Vec<u8>>,
+}
+
+impl<'a> StateGroupSubscriber<'a> {
+    /// Create a subscriber of the state group `group` owned by `peer`.
+    /// start() should be called to join the group and request the first snapshot.
+    pub fn new(
+        ipcon: &'a Ipcon,
+        peer: &str,
+        group: &str,
+    ) -> Result<StateGroupSubscriber<'a>, IpconError> {
+        valid_name(peer).attach_printable(format!("Invalid peer name: {}", peer))?;
+        valid_name(group).attach_printable(format!("Invalid group name: {}", group))?;
+
+        Ok(StateGroupSubscriber {
+            ipcon,
+            peer: peer.to_owned(),
+            group: group.to_owned(),
+            epoch: None,
+            seq: 0,
+            syncing: false,
+            sync_timeout: STATE_SYNC_TIMEOUT,
+            sync_deadline: None,
+            pending: Vec::new(),
+            snapshot: BTreeMap::new(),
+            values: BTreeMap::new(),
+        })
+    }
+
+    /// Set the time to wait for a snapshot before the sync request is sent again.
+    /// STATE_SYNC_TIMEOUT is used by default.
+    pub fn set_sync_timeout(&mut self, timeout: Duration) {
+        self.sync_timeout = timeout;
+    }
+
+    /// Join the group and request a snapshot from the owner.
+    pub fn start(&mut self) -> Result<(), IpconError> {
+        self.ipcon.join_group(&self.peer, &self.group)?;
+        self.request_sync()
+    }
+
+    /// Whether the local copy is in sync with the owner.
+    pub fn is_synced(&self) -> bool {
+        self.epoch.is_some() && !self.syncing
+    }
+
+    /// Get the current value of a key.
+    pub fn get(&self, key: &str) -> Option<&[u8]> {
+        self.values.get(key).map(|v| v.as_slice())
+    }
+
+    /// Get all the current values.
+    pub fn values(&self) -> &BTreeMap<String, Vec<u8>> {
+        &self.values
+    }
+
+    /// Request a new snapshot from the owner.
+    /// If sending the request fails, it is sent again when the sync timeout expires.
+    pub fn request_sync(&mut self) -> Result<(), IpconError> {
+        let buf = StateFrame {
+            kind: STATE_KIND_SYNC_REQUEST,
+            header: SeqHeader { epoch: 0, seq: 0 },
+            group: &self.group,
+            key: "",
+            value: &[],
+        }
+        .encode()?;
+
+        self.syncing = true;
+        self.sync_deadline = Some(Instant::now() + self.sync_timeout);
+        self.pending.clear();
+        self.snapshot.clear();
+        self.ipcon
+            .send_unicast_msg(&self.peer, &buf)
+            .attach_printable(format!(
+                "Failed to request snapshot of `{}@{}`",
+                self.group, self.peer
+            ))
+    }
+
+    /// Send the sync request again if the snapshot has not been received in time.
+    /// Returns StateChange::Resync if a new request has been sent.
+    pub fn check_sync_timeout(&mut self) -> Result<Option<StateChange>, IpconError> {
+        match self.sync_deadline {
+            Some(deadline) if self.syncing && Instant::now() >= deadline => {
+                jwarn!(
+                    group = self.group,
+                    peer = self.peer,
+                    "Snapshot not received, request it again"
+                );
+                self.request_sync()?;
+                Ok(Some(StateChange::Resync))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Handle a received message.
+    /// Returns the changes applied to the local copy. Messages which do not belong to the state
+    /// group are ignored and an empty list is returned.
+    pub fn handle_msg(&mut self, msg: &IpconMsg) -> Result<Vec<StateChange>, IpconError> {
+        let mut changes: Vec<StateChange> = self.check_sync_timeout()?.into_iter().collect();
+
+        let body = match msg {
+            IpconMsg::IpconMsgUser(body) if body.peer == self.peer => body,
+            _ => return Ok(changes),
+        };
+
+        let frame = match StateFrame::decode(&body.buf) {
+            Some(frame) if frame.group == self.group => frame,
+            _ => return Ok(changes),
+        };
+
+        match body.msg_type {
+            IpconMsgType::IpconMsgTypeGroup if self.is_group_msg(body) => {
+                self.handle_update(frame, &mut changes)?
+            }
+            IpconMsgType::IpconMsgTypeNormal => self.handle_snapshot(frame, &mut changes)?,
+            _ => {}
+        }
+
+        Ok(changes)
+    }
+
+    fn is_group_msg(&self, body: &IpconMsgBody) -> bool {
+        body.group.as_deref() == Some(self.group.as_str())
+    }
+
+    fn handle_update(
+        &mut self,
+        frame: StateFrame,
+        changes: &mut Vec<StateChange>,
+    ) -> Result<(), IpconError> {
+        if frame.kind != STATE_KIND_UPDATE && frame.kind != STATE_KIND_REMOVE {
+            return Ok(());
+        }
+
+        if self.syncing {
+            self.pending.push(PendingUpdate {
+                kind: frame.kind,
+                header: frame.header,
+                key: frame.key.to_owned(),
+                value: frame.value.to_vec(),
+            });
+            return Ok(());
+        }
+
+        self.apply_update(frame.kind, frame.header, frame.key, frame.value, changes)
+    }
+
+    fn apply_update(
+        &mut self,
+        kind: u8,
+        header: SeqHeader,
+        key: &str,
+        value: &[u8],
+        changes: &mut Vec<StateChange>,
+    ) -> Result<(), IpconError> {
+        if self.epoch == Some(header.epoch) && header.seq <= self.seq {
+            /* Already included in the snapshot. */
+            return Ok(());
+        }
+
+        if self.epoch != Some(header.epoch) || header.seq != self.seq + 1 {
+            jwarn!(
+                group = self.group,
+                peer = self.peer,
+                expected = self.seq + 1,
+                received = header.seq,
+                "State group out of sync"
+            );
+            changes.push(StateChange::Resync);
+            return self.request_sync();
+        }
+
+        self.seq = header.seq;
+        if kind == STATE_KIND_UPDATE {
+            self.values.insert(key.to_owned(), value.to_vec());
+            changes.push(StateChange::Updated(key.to_owned()));
+        } else if self.values.remove(key).is_some() {
+            changes.push(StateChange::Removed(key.to_owned()));
+        }
+
+        Ok(())
+    }
+
+    fn handle_snapshot(
+        &mut self,
+        frame: StateFrame,
+        changes: &mut Vec<StateChange>,
+    ) -> Result<(), IpconError> {
+        if !self.syncing {
+            return Ok(());
+        }
+
+        match frame.kind {
+            STATE_KIND_SNAPSHOT_BEGIN => self.snapshot.clear(),
+            STATE_KIND_SNAPSHOT_ENTRY => {
+                self.snapshot
+                    .insert(frame.key.to_owned(), frame.value.to_vec());
+            }
+            STATE_KIND_SNAPSHOT_END => {
+                self.values = std::mem::take(&mut self.snapshot);
+                self.epoch = Some(frame.header.epoch);
+                self.seq = frame.header.seq;
+                self.syncing = false;
+                self.sync_deadline = None;
+
+                changes.push(StateChange::Synced);
+                for update in std::mem::take(&mut self.pending) {
+                    self.apply_update(
+                        update.kind,
+                        update.header,
+                        &update.key,
+                        &update.value,
+                        changes,
+                    )?;
+
+                    if self.syncing {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipcon::IPF_DEFAULT;
+    use crate::ipcon_loopback::LoopbackBus;
+
+    fn recv(ipcon: &Ipcon) -> IpconMsg {
+        ipcon.receive_msg_timeout(1, 0).unwrap()
+    }
+
+    /* Feed the subscriber until it has consumed `count` messages. */
+    fn feed(sub: &mut StateGroupSubscriber, ipcon: &Ipcon, count: usize) -> Vec<StateChange> {
+        let mut changes = Vec::new();
+        for _ in 0..count {
+            changes.extend(sub.handle_msg(&recv(ipcon)).unwrap());
+        }
+        changes
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let frame = StateFrame {
+            kind: STATE_KIND_UPDATE,
+            header: SeqHeader { epoch: 7, seq: 3 },
+            group: "state",
+            key: "key",
+            value: b"value",
+        };
+        let buf = frame.encode().unwrap();
+        assert_eq!(SeqHeader::decode(&buf).unwrap().0, frame.header);
+
+        let decoded = StateFrame::decode(&buf).unwrap();
+        assert_eq!(decoded.kind, STATE_KIND_UPDATE);
+        assert_eq!(decoded.header, frame.header);
+        assert_eq!(decoded.group, "state");
+        assert_eq!(decoded.key, "key");
+        assert_eq!(decoded.value, b"value");
+
+        assert!(StateFrame::decode(&buf[..buf.len() - 6]).is_none());
+        assert!(StateFrame::decode(b"not a state frame").is_none());
+
+        let large = vec![0_u8; SEQ_MAX_PAYLOAD_LEN];
+        let frame = StateFrame {
+            value: &large,
+            ..frame
+        };
+        assert!(frame.encode().is_err());
+    }
+
+    #[test]
+    fn snapshot_then_updates() {
+        let bus = LoopbackBus::new();
+        let owner_ipcon = bus.peer(Some("owner"), Some(IPF_DEFAULT)).unwrap();
+        let sub_ipcon = bus.peer(Some("sub"), Some(IPF_DEFAULT)).unwrap();
+        owner_ipcon.register_group("state").unwrap();
+
+        let mut owner = StateGroupOwner::new(&owner_ipcon, "state").unwrap();
+        owner.set("a", b"1").unwrap();
+        owner.set("b", b"2").unwrap();
+
+        let mut sub = StateGroupSubscriber::new(&sub_ipcon, "owner", "state").unwrap();
+        sub.start().unwrap();
+        assert!(!sub.is_synced());
+        assert!(owner.handle_msg(&recv(&owner_ipcon)).unwrap());
+
+        /* Begin, two entries and end. */
+        assert_eq!(feed(&mut sub, &sub_ipcon, 4), vec![StateChange::Synced]);
+        assert!(sub.is_synced());
+        assert_eq!(sub.get("a"), Some(&b"1"[..]));
+        assert_eq!(sub.get("b"), Some(&b"2"[..]));
+
+        owner.set("a", b"3").unwrap();
+        owner.remove("b").unwrap();
+        assert_eq!(
+            feed(&mut sub, &sub_ipcon, 2),
+            vec![
+                StateChange::Updated("a".to_owned()),
+                StateChange::Removed("b".to_owned())
+            ]
+        );
+        assert_eq!(sub.get("a"), Some(&b"3"[..]));
+        assert_eq!(sub.get("b"), None);
+    }
+
+    #[test]
+    fn other_groups_are_ignored() {
+        let bus = LoopbackBus::new();
+        let owner_ipcon = bus.peer(Some("owner"), Some(IPF_DEFAULT)).unwrap();
+        let sub_ipcon = bus.peer(Some("sub"), Some(IPF_DEFAULT)).unwrap();
+        owner_ipcon.register_group("state").unwrap();
+        owner_ipcon.register_group("other").unwrap();
+
+        let mut owner = StateGroupOwner::new(&owner_ipcon, "state").unwrap();
+        let mut other = StateGroupOwner::new(&owner_ipcon, "other").unwrap();
+        owner.set("a", b"1").unwrap();
+        other.set("a", b"other").unwrap();
+
+        let mut sub = StateGroupSubscriber::new(&sub_ipcon, "owner", "state").unwrap();
+        sub.start().unwrap();
+        let request = recv(&owner_ipcon);
+        assert!(!other.handle_msg(&request).unwrap());
+
+        /* A snapshot of another group of the same owner is not applied. */
+        other.send_snapshot("sub").unwrap();
+        assert!(feed(&mut sub, &sub_ipcon, 3).is_empty());
+        assert!(!sub.is_synced());
+
+        assert!(owner.handle_msg(&request).unwrap());
+        assert_eq!(feed(&mut sub, &sub_ipcon, 3), vec![StateChange::Synced]);
+        assert_eq!(sub.get("a"), Some(&b"1"[..]));
+    }
+
+    #[test]
+    fn gap_requests_a_new_snapshot() {
+        let bus = LoopbackBus::new();
+        let owner_ipcon = bus.peer(Some("owner"), Some(IPF_DEFAULT)).unwrap();
+        let sub_ipcon = bus.peer(Some("sub"), Some(IPF_DEFAULT)).unwrap();
+        owner_ipcon.register_group("state").unwrap();
+
+        let mut owner = StateGroupOwner::new(&owner_ipcon, "state").unwrap();
+        let mut sub = StateGroupSubscriber::new(&sub_ipcon, "owner", "state").unwrap();
+        sub.start().unwrap();
+        assert!(owner.handle_msg(&recv(&owner_ipcon)).unwrap());
+        assert_eq!(feed(&mut sub, &sub_ipcon, 2), vec![StateChange::Synced]);
+
+        owner.set("a", b"1").unwrap();
+        owner.set("b", b"2").unwrap();
+        recv(&sub_ipcon);
+        assert_eq!(feed(&mut sub, &sub_ipcon, 1), vec![StateChange::Resync]);
+        assert!(!sub.is_synced());
+
+        assert!(owner.handle_msg(&recv(&owner_ipcon)).unwrap());
+        assert_eq!(feed(&mut sub, &sub_ipcon, 4), vec![StateChange::Synced]);
+        assert_eq!(sub.values().len(), 2);
+    }
+
+    #[test]
+    fn sync_request_is_sent_again() {
+        let bus = LoopbackBus::new();
+        let owner_ipcon = bus.peer(Some("owner"), Some(IPF_DEFAULT)).unwrap();
+        let sub_ipcon = bus.peer(Some("sub"), Some(IPF_DEFAULT)).unwrap();
+        owner_ipcon.register_group("state").unwrap();
+
+        let owner = StateGroupOwner::new(&owner_ipcon, "state").unwrap();
+        let mut sub = StateGroupSubscriber::new(&sub_ipcon, "owner", "state").unwrap();
+        sub.set_sync_timeout(Duration::from_millis(10));
+        sub.start().unwrap();
+
+        /* The first request is lost. */
+        recv(&owner_ipcon);
+        assert_eq!(sub.check_sync_timeout().unwrap(), None);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(sub.check_sync_timeout().unwrap(), Some(StateChange::Resync));
+
+        assert!(owner.handle_msg(&recv(&owner_ipcon)).unwrap());
+        assert_eq!(feed(&mut sub, &sub_ipcon, 2), vec![StateChange::Synced]);
+        assert_eq!(sub.check_sync_timeout().unwrap(), None);
+    }
+}
@@ -0,0 +1,613 @@
+//! # Peer statistics
+//! Every Ipcon peer counts the messages and bytes it sends and receives, per destination (or
+//! source) peer and per group, the send and receive errors by IpconError kind, the receive
+//! timeouts and the distribution of the payload sizes. A snapshot is returned by Ipcon::stats().
+//! At most STATS_MAX_REMOTES peers and STATS_MAX_REMOTES groups are counted separately, the
+//! traffic of the others is only counted in IpconStats::untracked.
+//!
+//! With the `metrics` feature, the same values are also reported through the `metrics` crate
+//! facade, labeled with the name of the peer, so that they can be picked up by any exporter
+//! installed by the application. The message and byte counters are labeled with the kind of
+//! traffic (unicast or multicast) only. Labeling them with the remote peer or group as well is
+//! enabled by Ipcon::set_metrics_per_remote(), the remotes beyond STATS_MAX_REMOTES are then
+//! labeled `other`. The metrics handles are registered the first time they are used
+//! and reused afterwards, so that reporting doesn't allocate the labels on every message. The
+//! recorder must therefore be installed before the peers are created.
+
+use crate::ipcon_error::IpconError;
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+#[cfg(feature = "metrics")]
+use {
+    metrics::{Counter, Histogram, SharedString},
+    std::sync::atomic::{AtomicBool, Ordering},
+    std::sync::Arc,
+};
+
+/// Maximum number of peers, and of groups, counted separately.
+pub const STATS_MAX_REMOTES: usize = 1024;
+
+/// Upper bounds (inclusive) of the payload size histogram buckets.
+/// The last bucket counts the payloads larger than the last bound.
+pub const PAYLOAD_SIZE_BUCKETS: [usize; 7] = [0, 16, 64, 256, 512, 1024, 2048];
+
+/// Distribution of payload sizes.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SizeHistogram {
+    /// Count of payloads of each bucket of PAYLOAD_SIZE_BUCKETS, plus the overflow bucket.
+    pub counts: [u64; PAYLOAD_SIZE_BUCKETS.len() + 1],
+    pub sum: u64,
+    pub min: Option<usize>,
+    pub max: Option<usize>,
+}
+
+impl SizeHistogram {
+    fn record(&mut self, size: usize) {
+        let i = PAYLOAD_SIZE_BUCKETS
+            .iter()
+            .position(|&b| size <= b)
+            .unwrap_or(PAYLOAD_SIZE_BUCKETS.len());
+
+        self.counts[i] += 1;
+        self.sum += size as u64;
+        self.min = Some(self.min.map_or(size, |m| m.min(size)));
+        self.max = Some(self.max.map_or(size, |m| m.max(size)));
+    }
+
+    /// Number of recorded payloads.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Average payload size.
+    pub fn mean(&self) -> Option<f64> {
+        match self.count() {
+            0 => None,
+            n => Some(self.sum as f64 / n as f64),
+        }
+    }
+}
+
+/// Traffic counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TrafficStats {
+    pub sent_msgs: u64,
+    pub sent_bytes: u64,
+    pub received_msgs: u64,
+    pub received_bytes: u64,
+}
+
+/// A multicast group, identified by the name of its owner and its name.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct GroupKey {
+    pub peer: String,
+    pub group: String,
+}
+
+/* Borrowed form of GroupKey, so that the counters of a group are found without allocating the
+ * key. Hash and Eq must be the same as the derived ones of GroupKey. */
+trait GroupKeyRef {
+    fn key(&self) -> (&str, &str);
+}
+
+impl GroupKeyRef for GroupKey {
+    fn key(&self) -> (&str, &str) {
+        (&self.peer, &self.group)
+    }
+}
+
+impl GroupKeyRef for (&str, &str) {
+    fn key(&self) -> (&str, &str) {
+        (self.0, self.1)
+    }
+}
+
+impl<'a> Borrow<dyn GroupKeyRef + 'a> for GroupKey {
+    fn borrow(&self) -> &(dyn GroupKeyRef + 'a) {
+        self
+    }
+}
+
+impl Hash for dyn GroupKeyRef + '_ {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let (peer, group) = self.key();
+        peer.hash(state);
+        group.hash(state);
+    }
+}
+
+impl PartialEq for dyn GroupKeyRef + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for dyn GroupKeyRef + '_ {}
+
+/// Snapshot of the statistics of an Ipcon peer.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IpconStats {
+    /// Totals of all the user messages.
+    pub total: TrafficStats,
+    /// Unicast messages sent to and received from each peer.
+    pub peers: HashMap<String, TrafficStats>,
+    /// Multicast messages sent to the owned groups and received from subscribed groups.
+    pub groups: HashMap<GroupKey, TrafficStats>,
+    /// Messages of the peers and groups seen after STATS_MAX_REMOTES were already counted.
+    pub untracked: TrafficStats,
+    /// Kernel event messages received.
+    pub kevents: u64,
+    /// Send errors by kind.
+    pub send_errors: HashMap<IpconError, u64>,
+    /// Receive errors by kind, timeouts excluded.
+    pub receive_errors: HashMap<IpconError, u64>,
+    /// receive_msg_timeout() calls which timed out.
+    pub receive_timeouts: u64,
+    /// Sizes of the sent payloads.
+    pub sent_sizes: SizeHistogram,
+    /// Sizes of the received payloads.
+    pub received_sizes: SizeHistogram,
+}
+
+/// Destination or source of a message.
+pub(crate) enum StatsTarget<'a> {
+    Peer(&'a str),
+    /// Group of another peer: (peer, group).
+    Group(&'a str, &'a str),
+    /// Group owned by this peer.
+    OwnedGroup(&'a str),
+}
+
+/* Messages and bytes counters of a remote peer or group, in one direction. */
+#[cfg(feature = "metrics")]
+struct TrafficMetrics {
+    messages: Counter,
+    bytes: Counter,
+}
+
+#[cfg(feature = "metrics")]
+impl TrafficMetrics {
+    fn new(
+        name: &SharedString,
+        remote: Option<String>,
+        kind: &'static str,
+        direction: &'static str,
+    ) -> Self {
+        let mut labels = vec![("peer", name.clone())];
+        if let Some(remote) = remote {
+            labels.push(("remote", SharedString::from(remote)));
+        }
+        labels.push(("kind", SharedString::from(kind)));
+        labels.push(("direction", SharedString::from(direction)));
+
+        TrafficMetrics {
+            messages: metrics::counter!("ipcon_messages_total", &labels),
+            bytes: metrics::counter!("ipcon_bytes_total", &labels),
+        }
+    }
+
+    fn record(&self, size: usize) {
+        self.messages.increment(1);
+        self.bytes.increment(size as u64);
+    }
+}
+
+#[cfg(feature = "metrics")]
+struct RemoteMetrics {
+    sent: TrafficMetrics,
+    received: TrafficMetrics,
+}
+
+#[cfg(feature = "metrics")]
+impl RemoteMetrics {
+    fn new(name: &SharedString, remote: Option<String>, kind: &'static str) -> RemoteMetrics {
+        RemoteMetrics {
+            sent: TrafficMetrics::new(name, remote.clone(), kind, "sent"),
+            received: TrafficMetrics::new(name, remote, kind, "received"),
+        }
+    }
+
+    fn record(&self, sent: bool, size: usize) {
+        if sent {
+            self.sent.record(size)
+        } else {
+            self.received.record(size)
+        }
+    }
+}
+
+/* Metrics handles labeled with the remote peers and groups, keyed as the counters of IpconStats. */
+#[cfg(feature = "metrics")]
+#[derive(Default)]
+struct MetricsHandles {
+    peers: HashMap<String, RemoteMetrics>,
+    groups: HashMap<GroupKey, RemoteMetrics>,
+    /* Keyed by (metric name, error kind). */
+    errors: HashMap<(&'static str, IpconError), Counter>,
+}
+
+#[cfg(feature = "metrics")]
+struct PeerMetrics {
+    name: SharedString,
+    per_remote: AtomicBool,
+    unicast: RemoteMetrics,
+    multicast: RemoteMetrics,
+    other_unicast: RemoteMetrics,
+    other_multicast: RemoteMetrics,
+    kevents: Counter,
+    receive_timeouts: Counter,
+    sent_sizes: Histogram,
+    received_sizes: Histogram,
+    handles: Mutex<MetricsHandles>,
+}
+
+#[cfg(feature = "metrics")]
+impl PeerMetrics {
+    fn new(name: &str) -> PeerMetrics {
+        let name = SharedString::from(Arc::<str>::from(name));
+
+        PeerMetrics {
+            per_remote: AtomicBool::new(false),
+            unicast: RemoteMetrics::new(&name, None, "unicast"),
+            multicast: RemoteMetrics::new(&name, None, "multicast"),
+            other_unicast: RemoteMetrics::new(&name, Some("other".to_owned()), "unicast"),
+            other_multicast: RemoteMetrics::new(&name, Some("other".to_owned()), "multicast"),
+            kevents: metrics::counter!("ipcon_kevents_received_total", "peer" => name.clone()),
+            receive_timeouts: metrics::counter!(
+                "ipcon_receive_timeouts_total",
+                "peer" => name.clone()
+            ),
+            sent_sizes: metrics::histogram!(
+                "ipcon_payload_size_bytes",
+                "peer" => name.clone(),
+                "direction" => "sent"
+            ),
+            received_sizes: metrics::histogram!(
+                "ipcon_payload_size_bytes",
+                "peer" => name.clone(),
+                "direction" => "received"
+            ),
+            handles: Mutex::new(MetricsHandles::default()),
+            name,
+        }
+    }
+
+    fn handles(&self) -> std::sync::MutexGuard<'_, MetricsHandles> {
+        self.handles.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn error(&self, metric: &'static str, e: IpconError) {
+        self.handles()
+            .errors
+            .entry((metric, e))
+            .or_insert_with(|| {
+                metrics::counter!(
+                    metric,
+                    "peer" => self.name.clone(),
+                    "kind" => format!("{:?}", e)
+                )
+            })
+            .increment(1);
+    }
+
+    fn traffic(&self, sent: bool, target: &StatsTarget, size: usize) {
+        if !self.per_remote.load(Ordering::Relaxed) {
+            return match target {
+                StatsTarget::Peer(_) => self.unicast.record(sent, size),
+                _ => self.multicast.record(sent, size),
+            };
+        }
+
+        let mut handles = self.handles();
+        let (peer, group) = match target {
+            StatsTarget::Peer(peer) => {
+                if !handles.peers.contains_key(*peer) {
+                    if handles.peers.len() >= STATS_MAX_REMOTES {
+                        return self.other_unicast.record(sent, size);
+                    }
+                    let m = RemoteMetrics::new(&self.name, Some(peer.to_string()), "unicast");
+                    handles.peers.insert(peer.to_string(), m);
+                }
+                return handles.peers[*peer].record(sent, size);
+            }
+            StatsTarget::Group(peer, group) => (*peer, *group),
+            StatsTarget::OwnedGroup(group) => (&*self.name, *group),
+        };
+
+        let key: &dyn GroupKeyRef = &(peer, group);
+        if !handles.groups.contains_key(key) {
+            if handles.groups.len() >= STATS_MAX_REMOTES {
+                return self.other_multicast.record(sent, size);
+            }
+            let remote = format!("{}@{}", group, peer);
+            handles.groups.insert(
+                GroupKey {
+                    peer: peer.to_string(),
+                    group: group.to_string(),
+                },
+                RemoteMetrics::new(&self.name, Some(remote), "multicast"),
+            );
+        }
+        handles.groups[key].record(sent, size);
+    }
+}
+
+/// Statistics collector of an Ipcon peer.
+pub(crate) struct StatsCollector {
+    name: String,
+    stats: Mutex<IpconStats>,
+    #[cfg(feature = "metrics")]
+    metrics: PeerMetrics,
+}
+
+impl StatsCollector {
+    pub(crate) fn new(name: Option<&str>) -> StatsCollector {
+        let name = name.unwrap_or("Anon");
+
+        StatsCollector {
+            name: name.to_owned(),
+            stats: Mutex::new(IpconStats::default()),
+            #[cfg(feature = "metrics")]
+            metrics: PeerMetrics::new(name),
+        }
+    }
+
+    fn update<F: FnOnce(&mut IpconStats)>(&self, f: F) {
+        let mut stats = self.stats.lock().unwrap_or_else(|e| e.into_inner());
+        f(&mut stats)
+    }
+
+    pub(crate) fn snapshot(&self) -> IpconStats {
+        self.stats.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    #[cfg(feature = "metrics")]
+    pub(crate) fn set_metrics_per_remote(&self, enable: bool) {
+        self.metrics.per_remote.store(enable, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reset(&self) {
+        self.update(|s| *s = IpconStats::default());
+    }
+
+    fn traffic<'a>(&self, s: &'a mut IpconStats, target: &StatsTarget) -> &'a mut TrafficStats {
+        /* The keys are only allocated the first time a peer or a group is seen. */
+        let (peer, group) = match target {
+            StatsTarget::Peer(peer) => {
+                if !s.peers.contains_key(*peer) {
+                    if s.peers.len() >= STATS_MAX_REMOTES {
+                        return &mut s.untracked;
+                    }
+                    s.peers.insert(peer.to_string(), TrafficStats::default());
+                }
+                return s.peers.get_mut(*peer).unwrap();
+            }
+            StatsTarget::Group(peer, group) => (*peer, *group),
+            StatsTarget::OwnedGroup(group) => (self.name.as_str(), *group),
+        };
+
+        let key: &dyn GroupKeyRef = &(peer, group);
+        if !s.groups.contains_key(key) {
+            if s.groups.len() >= STATS_MAX_REMOTES {
+                return &mut s.untracked;
+            }
+            s.groups.insert(
+                GroupKey {
+                    peer: peer.to_string(),
+                    group: group.to_string(),
+                },
+                TrafficStats::default(),
+            );
+        }
+        s.groups.get_mut(key).unwrap()
+    }
+
+    pub(crate) fn sent(&self, target: StatsTarget, size: usize) {
+        self.update(|s| {
+            let t = self.traffic(s, &target);
+            t.sent_msgs += 1;
+            t.sent_bytes += size as u64;
+            s.total.sent_msgs += 1;
+            s.total.sent_bytes += size as u64;
+            s.sent_sizes.record(size);
+        });
+
+        #[cfg(feature = "metrics")]
+        {
+            self.metrics.traffic(true, &target, size);
+            self.metrics.sent_sizes.record(size as f64);
+        }
+    }
+
+    pub(crate) fn received(&self, source: StatsTarget, size: usize) {
+        self.update(|s| {
+            let t = self.traffic(s, &source);
+            t.received_msgs += 1;
+            t.received_bytes += size as u64;
+            s.total.received_msgs += 1;
+            s.total.received_bytes += size as u64;
+            s.received_sizes.record(size);
+        });
+
+        #[cfg(feature = "metrics")]
+        {
+            self.metrics.traffic(false, &source, size);
+            self.metrics.received_sizes.record(size as f64);
+        }
+    }
+
+    pub(crate) fn received_kevent(&self) {
+        self.update(|s| s.kevents += 1);
+
+        #[cfg(feature = "metrics")]
+        self.metrics.kevents.increment(1);
+    }
+
+    pub(crate) fn send_error(&self, e: IpconError) {
+        self.update(|s| *s.send_errors.entry(e).or_default() += 1);
+
+        #[cfg(feature = "metrics")]
+        self.metrics.error("ipcon_send_errors_total", e);
+    }
+
+    pub(crate) fn receive_error(&self, e: IpconError) {
+        if matches!(e, IpconError::SysErrorTimeOut) {
+            self.update(|s| s.receive_timeouts += 1);
+
+            #[cfg(feature = "metrics")]
+            self.metrics.receive_timeouts.increment(1);
+            return;
+        }
+
+        self.update(|s| *s.receive_errors.entry(e).or_default() += 1);
+
+        #[cfg(feature = "metrics")]
+        self.metrics.error("ipcon_receive_errors_total", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size_histogram() {
+        let mut h = SizeHistogram::default();
+        assert_eq!(h.count(), 0);
+        assert_eq!(h.mean(), None);
+
+        for size in [0, 16, 17, 2048, 4096] {
+            h.record(size);
+        }
+
+        assert_eq!(h.counts, [1, 1, 1, 0, 0, 0, 1, 1]);
+        assert_eq!(h.count(), 5);
+        assert_eq!(h.sum, 6177);
+        assert_eq!((h.min, h.max), (Some(0), Some(4096)));
+        assert_eq!(h.mean(), Some(6177.0 / 5.0));
+    }
+
+    #[test]
+    fn traffic_by_remote() {
+        let c = StatsCollector::new(Some("me"));
+        c.sent(StatsTarget::Peer("a"), 10);
+        c.received(StatsTarget::Peer("a"), 20);
+        c.sent(StatsTarget::OwnedGroup("g"), 30);
+        c.received(StatsTarget::Group("b", "g"), 40);
+        c.received_kevent();
+        c.send_error(IpconError::SystemErrorNotExist);
+        c.receive_error(IpconError::SysErrorTimeOut);
+        c.receive_error(IpconError::Cancelled);
+
+        let s = c.snapshot();
+        assert_eq!(s.total.sent_msgs, 2);
+        assert_eq!(s.total.received_bytes, 60);
+        assert_eq!(s.peers["a"].sent_bytes, 10);
+        assert_eq!(s.peers["a"].received_bytes, 20);
+
+        let owned = GroupKey {
+            peer: "me".to_owned(),
+            group: "g".to_owned(),
+        };
+        let other = GroupKey {
+            peer: "b".to_owned(),
+            group: "g".to_owned(),
+        };
+        assert_eq!(s.groups[&owned].sent_bytes, 30);
+        assert_eq!(s.groups[&other].received_bytes, 40);
+
+        assert_eq!(s.kevents, 1);
+        assert_eq!(s.send_errors[&IpconError::SystemErrorNotExist], 1);
+        assert_eq!(s.receive_timeouts, 1);
+        assert_eq!(s.receive_errors[&IpconError::Cancelled], 1);
+        assert_eq!(s.sent_sizes.count(), 2);
+
+        c.reset();
+        assert_eq!(c.snapshot(), IpconStats::default());
+    }
+
+    #[test]
+    fn remotes_are_capped() {
+        let c = StatsCollector::new(Some("me"));
+        for i in 0..STATS_MAX_REMOTES + 2 {
+            c.sent(StatsTarget::Peer(&i.to_string()), 1);
+            c.sent(StatsTarget::Group("b", &i.to_string()), 1);
+        }
+        c.sent(StatsTarget::Peer("0"), 1);
+
+        let s = c.snapshot();
+        assert_eq!(s.peers.len(), STATS_MAX_REMOTES);
+        assert_eq!(s.groups.len(), STATS_MAX_REMOTES);
+        assert_eq!(s.peers["0"].sent_msgs, 2);
+        assert_eq!(s.untracked.sent_msgs, 4);
+        assert_eq!(s.total.sent_msgs, 2 * STATS_MAX_REMOTES as u64 + 5);
+    }
+
+    #[cfg(feature = "metrics")]
+    mod metrics {
+        use super::*;
+        use ::metrics::{Key, KeyName, Metadata, Recorder, Unit};
+
+        /* Records the keys of the registered metrics. */
+        #[derive(Default)]
+        struct KeyRecorder {
+            keys: Mutex<Vec<Key>>,
+        }
+
+        impl KeyRecorder {
+            fn remotes(&self, name: &str) -> Vec<String> {
+                self.keys
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|k| k.name() == name)
+                    .filter_map(|k| k.labels().find(|l| l.key() == "remote"))
+                    .map(|l| l.value().to_owned())
+                    .collect()
+            }
+        }
+
+        impl Recorder for KeyRecorder {
+            fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+            fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+                self.keys.lock().unwrap().push(key.clone());
+                Counter::noop()
+            }
+
+            fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> ::metrics::Gauge {
+                ::metrics::Gauge::noop()
+            }
+
+            fn register_histogram(&self, _: &Key, _: &Metadata<'_>) -> Histogram {
+                Histogram::noop()
+            }
+        }
+
+        #[test]
+        fn remote_label_is_opt_in() {
+            let recorder = KeyRecorder::default();
+
+            ::metrics::with_local_recorder(&recorder, || {
+                let c = StatsCollector::new(Some("me"));
+                c.sent(StatsTarget::Peer("a"), 1);
+                c.sent(StatsTarget::OwnedGroup("g"), 1);
+                /* Only the handles of the remotes beyond the limit carry a remote label. */
+                assert_eq!(recorder.remotes("ipcon_messages_total"), ["other"; 4]);
+
+                c.set_metrics_per_remote(true);
+                c.sent(StatsTarget::Peer("a"), 1);
+                c.sent(StatsTarget::Peer("a"), 1);
+                c.received(StatsTarget::Group("b", "g"), 1);
+                assert_eq!(
+                    recorder.remotes("ipcon_messages_total")[4..],
+                    ["a", "a", "g@b", "g@b"]
+                );
+            });
+        }
+    }
+}
@@ -0,0 +1,451 @@
+//! # systemd integration
+//! An IPCON daemon started by a `Type=notify` service reports its readiness once the peer is
+//! created and its startup work (registering groups...) is done. SystemdRunner does it and runs
+//! the receive loop:
+//!
+//! ```ignore
+//! let ipcon = Ipcon::new(Some("server"), Some(IPF_DEFAULT))?;
+//! let handle = ipcon.shutdown_handle();
+//!
+//! SystemdRunner::new(ipcon)?
+//!     .on_start(|ipcon| ipcon.register_group("news"))
+//!     .status("Serving news")
+//!     .run(|ipcon, msg| {
+//!         jinfo!("{:?}", msg);
+//!         Ok(())
+//!     })?;
+//! ```
+//!
+//! The service manager is notified through the datagram socket named by `NOTIFY_SOCKET`, see
+//! sd_notify(3). Nothing is sent if it is not set, so the same binary runs outside systemd.
+//!
+//! If the service sets `WatchdogSec=`, the receive loop wakes up at half the watchdog interval
+//! and sends `WATCHDOG=1` as long as it is healthy: a handler stuck or a receive failing keeps
+//! the watchdog from being fed and systemd restarts the service. run() returns when the peer is
+//! shut down, after sending `STOPPING=1`.
+
+use crate::ipcon::Ipcon;
+use crate::ipcon_error::IpconError;
+use crate::ipcon_msg::IpconMsg;
+use std::ffi::OsStr;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+#[allow(unused)]
+use {
+    error_stack::{Report, Result, ResultExt},
+    jlogger_tracing::{jdebug, jerror, jinfo, jtrace, jwarn},
+};
+
+type StartHook = Box<dyn FnOnce(&Ipcon) -> Result<(), IpconError>>;
+
+/* Delay before receiving again after a receive failure. */
+const RECEIVE_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+fn io_error(e: std::io::Error) -> Report<IpconError> {
+    Report::new(IpconError::SystemErrorOther).attach_printable(e)
+}
+
+struct NotifierInner {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+}
+
+/// Client of the sd_notify protocol.
+#[derive(Clone)]
+pub struct Notifier {
+    inner: Arc<NotifierInner>,
+}
+
+impl Notifier {
+    /// Create a notifier sending to a socket path, a leading '@' names an abstract socket.
+    pub fn new<P: AsRef<OsStr>>(path: P) -> Result<Notifier, IpconError> {
+        let path = path.as_ref();
+
+        let addr = match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name),
+            None => SocketAddr::from_pathname(path),
+        }
+        .map_err(io_error)
+        .attach_printable(format!("Invalid notify socket: {}", path.to_string_lossy()))?;
+
+        let socket = UnixDatagram::unbound().map_err(io_error)?;
+
+        Ok(Notifier {
+            inner: Arc::new(NotifierInner { socket, addr }),
+        })
+    }
+
+    /// Create a notifier from `NOTIFY_SOCKET`, None if it is not set.
+    pub fn from_env() -> Result<Option<Notifier>, IpconError> {
+        Notifier::from_var(std::env::var_os("NOTIFY_SOCKET").as_deref())
+    }
+
+    fn from_var(path: Option<&OsStr>) -> Result<Option<Notifier>, IpconError> {
+        match path {
+            Some(path) if !path.is_empty() => Notifier::new(path).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Send a notification, made of newline separated `VARIABLE=value` assignments.
+    pub fn notify(&self, state: &str) -> Result<(), IpconError> {
+        self.inner
+            .socket
+            .send_to_addr(state.as_bytes(), &self.inner.addr)
+            .map_err(io_error)
+            .attach_printable(format!("Failed to notify `{}`", state))?;
+
+        Ok(())
+    }
+
+    /// Report that the service is ready.
+    pub fn ready(&self) -> Result<(), IpconError> {
+        self.notify("READY=1")
+    }
+
+    /// Report a status text shown by `systemctl status`.
+    pub fn status(&self, status: &str) -> Result<(), IpconError> {
+        self.notify(&format!("STATUS={}", status))
+    }
+
+    /// Feed the watchdog.
+    pub fn watchdog(&self) -> Result<(), IpconError> {
+        self.notify("WATCHDOG=1")
+    }
+
+    /// Report that the service is stopping.
+    pub fn stopping(&self) -> Result<(), IpconError> {
+        self.notify("STOPPING=1")
+    }
+}
+
+/// Watchdog interval of the service from `WATCHDOG_USEC`, None if the watchdog is disabled or
+/// meant for another process.
+pub fn watchdog_interval() -> Option<Duration> {
+    watchdog_interval_from(
+        std::env::var_os("WATCHDOG_USEC").as_deref(),
+        std::env::var_os("WATCHDOG_PID").as_deref(),
+    )
+}
+
+/// Watchdog interval of the service from the values of `WATCHDOG_USEC` and `WATCHDOG_PID`.
+pub fn watchdog_interval_from(usec: Option<&OsStr>, pid: Option<&OsStr>) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.to_str()?.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+
+    let usec = usec?.to_str()?.parse::<u64>().ok()?;
+    if usec == 0 {
+        return None;
+    }
+
+    Some(Duration::from_micros(usec))
+}
+
+/// Receive loop of an IPCON daemon run by systemd.
+pub struct SystemdRunner {
+    ipcon: Ipcon,
+    notifier: Option<Notifier>,
+    watchdog: Option<Duration>,
+    on_start: Vec<StartHook>,
+    status: String,
+}
+
+impl SystemdRunner {
+    /// Create a runner notifying the service manager found in the environment.
+    pub fn new(ipcon: Ipcon) -> Result<SystemdRunner, IpconError> {
+        Ok(SystemdRunner::with_notifier(
+            ipcon,
+            Notifier::from_env()?,
+            watchdog_interval(),
+        ))
+    }
+
+    /// Create a runner with an explicit notifier and watchdog interval.
+    pub fn with_notifier(
+        ipcon: Ipcon,
+        notifier: Option<Notifier>,
+        watchdog: Option<Duration>,
+    ) -> SystemdRunner {
+        SystemdRunner {
+            ipcon,
+            notifier,
+            watchdog,
+            on_start: Vec::new(),
+            status: "Running".to_owned(),
+        }
+    }
+
+    /// Add a startup step, readiness is reported once all of them succeeded.
+    pub fn on_start<F>(mut self, f: F) -> SystemdRunner
+    where
+        F: FnOnce(&Ipcon) -> Result<(), IpconError> + 'static,
+    {
+        self.on_start.push(Box::new(f));
+        self
+    }
+
+    /// Set the status text reported with the readiness.
+    pub fn status(mut self, status: &str) -> SystemdRunner {
+        self.status = status.to_owned();
+        self
+    }
+
+    /// Get the peer.
+    pub fn ipcon(&self) -> &Ipcon {
+        &self.ipcon
+    }
+
+    /// Get the notifier, to report a status from the handler.
+    pub fn notifier(&self) -> Option<&Notifier> {
+        self.notifier.as_ref()
+    }
+
+    fn notify(&self, state: &str) {
+        if let Some(notifier) = &self.notifier {
+            if let Err(e) = notifier.notify(state) {
+                jwarn!("{:?}", e);
+            }
+        }
+    }
+
+    /// Run the startup steps, report the readiness and call f with every received message until
+    /// the peer is shut down. A failed startup step is returned after being reported as status.
+    pub fn run<F>(mut self, mut f: F) -> Result<(), IpconError>
+    where
+        F: FnMut(&Ipcon, IpconMsg) -> Result<(), IpconError>,
+    {
+        for hook in std::mem::take(&mut self.on_start) {
+            if let Err(e) = hook(&self.ipcon) {
+                self.notify(&format!("STATUS=Startup failed: {}", e.current_context()));
+                return Err(e);
+            }
+        }
+
+        self.notify(&format!("READY=1\nSTATUS={}", self.status));
+
+        let ping = self.watchdog.map(|w| w / 2);
+        let mut last_ping = Instant::now();
+
+        loop {
+            let received = match ping {
+                Some(p) => self
+                    .ipcon
+                    .receive_msg_timeout(p.as_secs() as u32, p.subsec_micros()),
+                None => self.ipcon.receive_msg(),
+            };
+
+            let healthy = match received {
+                Ok(msg) => {
+                    if let Err(e) = f(&self.ipcon, msg) {
+                        jwarn!("Handler failed: {:?}", e);
+                    }
+                    true
+                }
+                Err(e) => match e.current_context() {
+                    IpconError::Cancelled => break,
+                    IpconError::SysErrorTimeOut => true,
+                    _ => {
+                        jwarn!("Receive failed: {:?}", e);
+                        std::thread::sleep(RECEIVE_ERROR_BACKOFF);
+                        false
+                    }
+                },
+            };
+
+            if let Some(p) = ping {
+                if healthy && last_ping.elapsed() >= p {
+                    self.notify("WATCHDOG=1");
+                    last_ping = Instant::now();
+                }
+            }
+        }
+
+        self.notify("STOPPING=1");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipcon::IPF_DEFAULT;
+    use crate::ipcon_loopback::LoopbackBus;
+    use std::path::PathBuf;
+
+    /* Fake service manager socket. */
+    struct Manager {
+        socket: UnixDatagram,
+        path: Option<PathBuf>,
+    }
+
+    impl Manager {
+        fn bind(name: &str) -> Manager {
+            let path = std::env::temp_dir().join(format!(
+                "ipcon-systemd-{}-{}.sock",
+                std::process::id(),
+                name
+            ));
+            let _ = std::fs::remove_file(&path);
+            let socket = UnixDatagram::bind(&path).unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+
+            Manager {
+                socket,
+                path: Some(path),
+            }
+        }
+
+        fn recv(&self) -> String {
+            let mut buf = [0_u8; 256];
+            let n = self.socket.recv(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf[..n]).into_owned()
+        }
+
+        fn notifier(&self) -> Notifier {
+            Notifier::new(self.path.as_ref().unwrap()).unwrap()
+        }
+    }
+
+    impl Drop for Manager {
+        fn drop(&mut self) {
+            if let Some(path) = &self.path {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
+    #[test]
+    fn notifier_payloads() {
+        let manager = Manager::bind("payloads");
+        let notifier = manager.notifier();
+
+        notifier.ready().unwrap();
+        assert_eq!(manager.recv(), "READY=1");
+        notifier.status("Serving").unwrap();
+        assert_eq!(manager.recv(), "STATUS=Serving");
+        notifier.watchdog().unwrap();
+        assert_eq!(manager.recv(), "WATCHDOG=1");
+        notifier.stopping().unwrap();
+        assert_eq!(manager.recv(), "STOPPING=1");
+    }
+
+    #[test]
+    fn abstract_socket() {
+        let name = format!("ipcon-systemd-{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let manager = Manager {
+            socket: UnixDatagram::bind_addr(&addr).unwrap(),
+            path: None,
+        };
+
+        Notifier::new(format!("@{}", name))
+            .unwrap()
+            .ready()
+            .unwrap();
+        assert_eq!(manager.recv(), "READY=1");
+    }
+
+    #[test]
+    fn environment() {
+        let var = |s: &'static str| Some(OsStr::new(s));
+        let pid = std::process::id().to_string();
+        let other = (std::process::id() + 1).to_string();
+
+        assert_eq!(watchdog_interval_from(None, None), None);
+        assert_eq!(
+            watchdog_interval_from(var("2000000"), None),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            watchdog_interval_from(var("2000000"), Some(OsStr::new(&pid))),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            watchdog_interval_from(var("2000000"), Some(OsStr::new(&other))),
+            None
+        );
+        assert_eq!(watchdog_interval_from(var("0"), None), None);
+        assert_eq!(watchdog_interval_from(var("soon"), None), None);
+        assert_eq!(watchdog_interval_from(None, Some(OsStr::new(&pid))), None);
+
+        assert!(Notifier::from_var(None).unwrap().is_none());
+        assert!(Notifier::from_var(var("")).unwrap().is_none());
+
+        let manager = Manager::bind("env");
+        Notifier::from_var(Some(manager.path.as_ref().unwrap().as_os_str()))
+            .unwrap()
+            .unwrap()
+            .ready()
+            .unwrap();
+        assert_eq!(manager.recv(), "READY=1");
+    }
+
+    #[test]
+    fn runner_reports_ready_after_startup() {
+        let manager = Manager::bind("runner");
+        let notifier = manager.notifier();
+        let bus = LoopbackBus::new();
+        let ipcon = bus.peer(Some("daemon"), Some(IPF_DEFAULT)).unwrap();
+        let handle = ipcon.shutdown_handle();
+
+        /* Stop the runner once the watchdog has been fed. */
+        let watcher = std::thread::spawn(move || {
+            let mut states = Vec::new();
+            loop {
+                let state = manager.recv();
+                if state == "WATCHDOG=1" {
+                    handle.shutdown();
+                }
+                let stopping = state == "STOPPING=1";
+                states.push(state);
+                if stopping {
+                    return states;
+                }
+            }
+        });
+
+        let hook = notifier.clone();
+        SystemdRunner::with_notifier(ipcon, Some(notifier), Some(Duration::from_millis(100)))
+            .on_start(|ipcon| ipcon.register_group("news"))
+            .on_start(move |_| hook.notify("HOOK=1"))
+            .status("Serving")
+            .run(|_, _| Ok(()))
+            .unwrap();
+
+        let states = watcher.join().unwrap();
+        assert_eq!(states[..2], ["HOOK=1", "READY=1\nSTATUS=Serving"]);
+        assert!(states[2..states.len() - 1]
+            .iter()
+            .all(|s| s == "WATCHDOG=1"));
+        assert_eq!(states.last().unwrap(), "STOPPING=1");
+    }
+
+    #[test]
+    fn runner_reports_failed_startup() {
+        let manager = Manager::bind("failed");
+        let bus = LoopbackBus::new();
+        let ipcon = bus.peer(Some("daemon"), Some(IPF_DEFAULT)).unwrap();
+
+        let err = SystemdRunner::with_notifier(ipcon, Some(manager.notifier()), None)
+            .on_start(|ipcon| ipcon.join_group("nobody", "news"))
+            .run(|_, _| Ok(()))
+            .unwrap_err();
+
+        assert_eq!(*err.current_context(), IpconError::SystemErrorNotExist);
+        assert!(manager.recv().starts_with("STATUS=Startup failed"));
+        manager
+            .socket
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let mut buf = [0_u8; 16];
+        assert!(manager.socket.recv(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,559 @@
+//! # tower adapters
+//! With the `tower` feature, IPCON request/response exchanges can be composed with tower
+//! middleware (timeouts, rate limits, retries, load shedding...).
+//!
+//! * IpconClient is a `tower::Service<IpconRequest>` sending the request with an AsyncIpcon
+//!   peer and resolving to the reply of the remote peer.
+//! * IpconServer feeds the messages received by an AsyncIpcon peer into a
+//!   `tower::Service<IpconMsgBody>` and sends the response back to the sender.
+//!
+//! Requests and replies are correlated with a small header prepended to the payload, holding a
+//! request id chosen by the client. A reply can also report a failure of the remote service.
+//! The server answers the messages without header with the bare response, so that plain peers
+//! can use it too.
+//!
+//! Both stop receiving when their peer is shut down, see ipcon_shutdown: the pending requests of
+//! a client then fail, and IpconServer::run() returns.
+
+use crate::ipcon_async::AsyncIpcon;
+use crate::ipcon_error::IpconError;
+use crate::ipcon_msg::{IpconMsg, IpconMsgBody, IpconMsgType, IPCON_MAX_PAYLOAD_LEN};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tower::{Service, ServiceExt};
+#[allow(unused)]
+use {
+    error_stack::{Report, Result, ResultExt},
+    jlogger_tracing::{jdebug, jerror, jinfo, jtrace, jwarn},
+};
+
+const RPC_MAGIC: u8 = 0x52;
+
+/// Length of the request/reply header: magic(1) + kind(1) + id(8).
+pub const RPC_HEADER_LEN: usize = 10;
+
+/// Maximum payload length of a request or a reply.
+pub const RPC_MAX_PAYLOAD_LEN: usize = IPCON_MAX_PAYLOAD_LEN - RPC_HEADER_LEN;
+
+/* Delay before receiving again after a receive failure. */
+const RECEIVE_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Kind of a request/reply message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RpcKind {
+    /// A request, answered by a reply with the same id.
+    Request,
+    /// A successful reply, the payload is the response.
+    Reply,
+    /// A failed reply, the payload is the error message.
+    Error,
+}
+
+/// Header correlating a request and its reply.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RpcHeader {
+    pub kind: RpcKind,
+    pub id: u64,
+}
+
+impl RpcHeader {
+    /// Prepend the header to a payload.
+    pub fn encode(&self, buf: &[u8]) -> Result<Vec<u8>, IpconError> {
+        if buf.len() > RPC_MAX_PAYLOAD_LEN {
+            return Err(Report::new(IpconError::InvalidData)).attach_printable(format!(
+                "Buffer length is too large {} > {}",
+                buf.len(),
+                RPC_MAX_PAYLOAD_LEN
+            ));
+        }
+
+        let mut v = Vec::with_capacity(RPC_HEADER_LEN + buf.len());
+        v.push(RPC_MAGIC);
+        v.push(match self.kind {
+            RpcKind::Request => 0,
+            RpcKind::Reply => 1,
+            RpcKind::Error => 2,
+        });
+        v.extend_from_slice(&self.id.to_be_bytes());
+        v.extend_from_slice(buf);
+
+        Ok(v)
+    }
+
+    /// Split a received buffer into the header and the payload.
+    pub fn decode(buf: &[u8]) -> Result<(RpcHeader, &[u8]), IpconError> {
+        if buf.len() < RPC_HEADER_LEN || buf[0] != RPC_MAGIC {
+            return Err(Report::new(IpconError::InvalidData))
+                .attach_printable("No request header found");
+        }
+
+        let kind = match buf[1] {
+            0 => RpcKind::Request,
+            1 => RpcKind::Reply,
+            2 => RpcKind::Error,
+            k => {
+                return Err(Report::new(IpconError::InvalidData))
+                    .attach_printable(format!("Invalid request kind {}", k))
+            }
+        };
+
+        let mut id = [0_u8; 8];
+        id.copy_from_slice(&buf[2..RPC_HEADER_LEN]);
+
+        Ok((
+            RpcHeader {
+                kind,
+                id: u64::from_be_bytes(id),
+            },
+            &buf[RPC_HEADER_LEN..],
+        ))
+    }
+}
+
+/// A request sent by an IpconClient.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IpconRequest {
+    /// Peer serving the request.
+    pub peer: String,
+    /// Payload of the request.
+    pub buf: Vec<u8>,
+}
+
+impl IpconRequest {
+    pub fn new(peer: &str, buf: &[u8]) -> IpconRequest {
+        IpconRequest {
+            peer: peer.to_owned(),
+            buf: buf.to_vec(),
+        }
+    }
+}
+
+/* Pending requests by id: peer serving the request and reply channel. */
+#[derive(Default)]
+struct PendingRequests {
+    requests: HashMap<u64, (String, oneshot::Sender<Result<IpconMsgBody, IpconError>>)>,
+    /* Set once the replies are no longer received. */
+    closed: bool,
+}
+
+type Pending = Mutex<PendingRequests>;
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+struct ClientShared {
+    ipcon: Arc<AsyncIpcon>,
+    pending: Arc<Pending>,
+    next_id: AtomicU64,
+    /* Concurrent sends on the same peer are serialized. */
+    send: tokio::sync::Mutex<()>,
+    receiver: JoinHandle<()>,
+}
+
+impl Drop for ClientShared {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+/* Remove the pending request when the response future is dropped, e.g. on timeout. */
+struct PendingGuard<'a> {
+    pending: &'a Pending,
+    id: u64,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        lock(self.pending).requests.remove(&self.id);
+    }
+}
+
+async fn dispatch_replies(ipcon: Arc<AsyncIpcon>, pending: Arc<Pending>) {
+    loop {
+        let body = match ipcon.receive_msg().await {
+            Ok(IpconMsg::IpconMsgUser(body))
+                if body.msg_type == IpconMsgType::IpconMsgTypeNormal =>
+            {
+                body
+            }
+            Ok(_) => continue,
+            Err(e) if *e.current_context() == IpconError::Cancelled => break,
+            Err(e) => {
+                jwarn!("Client receive failed: {:?}", e);
+                tokio::time::sleep(RECEIVE_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+
+        let (header, payload) = match RpcHeader::decode(&body.buf) {
+            Ok((h, payload)) if h.kind != RpcKind::Request => (h, payload.to_vec()),
+            _ => {
+                jdebug!("Message from {} is not a reply, dropped", body.peer);
+                continue;
+            }
+        };
+
+        let tx = {
+            let mut pending = lock(&pending);
+            match pending.requests.get(&header.id) {
+                Some((peer, _)) if *peer == body.peer => {
+                    pending.requests.remove(&header.id).map(|p| p.1)
+                }
+                _ => None,
+            }
+        };
+
+        let tx = match tx {
+            Some(tx) => tx,
+            None => {
+                jdebug!("Unexpected reply {} from {}, dropped", header.id, body.peer);
+                continue;
+            }
+        };
+
+        let reply = match header.kind {
+            RpcKind::Error => Err(Report::new(IpconError::SystemErrorOther).attach_printable(
+                format!(
+                    "Request failed in {}: {}",
+                    body.peer,
+                    String::from_utf8_lossy(&payload)
+                ),
+            )),
+            _ => Ok(IpconMsgBody {
+                buf: payload,
+                ..body
+            }),
+        };
+
+        let _ = tx.send(reply);
+    }
+
+    /* Dropping the reply channels fails the pending requests. */
+    let mut pending = lock(&pending);
+    pending.closed = true;
+    pending.requests.clear();
+}
+
+/// `tower::Service` sending requests to IPCON peers and resolving to their replies.
+///
+/// The client owns the receiving side of its peer: all the received unicast messages which are
+/// not replies to a pending request are dropped. Clones share the same peer.
+#[derive(Clone)]
+pub struct IpconClient {
+    shared: Arc<ClientShared>,
+}
+
+impl IpconClient {
+    /// Create a client sending with the peer.
+    /// It must be called in the context of a tokio runtime, a task receiving the replies is
+    /// spawned.
+    pub fn new(ipcon: AsyncIpcon) -> IpconClient {
+        let ipcon = Arc::new(ipcon);
+        let pending = Arc::new(Mutex::new(PendingRequests::default()));
+        let receiver = tokio::spawn(dispatch_replies(ipcon.clone(), pending.clone()));
+
+        IpconClient {
+            shared: Arc::new(ClientShared {
+                ipcon,
+                pending,
+                next_id: AtomicU64::new(1),
+                send: tokio::sync::Mutex::new(()),
+                receiver,
+            }),
+        }
+    }
+
+    /// Get the peer of the client.
+    pub fn ipcon(&self) -> &AsyncIpcon {
+        &self.shared.ipcon
+    }
+
+    /// Send a request and wait for its reply.
+    pub async fn request(&self, req: IpconRequest) -> Result<IpconMsgBody, IpconError> {
+        let shared = &self.shared;
+        let id = shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let buf = RpcHeader {
+            kind: RpcKind::Request,
+            id,
+        }
+        .encode(&req.buf)?;
+
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut pending = lock(&shared.pending);
+            if pending.closed {
+                return Err(Report::new(IpconError::Cancelled))
+                    .attach_printable("Client peer is shut down");
+            }
+            pending.requests.insert(id, (req.peer.clone(), tx));
+        }
+        let _guard = PendingGuard {
+            pending: &shared.pending,
+            id,
+        };
+
+        {
+            let _send = shared.send.lock().await;
+            shared
+                .ipcon
+                .send_unicast_msg(&req.peer, &buf)
+                .await
+                .attach_printable(format!("Failed to send request to {}", req.peer))?;
+        }
+
+        /* The reply channel is only dropped by the dispatcher when the peer is shut down. */
+        rx.await.map_err(|_| {
+            if lock(&shared.pending).closed {
+                Report::new(IpconError::Cancelled).attach_printable("Client peer is shut down")
+            } else {
+                Report::new(IpconError::Unexpected).attach_printable("Reply channel closed")
+            }
+        })?
+    }
+}
+
+impl Service<IpconRequest> for IpconClient {
+    type Response = IpconMsgBody;
+    type Error = Report<IpconError>;
+    type Future = Pin<Box<dyn Future<Output = Result<IpconMsgBody, IpconError>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), IpconError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: IpconRequest) -> Self::Future {
+        let client = self.clone();
+        Box::pin(async move { client.request(req).await })
+    }
+}
+
+/* Error reply of a failed request, the message is truncated to fit in the reply. */
+fn error_reply(id: u64, msg: &str) -> Result<Vec<u8>, IpconError> {
+    let mut len = msg.len().min(RPC_MAX_PAYLOAD_LEN);
+    while !msg.is_char_boundary(len) {
+        len -= 1;
+    }
+
+    RpcHeader {
+        kind: RpcKind::Error,
+        id,
+    }
+    .encode(&msg.as_bytes()[..len])
+}
+
+/// Driver feeding the requests received by an IPCON peer into a `tower::Service`.
+///
+/// The response of the service is sent back to the sender of the request. A failure of the
+/// service is reported to an IpconClient as an error reply, truncated to RPC_MAX_PAYLOAD_LEN,
+/// and logged for the requests without header. The service is polled for readiness before a
+/// request is received, so that backpressure from middleware like concurrency limits applies to
+/// the peer.
+pub struct IpconServer<S> {
+    ipcon: Arc<AsyncIpcon>,
+    service: S,
+    send: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl<S> IpconServer<S>
+where
+    S: Service<IpconMsgBody, Response = Vec<u8>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: std::fmt::Display + Send,
+{
+    /// Create a server handling the unicast messages received by the peer with the service.
+    pub fn new(ipcon: AsyncIpcon, service: S) -> IpconServer<S> {
+        IpconServer {
+            ipcon: Arc::new(ipcon),
+            service,
+            send: Arc::new(tokio::sync::Mutex::new(())),
+        }
+    }
+
+    /// Get the peer of the server.
+    pub fn ipcon(&self) -> &AsyncIpcon {
+        &self.ipcon
+    }
+
+    /// Serve the requests. Each request is handled in its own task.
+    /// It returns when the peer is shut down, or fails if the service fails to get ready.
+    pub async fn run(mut self) -> Result<(), IpconError> {
+        loop {
+            if let Err(e) = self.service.ready().await {
+                return Err(Report::new(IpconError::Unexpected))
+                    .attach_printable(format!("Service failed: {}", e));
+            }
+
+            let mut body = match self.ipcon.receive_msg().await {
+                Ok(IpconMsg::IpconMsgUser(body))
+                    if body.msg_type == IpconMsgType::IpconMsgTypeNormal =>
+                {
+                    body
+                }
+                Ok(_) => continue,
+                Err(e) if *e.current_context() == IpconError::Cancelled => return Ok(()),
+                Err(e) => {
+                    jwarn!("Server receive failed: {:?}", e);
+                    tokio::time::sleep(RECEIVE_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+
+            let id = match RpcHeader::decode(&body.buf) {
+                Ok((h, payload)) if h.kind == RpcKind::Request => {
+                    body.buf = payload.to_vec();
+                    Some(h.id)
+                }
+                Ok(_) => {
+                    jdebug!("Reply from {} dropped", body.peer);
+                    continue;
+                }
+                Err(_) => None,
+            };
+
+            let peer = body.peer.clone();
+            let response = self.service.call(body);
+            let ipcon = self.ipcon.clone();
+            let send = self.send.clone();
+
+            tokio::spawn(async move {
+                let reply = match (response.await, id) {
+                    (Ok(buf), Some(id)) => RpcHeader {
+                        kind: RpcKind::Reply,
+                        id,
+                    }
+                    .encode(&buf)
+                    .or_else(|_| {
+                        error_reply(
+                            id,
+                            &format!(
+                                "Response length is too large {} > {}",
+                                buf.len(),
+                                RPC_MAX_PAYLOAD_LEN
+                            ),
+                        )
+                    }),
+                    (Ok(buf), None) => Ok(buf),
+                    (Err(e), Some(id)) => error_reply(id, &e.to_string()),
+                    (Err(e), None) => {
+                        jwarn!("Request from {} failed: {}", peer, e);
+                        return;
+                    }
+                };
+
+                let result = match reply {
+                    Ok(reply) => {
+                        let _send = send.lock().await;
+                        ipcon.send_unicast_msg(&peer, &reply).await
+                    }
+                    Err(e) => Err(e),
+                };
+
+                if let Err(e) = result {
+                    jwarn!("Failed to reply to {}: {:?}", peer, e);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipcon::IPF_DEFAULT;
+    use crate::ipcon_loopback::LoopbackBus;
+
+    #[test]
+    fn header_round_trip() {
+        for kind in [RpcKind::Request, RpcKind::Reply, RpcKind::Error] {
+            let header = RpcHeader {
+                kind,
+                id: 0x0102_0304_0506_0708,
+            };
+            let buf = header.encode(b"payload").unwrap();
+            assert_eq!(buf.len(), RPC_HEADER_LEN + 7);
+            assert_eq!(RpcHeader::decode(&buf).unwrap(), (header, &b"payload"[..]));
+        }
+    }
+
+    #[test]
+    fn invalid_headers_are_rejected() {
+        let header = RpcHeader {
+            kind: RpcKind::Request,
+            id: 1,
+        };
+        assert!(header.encode(&[0; RPC_MAX_PAYLOAD_LEN]).is_ok());
+        assert!(header.encode(&[0; RPC_MAX_PAYLOAD_LEN + 1]).is_err());
+
+        let buf = header.encode(b"").unwrap();
+        assert!(RpcHeader::decode(&buf[..RPC_HEADER_LEN - 1]).is_err());
+
+        let mut bad_magic = buf.clone();
+        bad_magic[0] = 0;
+        assert!(RpcHeader::decode(&bad_magic).is_err());
+
+        let mut bad_kind = buf;
+        bad_kind[1] = 3;
+        assert!(RpcHeader::decode(&bad_kind).is_err());
+    }
+
+    #[test]
+    fn error_replies_are_truncated() {
+        let msg = "é".repeat(RPC_MAX_PAYLOAD_LEN);
+        let buf = error_reply(7, &msg).unwrap();
+        let (header, payload) = RpcHeader::decode(&buf).unwrap();
+
+        assert_eq!(header.kind, RpcKind::Error);
+        assert_eq!(header.id, 7);
+        assert!(payload.len() <= RPC_MAX_PAYLOAD_LEN);
+        assert!(std::str::from_utf8(payload).is_ok());
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_client_and_server() {
+        let bus = LoopbackBus::new();
+        let server = AsyncIpcon::from(bus.peer(Some("server"), Some(IPF_DEFAULT)).unwrap());
+        let client = AsyncIpcon::from(bus.peer(Some("client"), Some(IPF_DEFAULT)).unwrap());
+        let server_shutdown = server.shutdown_handle();
+        let client_shutdown = client.shutdown_handle();
+
+        let service = tower::service_fn(|body: IpconMsgBody| async move {
+            if body.buf.is_empty() {
+                Err("empty request")
+            } else {
+                Ok(body.buf)
+            }
+        });
+        let server = tokio::spawn(IpconServer::new(server, service).run());
+        let client = IpconClient::new(client);
+
+        let reply = client
+            .request(IpconRequest::new("server", b"ping"))
+            .await
+            .unwrap();
+        assert_eq!(reply.buf, b"ping");
+        let e = client
+            .request(IpconRequest::new("server", b""))
+            .await
+            .unwrap_err();
+        assert!(format!("{:?}", e).contains("empty request"));
+
+        /* The request fails whether it is sent before or after the client stops receiving. */
+        client_shutdown.shutdown();
+        let e = client
+            .request(IpconRequest::new("server", b"ping"))
+            .await
+            .unwrap_err();
+        assert_eq!(*e.current_context(), IpconError::Cancelled);
+
+        server_shutdown.shutdown();
+        assert!(server.await.unwrap().is_ok());
+    }
+}
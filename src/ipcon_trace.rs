@@ -0,0 +1,391 @@
+//! # Trace context propagation
+//! When trace propagation is enabled on a peer with Ipcon::set_trace_propagation(), every sent
+//! message carries a small envelope holding a W3C traceparent style trace context: a 128 bits
+//! trace id, the 64 bits id of the sending span and the trace flags.
+//!
+//! The trace context is attached to a `tracing` span, see TraceContext::span(). The sender uses
+//! the context of the current span or of its closest ancestor holding one, so that it follows the
+//! spans across `.await` points and threads as `tracing` does. If there is none, a new trace is
+//! started and attached to the current span. The receiver strips the envelope, and the received
+//! IpconMsgBody holds a child context whose parent is the span of the sender, with a span opened
+//! for it. Entering that span with IpconMsgBody::enter_trace() makes the messages sent while
+//! handling the message part of the same trace, so that a request can be followed across the
+//! cooperating peers.
+//!
+//! The contexts are stored in the span extensions of `tracing_subscriber::Registry`, the
+//! subscriber installed by the application must be built on it, as the `fmt` subscriber is.
+//! Without it, every message sent starts a new trace.
+
+use crate::ipcon_error::IpconError;
+use crate::ipcon_msg::{IpconMsg, IpconMsgBody, IPCON_MAX_PAYLOAD_LEN};
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::Span;
+use tracing_subscriber::registry::{LookupSpan, Registry};
+#[allow(unused)]
+use {
+    error_stack::{Report, Result, ResultExt},
+    jlogger_tracing::{jdebug, jerror, jinfo, jtrace, jwarn},
+};
+
+const TRACE_MAGIC: [u8; 4] = [0xff, b'T', b'R', b'C'];
+const TRACE_VERSION: u8 = 0;
+
+/// Length of the trace envelope: magic(4) + version(1) + flags(1) + trace id(16) + span id(8).
+pub const TRACE_HEADER_LEN: usize = 30;
+
+/// Maximum payload length of a message sent with trace propagation enabled.
+pub const TRACE_MAX_PAYLOAD_LEN: usize = IPCON_MAX_PAYLOAD_LEN - TRACE_HEADER_LEN;
+
+/// The trace is sampled.
+pub const TRACE_FLAG_SAMPLED: u8 = 0x1;
+
+fn random_u64() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut h = RandomState::new().build_hasher();
+    h.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    h.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0),
+    );
+
+    /* Zero is an invalid id in W3C trace context. */
+    h.finish().max(1)
+}
+
+/// Trace context carried by an IPCON message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TraceContext {
+    #[cfg_attr(feature = "serde", serde(with = "crate::ipcon_serde::hex_u128"))]
+    pub trace_id: u128,
+    #[cfg_attr(feature = "serde", serde(with = "crate::ipcon_serde::hex_u64"))]
+    pub span_id: u64,
+    /// Span id of the sender if the context was received from another peer.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, with = "crate::ipcon_serde::hex_u64_option")
+    )]
+    pub parent_id: Option<u64>,
+    pub flags: u8,
+}
+
+impl TraceContext {
+    /// Start a new trace.
+    pub fn new_root() -> TraceContext {
+        TraceContext {
+            trace_id: ((random_u64() as u128) << 64) | random_u64() as u128,
+            span_id: random_u64(),
+            parent_id: None,
+            flags: TRACE_FLAG_SAMPLED,
+        }
+    }
+
+    /// Create a child context in the same trace.
+    pub fn child(&self) -> TraceContext {
+        TraceContext {
+            trace_id: self.trace_id,
+            span_id: random_u64(),
+            parent_id: Some(self.span_id),
+            flags: self.flags,
+        }
+    }
+
+    /// Get the trace context of the current span, or of its closest ancestor holding one.
+    pub fn current() -> Option<TraceContext> {
+        TraceContext::of_span(&Span::current())
+    }
+
+    /// Get the trace context of a span, or of its closest ancestor holding one.
+    pub fn of_span(span: &Span) -> Option<TraceContext> {
+        span.with_subscriber(|(id, dispatch)| {
+            dispatch
+                .downcast_ref::<Registry>()?
+                .span(id)?
+                .scope()
+                .find_map(|s| s.extensions().get::<TraceContext>().copied())
+        })
+        .flatten()
+    }
+
+    /// Attach this context to a span, the messages sent in the span are part of this trace.
+    /// It has no effect if the span is disabled or the subscriber is not built on
+    /// `tracing_subscriber::Registry`.
+    pub fn attach(&self, span: &Span) {
+        span.with_subscriber(|(id, dispatch)| {
+            if let Some(s) = dispatch.downcast_ref::<Registry>().and_then(|r| r.span(id)) {
+                s.extensions_mut().replace(*self);
+            }
+        });
+    }
+
+    /// Whether the trace is sampled.
+    pub fn is_sampled(&self) -> bool {
+        self.flags & TRACE_FLAG_SAMPLED != 0
+    }
+
+    /// Format the context as a W3C traceparent header.
+    pub fn traceparent(&self) -> String {
+        format!(
+            "{:02x}-{:032x}-{:016x}-{:02x}",
+            TRACE_VERSION, self.trace_id, self.span_id, self.flags
+        )
+    }
+
+    /// Parse a W3C traceparent header.
+    pub fn from_traceparent(s: &str) -> Result<TraceContext, IpconError> {
+        let fields: Vec<&str> = s.trim().split('-').collect();
+        let parse = || -> Option<TraceContext> {
+            if fields.len() != 4 || fields[1].len() != 32 || fields[2].len() != 16 {
+                return None;
+            }
+
+            Some(TraceContext {
+                trace_id: u128::from_str_radix(fields[1], 16).ok()?,
+                span_id: u64::from_str_radix(fields[2], 16).ok()?,
+                parent_id: None,
+                flags: u8::from_str_radix(fields[3], 16).ok()?,
+            })
+        };
+
+        parse()
+            .filter(|c| c.trace_id != 0 && c.span_id != 0)
+            .ok_or_else(|| Report::new(IpconError::InvalidData))
+            .attach_printable(format!("Invalid traceparent: {}", s))
+    }
+
+    /// Create a tracing span holding this context.
+    /// The messages sent in the span, or in its children, are part of this trace. It can be
+    /// entered, or used to instrument a future.
+    pub fn span(&self, name: &str) -> Span {
+        let span = tracing::info_span!(
+            "ipcon",
+            name = name,
+            trace_id = %format!("{:032x}", self.trace_id),
+            span_id = %format!("{:016x}", self.span_id),
+            parent_id = %self.parent_id.map(|p| format!("{:016x}", p)).unwrap_or_default(),
+        );
+
+        self.attach(&span);
+        span
+    }
+
+    /// Create the tracing span of this context and enter it.
+    /// Messages sent until the guard is dropped are part of this trace. Like an entered span, the
+    /// guard should not be held across an `.await` point, use span() to instrument a future.
+    pub fn enter(self, name: &str) -> TraceGuard {
+        TraceGuard {
+            _span: self.span(name).entered(),
+        }
+    }
+
+    /// Prepend the trace envelope to a payload.
+    pub fn encode(&self, buf: &[u8]) -> Result<Vec<u8>, IpconError> {
+        if buf.len() > TRACE_MAX_PAYLOAD_LEN {
+            return Err(Report::new(IpconError::InvalidData)).attach_printable(format!(
+                "Buffer length is too large {} > {}",
+                buf.len(),
+                TRACE_MAX_PAYLOAD_LEN
+            ));
+        }
+
+        let mut v = Vec::with_capacity(TRACE_HEADER_LEN + buf.len());
+        v.extend_from_slice(&TRACE_MAGIC);
+        v.push(TRACE_VERSION);
+        v.push(self.flags);
+        v.extend_from_slice(&self.trace_id.to_be_bytes());
+        v.extend_from_slice(&self.span_id.to_be_bytes());
+        v.extend_from_slice(buf);
+
+        Ok(v)
+    }
+
+    /// Split a received buffer into the trace context of the sender and the payload.
+    /// None is returned if the buffer has no trace envelope.
+    pub fn decode(buf: &[u8]) -> Option<(TraceContext, &[u8])> {
+        if buf.len() < TRACE_HEADER_LEN || buf[0..4] != TRACE_MAGIC || buf[4] != TRACE_VERSION {
+            return None;
+        }
+
+        let ctx = TraceContext {
+            trace_id: u128::from_be_bytes(buf[6..22].try_into().ok()?),
+            span_id: u64::from_be_bytes(buf[22..30].try_into().ok()?),
+            parent_id: None,
+            flags: buf[5],
+        };
+
+        Some((ctx, &buf[TRACE_HEADER_LEN..]))
+    }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.traceparent())
+    }
+}
+
+/// Guard of an entered trace context, see TraceContext::enter().
+pub struct TraceGuard {
+    _span: tracing::span::EnteredSpan,
+}
+
+/// Wrap a payload to be sent with the trace envelope of the current span.
+pub(crate) fn inject(buf: &[u8]) -> Result<Vec<u8>, IpconError> {
+    let ctx = TraceContext::current().unwrap_or_else(|| {
+        /* Keep the following messages sent in the span in the same trace. */
+        let ctx = TraceContext::new_root();
+        ctx.attach(&Span::current());
+        ctx
+    });
+
+    ctx.encode(buf)
+}
+
+fn span_name(peer: &str, group: Option<&str>) -> String {
+    match group {
+        Some(group) => format!("{}@{}", group, peer),
+        None => peer.to_owned(),
+    }
+}
+
+/// Strip the trace envelope from a received message and open the span of its context.
+pub(crate) fn extract(msg: IpconMsg) -> IpconMsg {
+    match msg {
+        IpconMsg::IpconMsgUser(mut body) => {
+            if let Some((ctx, payload)) = TraceContext::decode(&body.buf) {
+                let ctx = ctx.child();
+                body.span = Some(ctx.span(&span_name(&body.peer, body.group.as_deref())));
+                body.trace = Some(ctx);
+                body.buf = payload.to_vec();
+            }
+            IpconMsg::IpconMsgUser(body)
+        }
+        _ => msg,
+    }
+}
+
+impl IpconMsgBody {
+    /// Enter the span opened for the trace context carried by this message.
+    /// None is returned if the message was received without a trace context.
+    pub fn enter_trace(&self) -> Option<TraceGuard> {
+        /* A deserialized message has a context but no span yet. */
+        let span = self.span.clone().or_else(|| {
+            self.trace
+                .map(|t| t.span(&span_name(&self.peer, self.group.as_deref())))
+        })?;
+
+        Some(TraceGuard {
+            _span: span.entered(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipcon::{Ipcon, IPF_DEFAULT};
+    use crate::ipcon_loopback::LoopbackBus;
+
+    fn recv(ipcon: &Ipcon) -> IpconMsgBody {
+        match ipcon.receive_msg_timeout(1, 0).unwrap() {
+            IpconMsg::IpconMsgUser(body) => body,
+            m => panic!("Unexpected message {:?}", m),
+        }
+    }
+
+    fn peers(bus: &LoopbackBus) -> (Ipcon, Ipcon) {
+        let a = bus.peer(Some("a"), Some(IPF_DEFAULT)).unwrap();
+        let b = bus.peer(Some("b"), Some(IPF_DEFAULT)).unwrap();
+        a.set_trace_propagation(true);
+        b.set_trace_propagation(true);
+        (a, b)
+    }
+
+    #[test]
+    fn envelope_round_trip() {
+        let ctx = TraceContext::new_root();
+        let buf = ctx.encode(b"payload").unwrap();
+        assert_eq!(buf.len(), TRACE_HEADER_LEN + 7);
+        assert_eq!(TraceContext::decode(&buf), Some((ctx, &b"payload"[..])));
+        assert_eq!(TraceContext::decode(b"payload"), None);
+        assert!(ctx.encode(&[0; TRACE_MAX_PAYLOAD_LEN + 1]).is_err());
+
+        let parsed = TraceContext::from_traceparent(&ctx.traceparent()).unwrap();
+        assert_eq!(parsed, ctx);
+        assert!(TraceContext::from_traceparent("00-0-0-01").is_err());
+
+        let child = ctx.child();
+        assert_eq!(child.trace_id, ctx.trace_id);
+        assert_eq!(child.parent_id, Some(ctx.span_id));
+    }
+
+    #[test]
+    fn context_follows_the_spans() {
+        let bus = LoopbackBus::new();
+        let (a, b) = peers(&bus);
+
+        tracing::subscriber::with_default(Registry::default(), || {
+            let ctx = TraceContext::new_root();
+            let span = ctx.span("request");
+            assert_eq!(TraceContext::of_span(&span), Some(ctx));
+
+            /* The context is found from a child span. */
+            span.in_scope(|| {
+                let _child = tracing::info_span!("child").entered();
+                assert_eq!(TraceContext::current(), Some(ctx));
+                a.send_unicast_msg("b", b"request").unwrap();
+            });
+
+            let request = recv(&b);
+            assert_eq!(request.buf, b"request");
+            let received = request.trace().unwrap();
+            assert_eq!(received.trace_id, ctx.trace_id);
+            assert_eq!(received.parent_id, Some(ctx.span_id));
+            assert_eq!(
+                request.span().and_then(TraceContext::of_span),
+                Some(received)
+            );
+
+            {
+                let _guard = request.enter_trace().unwrap();
+                b.send_unicast_msg("a", b"reply").unwrap();
+            }
+
+            let reply = recv(&a).trace().unwrap();
+            assert_eq!(reply.trace_id, ctx.trace_id);
+            assert_eq!(reply.parent_id, Some(received.span_id));
+        });
+    }
+
+    #[test]
+    fn new_trace_is_kept_in_the_span() {
+        let bus = LoopbackBus::new();
+        let (a, b) = peers(&bus);
+
+        tracing::subscriber::with_default(Registry::default(), || {
+            tracing::info_span!("sender").in_scope(|| {
+                a.send_unicast_msg("b", b"1").unwrap();
+                a.send_unicast_msg("b", b"2").unwrap();
+            });
+
+            let first = recv(&b).trace().unwrap();
+            let second = recv(&b).trace().unwrap();
+            assert_eq!(first.trace_id, second.trace_id);
+            assert_eq!(first.parent_id, second.parent_id);
+        });
+
+        /* Without a span, every message starts a new trace. */
+        a.send_unicast_msg("b", b"1").unwrap();
+        a.send_unicast_msg("b", b"2").unwrap();
+        assert_ne!(
+            recv(&b).trace().unwrap().trace_id,
+            recv(&b).trace().unwrap().trace_id
+        );
+    }
+}
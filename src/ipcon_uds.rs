@@ -0,0 +1,558 @@
+//! # Unix domain socket bridge
+//! UdsBridge exposes an Ipcon peer over a Unix domain socket, so that programs which can not
+//! link libipcon (scripts, containers without the netlink family...) can take part in IPCON
+//! communication. The messages are exchanged with the framing described in ipcon_frame:
+//!
+//! * A unicast or multicast frame sent by a client is sent by the bridge peer with
+//!   send_unicast_msg() or send_multicast(). A failure is reported to the client with an error
+//!   frame.
+//! * The unicast messages received by the bridge peer from an exposed peer are forwarded as
+//!   message frames to the client which last sent a message to that peer, or to every client if
+//!   none did. The messages of the subscribed groups and the kernel events are forwarded to
+//!   every client.
+//!
+//! Every client has a bounded queue of frames written by its own thread. A client which doesn't
+//! read its frames fast enough is disconnected when its queue is full, so that it doesn't
+//! delay the other clients.
+//!
+//! The credentials of a connecting client are read with SO_PEERCRED. Clients running with the
+//! user id of the bridge, or with one of the allowed user or group ids, are accepted.
+//!
+//! UdsClient is the client side of the bridge for Rust programs.
+
+use crate::ipcon::Ipcon;
+use crate::ipcon_error::IpconError;
+use crate::ipcon_frame::{read_frame, write_frame, Frame};
+use crate::ipcon_msg::IpconMsg;
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use std::collections::HashMap;
+use std::io::Write;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex, MutexGuard};
+#[allow(unused)]
+use {
+    error_stack::{Report, Result, ResultExt},
+    jlogger_tracing::{jdebug, jerror, jinfo, jtrace, jwarn},
+};
+
+/// Receive timeout of the bridge peer in microseconds, the stop request is checked at this
+/// interval.
+const BRIDGE_RECEIVE_TIMEOUT_US: u32 = 100_000;
+
+/// Number of frames queued for a client before it is disconnected.
+const CLIENT_QUEUE_LEN: usize = 256;
+
+fn io_error(e: std::io::Error) -> Report<IpconError> {
+    let error = match e.kind() {
+        std::io::ErrorKind::NotFound => IpconError::SystemErrorNotExist,
+        std::io::ErrorKind::PermissionDenied => IpconError::SysErrorPermission,
+        _ => IpconError::SystemErrorOther,
+    };
+    Report::new(error).attach_printable(e.to_string())
+}
+
+/// Configuration of a UdsBridge.
+#[derive(Clone, Debug, Default)]
+pub struct UdsBridgeConfig {
+    /// Peers the clients can exchange unicast messages with, all peers if None.
+    pub peers: Option<Vec<String>>,
+    /// Groups registered by the bridge peer, the clients can send multicast messages to them.
+    pub groups: Vec<String>,
+    /// Groups of other peers joined by the bridge peer: (peer, group).
+    pub subscriptions: Vec<(String, String)>,
+    /// User ids allowed to connect besides the user id of the bridge.
+    pub uids: Vec<u32>,
+    /// Group ids allowed to connect.
+    pub gids: Vec<u32>,
+}
+
+impl UdsBridgeConfig {
+    fn peer_allowed(&self, peer: &str) -> bool {
+        self.peers
+            .as_ref()
+            .is_none_or(|peers| peers.iter().any(|p| p == peer))
+    }
+
+    fn forwarded(&self, msg: &IpconMsg) -> bool {
+        match msg {
+            IpconMsg::IpconMsgUser(body) => match &body.group {
+                Some(group) => self
+                    .subscriptions
+                    .iter()
+                    .any(|(p, g)| *p == body.peer && g == group),
+                None => self.peer_allowed(&body.peer),
+            },
+            IpconMsg::IpconMsgKevent(_) => true,
+            IpconMsg::IpconMsgInvalid => false,
+        }
+    }
+}
+
+/* Connected client, its frames are written by a dedicated thread. */
+struct Client {
+    queue: SyncSender<Arc<Vec<u8>>>,
+    stream: UnixStream,
+}
+
+impl Client {
+    /* Queue a frame, false if the client is gone or too slow. */
+    fn queue(&self, id: u64, data: &Arc<Vec<u8>>) -> bool {
+        if self.queue.try_send(data.clone()).is_ok() {
+            return true;
+        }
+
+        jwarn!("Client {} doesn't read its messages, disconnected", id);
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+        false
+    }
+}
+
+/// Bridge between an Ipcon peer and a Unix domain socket.
+pub struct UdsBridge {
+    ipcon: Ipcon,
+    config: UdsBridgeConfig,
+    path: PathBuf,
+    listener: UnixListener,
+    clients: Mutex<HashMap<u64, Client>>,
+    /* Client which last sent a message to a peer, it gets the replies of the peer. */
+    routes: Mutex<HashMap<String, u64>>,
+    next_id: AtomicU64,
+    stop: AtomicBool,
+}
+
+impl UdsBridge {
+    /// Create a bridge listening on path.
+    /// The groups of the configuration are registered and the subscriptions are joined. A stale
+    /// socket at path is removed, any other kind of file is left as is.
+    pub fn new<P: AsRef<Path>>(
+        ipcon: Ipcon,
+        path: P,
+        config: UdsBridgeConfig,
+    ) -> Result<UdsBridge, IpconError> {
+        for group in &config.groups {
+            ipcon.register_group(group)?;
+        }
+
+        for (peer, group) in &config.subscriptions {
+            ipcon.join_group(peer, group)?;
+        }
+
+        let path = path.as_ref().to_path_buf();
+        if UnixStream::connect(&path).is_err() {
+            let stale = std::fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_socket());
+            if stale {
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+
+        let listener = UnixListener::bind(&path)
+            .map_err(io_error)
+            .attach_printable(format!("Failed to bind {}", path.display()))?;
+
+        Ok(UdsBridge {
+            ipcon,
+            config,
+            path,
+            listener,
+            clients: Mutex::new(HashMap::new()),
+            routes: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            stop: AtomicBool::new(false),
+        })
+    }
+
+    /// Get the bridge peer.
+    pub fn ipcon(&self) -> &Ipcon {
+        &self.ipcon
+    }
+
+    fn clients(&self) -> MutexGuard<'_, HashMap<u64, Client>> {
+        self.clients.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn routes(&self) -> MutexGuard<'_, HashMap<String, u64>> {
+        self.routes.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn send_to(&self, id: u64, data: Arc<Vec<u8>>) {
+        let mut clients = self.clients();
+        if clients.get(&id).is_some_and(|c| !c.queue(id, &data)) {
+            clients.remove(&id);
+        }
+    }
+
+    fn broadcast(&self, data: Arc<Vec<u8>>) {
+        self.clients().retain(|id, c| c.queue(*id, &data));
+    }
+
+    fn authorized(&self, stream: &UnixStream) -> Result<bool, IpconError> {
+        let cred = getsockopt(stream.as_raw_fd(), PeerCredentials)
+            .map_err(|e| Report::new(IpconError::SystemErrorOther).attach_printable(e))
+            .attach_printable("Failed to get the credentials of the client")?;
+
+        Ok(cred.uid() == nix::unistd::geteuid().as_raw()
+            || self.config.uids.contains(&cred.uid())
+            || self.config.gids.contains(&cred.gid()))
+    }
+
+    fn send_error(&self, id: u64, e: &Report<IpconError>) {
+        let frame = Frame::Error {
+            error: *e.current_context(),
+            message: e
+                .frames()
+                .filter_map(|f| {
+                    f.downcast_ref::<String>()
+                        .cloned()
+                        .or_else(|| f.downcast_ref::<&str>().map(|s| s.to_string()))
+                })
+                .collect::<Vec<String>>()
+                .join(": "),
+        };
+
+        match frame.encode() {
+            Ok(data) => self.send_to(id, Arc::new(data)),
+            Err(e) => jwarn!("Failed to report error to client {}: {:?}", id, e),
+        }
+    }
+
+    fn handle_frame(&self, id: u64, frame: Frame) -> Result<(), IpconError> {
+        match frame {
+            Frame::Unicast { peer, buf } => {
+                if !self.config.peer_allowed(&peer) {
+                    return Err(Report::new(IpconError::SysErrorPermission))
+                        .attach_printable(format!("Peer {} is not exposed", peer));
+                }
+                /* The reply may come before the send returns. */
+                self.routes().insert(peer.clone(), id);
+                self.ipcon.send_unicast_msg(&peer, &buf)
+            }
+            Frame::Multicast { group, buf, sync } => {
+                if !self.config.groups.contains(&group) {
+                    return Err(Report::new(IpconError::SysErrorPermission))
+                        .attach_printable(format!("Group {} is not exposed", group));
+                }
+                self.ipcon.send_multicast(&group, &buf, sync)
+            }
+            f => Err(Report::new(IpconError::InvalidData))
+                .attach_printable(format!("Unexpected frame from client: {:?}", f)),
+        }
+    }
+
+    fn serve_client(&self, id: u64, mut stream: UnixStream) {
+        loop {
+            match read_frame(&mut stream) {
+                Ok(Some(frame)) => {
+                    if let Err(e) = self.handle_frame(id, frame) {
+                        self.send_error(id, &e);
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    if !self.stop.load(Ordering::Relaxed) {
+                        jwarn!("Client {} disconnected: {:?}", id, e);
+                    }
+                    break;
+                }
+            }
+        }
+
+        if let Some(client) = self.clients().remove(&id) {
+            let _ = client.stream.shutdown(std::net::Shutdown::Both);
+        }
+        self.routes().retain(|_, c| *c != id);
+        jdebug!("Client {} disconnected", id);
+    }
+
+    fn write_client(&self, id: u64, mut stream: UnixStream, queue: Receiver<Arc<Vec<u8>>>) {
+        /* The queue is closed when the client is removed. */
+        for data in queue {
+            if let Err(e) = stream.write_all(&data) {
+                if !self.stop.load(Ordering::Relaxed) {
+                    jwarn!("Failed to forward message to client {}: {}", id, e);
+                }
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+                break;
+            }
+        }
+    }
+
+    fn forward(&self) {
+        while !self.stop.load(Ordering::Relaxed) {
+            let msg = match self.ipcon.receive_msg_timeout(0, BRIDGE_RECEIVE_TIMEOUT_US) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    if *e.current_context() == IpconError::Cancelled {
+                        break;
+                    }
+
+                    if *e.current_context() != IpconError::SysErrorTimeOut {
+                        jwarn!("Bridge receive failed: {:?}", e);
+                    }
+                    continue;
+                }
+            };
+
+            if !self.config.forwarded(&msg) {
+                continue;
+            }
+
+            let route = match &msg {
+                IpconMsg::IpconMsgUser(body) if body.group.is_none() => {
+                    self.routes().get(&body.peer).copied()
+                }
+                _ => None,
+            };
+
+            let data = match Frame::Message(msg).encode() {
+                Ok(data) => Arc::new(data),
+                Err(e) => {
+                    jwarn!("Failed to encode message: {:?}", e);
+                    continue;
+                }
+            };
+
+            match route {
+                Some(id) => self.send_to(id, data),
+                None => self.broadcast(data),
+            }
+        }
+    }
+
+    /// Serve the clients until stop() is called.
+    pub fn run(&self) -> Result<(), IpconError> {
+        std::thread::scope(|s| {
+            s.spawn(|| self.forward());
+
+            for stream in self.listener.incoming() {
+                if self.stop.load(Ordering::Relaxed) {
+                    break;
+                }
+
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        jwarn!("Failed to accept client: {}", e);
+                        continue;
+                    }
+                };
+
+                match self.authorized(&stream) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        let mut stream = stream;
+                        let _ = write_frame(
+                            &mut stream,
+                            &Frame::Error {
+                                error: IpconError::SysErrorPermission,
+                                message: "Access denied".to_owned(),
+                            },
+                        );
+                        continue;
+                    }
+                    Err(e) => {
+                        jwarn!("{:?}", e);
+                        continue;
+                    }
+                }
+
+                let (writer, control) = match stream.try_clone().and_then(|w| {
+                    let c = stream.try_clone()?;
+                    Ok((w, c))
+                }) {
+                    Ok(clones) => clones,
+                    Err(e) => {
+                        jwarn!("Failed to clone client stream: {}", e);
+                        continue;
+                    }
+                };
+
+                let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                let (queue, frames) = sync_channel(CLIENT_QUEUE_LEN);
+                self.clients().insert(
+                    id,
+                    Client {
+                        queue,
+                        stream: control,
+                    },
+                );
+                jdebug!("Client {} connected", id);
+
+                s.spawn(move || self.write_client(id, writer, frames));
+                s.spawn(move || self.serve_client(id, stream));
+            }
+
+            /* Wake up the clients blocked in read. */
+            for client in self.clients().values() {
+                let _ = client.stream.shutdown(std::net::Shutdown::Both);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Stop run().
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+
+        /* Wake up the accept loop. */
+        let _ = UnixStream::connect(&self.path);
+    }
+}
+
+impl Drop for UdsBridge {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Client of a UdsBridge.
+pub struct UdsClient {
+    stream: UnixStream,
+}
+
+impl UdsClient {
+    /// Connect to the bridge listening on path.
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<UdsClient, IpconError> {
+        let stream = UnixStream::connect(path.as_ref())
+            .map_err(io_error)
+            .attach_printable(format!("Failed to connect {}", path.as_ref().display()))?;
+
+        Ok(UdsClient { stream })
+    }
+
+    /// Send an unicast message to peer through the bridge.
+    pub fn send_unicast_msg(&self, peer: &str, buf: &[u8]) -> Result<(), IpconError> {
+        write_frame(
+            &mut &self.stream,
+            &Frame::Unicast {
+                peer: peer.to_owned(),
+                buf: buf.to_vec(),
+            },
+        )
+    }
+
+    /// Send a multicast message to a group registered by the bridge.
+    pub fn send_multicast(&self, group: &str, buf: &[u8], sync: bool) -> Result<(), IpconError> {
+        write_frame(
+            &mut &self.stream,
+            &Frame::Multicast {
+                group: group.to_owned(),
+                buf: buf.to_vec(),
+                sync,
+            },
+        )
+    }
+
+    /// Receive a message forwarded by the bridge.
+    /// A send request which failed in the bridge is reported as an error by this function.
+    pub fn receive_msg(&self) -> Result<IpconMsg, IpconError> {
+        match read_frame(&mut &self.stream)? {
+            Some(Frame::Message(msg)) => Ok(msg),
+            Some(Frame::Error { error, message }) => {
+                Err(Report::new(error)).attach_printable(message)
+            }
+            Some(f) => Err(Report::new(IpconError::InvalidData))
+                .attach_printable(format!("Unexpected frame from bridge: {:?}", f)),
+            None => Err(Report::new(IpconError::SystemErrorNotExist))
+                .attach_printable("Bridge closed the connection"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipcon::IPF_DEFAULT;
+    use crate::ipcon_loopback::LoopbackBus;
+    use std::time::Duration;
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ipcon-uds-{}-{}.sock", std::process::id(), name))
+    }
+
+    #[test]
+    fn only_stale_sockets_are_removed() {
+        let bus = LoopbackBus::new();
+        let path = socket_path("file");
+        std::fs::write(&path, b"data").unwrap();
+
+        let ipcon = bus.peer(Some("bridge"), Some(IPF_DEFAULT)).unwrap();
+        assert!(UdsBridge::new(ipcon, &path, UdsBridgeConfig::default()).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+        std::fs::remove_file(&path).unwrap();
+
+        let path = socket_path("stale");
+        drop(UnixListener::bind(&path).unwrap());
+        let ipcon = bus.peer(Some("bridge"), Some(IPF_DEFAULT)).unwrap();
+        let bridge = UdsBridge::new(ipcon, &path, UdsBridgeConfig::default()).unwrap();
+        drop(bridge);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn replies_go_to_the_requesting_client() {
+        let bus = LoopbackBus::new();
+        let server = bus.peer(Some("server"), Some(IPF_DEFAULT)).unwrap();
+        let path = socket_path("routes");
+        let bridge = UdsBridge::new(
+            bus.peer(Some("bridge"), Some(IPF_DEFAULT)).unwrap(),
+            &path,
+            UdsBridgeConfig::default(),
+        )
+        .unwrap();
+
+        /* Stop the bridge even if an assertion fails, the scope would never end otherwise. */
+        struct Stop<'a>(&'a UdsBridge);
+        impl Drop for Stop<'_> {
+            fn drop(&mut self) {
+                self.0.stop();
+            }
+        }
+
+        std::thread::scope(|s| {
+            s.spawn(|| bridge.run().unwrap());
+            let _stop = Stop(&bridge);
+
+            let clients: Vec<UdsClient> =
+                (0..2).map(|_| UdsClient::connect(&path).unwrap()).collect();
+            for c in &clients {
+                c.stream
+                    .set_read_timeout(Some(Duration::from_millis(500)))
+                    .unwrap();
+            }
+
+            for (i, c) in clients.iter().enumerate() {
+                let request = [i as u8];
+                c.send_unicast_msg("server", &request).unwrap();
+
+                match server.receive_msg_timeout(5, 0).unwrap() {
+                    IpconMsg::IpconMsgUser(body) => assert_eq!(body.buf, request),
+                    m => panic!("Unexpected message {:?}", m),
+                }
+                server.send_unicast_msg("bridge", &request).unwrap();
+
+                match c.receive_msg().unwrap() {
+                    IpconMsg::IpconMsgUser(body) => assert_eq!(body.buf, request),
+                    m => panic!("Unexpected message {:?}", m),
+                }
+            }
+
+            /* Neither client got the reply of the other one. */
+            for c in &clients {
+                assert!(c.receive_msg().is_err());
+            }
+
+            /* A send failure is reported to the sender only. */
+            clients[0].send_unicast_msg("nobody", b"").unwrap();
+            assert_eq!(
+                *clients[0].receive_msg().unwrap_err().current_context(),
+                IpconError::SystemErrorNotExist
+            );
+        });
+    }
+}
@@ -17,3 +17,58 @@ pub mod ipcon_async;
 pub mod ipcon_msg;
 
 pub mod ipcon_error;
+
+pub mod ipcon_loopback;
+
+pub mod ipcon_name;
+
+pub mod ipcon_state;
+
+pub mod ipcon_seq;
+
+pub mod ipcon_flow;
+
+pub mod ipcon_sched;
+
+pub mod ipcon_trace;
+
+pub mod ipcon_stats;
+
+pub mod ipcon_capture;
+
+pub mod ipcon_replay;
+
+pub mod ipcon_shutdown;
+
+#[cfg(feature = "serde")]
+pub mod ipcon_serde;
+
+pub mod ipcon_frame;
+
+pub mod ipcon_uds;
+
+pub mod ipcon_gateway;
+
+#[cfg(feature = "dbus")]
+pub mod ipcon_dbus;
+
+#[cfg(feature = "tower")]
+pub mod ipcon_tower;
+
+#[cfg(feature = "async")]
+pub mod ipcon_server;
+
+#[cfg(feature = "mio")]
+pub mod ipcon_mio;
+
+#[cfg(feature = "calloop")]
+pub mod ipcon_calloop;
+
+#[cfg(feature = "glib")]
+pub mod ipcon_glib;
+
+#[cfg(feature = "systemd")]
+pub mod ipcon_systemd;
+
+#[cfg(feature = "config")]
+pub mod ipcon_config;
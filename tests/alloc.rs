@@ -0,0 +1,79 @@
+//! Sending through the handles of Ipcon::unicast_target() and Ipcon::group_publisher() must not
+//! allocate once the destination has been seen, statistics and metrics included.
+
+use ipcon_sys::ipcon::{Ipcon, IPF_DEFAULT};
+use ipcon_sys::ipcon_loopback::LoopbackBus;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|c| c.set(c.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|c| c.set(c.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/* Number of allocations made by the current thread while running f. */
+fn allocations<F: FnOnce()>(f: F) -> usize {
+    let before = ALLOCATIONS.with(|c| c.get());
+    f();
+    ALLOCATIONS.with(|c| c.get()) - before
+}
+
+fn drain(ipcon: &Ipcon, count: usize) {
+    for _ in 0..count {
+        ipcon.receive_msg_timeout(1, 0).unwrap();
+    }
+}
+
+#[test]
+fn handles_do_not_allocate() {
+    const ROUNDS: usize = 32;
+    const PAYLOAD: &[u8] = b"0123456789abcdef";
+
+    let bus = LoopbackBus::new();
+    let sender = bus.peer(Some("sender"), Some(IPF_DEFAULT)).unwrap();
+    let receiver = bus.peer(Some("receiver"), Some(IPF_DEFAULT)).unwrap();
+    sender.register_group("group").unwrap();
+    receiver.join_group("sender", "group").unwrap();
+
+    let target = sender.unicast_target("receiver").unwrap();
+    let publisher = sender.group_publisher("group").unwrap();
+
+    /* Register the statistics and the metrics of the destinations, and grow the receive queue. */
+    for _ in 0..ROUNDS {
+        target.send(PAYLOAD).unwrap();
+        publisher.send(PAYLOAD, false).unwrap();
+    }
+    drain(&receiver, 2 * ROUNDS);
+
+    let n = allocations(|| {
+        for _ in 0..ROUNDS {
+            target.send(PAYLOAD).unwrap();
+            publisher.send(PAYLOAD, false).unwrap();
+        }
+    });
+    assert_eq!(n, 0);
+
+    drain(&receiver, 2 * ROUNDS);
+    let stats = sender.stats();
+    assert_eq!(stats.total.sent_msgs, 4 * ROUNDS as u64);
+}
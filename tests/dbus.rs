@@ -0,0 +1,264 @@
+//! D-Bus bridge on a private dbus-daemon, the IPCON side being a loopback bus.
+//! The tests are skipped if dbus-daemon is not installed.
+#![cfg(feature = "dbus")]
+
+use ipcon_sys::ipcon::{Ipcon, IPF_DEFAULT};
+use ipcon_sys::ipcon_dbus::{
+    DbusBridge, DbusBridgeConfig, DbusMethod, DbusSignalRoute, DBUS_INTERFACE,
+};
+use ipcon_sys::ipcon_loopback::LoopbackBus;
+use ipcon_sys::ipcon_msg::IpconMsg;
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use zbus::blocking::{connection, Connection, MessageIterator};
+use zbus::MatchRule;
+
+const SERVICE: &str = "org.ipcon.Bridge";
+const PATH: &str = "/org/ipcon/Bridge";
+
+struct Daemon {
+    child: Child,
+    address: String,
+}
+
+impl Daemon {
+    fn spawn() -> Option<Daemon> {
+        let mut child = match Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => {
+                eprintln!("dbus-daemon not available, test skipped: {}", e);
+                return None;
+            }
+        };
+
+        let mut address = String::new();
+        BufReader::new(child.stdout.take()?)
+            .read_line(&mut address)
+            .ok()?;
+
+        Some(Daemon {
+            child,
+            address: address.trim().to_owned(),
+        })
+    }
+
+    fn connect(&self) -> Connection {
+        connection::Builder::address(self.address.as_str())
+            .unwrap()
+            .build()
+            .unwrap()
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn receive(ipcon: &Ipcon) -> Option<(String, Option<String>, Vec<u8>)> {
+    match ipcon.receive_msg_timeout(0, 200_000) {
+        Ok(IpconMsg::IpconMsgUser(body)) => Some((body.peer, body.group, body.buf)),
+        Ok(m) => panic!("Unexpected message {:?}", m),
+        Err(_) => None,
+    }
+}
+
+macro_rules! call {
+    ($conn:expr, $method:expr, $body:expr) => {
+        $conn.call_method(Some(SERVICE), PATH, Some(DBUS_INTERFACE), $method, $body)
+    };
+}
+
+#[test]
+fn bridge_methods_signals_and_routes() {
+    let daemon = match Daemon::spawn() {
+        Some(d) => d,
+        None => return,
+    };
+
+    let bus = LoopbackBus::new();
+    let server = bus.peer(Some("server"), Some(IPF_DEFAULT)).unwrap();
+    let bridge = DbusBridge::new(
+        bus.peer(Some("bridge"), Some(IPF_DEFAULT)).unwrap(),
+        DbusBridgeConfig {
+            address: Some(daemon.address.clone()),
+            groups: vec!["news".to_owned()],
+            signals: vec![DbusSignalRoute {
+                interface: "org.example.Feed".to_owned(),
+                member: Some("Update".to_owned()),
+                group: "feed".to_owned(),
+            }],
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let client = daemon.connect();
+    let messages = MessageIterator::for_match_rule(
+        MatchRule::builder()
+            .msg_type(zbus::message::Type::Signal)
+            .interface(DBUS_INTERFACE)
+            .unwrap()
+            .member("Message")
+            .unwrap()
+            .build(),
+        &client,
+        None,
+    )
+    .unwrap();
+
+    std::thread::scope(|s| {
+        s.spawn(|| bridge.run().unwrap());
+
+        /* Send */
+        call!(client, "Send", &("server", b"ping".to_vec())).unwrap();
+        assert_eq!(
+            receive(&server),
+            Some(("bridge".to_owned(), None, b"ping".to_vec()))
+        );
+        assert!(call!(client, "Send", &("nobody", b"ping".to_vec())).is_err());
+
+        /* IsPeerPresent */
+        for (peer, present) in [("server", true), ("nobody", false)] {
+            let reply = call!(client, "IsPeerPresent", &(peer,)).unwrap();
+            assert_eq!(reply.body().deserialize::<bool>().unwrap(), present);
+        }
+
+        /* Multicast */
+        server.join_group("bridge", "news").unwrap();
+        call!(client, "Multicast", &("news", b"hello".to_vec(), false)).unwrap();
+        assert_eq!(
+            receive(&server),
+            Some((
+                "bridge".to_owned(),
+                Some("news".to_owned()),
+                b"hello".to_vec()
+            ))
+        );
+        assert!(call!(client, "Multicast", &("other", b"".to_vec(), false)).is_err());
+
+        /* Message signal */
+        server.send_unicast_msg("bridge", b"pong").unwrap();
+        let signal = messages.into_iter().next().unwrap().unwrap();
+        let (peer, payload): (String, Vec<u8>) = signal.body().deserialize().unwrap();
+        assert_eq!(
+            (peer.as_str(), payload.as_slice()),
+            ("server", &b"pong"[..])
+        );
+
+        /* Signal route, emitted until the route is subscribed. */
+        server.join_group("bridge", "feed").unwrap();
+        let routed = (0..50).find_map(|_| {
+            client
+                .emit_signal(
+                    None::<&str>,
+                    "/org/example/Feed",
+                    "org.example.Feed",
+                    "Update",
+                    &(b"update".to_vec(),),
+                )
+                .unwrap();
+            receive(&server)
+        });
+        assert_eq!(
+            routed,
+            Some((
+                "bridge".to_owned(),
+                Some("feed".to_owned()),
+                b"update".to_vec()
+            ))
+        );
+
+        bridge.stop();
+    });
+}
+
+/* Method handling the messages received by the bridge, slowly. */
+struct SlowHandler;
+
+#[zbus::interface(name = "org.example.Handler")]
+impl SlowHandler {
+    fn handle(&self, _peer: &str, payload: Vec<u8>) -> Vec<u8> {
+        std::thread::sleep(Duration::from_secs(1));
+        payload.into_iter().rev().collect()
+    }
+}
+
+#[test]
+fn slow_method_doesnt_hold_back_signals() {
+    let daemon = match Daemon::spawn() {
+        Some(d) => d,
+        None => return,
+    };
+
+    let _handler = connection::Builder::address(daemon.address.as_str())
+        .unwrap()
+        .serve_at("/org/example/Handler", SlowHandler)
+        .unwrap()
+        .name("org.example.Handler")
+        .unwrap()
+        .build()
+        .unwrap();
+
+    let bus = LoopbackBus::new();
+    let server = bus.peer(Some("server"), Some(IPF_DEFAULT)).unwrap();
+    server.register_group("news").unwrap();
+    let bridge = DbusBridge::new(
+        bus.peer(Some("bridge"), Some(IPF_DEFAULT)).unwrap(),
+        DbusBridgeConfig {
+            address: Some(daemon.address.clone()),
+            subscriptions: vec![("server".to_owned(), "news".to_owned())],
+            method: Some(DbusMethod {
+                destination: "org.example.Handler".to_owned(),
+                path: "/org/example/Handler".to_owned(),
+                interface: "org.example.Handler".to_owned(),
+                member: "Handle".to_owned(),
+            }),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let client = daemon.connect();
+    let group_messages = MessageIterator::for_match_rule(
+        MatchRule::builder()
+            .msg_type(zbus::message::Type::Signal)
+            .interface(DBUS_INTERFACE)
+            .unwrap()
+            .member("GroupMessage")
+            .unwrap()
+            .build(),
+        &client,
+        None,
+    )
+    .unwrap();
+
+    std::thread::scope(|s| {
+        s.spawn(|| bridge.run().unwrap());
+
+        /* The group message is signaled while the method call is in progress. */
+        let start = Instant::now();
+        server.send_unicast_msg("bridge", b"ping").unwrap();
+        server.send_multicast("news", b"hello", false).unwrap();
+        let signal = group_messages.into_iter().next().unwrap().unwrap();
+        assert!(start.elapsed() < Duration::from_millis(800));
+        let (_, group, payload): (String, String, Vec<u8>) = signal.body().deserialize().unwrap();
+        assert_eq!(
+            (group.as_str(), payload.as_slice()),
+            ("news", &b"hello"[..])
+        );
+
+        let reply = (0..25).find_map(|_| receive(&server));
+        assert_eq!(reply, Some(("bridge".to_owned(), None, b"gnip".to_vec())));
+
+        bridge.stop();
+    });
+}
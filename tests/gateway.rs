@@ -0,0 +1,228 @@
+//! Two gateways linked over localhost, each host being a loopback bus.
+
+use error_stack::Result;
+use ipcon_sys::ipcon::{Ipcon, Transport, IPF_DEFAULT};
+use ipcon_sys::ipcon_error::IpconError;
+use ipcon_sys::ipcon_gateway::{Gateway, GatewayConfig};
+use ipcon_sys::ipcon_loopback::LoopbackBus;
+use ipcon_sys::ipcon_msg::IpconMsg;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+fn wait_for<F: FnMut() -> bool>(mut f: F) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !f() {
+        assert!(Instant::now() < deadline, "Timed out");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn receive(ipcon: &Ipcon) -> (String, Option<String>, Vec<u8>) {
+    loop {
+        match ipcon.receive_msg_timeout(5, 0).unwrap() {
+            IpconMsg::IpconMsgUser(body) => return (body.peer, body.group, body.buf),
+            IpconMsg::IpconMsgKevent(_) => continue,
+            m => panic!("Unexpected message {:?}", m),
+        }
+    }
+}
+
+fn peer(bus: &LoopbackBus, name: &str) -> Result<Ipcon, IpconError> {
+    bus.peer(Some(name), Some(IPF_DEFAULT))
+}
+
+/* Host b exports its peer server and the group news, host a connects to it. */
+fn link(
+    a: &LoopbackBus,
+    b: &LoopbackBus,
+    imports: &[&str],
+    requester_ttl: Duration,
+) -> (Gateway, Gateway) {
+    let server = Gateway::listen(
+        "127.0.0.1:0",
+        GatewayConfig {
+            peers: vec!["server".to_owned()],
+            groups: vec![("server".to_owned(), "news".to_owned())],
+            imports: imports.iter().map(|i| i.to_string()).collect(),
+            requester_ttl,
+            transport: Transport::Loopback(b.clone()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let client = Gateway::connect(
+        &server.local_addr().unwrap().to_string(),
+        GatewayConfig {
+            transport: Transport::Loopback(a.clone()),
+            reconnect_interval: Duration::from_millis(50),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    wait_for(|| client.is_connected() && server.is_connected());
+    (client, server)
+}
+
+#[test]
+fn unicast_and_multicast_across_gateways() {
+    let (a, b) = (LoopbackBus::new(), LoopbackBus::new());
+    let server = peer(&b, "server").unwrap();
+    server.register_group("news").unwrap();
+    let client = peer(&a, "client").unwrap();
+
+    let (gw_a, _gw_b) = link(&a, &b, &["*"], Duration::from_secs(60));
+    wait_for(|| client.is_group_present("remote.server", "news"));
+    assert_eq!(gw_a.proxies(), ["server"]);
+
+    client.send_unicast_msg("remote.server", b"ping").unwrap();
+    assert_eq!(
+        receive(&server),
+        ("remote.client".to_owned(), None, b"ping".to_vec())
+    );
+
+    server.send_unicast_msg("remote.client", b"pong").unwrap();
+    assert_eq!(
+        receive(&client),
+        ("remote.server".to_owned(), None, b"pong".to_vec())
+    );
+
+    client.join_group("remote.server", "news").unwrap();
+    server.send_multicast("news", b"hello", false).unwrap();
+    assert_eq!(
+        receive(&client),
+        (
+            "remote.server".to_owned(),
+            Some("news".to_owned()),
+            b"hello".to_vec()
+        )
+    );
+
+    /* The proxies follow the exported peers. */
+    drop(server);
+    wait_for(|| !client.is_peer_present("remote.server"));
+}
+
+#[test]
+fn remote_peers_not_imported_are_refused() {
+    let (a, b) = (LoopbackBus::new(), LoopbackBus::new());
+    let server = peer(&b, "server").unwrap();
+    server.register_group("news").unwrap();
+    let client = peer(&a, "client").unwrap();
+    let trusted = peer(&a, "trusted.client").unwrap();
+
+    let (_gw_a, _gw_b) = link(&a, &b, &["trusted.*"], Duration::from_secs(60));
+    wait_for(|| client.is_peer_present("remote.server"));
+
+    client.send_unicast_msg("remote.server", b"ping").unwrap();
+    trusted.send_unicast_msg("remote.server", b"ping").unwrap();
+    assert_eq!(
+        receive(&server),
+        ("remote.trusted.client".to_owned(), None, b"ping".to_vec())
+    );
+    assert!(server.receive_msg_timeout(0, 200_000).is_err());
+    assert!(!server.is_peer_present("remote.client"));
+}
+
+#[test]
+fn local_names_are_not_replaced() {
+    let (a, b) = (LoopbackBus::new(), LoopbackBus::new());
+    let server = peer(&b, "server").unwrap();
+    server.register_group("news").unwrap();
+    /* A local peer already uses the name of the proxy. */
+    let squatter = peer(&a, "remote.server").unwrap();
+
+    let (gw_a, _gw_b) = link(&a, &b, &["*"], Duration::from_secs(60));
+    std::thread::sleep(Duration::from_millis(200));
+    assert!(gw_a.proxies().is_empty());
+    assert!(!squatter.is_group_present("remote.server", "news"));
+}
+
+#[test]
+fn idle_requester_proxies_expire() {
+    let (a, b) = (LoopbackBus::new(), LoopbackBus::new());
+    let server = peer(&b, "server").unwrap();
+    let client = peer(&a, "client").unwrap();
+
+    let (_gw_a, gw_b) = link(&a, &b, &["*"], Duration::from_millis(100));
+    wait_for(|| client.is_peer_present("remote.server"));
+
+    client.send_unicast_msg("remote.server", b"ping").unwrap();
+    receive(&server);
+    assert!(server.is_peer_present("remote.client"));
+
+    wait_for(|| !server.is_peer_present("remote.client"));
+    assert!(gw_b.proxies().is_empty());
+}
+
+fn exporting(b: &LoopbackBus, link_timeout: Duration) -> Gateway {
+    Gateway::listen(
+        "127.0.0.1:0",
+        GatewayConfig {
+            peers: vec!["server".to_owned()],
+            heartbeat_interval: Duration::from_millis(50),
+            link_timeout,
+            transport: Transport::Loopback(b.clone()),
+            ..Default::default()
+        },
+    )
+    .unwrap()
+}
+
+fn connecting(a: &LoopbackBus, server: &Gateway) -> Gateway {
+    Gateway::connect(
+        &server.local_addr().unwrap().to_string(),
+        GatewayConfig {
+            heartbeat_interval: Duration::from_millis(50),
+            link_timeout: Duration::from_millis(300),
+            reconnect_interval: Duration::from_millis(50),
+            transport: Transport::Loopback(a.clone()),
+            ..Default::default()
+        },
+    )
+    .unwrap()
+}
+
+#[test]
+fn idle_link_is_kept_by_heartbeats() {
+    let (a, b) = (LoopbackBus::new(), LoopbackBus::new());
+    let server = peer(&b, "server").unwrap();
+    let client = peer(&a, "client").unwrap();
+
+    let gw_b = exporting(&b, Duration::from_millis(300));
+    let gw_a = connecting(&a, &gw_b);
+    wait_for(|| client.is_peer_present("remote.server"));
+
+    std::thread::sleep(Duration::from_secs(1));
+    assert!(gw_a.is_connected() && gw_b.is_connected());
+
+    client.send_unicast_msg("remote.server", b"ping").unwrap();
+    assert_eq!(receive(&server).2, b"ping");
+}
+
+#[test]
+fn silent_link_times_out() {
+    let b = LoopbackBus::new();
+    let gw_b = exporting(&b, Duration::from_millis(300));
+
+    /* A half-open link never sends anything. */
+    let _silent = TcpStream::connect(gw_b.local_addr().unwrap()).unwrap();
+    wait_for(|| gw_b.is_connected());
+    wait_for(|| !gw_b.is_connected());
+}
+
+#[test]
+fn new_link_replaces_the_stale_one() {
+    let (a, b) = (LoopbackBus::new(), LoopbackBus::new());
+    let _server = peer(&b, "server").unwrap();
+    let client = peer(&a, "client").unwrap();
+    let gw_b = exporting(&b, Duration::from_secs(60));
+
+    let _stale = TcpStream::connect(gw_b.local_addr().unwrap()).unwrap();
+    wait_for(|| gw_b.is_connected());
+
+    let gw_a = connecting(&a, &gw_b);
+    wait_for(|| client.is_peer_present("remote.server"));
+    assert!(gw_a.is_connected());
+}
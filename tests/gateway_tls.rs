@@ -0,0 +1,169 @@
+//! Two gateways linked with TLS over localhost, with a self-signed certificate.
+#![cfg(feature = "tls")]
+
+use ipcon_sys::ipcon::{Ipcon, Transport, IPF_DEFAULT};
+use ipcon_sys::ipcon_gateway::{Gateway, GatewayConfig};
+use ipcon_sys::ipcon_loopback::LoopbackBus;
+use ipcon_sys::ipcon_msg::IpconMsg;
+use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+fn wait_for<F: FnMut() -> bool>(mut f: F) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !f() {
+        assert!(Instant::now() < deadline, "Timed out");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn receive(ipcon: &Ipcon) -> (String, Vec<u8>) {
+    loop {
+        match ipcon.receive_msg_timeout(5, 0).unwrap() {
+            IpconMsg::IpconMsgUser(body) => return (body.peer, body.buf),
+            IpconMsg::IpconMsgKevent(_) => continue,
+            m => panic!("Unexpected message {:?}", m),
+        }
+    }
+}
+
+fn tls_configs() -> (Arc<rustls::ServerConfig>, Arc<rustls::ClientConfig>) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let cert = certified.cert.der().clone();
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+
+    let server = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.clone()], key)
+        .unwrap();
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(cert).unwrap();
+    let client = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    (Arc::new(server), Arc::new(client))
+}
+
+/* Host b exports its peer server, host a connects to it with TLS. */
+fn link(a: &LoopbackBus, b: &LoopbackBus, link_timeout: Duration) -> (Gateway, Gateway) {
+    let (server_tls, client_tls) = tls_configs();
+
+    let server = Gateway::listen_tls(
+        "127.0.0.1:0",
+        server_tls,
+        GatewayConfig {
+            peers: vec!["server".to_owned()],
+            link_timeout,
+            transport: Transport::Loopback(b.clone()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let client = Gateway::connect_tls(
+        &server.local_addr().unwrap().to_string(),
+        "localhost",
+        client_tls,
+        GatewayConfig {
+            transport: Transport::Loopback(a.clone()),
+            reconnect_interval: Duration::from_millis(50),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    (client, server)
+}
+
+#[test]
+fn messages_cross_a_tls_link() {
+    let (a, b) = (LoopbackBus::new(), LoopbackBus::new());
+    let server = b.peer(Some("server"), Some(IPF_DEFAULT)).unwrap();
+    let client = a.peer(Some("client"), Some(IPF_DEFAULT)).unwrap();
+
+    let (_gw_a, _gw_b) = link(&a, &b, Duration::from_secs(15));
+    wait_for(|| client.is_peer_present("remote.server"));
+
+    client.send_unicast_msg("remote.server", b"ping").unwrap();
+    assert_eq!(
+        receive(&server),
+        ("remote.client".to_owned(), b"ping".to_vec())
+    );
+
+    /* Both directions are flooded at the same time, a blocked write must not keep the link
+     * from reading. */
+    const COUNT: usize = 4000;
+    let payload = vec![0x5a_u8; 2000];
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            for _ in 0..COUNT {
+                client.send_unicast_msg("remote.server", &payload).unwrap();
+            }
+        });
+        s.spawn(|| {
+            for _ in 0..COUNT {
+                server.send_unicast_msg("remote.client", &payload).unwrap();
+            }
+        });
+    });
+
+    for _ in 0..COUNT {
+        assert_eq!(receive(&server).1, payload);
+        assert_eq!(receive(&client).1, payload);
+    }
+}
+
+#[test]
+fn unfinished_handshake_is_replaced() {
+    let (a, b) = (LoopbackBus::new(), LoopbackBus::new());
+    let _server = b.peer(Some("server"), Some(IPF_DEFAULT)).unwrap();
+    let client = a.peer(Some("client"), Some(IPF_DEFAULT)).unwrap();
+
+    let (server_tls, client_tls) = tls_configs();
+    let gw_b = Gateway::listen_tls(
+        "127.0.0.1:0",
+        server_tls,
+        GatewayConfig {
+            peers: vec!["server".to_owned()],
+            transport: Transport::Loopback(b.clone()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    /* A client which never starts the handshake. */
+    let _stale = TcpStream::connect(gw_b.local_addr().unwrap()).unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+
+    let _gw_a = Gateway::connect_tls(
+        &gw_b.local_addr().unwrap().to_string(),
+        "localhost",
+        client_tls,
+        GatewayConfig {
+            transport: Transport::Loopback(a.clone()),
+            reconnect_interval: Duration::from_millis(50),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    wait_for(|| client.is_peer_present("remote.server"));
+}
+
+#[test]
+fn unfinished_handshake_times_out() {
+    let (a, b) = (LoopbackBus::new(), LoopbackBus::new());
+    let (_gw_a, gw_b) = link(&a, &b, Duration::from_millis(200));
+    wait_for(|| gw_b.is_connected());
+    drop(_gw_a);
+    wait_for(|| !gw_b.is_connected());
+
+    /* The gateway stops while a handshake is pending. */
+    let _stale = TcpStream::connect(gw_b.local_addr().unwrap()).unwrap();
+    std::thread::sleep(Duration::from_millis(500));
+    let start = Instant::now();
+    drop(gw_b);
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
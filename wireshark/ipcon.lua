@@ -0,0 +1,112 @@
+-- Wireshark dissector of IPCON capture files written by ipcon-sys.
+--
+-- Copy this file to the Wireshark plugin directory (for example ~/.local/lib/wireshark/plugins)
+-- and open a pcapng file recorded with Ipcon::capture_to_file().
+--
+-- The records use the LINKTYPE_USER0 link type, see src/ipcon_capture.rs for the layout.
+
+local ipcon = Proto("ipcon", "IPC Over Netlink")
+
+local directions = { [0] = "Sent", [1] = "Received" }
+local msg_types = { [0] = "Normal", [1] = "Group", [2] = "Kevent", [3] = "Invalid" }
+local kevent_types = {
+    [0] = "Peer added",
+    [1] = "Peer removed",
+    [2] = "Group added",
+    [3] = "Group removed",
+}
+
+local f_version = ProtoField.uint8("ipcon.version", "Version")
+local f_direction = ProtoField.uint8("ipcon.direction", "Direction", base.DEC, directions)
+local f_msg_type = ProtoField.uint8("ipcon.type", "Message type", base.DEC, msg_types)
+local f_peer = ProtoField.stringz("ipcon.peer", "Peer")
+local f_group = ProtoField.stringz("ipcon.group", "Group")
+local f_len = ProtoField.uint32("ipcon.len", "Payload length")
+local f_payload = ProtoField.bytes("ipcon.payload", "Payload")
+local f_ke_type = ProtoField.int32("ipcon.kevent.type", "Kevent type", base.DEC, kevent_types)
+local f_ke_peer = ProtoField.stringz("ipcon.kevent.peer", "Kevent peer")
+local f_ke_group = ProtoField.stringz("ipcon.kevent.group", "Kevent group")
+
+ipcon.fields = {
+    f_version, f_direction, f_msg_type, f_peer, f_group, f_len, f_payload,
+    f_ke_type, f_ke_peer, f_ke_group,
+}
+
+local NAME_LEN = 32
+local HEADER_LEN = 8 + 2 * NAME_LEN
+
+local function dissect_kevent(buf, tree)
+    if buf:len() < 4 + 2 * NAME_LEN then
+        return
+    end
+
+    local ke_type = buf(0, 4):le_int()
+    local subtree = tree:add(ipcon, buf, "Kernel event")
+    subtree:add_le(f_ke_type, buf(0, 4))
+
+    if ke_type == 0 or ke_type == 1 then
+        subtree:add(f_ke_peer, buf(4, NAME_LEN))
+        return kevent_types[ke_type] .. " " .. buf(4, NAME_LEN):stringz()
+    end
+
+    -- struct ipcon_kevent_group: group name first, then peer name.
+    subtree:add(f_ke_group, buf(4, NAME_LEN))
+    subtree:add(f_ke_peer, buf(4 + NAME_LEN, NAME_LEN))
+    return (kevent_types[ke_type] or "Unknown") .. " "
+        .. buf(4, NAME_LEN):stringz() .. "@" .. buf(4 + NAME_LEN, NAME_LEN):stringz()
+end
+
+function ipcon.dissector(buf, pinfo, tree)
+    if buf:len() < HEADER_LEN then
+        return 0
+    end
+
+    pinfo.cols.protocol = "IPCON"
+
+    local direction = buf(1, 1):uint()
+    local msg_type = buf(2, 1):uint()
+    local peer = buf(4, NAME_LEN):stringz()
+    local group = buf(4 + NAME_LEN, NAME_LEN):stringz()
+    local len = buf(4 + 2 * NAME_LEN, 4):uint()
+
+    local subtree = tree:add(ipcon, buf(0, HEADER_LEN), "IPCON message")
+    subtree:add(f_version, buf(0, 1))
+    subtree:add(f_direction, buf(1, 1))
+    subtree:add(f_msg_type, buf(2, 1))
+    subtree:add(f_peer, buf(4, NAME_LEN))
+    subtree:add(f_group, buf(4 + NAME_LEN, NAME_LEN))
+    subtree:add(f_len, buf(4 + 2 * NAME_LEN, 4))
+
+    if direction == 0 then
+        pinfo.cols.src = "local"
+        pinfo.cols.dst = peer
+    else
+        pinfo.cols.src = peer
+        pinfo.cols.dst = "local"
+    end
+
+    local info = (msg_types[msg_type] or "Unknown")
+    if group ~= "" then
+        info = info .. " " .. group .. "@" .. peer
+    end
+
+    local avail = math.min(len, buf:len() - HEADER_LEN)
+    if avail > 0 then
+        local payload = buf(HEADER_LEN, avail)
+        subtree:add(f_payload, payload)
+
+        if msg_type == 2 then
+            local ke = dissect_kevent(payload:tvb(), tree)
+            if ke then
+                info = ke
+            end
+        else
+            Dissector.get("data"):call(payload:tvb(), pinfo, tree)
+        end
+    end
+
+    pinfo.cols.info = info
+    return buf:len()
+end
+
+DissectorTable.get("wtap_encap"):add(wtap.USER0, ipcon)